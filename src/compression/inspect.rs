@@ -0,0 +1,349 @@
+// Field-by-field annotation of a compressed block, for debugging and
+// teaching
+//
+// Walks a block's stream the way decoding does (decode_stream in
+// storage/mod.rs), recording for each field where it starts, how many
+// bits it takes, what it is and what it decodes to. Field names:
+//   header              block start time (64)
+//   first_delta         first timestamp minus the header (14)
+//   first_delta_wide    the same in 64 bits, after an all-ones first_delta
+//   small_int_flag      IntegerSmall hint: '1' if a 16-bit integer follows
+//   first_value         the first value (64 bits, or 16 as an integer)
+//   dod_prefix, dod     timestamp bucket ('0', '10', '110', '1110' or
+//                       '1111') and payload, decoded to the delta-of-delta
+//   delta_prefix, delta the same under the Delta timestamp codec
+//   xor_control         '0' (value repeats), '10' (previous window) or
+//                       '11' (new window)
+//   leading_zeros       a new window's leading zeros (5)
+//   meaningful_length   its meaningful bit count (6; 0 stands for 64)
+//   meaningful_bits     the XOR's meaningful bits, decoded to the value
+//   int_flag            IntegerDelta: '1' if a difference follows, '0'
+//                       if the 64-bit float does
+//   int_delta_prefix, int_delta   that difference, in timestamp buckets
+//   raw_value           a value stored as its 64 bits
+
+use super::BitReader;
+use super::timestamp::{
+    BUCKET_WIDTHS, FIRST_DELTA_BITS, FIRST_DELTA_ESCAPE, TimestampCodec, bucket_value,
+};
+use super::value::{ValueCodec, ValueHint, exact_integer};
+use alloc::vec::Vec;
+
+/// One field of a compressed block
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EncodedField {
+    pub offset: usize,      // Bit offset from the start of the stream
+    pub bits: u8,           // Length in bits
+    pub name: &'static str, // What it holds (see the list in inspect.rs)
+    pub point: usize,       // Index of the point it belongs to
+    pub value: FieldValue,
+}
+
+/// What a field decodes to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldValue {
+    Timestamp(u64), // The block start (header)
+    Delta(i64),     // A timestamp delta, delta-of-delta or value difference
+    Value(f64),     // A point's value
+    Bits(u64),      // Control bits, prefixes and window sizes, as stored
+}
+
+/// Annotate the fields of a block stream holding `point_count` points
+///
+/// Stops at the first field that can't be read or doesn't decode, so on
+/// a truncated or corrupt stream the list ends where it breaks.
+pub fn describe_block(
+    data: &[u8],
+    point_count: usize,
+    timestamp_codec: TimestampCodec,
+    value_hint: ValueHint,
+    value_codec: ValueCodec,
+) -> Vec<EncodedField> {
+    let mut walker = Walker {
+        reader: BitReader::new(data),
+        offset: 0,
+        point: 0,
+        fields: Vec::new(),
+    };
+    walker.walk(point_count, timestamp_codec, value_hint, value_codec);
+    walker.fields
+}
+
+struct Walker<'a> {
+    reader: BitReader<'a>,
+    offset: usize,
+    point: usize,
+    fields: Vec<EncodedField>,
+}
+
+impl Walker<'_> {
+    fn walk(
+        &mut self,
+        point_count: usize,
+        timestamp_codec: TimestampCodec,
+        value_hint: ValueHint,
+        value_codec: ValueCodec,
+    ) -> Option<()> {
+        if point_count == 0 {
+            return Some(());
+        }
+        let start = self.read("header", 64)?;
+        self.decoded(FieldValue::Timestamp(start));
+        let mut first_delta = self.read("first_delta", FIRST_DELTA_BITS)?;
+        if first_delta == FIRST_DELTA_ESCAPE {
+            first_delta = self.read("first_delta_wide", 64)?;
+        }
+        self.decoded(FieldValue::Delta(first_delta as i64));
+        let mut timestamp = start.checked_add(first_delta)?;
+        let mut value = self.first_value(value_hint)?;
+
+        let (prefix, payload) = match timestamp_codec {
+            TimestampCodec::DeltaOfDelta => ("dod_prefix", "dod"),
+            TimestampCodec::Delta => ("delta_prefix", "delta"),
+        };
+        let mut delta = 0i64;
+        // (leading, trailing) zeros of the last XOR window
+        let mut window = None;
+        for point in 1..point_count {
+            self.point = point;
+            let encoded = self.bucket(prefix, payload)?;
+            delta = match timestamp_codec {
+                TimestampCodec::DeltaOfDelta => delta.checked_add(encoded)?,
+                TimestampCodec::Delta => encoded,
+            };
+            timestamp = timestamp.checked_add_signed(delta)?;
+            value = match value_codec {
+                ValueCodec::Xor => self.xor_value(value, &mut window)?,
+                ValueCodec::IntegerDelta => self.integer_value(value)?,
+                ValueCodec::Raw => self.raw_value()?,
+            };
+        }
+        Some(())
+    }
+
+    /// Read a `bits`-wide field, recorded as stored
+    fn read(&mut self, name: &'static str, bits: u8) -> Option<u64> {
+        let raw = self.reader.read_bits(bits)?;
+        self.push(name, bits, FieldValue::Bits(raw));
+        Some(raw)
+    }
+
+    fn push(&mut self, name: &'static str, bits: u8, value: FieldValue) {
+        self.fields.push(EncodedField {
+            offset: self.offset,
+            bits,
+            name,
+            point: self.point,
+            value,
+        });
+        self.offset += bits as usize;
+    }
+
+    /// Set what the field just read decodes to
+    fn decoded(&mut self, value: FieldValue) {
+        if let Some(field) = self.fields.last_mut() {
+            field.value = value;
+        }
+    }
+
+    fn first_value(&mut self, hint: ValueHint) -> Option<f64> {
+        let value = if hint == ValueHint::IntegerSmall && self.read("small_int_flag", 1)? == 1 {
+            self.read("first_value", 16)? as u16 as i16 as f64
+        } else {
+            f64::from_bits(self.read("first_value", 64)?)
+        };
+        self.decoded(FieldValue::Value(value));
+        Some(value)
+    }
+
+    /// Read a bucket prefix and its payload (see encode_timestamp_delta),
+    /// returning the number they store
+    fn bucket(&mut self, prefix: &'static str, payload: &'static str) -> Option<i64> {
+        let (mut ones, mut raw) = (0, 0);
+        while ones < 4 {
+            let bit = self.reader.read_bit()?;
+            raw = raw << 1 | bit as u64;
+            if !bit {
+                break;
+            }
+            ones += 1;
+        }
+        let prefix_bits = if ones == 4 { 4 } else { ones as u8 + 1 };
+        self.push(prefix, prefix_bits, FieldValue::Bits(raw));
+        if ones == 0 {
+            return Some(0);
+        }
+        let number = bucket_value(ones, self.read(payload, BUCKET_WIDTHS[ones - 1])?);
+        self.decoded(FieldValue::Delta(number));
+        Some(number)
+    }
+
+    /// Read a value written by encode_value_xor
+    fn xor_value(&mut self, prev: f64, window: &mut Option<(u32, u32)>) -> Option<f64> {
+        if !self.reader.read_bit()? {
+            self.push("xor_control", 1, FieldValue::Bits(0b0));
+            return Some(prev);
+        }
+        let new_window = self.reader.read_bit()?;
+        self.push("xor_control", 2, FieldValue::Bits(0b10 | new_window as u64));
+
+        let (leading, meaningful) = if new_window {
+            let leading = self.read("leading_zeros", 5)? as u32;
+            let meaningful = match self.read("meaningful_length", 6)? as u32 {
+                0 => 64,
+                n => n,
+            };
+            (leading, meaningful)
+        } else {
+            let (leading, trailing) = (*window)?;
+            (leading, 64 - leading - trailing)
+        };
+        let trailing = 64u32.checked_sub(leading + meaningful)?;
+        *window = Some((leading, trailing));
+
+        let bits = self.read("meaningful_bits", meaningful as u8)?;
+        let value = f64::from_bits(prev.to_bits() ^ (bits << trailing));
+        self.decoded(FieldValue::Value(value));
+        Some(value)
+    }
+
+    /// Read a value written by the IntegerDelta codec
+    fn integer_value(&mut self, prev: f64) -> Option<f64> {
+        if self.read("int_flag", 1)? == 0 {
+            return self.raw_value();
+        }
+        let difference = self.bucket("int_delta_prefix", "int_delta")?;
+        Some(exact_integer(prev)?.checked_add(difference)? as f64)
+    }
+
+    fn raw_value(&mut self) -> Option<f64> {
+        let value = f64::from_bits(self.read("raw_value", 64)?);
+        self.decoded(FieldValue::Value(value));
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::BitWriter;
+    use crate::compression::timestamp::{TimestampCompressor, encode_first_delta};
+    use crate::compression::value::ValueCompressor;
+
+    /// A stream laid out as TimeSeriesBlock::compress writes one
+    fn encode(points: &[(u64, f64)], start: u64, hint: ValueHint, codec: ValueCodec) -> Vec<u8> {
+        let mut writer = BitWriter::new();
+        writer.write_bits(start, 64);
+        encode_first_delta(&mut writer, points[0].0 - start);
+        hint.write_first_value(&mut writer, points[0].1);
+        let mut timestamps = TimestampCompressor::new(points[0].0);
+        let mut values = ValueCompressor::with_codec(points[0].1, codec);
+        for &(timestamp, value) in &points[1..] {
+            timestamps.add_timestamp(&mut writer, timestamp);
+            values.add_value(&mut writer, value);
+        }
+        writer.finish()
+    }
+
+    fn names(fields: &[EncodedField]) -> Vec<&'static str> {
+        fields.iter().map(|field| field.name).collect()
+    }
+
+    #[test]
+    fn test_fields_tile_the_stream() {
+        let points = [(7262, 12.0), (7322, 24.0), (7382, 24.0), (7742, 12.0)];
+        let data = encode(&points, 7200, ValueHint::Float, ValueCodec::Xor);
+        let fields = describe_block(
+            &data,
+            points.len(),
+            TimestampCodec::DeltaOfDelta,
+            ValueHint::Float,
+            ValueCodec::Xor,
+        );
+
+        assert_eq!(
+            names(&fields),
+            [
+                "header",
+                "first_delta",
+                "first_value",
+                "dod_prefix",
+                "dod",
+                "xor_control",
+                "leading_zeros",
+                "meaningful_length",
+                "meaningful_bits",
+                "dod_prefix",
+                "xor_control",
+                "dod_prefix",
+                "dod",
+                "xor_control",
+                "meaningful_bits",
+            ]
+        );
+        // Each field starts where the previous one ends
+        for pair in fields.windows(2) {
+            assert_eq!(pair[1].offset, pair[0].offset + pair[0].bits as usize);
+        }
+        assert_eq!(fields[4].value, FieldValue::Delta(60));
+        assert_eq!(fields[5].value, FieldValue::Bits(0b11));
+        assert_eq!(fields[8].value, FieldValue::Value(24.0));
+        assert_eq!(fields[12].value, FieldValue::Delta(300));
+        assert_eq!(fields[13].value, FieldValue::Bits(0b10));
+        assert_eq!(
+            (fields[14].point, fields[14].value),
+            (3, FieldValue::Value(12.0))
+        );
+
+        // A truncated stream lists the fields up to the break
+        let cut = describe_block(
+            &data[..12],
+            points.len(),
+            TimestampCodec::DeltaOfDelta,
+            ValueHint::Float,
+            ValueCodec::Xor,
+        );
+        assert_eq!(names(&cut), ["header", "first_delta"]);
+    }
+
+    #[test]
+    fn test_other_codecs() {
+        let points = [(100, 5.0), (160, 7.0), (220, 0.5)];
+        let data = encode(
+            &points,
+            0,
+            ValueHint::IntegerSmall,
+            ValueCodec::IntegerDelta,
+        );
+        let fields = describe_block(
+            &data,
+            points.len(),
+            TimestampCodec::DeltaOfDelta,
+            ValueHint::IntegerSmall,
+            ValueCodec::IntegerDelta,
+        );
+        assert_eq!(
+            names(&fields),
+            [
+                "header",
+                "first_delta",
+                "small_int_flag",
+                "first_value",
+                "dod_prefix",
+                "dod",
+                "int_flag",
+                "int_delta_prefix",
+                "int_delta",
+                "dod_prefix",
+                "int_flag",
+                "raw_value",
+            ]
+        );
+        assert_eq!(
+            (fields[3].bits, fields[3].value),
+            (16, FieldValue::Value(5.0))
+        );
+        assert_eq!(fields[8].value, FieldValue::Delta(2));
+        assert_eq!(fields[11].value, FieldValue::Value(0.5));
+    }
+}
@@ -1,11 +1,28 @@
 // Implements Gorilla's innovative compression algorithms
 // Paper Section 4.1: Time series compression
+//
+// Only core and alloc are used here (no std), so the codecs also build
+// in no_std crates; tests/no_std_codecs.rs checks that.
 
+use alloc::vec::Vec;
+
+pub mod inspect;
+pub mod reference;
+pub mod stream;
 pub mod timestamp;
 pub mod value;
 
+/// Version of the encoding, written at the start of every serialized
+/// frame (encode_range streams, spill files, snapshots)
+///
+/// Version 1 is the encoding from before frames carried a version. Its
+/// bit layout is the same, so migrating it only adds the version (see
+/// stream::upgrade_v1; snapshots know it from their own version).
+pub const FORMAT_VERSION: u8 = 2;
+
 /// BitWriter allows writing individual bits to a byte buffer
 /// This is essential for Gorilla's variable-length encoding
+#[derive(Default)]
 pub struct BitWriter {
     buffer: Vec<u8>,
     current_byte: u8,
@@ -21,6 +38,34 @@ impl BitWriter {
         }
     }
 
+    /// Resume writing after bytes produced by `finish` or `to_parts`
+    ///
+    /// `bit_position` is the number of bits used in the last byte of
+    /// `buffer` (0 when it is fully used); writing continues right after
+    /// them instead of starting a fresh byte.
+    pub fn from_parts(mut buffer: Vec<u8>, bit_position: u8) -> Self {
+        assert!(
+            bit_position < 8,
+            "bit position {} out of range",
+            bit_position
+        );
+
+        let mut current_byte = 0;
+        if bit_position > 0 {
+            let last = buffer
+                .pop()
+                .expect("a partial byte needs a non-empty buffer");
+            // Clear any padding below the used bits
+            current_byte = last & !(0xFF >> bit_position);
+        }
+
+        BitWriter {
+            buffer,
+            current_byte,
+            bit_position,
+        }
+    }
+
     /// Write a single bit (0 or 1)
     pub fn write_bit(&mut self, bit: bool) {
         if bit {
@@ -53,24 +98,57 @@ impl BitWriter {
         self.buffer
     }
 
+    /// Export the bytes written so far without consuming the writer
+    ///
+    /// Includes the partially filled last byte; pass both values to
+    /// `from_parts` to continue appending later.
+    pub fn to_parts(&self) -> (Vec<u8>, u8) {
+        let mut bytes = self.buffer.clone();
+        if self.bit_position > 0 {
+            bytes.push(self.current_byte);
+        }
+        (bytes, self.bit_position)
+    }
+
     /// Get current size in bits
     pub fn bit_count(&self) -> usize {
         self.buffer.len() * 8 + self.bit_position as usize
     }
+
+    /// Mark the current position, to check an encoder's width with
+    /// assert_advanced
+    pub fn checkpoint(&self) -> usize {
+        self.bit_count()
+    }
+
+    /// Panic unless exactly `expected_bits` were written since
+    /// `checkpoint`
+    ///
+    /// Catches encoders whose output disagrees with the width they
+    /// advertise, which otherwise shows up as garbage further down the
+    /// stream. Checked in debug builds only.
+    #[track_caller]
+    pub fn assert_advanced(&self, checkpoint: usize, expected_bits: usize) {
+        debug_assert_eq!(
+            self.bit_count() - checkpoint,
+            expected_bits,
+            "bits written since checkpoint {} differ from the expected width",
+            checkpoint
+        );
+    }
 }
 
 /// BitReader allows reading individual bits from a byte buffer
-/// Used for decompression (not shown in this demo, but needed for production)
-#[allow(dead_code)]
-pub struct BitReader {
-    buffer: Vec<u8>,
+/// Used for decompression of blocks (e.g. when loading a snapshot)
+#[derive(Clone)]
+pub struct BitReader<'a> {
+    buffer: &'a [u8],
     byte_position: usize,
     bit_position: u8, // 0-7
 }
 
-#[allow(dead_code)]
-impl BitReader {
-    pub fn new(buffer: Vec<u8>) -> Self {
+impl<'a> BitReader<'a> {
+    pub fn new(buffer: &'a [u8]) -> Self {
         BitReader {
             buffer,
             byte_position: 0,
@@ -105,6 +183,19 @@ impl BitReader {
         }
         Some(value)
     }
+
+    /// Move past `bits` bits without reading them
+    ///
+    /// Returns None (leaving the reader where it was) if fewer remain.
+    pub fn skip_bits(&mut self, bits: usize) -> Option<()> {
+        let position = (self.byte_position * 8 + self.bit_position as usize).checked_add(bits)?;
+        if position > self.buffer.len() * 8 {
+            return None;
+        }
+        self.byte_position = position / 8;
+        self.bit_position = (position % 8) as u8;
+        Some(())
+    }
 }
 
 #[cfg(test)]
@@ -122,11 +213,64 @@ mod tests {
         writer.write_bits(0b1010, 4);
 
         let buffer = writer.finish();
-        let mut reader = BitReader::new(buffer);
+        let mut reader = BitReader::new(&buffer);
 
         assert_eq!(reader.read_bit(), Some(true));
         assert_eq!(reader.read_bit(), Some(false));
         assert_eq!(reader.read_bit(), Some(true));
         assert_eq!(reader.read_bits(4), Some(0b1010));
     }
+
+    #[test]
+    fn test_bit_writer_resume_from_parts() {
+        let mut writer = BitWriter::new();
+        writer.write_bits(0b101, 3);
+        writer.write_bits(0xABCD, 16);
+
+        let (bytes, bit_position) = writer.to_parts();
+        assert_eq!(bit_position, 3);
+        assert_eq!(bytes.len(), 3);
+        // Exporting does not disturb the writer
+        assert_eq!(writer.bit_count(), 19);
+
+        let mut resumed = BitWriter::from_parts(bytes, bit_position);
+        assert_eq!(resumed.bit_count(), 19);
+        resumed.write_bit(true);
+        resumed.write_bits(0x3F, 6);
+        resumed.write_bits(u64::MAX, 64);
+
+        let buffer = resumed.finish();
+        let mut reader = BitReader::new(&buffer);
+        assert_eq!(reader.read_bits(3), Some(0b101));
+        assert_eq!(reader.read_bits(16), Some(0xABCD));
+        assert_eq!(reader.read_bit(), Some(true));
+        assert_eq!(reader.read_bits(6), Some(0x3F));
+        assert_eq!(reader.read_bits(64), Some(u64::MAX));
+
+        // Byte-aligned exports resume at a fresh byte
+        let aligned = BitWriter::from_parts(vec![0xFF], 0);
+        assert_eq!(aligned.bit_count(), 8);
+        assert_eq!(aligned.finish(), vec![0xFF]);
+    }
+
+    #[test]
+    fn test_assert_advanced_accepts_exact_width() {
+        let mut writer = BitWriter::new();
+        writer.write_bits(0b1, 3);
+        let checkpoint = writer.checkpoint();
+        writer.write_bits(0x1FF, 9);
+        writer.assert_advanced(checkpoint, 9);
+        writer.assert_advanced(0, 12);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "differ from the expected width")]
+    fn test_assert_advanced_catches_wrong_width() {
+        // An encoder advertising a 12-bit field but writing only 9
+        let mut writer = BitWriter::new();
+        let checkpoint = writer.checkpoint();
+        writer.write_bits(0xFFF, 9);
+        writer.assert_advanced(checkpoint, 12);
+    }
 }
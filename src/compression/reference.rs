@@ -0,0 +1,255 @@
+// Decoder for the reference Gorilla bitstream, as the paper describes it
+// and implementations such as Beringei and go-tsz write it, for
+// migrating existing data
+//
+// Layout (bits):
+//   header (64): the block's start time
+//   first timestamp: delta from the header (14); all ones marks an empty
+//     stream
+//   first value (64)
+//   per further point: delta-of-delta timestamp, XOR value
+//   end-of-stream marker: '1111' + 32 one bits (streams with a separate
+//     point count may leave it out)
+//
+// The values are encoded exactly as here (value.rs: 5-bit leading zeros
+// capped at 31, 6-bit meaningful length with 0 standing for 64). The
+// timestamps differ from this crate's own encoding in two ways, which is
+// why native bytes don't decode here or the other way round:
+// - The reference encodes the second timestamp's delta-of-delta against
+//   the first delta (from the header); the native codecs start from a
+//   previous delta of 0.
+// - The reference stores each bucket's payload in two's complement, with
+//   the bucket's top value read as positive (so 7 bits hold [-63, 64]);
+//   the native buckets store it offset by the range's minimum.
+// The native encoding is kept as is, since existing blocks, spill files
+// and snapshots depend on it; the reference format is only read.
+
+use super::BitReader;
+use super::value::ValueDecompressor;
+use crate::tsdb::TsdbError;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+/// First delta that marks a stream without points
+const EMPTY_STREAM: u64 = (1 << 14) - 1;
+
+/// Payload of the '1111' bucket that marks the end of the stream
+const END_OF_STREAM: u64 = 0xFFFF_FFFF;
+
+/// Decode a reference Gorilla stream that ends with the end-of-stream
+/// marker
+///
+/// Returns the points as (timestamp, value) pairs, oldest first, or
+/// Corrupt if the stream is truncated, lacks its marker, or steps a
+/// timestamp out of range.
+pub fn decode_reference(data: &[u8]) -> Result<Vec<(u64, f64)>, TsdbError> {
+    decode(data, None)
+}
+
+/// Decode the first `count` points of a reference Gorilla block
+///
+/// For blocks stored with their point count instead of an end-of-stream
+/// marker (as Beringei keeps them). Corrupt if the block holds fewer
+/// points.
+pub fn decode_reference_block(data: &[u8], count: usize) -> Result<Vec<(u64, f64)>, TsdbError> {
+    decode(data, Some(count))
+}
+
+/// A timestamp bucket: a delta-of-delta, or the end-of-stream marker
+enum Bucket {
+    DeltaOfDelta(i64),
+    End,
+}
+
+fn decode(data: &[u8], count: Option<usize>) -> Result<Vec<(u64, f64)>, TsdbError> {
+    let truncated = || TsdbError::Corrupt("reference stream ends mid-point".to_string());
+    let mut reader = BitReader::new(data);
+    let mut points = Vec::new();
+
+    let header = reader.read_bits(64).ok_or_else(truncated)?;
+    if count == Some(0) {
+        return Ok(points);
+    }
+    let first_delta = reader.read_bits(14).ok_or_else(truncated)?;
+    if count.is_none() && first_delta == EMPTY_STREAM {
+        return Ok(points);
+    }
+    let mut timestamp = header.checked_add(first_delta).ok_or_else(out_of_range)?;
+    let first_value = f64::from_bits(reader.read_bits(64).ok_or_else(truncated)?);
+    points.push((timestamp, first_value));
+
+    let mut delta = first_delta as i64;
+    let mut values = ValueDecompressor::new(first_value);
+    while count.is_none_or(|count| points.len() < count) {
+        match read_bucket(&mut reader).ok_or_else(truncated)? {
+            Bucket::DeltaOfDelta(delta_of_delta) => {
+                delta = delta.checked_add(delta_of_delta).ok_or_else(out_of_range)?;
+                timestamp = timestamp
+                    .checked_add_signed(delta)
+                    .ok_or_else(out_of_range)?;
+                let value = values.next_value(&mut reader).ok_or_else(truncated)?;
+                points.push((timestamp, value));
+            }
+            Bucket::End if count.is_none() => return Ok(points),
+            Bucket::End => {
+                return Err(TsdbError::Corrupt(
+                    "reference block holds fewer points than its count".to_string(),
+                ));
+            }
+        }
+    }
+    Ok(points)
+}
+
+fn out_of_range() -> TsdbError {
+    TsdbError::Corrupt("reference stream steps a timestamp out of range".to_string())
+}
+
+/// Read one timestamp bucket; None if the stream ends mid-bucket
+///
+/// Payloads are two's complement, with the top value of each width read
+/// as positive: 7 bits hold [-63, 64], 9 bits [-255, 256], 12 bits
+/// [-2047, 2048] and 32 bits the rest.
+fn read_bucket(reader: &mut BitReader) -> Option<Bucket> {
+    if !reader.read_bit()? {
+        return Some(Bucket::DeltaOfDelta(0)); // '0'
+    }
+    // '10', '110', '1110'
+    for width in [7, 9, 12] {
+        if !reader.read_bit()? {
+            return Some(Bucket::DeltaOfDelta(signed(
+                reader.read_bits(width)?,
+                width,
+            )));
+        }
+    }
+
+    // '1111': 32 bits, or the end-of-stream marker
+    let payload = reader.read_bits(32)?;
+    if payload == END_OF_STREAM {
+        return Some(Bucket::End);
+    }
+    Some(Bucket::DeltaOfDelta(signed(payload, 32)))
+}
+
+/// A `width`-bit payload as a signed value, its top value read as positive
+fn signed(payload: u64, width: u8) -> i64 {
+    if payload > 1 << (width - 1) {
+        payload as i64 - (1 << width)
+    } else {
+        payload as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::BitWriter;
+
+    /// Write a string of '0'/'1' bits; spaces are ignored
+    fn write(writer: &mut BitWriter, bits: &str) {
+        for bit in bits.chars().filter(|&c| c != ' ') {
+            writer.write_bit(bit == '1');
+        }
+    }
+
+    const HEADER: u64 = 1_699_999_200;
+
+    /// The paper's example block plus a point per bucket width, written
+    /// bit by bit as the reference encoder lays it out
+    fn reference_block() -> BitWriter {
+        let mut writer = BitWriter::new();
+        writer.write_bits(HEADER, 64);
+        // +62 from the header, 12.0
+        write(&mut writer, "00000000111110");
+        writer.write_bits(12.0f64.to_bits(), 64);
+        // Delta 60, D = -2: '10' + 1111110; 24.0: new window of 11
+        // leading zeros and 1 meaningful bit
+        write(&mut writer, "10 1111110");
+        write(&mut writer, "11 01011 000001 1");
+        // D = 0, value unchanged
+        write(&mut writer, "0 0");
+        // Delta 360, D = 300: '1110' + 12 bits; 12.0 in the previous window
+        write(&mut writer, "1110 000100101100");
+        write(&mut writer, "10 1");
+        // Delta 260, D = -100: '110' + 412 in 9 bits; value unchanged
+        write(&mut writer, "110 110011100");
+        write(&mut writer, "0");
+        writer
+    }
+
+    const EXPECTED: [(u64, f64); 5] = [
+        (HEADER + 62, 12.0),
+        (HEADER + 122, 24.0),
+        (HEADER + 182, 24.0),
+        (HEADER + 542, 12.0),
+        (HEADER + 802, 12.0),
+    ];
+
+    #[test]
+    fn test_decode_reference_stream() {
+        let mut writer = reference_block();
+        write(&mut writer, "1111");
+        writer.write_bits(END_OF_STREAM, 32);
+        let bytes = writer.finish();
+        assert_eq!(decode_reference(&bytes).unwrap(), EXPECTED);
+
+        // Counted blocks stop at their count, marker or not
+        assert_eq!(decode_reference_block(&bytes, 3).unwrap(), EXPECTED[..3]);
+        let unmarked = reference_block().finish();
+        assert_eq!(decode_reference_block(&unmarked, 5).unwrap(), EXPECTED);
+        assert!(matches!(
+            decode_reference_block(&bytes, 6),
+            Err(TsdbError::Corrupt(_))
+        ));
+
+        // Without its marker the stream reads as truncated
+        assert!(matches!(
+            decode_reference(&unmarked),
+            Err(TsdbError::Corrupt(_))
+        ));
+        assert!(decode_reference(&bytes[..bytes.len() - 6]).is_err());
+    }
+
+    #[test]
+    fn test_decode_reference_edge_cases() {
+        // An empty stream: the first delta is all ones
+        let mut writer = BitWriter::new();
+        writer.write_bits(HEADER, 64);
+        write(&mut writer, "11111111111111");
+        assert_eq!(decode_reference(&writer.finish()).unwrap(), []);
+        assert!(decode_reference(&[0; 4]).is_err());
+
+        // 32-bit deltas-of-deltas, either sign
+        let mut writer = BitWriter::new();
+        writer.write_bits(HEADER, 64);
+        writer.write_bits(10, 14);
+        writer.write_bits(1.5f64.to_bits(), 64);
+        write(&mut writer, "1111");
+        writer.write_bits(100_000, 32);
+        write(&mut writer, "0");
+        write(&mut writer, "1111");
+        writer.write_bits((-100_000i64) as u64 & 0xFFFF_FFFF, 32);
+        write(&mut writer, "0");
+        write(&mut writer, "1111");
+        writer.write_bits(END_OF_STREAM, 32);
+        assert_eq!(
+            decode_reference(&writer.finish()).unwrap(),
+            [
+                (HEADER + 10, 1.5),
+                (HEADER + 100_020, 1.5),
+                (HEADER + 100_030, 1.5)
+            ]
+        );
+
+        // A delta stepping before zero is refused
+        let mut writer = BitWriter::new();
+        writer.write_bits(0, 64);
+        writer.write_bits(10, 14);
+        writer.write_bits(1.0f64.to_bits(), 64);
+        write(&mut writer, "1111");
+        writer.write_bits((-100i64) as u64 & 0xFFFF_FFFF, 32);
+        write(&mut writer, "0");
+        assert!(decode_reference_block(&writer.finish(), 2).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+// Self-delimiting Gorilla streams for exchanging ranges of points, also
+// usable as a standalone codec (StreamCompressor / StreamDecompressor)
+//
+// Unlike a storage block, a stream carries no external point count, so it
+// ends with the paper's end-of-stream marker: the '1111' timestamp prefix
+// followed by 32 one bits. The encoder writes -1 with the 9-bit form, so
+// the marker never collides with a real delta-of-delta.
+//
+// Layout (bits):
+//   format version (8): FORMAT_VERSION; version 1 streams lack this byte
+//   presence flag (1): 0 for an empty stream, which ends there
+//   first timestamp (64), first value (64)
+//   per further point: delta-of-delta timestamp, XOR value
+//   end-of-stream marker (36)
+
+use super::timestamp::{TimestampCompressor, TimestampDecompressor};
+use super::value::{ValueCompressor, ValueDecompressor};
+use super::{BitReader, BitWriter, FORMAT_VERSION};
+use crate::tsdb::TsdbError;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+const END_OF_STREAM: u64 = 0xF_FFFF_FFFF;
+const END_OF_STREAM_BITS: u8 = 36;
+
+/// Encode points as a self-delimiting Gorilla stream
+///
+/// Points must be in strictly increasing timestamp order, with a
+/// delta-of-delta that fits in 32 bits; returns None otherwise.
+pub fn encode_range(points: &[(u64, f64)]) -> Option<Vec<u8>> {
+    let Some((&(first_timestamp, first_value), rest)) = points.split_first() else {
+        let mut writer = BitWriter::new();
+        writer.write_bits(FORMAT_VERSION.into(), 8);
+        writer.write_bit(false);
+        return Some(writer.finish());
+    };
+
+    let mut compressor = StreamCompressor::new(first_timestamp, first_value);
+    for &(timestamp, value) in rest {
+        if !compressor.accepts(timestamp) {
+            return None;
+        }
+        compressor.push(timestamp, value);
+    }
+    Some(compressor.finish())
+}
+
+/// Gorilla compression as a standalone codec, one point at a time
+///
+/// Produces the same stream as encode_range, without a series or a
+/// Gorilla instance behind it (e.g. to compress an in-memory array).
+/// Read it back with StreamDecompressor.
+pub struct StreamCompressor {
+    writer: BitWriter,
+    timestamps: TimestampCompressor,
+    values: ValueCompressor,
+    prev_timestamp: u64,
+    prev_delta: i64,
+}
+
+impl StreamCompressor {
+    pub fn new(first_timestamp: u64, first_value: f64) -> Self {
+        let mut writer = BitWriter::new();
+        writer.write_bits(FORMAT_VERSION.into(), 8);
+        writer.write_bit(true);
+        writer.write_bits(first_timestamp, 64);
+        writer.write_bits(first_value.to_bits(), 64);
+
+        StreamCompressor {
+            writer,
+            timestamps: TimestampCompressor::new(first_timestamp),
+            values: ValueCompressor::new(first_value),
+            prev_timestamp: first_timestamp,
+            prev_delta: 0,
+        }
+    }
+
+    /// Whether a point at `timestamp` can be pushed next: it must come
+    /// after the previous point, with a delta-of-delta that fits in 32 bits
+    pub fn accepts(&self, timestamp: u64) -> bool {
+        timestamp > self.prev_timestamp
+            && i64::try_from(timestamp - self.prev_timestamp)
+                .is_ok_and(|delta| i32::try_from(delta - self.prev_delta).is_ok())
+    }
+
+    /// Append a point
+    ///
+    /// Panics if the timestamp isn't accepted (see `accepts`).
+    pub fn push(&mut self, timestamp: u64, value: f64) {
+        assert!(
+            self.accepts(timestamp),
+            "timestamp {} can't follow {}",
+            timestamp,
+            self.prev_timestamp
+        );
+        self.prev_delta = (timestamp - self.prev_timestamp) as i64;
+        self.prev_timestamp = timestamp;
+        self.timestamps.add_timestamp(&mut self.writer, timestamp);
+        self.values.add_value(&mut self.writer, value);
+    }
+
+    /// End the stream and return its bytes
+    pub fn finish(mut self) -> Vec<u8> {
+        self.writer.write_bits(END_OF_STREAM, END_OF_STREAM_BITS);
+        self.writer.finish()
+    }
+}
+
+/// Decoder for streams written by StreamCompressor or encode_range
+pub type StreamDecompressor<'a> = GorillaStreamIter<'a>;
+
+/// Decode a whole stream written by encode_range
+///
+/// Fails with UnsupportedVersion for streams of another format version,
+/// and with Corrupt if the stream is truncated (no end marker).
+pub fn decode_range(bytes: &[u8]) -> Result<Vec<(u64, f64)>, TsdbError> {
+    let mut iter = GorillaStreamIter::new(bytes)?;
+    let points: Vec<(u64, f64)> = iter.by_ref().collect();
+    if !iter.is_complete() {
+        return Err(TsdbError::Corrupt(
+            "stream ends before its end marker".to_string(),
+        ));
+    }
+    Ok(points)
+}
+
+/// Migrate a version 1 stream to the current format
+///
+/// Version 1 streams have no version byte, so they can't be recognized
+/// from their contents; callers holding old blobs upgrade them explicitly.
+pub fn upgrade_v1(bytes: &[u8]) -> Vec<u8> {
+    let mut upgraded = Vec::with_capacity(bytes.len() + 1);
+    upgraded.push(FORMAT_VERSION);
+    upgraded.extend_from_slice(bytes);
+    upgraded
+}
+
+/// Lazily decodes a stream written by encode_range
+///
+/// Yields points until the end-of-stream marker. A truncated or corrupt
+/// stream simply ends early; `is_complete` tells the two apart.
+pub struct GorillaStreamIter<'a> {
+    reader: BitReader<'a>,
+    state: StreamState,
+}
+
+enum StreamState {
+    Start,
+    Points {
+        timestamps: TimestampDecompressor,
+        values: ValueDecompressor,
+    },
+    Complete,
+    Broken,
+}
+
+impl<'a> GorillaStreamIter<'a> {
+    /// Start decoding, after checking the stream's format version
+    pub fn new(bytes: &'a [u8]) -> Result<Self, TsdbError> {
+        let Some((&version, body)) = bytes.split_first() else {
+            return Err(TsdbError::Corrupt("empty stream".to_string()));
+        };
+        if version != FORMAT_VERSION {
+            return Err(TsdbError::UnsupportedVersion {
+                found: version.into(),
+                supported: FORMAT_VERSION.into(),
+            });
+        }
+        Ok(GorillaStreamIter {
+            reader: BitReader::new(body),
+            state: StreamState::Start,
+        })
+    }
+
+    /// Whether the end-of-stream marker has been reached
+    pub fn is_complete(&self) -> bool {
+        matches!(self.state, StreamState::Complete)
+    }
+
+    fn first_point(&mut self) -> Option<(u64, f64)> {
+        if !self.reader.read_bit()? {
+            self.state = StreamState::Complete;
+            return None;
+        }
+        let timestamp = self.reader.read_bits(64)?;
+        let value = f64::from_bits(self.reader.read_bits(64)?);
+        self.state = StreamState::Points {
+            timestamps: TimestampDecompressor::new(timestamp),
+            values: ValueDecompressor::new(value),
+        };
+        Some((timestamp, value))
+    }
+
+    fn next_point(&mut self) -> Option<(u64, f64)> {
+        // Peek for the marker without consuming a real point
+        let mut peek = self.reader.clone();
+        if peek.read_bits(END_OF_STREAM_BITS) == Some(END_OF_STREAM) {
+            self.reader = peek;
+            self.state = StreamState::Complete;
+            return None;
+        }
+
+        let StreamState::Points { timestamps, values } = &mut self.state else {
+            return None;
+        };
+        let timestamp = timestamps.next_timestamp(&mut self.reader)?;
+        let value = values.next_value(&mut self.reader)?;
+        Some((timestamp, value))
+    }
+}
+
+impl Iterator for GorillaStreamIter<'_> {
+    type Item = (u64, f64);
+
+    fn next(&mut self) -> Option<(u64, f64)> {
+        let point = match self.state {
+            StreamState::Start => self.first_point(),
+            StreamState::Points { .. } => self.next_point(),
+            StreamState::Complete | StreamState::Broken => return None,
+        };
+        if point.is_none() && !self.is_complete() {
+            self.state = StreamState::Broken;
+        }
+        point
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_iter_matches_decode_range() {
+        let points: Vec<(u64, f64)> = (0..500u64)
+            .map(|i| {
+                (
+                    1_700_000_000 + i * 60 + (i % 5),
+                    (i as f64 * 0.1).cos() * 50.0,
+                )
+            })
+            .collect();
+        let bytes = encode_range(&points).unwrap();
+
+        let decoded = decode_range(&bytes).unwrap();
+        assert_eq!(decoded, points);
+
+        let mut iter = GorillaStreamIter::new(&bytes).unwrap();
+        let streamed: Vec<(u64, f64)> = iter.by_ref().collect();
+        assert_eq!(streamed, decoded);
+        assert!(iter.is_complete());
+        assert_eq!(iter.next(), None);
+
+        // Lazy: taking a prefix decodes only that much
+        let head: Vec<(u64, f64)> = GorillaStreamIter::new(&bytes).unwrap().take(3).collect();
+        assert_eq!(head, points[..3]);
+    }
+
+    #[test]
+    fn test_stream_edge_cases() {
+        let empty = encode_range(&[]).unwrap();
+        assert_eq!(decode_range(&empty), Ok(Vec::new()));
+
+        let single = encode_range(&[(42, 1.5)]).unwrap();
+        assert_eq!(decode_range(&single), Ok(vec![(42, 1.5)]));
+
+        // A delta-of-delta of -1 must not be mistaken for the marker
+        let points = [(100, 1.0), (110, 2.0), (119, 3.0), (200, 4.0)];
+        let bytes = encode_range(&points).unwrap();
+        assert_eq!(decode_range(&bytes).unwrap(), points);
+
+        // Truncation ends the iterator early and is reported
+        let mut iter = GorillaStreamIter::new(&bytes[..bytes.len() - 3]).unwrap();
+        assert!(iter.by_ref().count() <= points.len());
+        assert!(!iter.is_complete());
+        assert!(matches!(
+            decode_range(&bytes[..bytes.len() - 3]),
+            Err(TsdbError::Corrupt(_))
+        ));
+        assert!(matches!(decode_range(&[]), Err(TsdbError::Corrupt(_))));
+
+        // Unordered input is refused
+        assert_eq!(encode_range(&[(10, 1.0), (5, 2.0)]), None);
+    }
+
+    #[test]
+    fn test_stream_compressor_round_trip() {
+        let points: Vec<(u64, f64)> = (0..1000u64)
+            .map(|i| (1_000 + i * 15 + (i % 3), 20.0 + (i % 17) as f64 * 0.25))
+            .collect();
+
+        let mut compressor = StreamCompressor::new(points[0].0, points[0].1);
+        for &(timestamp, value) in &points[1..] {
+            compressor.push(timestamp, value);
+        }
+        assert!(!compressor.accepts(points[999].0));
+        let bytes = compressor.finish();
+        assert!(bytes.len() < points.len() * 16 / 4);
+        assert_eq!(Some(&bytes), encode_range(&points).as_ref());
+
+        let mut decompressor = StreamDecompressor::new(&bytes).unwrap();
+        let decoded: Vec<(u64, f64)> = decompressor.by_ref().collect();
+        assert_eq!(decoded, points);
+        assert!(decompressor.is_complete());
+
+        let single = StreamCompressor::new(7, -1.5).finish();
+        assert_eq!(decode_range(&single).unwrap(), vec![(7, -1.5)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't follow")]
+    fn test_stream_compressor_refuses_unordered() {
+        let mut compressor = StreamCompressor::new(100, 1.0);
+        compressor.push(100, 2.0);
+    }
+
+    #[test]
+    fn test_stream_versions() {
+        let points = [(1_700_000_000, 1.0), (1_700_000_060, 2.5)];
+        let mut bytes = encode_range(&points).unwrap();
+        assert_eq!(bytes[0], FORMAT_VERSION);
+        assert_eq!(decode_range(&bytes).unwrap(), points);
+
+        // A version 1 stream is the same bits without the version byte
+        assert_eq!(decode_range(&upgrade_v1(&bytes[1..])).unwrap(), points);
+
+        bytes[0] = FORMAT_VERSION + 1;
+        let unsupported = Err(TsdbError::UnsupportedVersion {
+            found: u32::from(FORMAT_VERSION) + 1,
+            supported: FORMAT_VERSION.into(),
+        });
+        assert_eq!(decode_range(&bytes), unsupported);
+        assert!(GorillaStreamIter::new(&bytes).is_err());
+    }
+}
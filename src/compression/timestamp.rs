@@ -1,7 +1,7 @@
 // Delta-of-delta timestamp compression
 // Paper Section 4.1.1: Compressing time stamps
 
-use super::BitWriter;
+use super::{BitReader, BitWriter};
 
 /// Compresses a timestamp using delta-of-delta encoding
 ///
@@ -18,11 +18,11 @@ use super::BitWriter;
 pub fn compress_timestamp(delta_of_delta: i64) -> usize {
     if delta_of_delta == 0 {
         1 // Just '0'
-    } else if delta_of_delta >= -63 && delta_of_delta <= 64 {
+    } else if (-63..=64).contains(&delta_of_delta) {
         9 // '10' + 7 bits
-    } else if delta_of_delta >= -255 && delta_of_delta <= 256 {
+    } else if (-255..=256).contains(&delta_of_delta) {
         12 // '110' + 9 bits
-    } else if delta_of_delta >= -2047 && delta_of_delta <= 2048 {
+    } else if (-2047..=2048).contains(&delta_of_delta) {
         16 // '1110' + 12 bits
     } else {
         36 // '1111' + 32 bits
@@ -30,11 +30,15 @@ pub fn compress_timestamp(delta_of_delta: i64) -> usize {
 }
 
 /// Encodes a timestamp delta-of-delta into a BitWriter
+///
+/// Payloads are stored offset by their bucket's minimum, not in two's
+/// complement as the reference encoders do (see reference.rs).
 pub fn encode_timestamp_delta(writer: &mut BitWriter, delta_of_delta: i64) {
+    let checkpoint = writer.checkpoint();
     if delta_of_delta == 0 {
         // Case: D == 0
         writer.write_bit(false); // '0'
-    } else if delta_of_delta >= -63 && delta_of_delta <= 64 {
+    } else if (-63..=64).contains(&delta_of_delta) {
         // Case: D in [-63, 64]
         writer.write_bit(true); // '1'
         writer.write_bit(false); // '0' -> '10'
@@ -42,7 +46,7 @@ pub fn encode_timestamp_delta(writer: &mut BitWriter, delta_of_delta: i64) {
         // Store as 7-bit signed integer
         let value = ((delta_of_delta + 63) as u64) & 0x7F;
         writer.write_bits(value, 7);
-    } else if delta_of_delta >= -255 && delta_of_delta <= 256 {
+    } else if (-255..=256).contains(&delta_of_delta) {
         // Case: D in [-255, 256]
         writer.write_bit(true); // '1'
         writer.write_bit(true); // '1'
@@ -51,7 +55,7 @@ pub fn encode_timestamp_delta(writer: &mut BitWriter, delta_of_delta: i64) {
         // Store as 9-bit signed integer
         let value = ((delta_of_delta + 255) as u64) & 0x1FF;
         writer.write_bits(value, 9);
-    } else if delta_of_delta >= -2047 && delta_of_delta <= 2048 {
+    } else if (-2047..=2048).contains(&delta_of_delta) {
         // Case: D in [-2047, 2048]
         writer.write_bit(true); // '1'
         writer.write_bit(true); // '1'
@@ -71,17 +75,121 @@ pub fn encode_timestamp_delta(writer: &mut BitWriter, delta_of_delta: i64) {
         // Store as 32-bit signed integer
         writer.write_bits(delta_of_delta as u64, 32);
     }
+    writer.assert_advanced(checkpoint, compress_timestamp(delta_of_delta));
+}
+
+/// Decodes one delta-of-delta written by encode_timestamp_delta
+///
+/// Returns None if the stream ends mid-value.
+pub fn decode_timestamp_delta(reader: &mut BitReader) -> Option<i64> {
+    // '0', '10', '110', '1110' or '1111'
+    let mut ones = 0;
+    while ones < 4 && reader.read_bit()? {
+        ones += 1;
+    }
+    if ones == 0 {
+        return Some(0);
+    }
+    Some(bucket_value(
+        ones,
+        reader.read_bits(BUCKET_WIDTHS[ones - 1])?,
+    ))
+}
+
+/// Width of a block's first-timestamp delta, as in the paper
+pub const FIRST_DELTA_BITS: u8 = 14;
+
+/// All ones in the first-delta field: the delta follows in 64 bits
+pub(super) const FIRST_DELTA_ESCAPE: u64 = (1 << FIRST_DELTA_BITS) - 1;
+
+/// Writes the first timestamp's delta from the block start
+///
+/// Deltas below 16383 take the paper's 14 bits. Blocks longer than
+/// about 4.5 hours can start later than that; those deltas write the
+/// all-ones escape, then the delta in 64 bits.
+pub fn encode_first_delta(writer: &mut BitWriter, delta: u64) {
+    if delta < FIRST_DELTA_ESCAPE {
+        writer.write_bits(delta, FIRST_DELTA_BITS);
+    } else {
+        writer.write_bits(FIRST_DELTA_ESCAPE, FIRST_DELTA_BITS);
+        writer.write_bits(delta, 64);
+    }
+}
+
+/// Bits encode_first_delta writes for `delta`
+pub fn first_delta_bits(delta: u64) -> u32 {
+    if delta < FIRST_DELTA_ESCAPE {
+        FIRST_DELTA_BITS.into()
+    } else {
+        u32::from(FIRST_DELTA_BITS) + 64
+    }
+}
+
+/// Decodes a delta written by encode_first_delta
+pub fn decode_first_delta(reader: &mut BitReader) -> Option<u64> {
+    match reader.read_bits(FIRST_DELTA_BITS)? {
+        FIRST_DELTA_ESCAPE => reader.read_bits(64),
+        delta => Some(delta),
+    }
+}
+
+/// Payload width of the buckets after '10', '110', '1110' and '1111'
+pub(super) const BUCKET_WIDTHS: [u8; 4] = [7, 9, 12, 32];
+
+/// The number a bucket payload stores, given the ones its prefix starts
+/// with (1 to 4)
+pub(super) fn bucket_value(ones: usize, payload: u64) -> i64 {
+    match ones {
+        1 => payload as i64 - 63,
+        2 => payload as i64 - 255,
+        3 => payload as i64 - 2047,
+        // 32-bit signed integer
+        _ => payload as u32 as i32 as i64,
+    }
+}
+
+/// What is stored for each timestamp after a block's first
+///
+/// Both go through the same bucket encoding (encode_timestamp_delta).
+/// Delta-of-delta suits regular or steadily drifting intervals; plain
+/// deltas suit intervals that jump around a small value, where the
+/// deltas-of-deltas would be twice as large.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampCodec {
+    #[default]
+    DeltaOfDelta,
+    Delta,
+}
+
+impl TimestampCodec {
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(TimestampCodec::DeltaOfDelta),
+            1 => Some(TimestampCodec::Delta),
+            _ => None,
+        }
+    }
 }
 
 /// Complete timestamp compression example
 pub struct TimestampCompressor {
+    codec: TimestampCodec,
     prev_timestamp: u64,
     prev_delta: i64,
 }
 
 impl TimestampCompressor {
     pub fn new(first_timestamp: u64) -> Self {
+        Self::with_codec(first_timestamp, TimestampCodec::DeltaOfDelta)
+    }
+
+    pub fn with_codec(first_timestamp: u64, codec: TimestampCodec) -> Self {
         TimestampCompressor {
+            codec,
             prev_timestamp: first_timestamp,
             prev_delta: 0,
         }
@@ -90,10 +198,13 @@ impl TimestampCompressor {
     /// Add a timestamp and return bits needed to encode it
     pub fn add_timestamp(&mut self, writer: &mut BitWriter, timestamp: u64) -> usize {
         let delta = (timestamp as i64) - (self.prev_timestamp as i64);
-        let delta_of_delta = delta - self.prev_delta;
+        let encoded = match self.codec {
+            TimestampCodec::DeltaOfDelta => delta - self.prev_delta,
+            TimestampCodec::Delta => delta,
+        };
 
         let bits_before = writer.bit_count();
-        encode_timestamp_delta(writer, delta_of_delta);
+        encode_timestamp_delta(writer, encoded);
         let bits_after = writer.bit_count();
 
         // Update state for next timestamp
@@ -104,6 +215,45 @@ impl TimestampCompressor {
     }
 }
 
+/// Mirror of TimestampCompressor: rebuilds timestamps from the stream
+pub struct TimestampDecompressor {
+    codec: TimestampCodec,
+    prev_timestamp: u64,
+    prev_delta: i64,
+}
+
+impl TimestampDecompressor {
+    pub fn new(first_timestamp: u64) -> Self {
+        Self::with_codec(first_timestamp, TimestampCodec::DeltaOfDelta)
+    }
+
+    /// Must be given the codec the stream was compressed with
+    pub fn with_codec(first_timestamp: u64, codec: TimestampCodec) -> Self {
+        TimestampDecompressor {
+            codec,
+            prev_timestamp: first_timestamp,
+            prev_delta: 0,
+        }
+    }
+
+    /// Read the next timestamp, or None on a truncated/invalid stream
+    pub fn next_timestamp(&mut self, reader: &mut BitReader) -> Option<u64> {
+        let encoded = decode_timestamp_delta(reader)?;
+        let delta = match self.codec {
+            TimestampCodec::DeltaOfDelta => self.prev_delta.checked_add(encoded)?,
+            TimestampCodec::Delta => encoded,
+        };
+        let timestamp = (self.prev_timestamp as i64).checked_add(delta)?;
+        if timestamp < 0 {
+            return None;
+        }
+
+        self.prev_timestamp = timestamp as u64;
+        self.prev_delta = delta;
+        Some(self.prev_timestamp)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -111,7 +261,7 @@ mod tests {
     #[test]
     fn test_regular_intervals() {
         // Simulating data arriving every 60 seconds
-        let timestamps = vec![1000, 1060, 1120, 1180, 1240];
+        let timestamps = [1000, 1060, 1120, 1180, 1240];
 
         let mut writer = BitWriter::new();
         let mut compressor = TimestampCompressor::new(timestamps[0]);
@@ -139,10 +289,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_first_delta_widens_past_14_bits() {
+        let deltas = [0, 7199, 16382, 16383, 20000, 86399, u64::MAX];
+        let mut writer = BitWriter::new();
+        for delta in deltas {
+            encode_first_delta(&mut writer, delta);
+        }
+        let bits: u32 = deltas.iter().map(|&delta| first_delta_bits(delta)).sum();
+        assert_eq!(bits, 3 * 14 + 4 * 78);
+
+        let data = writer.finish();
+        let mut reader = BitReader::new(&data);
+        for delta in deltas {
+            assert_eq!(decode_first_delta(&mut reader), Some(delta));
+        }
+    }
+
     #[test]
     fn test_irregular_intervals() {
         // Simulating slightly irregular data (59, 61, 60 second intervals)
-        let timestamps = vec![1000, 1059, 1120, 1180];
+        let timestamps = [1000, 1059, 1120, 1180];
 
         let mut writer = BitWriter::new();
         let mut compressor = TimestampCompressor::new(timestamps[0]);
@@ -154,4 +321,71 @@ mod tests {
         // Small variations still compress well (9 bits)
         println!("Irregular but close: compressed successfully");
     }
+
+    #[test]
+    fn test_timestamp_round_trip() {
+        // Covers every bucket: 0, 7, 9, 12 and 32-bit deltas-of-deltas
+        let timestamps = [
+            1000, 1060, 1120, 1179, 1300, 1500, 3000, 3001, 100_000, 100_060,
+        ];
+
+        let mut writer = BitWriter::new();
+        let mut compressor = TimestampCompressor::new(timestamps[0]);
+        for &ts in &timestamps[1..] {
+            compressor.add_timestamp(&mut writer, ts);
+        }
+        let buffer = writer.finish();
+
+        let mut reader = BitReader::new(&buffer);
+        let mut decompressor = TimestampDecompressor::new(timestamps[0]);
+        for &ts in &timestamps[1..] {
+            assert_eq!(decompressor.next_timestamp(&mut reader), Some(ts));
+        }
+    }
+
+    /// Compress and decode `timestamps` with `codec`, returning the bits
+    fn round_trip(timestamps: &[u64], codec: TimestampCodec) -> usize {
+        let mut writer = BitWriter::new();
+        let mut compressor = TimestampCompressor::with_codec(timestamps[0], codec);
+        let bits: usize = timestamps[1..]
+            .iter()
+            .map(|&ts| compressor.add_timestamp(&mut writer, ts))
+            .sum();
+        let buffer = writer.finish();
+
+        let mut reader = BitReader::new(&buffer);
+        let mut decompressor = TimestampDecompressor::with_codec(timestamps[0], codec);
+        for &ts in &timestamps[1..] {
+            assert_eq!(decompressor.next_timestamp(&mut reader), Some(ts));
+        }
+        bits
+    }
+
+    #[test]
+    fn test_codec_sizes() {
+        // Interval drifting linearly from 60s: deltas-of-deltas stay at 1
+        let mut drifting = vec![1000u64];
+        for i in 0..100 {
+            drifting.push(drifting.last().unwrap() + 60 + i);
+        }
+        let dod = round_trip(&drifting, TimestampCodec::DeltaOfDelta);
+        let delta = round_trip(&drifting, TimestampCodec::Delta);
+        assert!(dod < delta, "delta-of-delta {} vs delta {}", dod, delta);
+
+        // Intervals alternating between 10s and 250s: every other delta
+        // fits the 7-bit bucket, but each delta-of-delta (+-240) needs 9
+        let mut jittery = vec![1000u64];
+        for i in 0..100 {
+            jittery.push(jittery.last().unwrap() + if i % 2 == 0 { 10 } else { 250 });
+        }
+        let dod = round_trip(&jittery, TimestampCodec::DeltaOfDelta);
+        let delta = round_trip(&jittery, TimestampCodec::Delta);
+        assert!(delta < dod, "delta {} vs delta-of-delta {}", delta, dod);
+
+        for &byte in &[0, 1] {
+            let codec = TimestampCodec::from_byte(byte).unwrap();
+            assert_eq!(codec.to_byte(), byte);
+        }
+        assert_eq!(TimestampCodec::from_byte(2), None);
+    }
 }
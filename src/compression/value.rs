@@ -1,7 +1,8 @@
 // XOR-based floating point value compression
 // Paper Section 4.1.2: Compressing values
 
-use super::BitWriter;
+use super::timestamp::{compress_timestamp, decode_timestamp_delta, encode_timestamp_delta};
+use super::{BitReader, BitWriter};
 
 /// Compresses a floating point value using XOR with previous value
 ///
@@ -19,8 +20,7 @@ use super::BitWriter;
 /// 2. If XOR != 0: store '1' + either:
 ///    a) Control bit '0': Reuse previous leading/trailing zero counts
 ///    b) Control bit '1': Store new leading zeros (5 bits) +
-///                        meaningful bit length (6 bits) + value
-#[allow(dead_code)]
+///    meaningful bit length (6 bits) + value
 pub fn compress_value_xor(xor_result: u64) -> usize {
     if xor_result == 0 {
         1 // Just '0' bit
@@ -51,15 +51,19 @@ pub fn encode_value_xor(
     let prev_bits = prev_value.to_bits();
     let xor = value_bits ^ prev_bits;
 
-    let bits_before = writer.bit_count();
+    let checkpoint = writer.checkpoint();
 
+    // Width each case advertises: its control bits plus payload
+    let expected_bits;
     if xor == 0 {
         // Values are identical
         writer.write_bit(false); // '0'
+        expected_bits = 1;
     } else {
         writer.write_bit(true); // '1'
 
-        let leading = xor.leading_zeros();
+        // Leading zeros are stored in 5 bits, so cap the count at 31
+        let leading = xor.leading_zeros().min(31);
         let trailing = xor.trailing_zeros();
 
         // Check if we can reuse previous block position
@@ -81,6 +85,7 @@ pub fn encode_value_xor(
             let meaningful_value = (xor >> shift) & mask;
 
             writer.write_bits(meaningful_value, meaningful_bits as u8);
+            expected_bits = 2 + meaningful_bits as usize;
         } else {
             // Case (b): Need to store new block position
             writer.write_bit(true); // '1' -> control bit
@@ -88,7 +93,8 @@ pub fn encode_value_xor(
             // Store leading zeros count (5 bits, max 31)
             writer.write_bits(leading as u64, 5);
 
-            // Calculate and store meaningful bits length (6 bits, max 63)
+            // Calculate and store meaningful bits length (6 bits, max 63;
+            // a length of 64 wraps to 0, which is never a valid length)
             let meaningful_bits = 64 - leading - trailing;
             writer.write_bits(meaningful_bits as u64, 6);
 
@@ -106,42 +112,338 @@ pub fn encode_value_xor(
             // Update for next value
             *prev_leading = leading;
             *prev_trailing = trailing;
+            expected_bits = 2 + 5 + 6 + meaningful_bits as usize;
         }
     }
 
-    writer.bit_count() - bits_before
+    writer.assert_advanced(checkpoint, expected_bits);
+    writer.bit_count() - checkpoint
+}
+
+/// Decodes one value written by encode_value_xor
+///
+/// Returns None if the stream ends mid-value or describes an impossible
+/// bit window (see read_window).
+pub fn decode_value_xor(
+    reader: &mut BitReader,
+    prev_value: f64,
+    prev_leading: &mut u32,
+    prev_trailing: &mut u32,
+) -> Option<f64> {
+    if !reader.read_bit()? {
+        return Some(prev_value); // '0': value unchanged
+    }
+
+    let (meaningful, trailing) = read_window(reader, prev_leading, prev_trailing)?;
+    let bits = reader.read_bits(meaningful as u8)?;
+
+    Some(f64::from_bits(prev_value.to_bits() ^ (bits << trailing)))
+}
+
+/// Moves past one value written by encode_value_xor without rebuilding it
+///
+/// Only the control bits and window header are read; the meaningful bits
+/// are skipped. Returns None where decode_value_xor would.
+pub fn skip_value_xor(
+    reader: &mut BitReader,
+    prev_leading: &mut u32,
+    prev_trailing: &mut u32,
+) -> Option<()> {
+    if !reader.read_bit()? {
+        return Some(()); // '0': value unchanged
+    }
+
+    let (meaningful, _) = read_window(reader, prev_leading, prev_trailing)?;
+    reader.skip_bits(meaningful as usize)
+}
+
+/// Reads the control bit after a '1' and the window it selects, as
+/// (meaningful bits, trailing zeros), updating the remembered window
+///
+/// A window must fit the 64 bits: leading + meaningful + trailing == 64,
+/// with at least one meaningful bit. A corrupt stream can describe one
+/// that doesn't (31 leading zeros and 40 meaningful bits, say) or reuse
+/// a window before any was set; both give None instead of a shift out of
+/// range.
+fn read_window(
+    reader: &mut BitReader,
+    prev_leading: &mut u32,
+    prev_trailing: &mut u32,
+) -> Option<(u32, u32)> {
+    let (leading, meaningful) = if !reader.read_bit()? {
+        // Control bit '0': reuse previous window
+        let used = prev_leading.checked_add(*prev_trailing)?;
+        (*prev_leading, 64u32.checked_sub(used)?)
+    } else {
+        // Control bit '1': new window
+        let leading = reader.read_bits(5)? as u32;
+        let meaningful = match reader.read_bits(6)? as u32 {
+            0 => 64,
+            n => n,
+        };
+        (leading, meaningful)
+    };
+
+    let trailing = 64u32.checked_sub(leading.checked_add(meaningful)?)?;
+    if meaningful == 0 {
+        return None;
+    }
+    *prev_leading = leading;
+    *prev_trailing = trailing;
+    Some((meaningful, trailing))
+}
+
+/// What a series' values look like, so a block header can store its
+/// first value in fewer than 64 bits
+///
+/// With IntegerSmall the header writes '1' + a 16-bit signed integer when
+/// the value is one (negative zero excluded), else '0' + the 64-bit
+/// float; Float always writes the 64 bits. The hint only changes the
+/// header, so a wrong hint costs one bit per block, never correctness.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ValueHint {
+    #[default]
+    Float,
+    IntegerSmall,
+}
+
+impl ValueHint {
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(ValueHint::Float),
+            1 => Some(ValueHint::IntegerSmall),
+            _ => None,
+        }
+    }
+
+    /// Bits write_first_value uses for `value`
+    pub fn first_value_bits(self, value: f64) -> u32 {
+        match self {
+            ValueHint::Float => 64,
+            ValueHint::IntegerSmall if small_integer(value).is_some() => 1 + 16,
+            ValueHint::IntegerSmall => 1 + 64,
+        }
+    }
+
+    pub fn write_first_value(self, writer: &mut BitWriter, value: f64) {
+        if self == ValueHint::IntegerSmall {
+            let small = small_integer(value);
+            writer.write_bit(small.is_some());
+            if let Some(small) = small {
+                writer.write_bits(small as u16 as u64, 16);
+                return;
+            }
+        }
+        writer.write_bits(value.to_bits(), 64);
+    }
+
+    /// Read a value written by write_first_value with the same hint
+    pub fn read_first_value(self, reader: &mut BitReader) -> Option<f64> {
+        if self == ValueHint::IntegerSmall && reader.read_bit()? {
+            return Some(reader.read_bits(16)? as u16 as i16 as f64);
+        }
+        Some(f64::from_bits(reader.read_bits(64)?))
+    }
+}
+
+/// How the values after a block's first are stored
+///
+/// Xor is the paper's scheme and suits floats that drift. IntegerDelta
+/// suits counters and gauges holding whole numbers: each value is '1' +
+/// its difference from the previous one in the timestamp bucket encoding
+/// (see encode_timestamp_delta), or '0' + the 64-bit float when either
+/// value isn't an exact integer or the difference doesn't fit in 32 bits.
+/// Raw stores every value as its 64 bits. Any codec round-trips any
+/// value; a poor fit only costs space.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ValueCodec {
+    #[default]
+    Xor,
+    IntegerDelta,
+    Raw,
+}
+
+impl ValueCodec {
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(ValueCodec::Xor),
+            1 => Some(ValueCodec::IntegerDelta),
+            2 => Some(ValueCodec::Raw),
+            _ => None,
+        }
+    }
+
+    /// The codec suiting a sample of values: IntegerDelta if all are
+    /// exact integers, else Xor
+    pub fn detect(values: &[f64]) -> Self {
+        if !values.is_empty() && values.iter().all(|&value| exact_integer(value).is_some()) {
+            ValueCodec::IntegerDelta
+        } else {
+            ValueCodec::Xor
+        }
+    }
+
+    /// Bits this codec spends on `value` after `prev` (approximate for
+    /// Xor, see compress_value_xor)
+    pub fn value_bits(self, prev: f64, value: f64) -> usize {
+        match self {
+            ValueCodec::Xor => compress_value_xor(value.to_bits() ^ prev.to_bits()),
+            ValueCodec::IntegerDelta => match integer_delta(prev, value) {
+                Some(delta) => 1 + compress_timestamp(delta),
+                None => 1 + 64,
+            },
+            ValueCodec::Raw => 64,
+        }
+    }
+}
+
+/// `value` as an i64, if it is exactly one that survives the round trip
+/// through f64 (negative zero excluded)
+pub(super) fn exact_integer(value: f64) -> Option<i64> {
+    const LIMIT: f64 = (1u64 << 53) as f64;
+    let integer = value as i64;
+    (integer as f64 == value && value.abs() <= LIMIT && value.to_bits() != (-0.0f64).to_bits())
+        .then_some(integer)
+}
+
+/// `value - prev` for IntegerDelta, if both are exact integers and the
+/// difference fits the 32-bit bucket
+fn integer_delta(prev: f64, value: f64) -> Option<i64> {
+    let delta = exact_integer(value)? - exact_integer(prev)?;
+    i32::try_from(delta).is_ok().then_some(delta)
+}
+
+/// `value` as an i16, if it is exactly one
+fn small_integer(value: f64) -> Option<i16> {
+    let small = value as i16;
+    (small as f64 == value && value.to_bits() != (-0.0f64).to_bits()).then_some(small)
 }
 
 /// Complete value compression helper
 pub struct ValueCompressor {
+    codec: ValueCodec,
     prev_value: f64,
     prev_leading: u32,
     prev_trailing: u32,
 }
 
 impl ValueCompressor {
+    /// The previous window starts out "unset" (u32::MAX) so the first
+    /// non-zero XOR always writes its own leading/length header instead
+    /// of reusing a full 64-bit window.
     pub fn new(first_value: f64) -> Self {
+        Self::with_codec(first_value, ValueCodec::Xor)
+    }
+
+    pub fn with_codec(first_value: f64, codec: ValueCodec) -> Self {
         ValueCompressor {
+            codec,
             prev_value: first_value,
-            prev_leading: 0,
+            prev_leading: u32::MAX,
             prev_trailing: 0,
         }
     }
 
     pub fn add_value(&mut self, writer: &mut BitWriter, value: f64) -> usize {
-        let bits = encode_value_xor(
-            writer,
-            value,
-            self.prev_value,
-            &mut self.prev_leading,
-            &mut self.prev_trailing,
-        );
+        let bits = match self.codec {
+            ValueCodec::Xor => encode_value_xor(
+                writer,
+                value,
+                self.prev_value,
+                &mut self.prev_leading,
+                &mut self.prev_trailing,
+            ),
+            ValueCodec::IntegerDelta => {
+                let checkpoint = writer.checkpoint();
+                match integer_delta(self.prev_value, value) {
+                    Some(delta) => {
+                        writer.write_bit(true);
+                        encode_timestamp_delta(writer, delta);
+                    }
+                    None => {
+                        writer.write_bit(false);
+                        writer.write_bits(value.to_bits(), 64);
+                    }
+                }
+                writer.bit_count() - checkpoint
+            }
+            ValueCodec::Raw => {
+                writer.write_bits(value.to_bits(), 64);
+                64
+            }
+        };
 
         self.prev_value = value;
         bits
     }
 }
 
+/// Mirror of ValueCompressor: rebuilds values from the stream
+pub struct ValueDecompressor {
+    codec: ValueCodec,
+    prev_value: f64,
+    prev_leading: u32,
+    prev_trailing: u32,
+}
+
+impl ValueDecompressor {
+    pub fn new(first_value: f64) -> Self {
+        Self::with_codec(first_value, ValueCodec::Xor)
+    }
+
+    pub fn with_codec(first_value: f64, codec: ValueCodec) -> Self {
+        ValueDecompressor {
+            codec,
+            prev_value: first_value,
+            prev_leading: u32::MAX,
+            prev_trailing: 0,
+        }
+    }
+
+    /// Read the next value, or None on a truncated/invalid stream
+    pub fn next_value(&mut self, reader: &mut BitReader) -> Option<f64> {
+        let value = match self.codec {
+            ValueCodec::Xor => decode_value_xor(
+                reader,
+                self.prev_value,
+                &mut self.prev_leading,
+                &mut self.prev_trailing,
+            )?,
+            ValueCodec::IntegerDelta if reader.read_bit()? => {
+                let delta = decode_timestamp_delta(reader)?;
+                exact_integer(self.prev_value)?.checked_add(delta)? as f64
+            }
+            ValueCodec::IntegerDelta | ValueCodec::Raw => f64::from_bits(reader.read_bits(64)?),
+        };
+        self.prev_value = value;
+        Some(value)
+    }
+
+    /// Move past the next value without rebuilding it
+    ///
+    /// The decompressor can't produce values afterwards (it no longer
+    /// knows the previous one), so use it for skipping only from here on.
+    pub fn skip_value(&mut self, reader: &mut BitReader) -> Option<()> {
+        match self.codec {
+            ValueCodec::Xor => {
+                skip_value_xor(reader, &mut self.prev_leading, &mut self.prev_trailing)
+            }
+            ValueCodec::IntegerDelta if reader.read_bit()? => {
+                decode_timestamp_delta(reader).map(|_| ())
+            }
+            ValueCodec::IntegerDelta | ValueCodec::Raw => reader.skip_bits(64),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -149,7 +451,7 @@ mod tests {
     #[test]
     fn test_identical_values() {
         // Identical values compress to just 1 bit each
-        let values = vec![42.0, 42.0, 42.0, 42.0];
+        let values = [42.0, 42.0, 42.0, 42.0];
 
         let mut writer = BitWriter::new();
         let mut compressor = ValueCompressor::new(values[0]);
@@ -172,7 +474,7 @@ mod tests {
     #[test]
     fn test_similar_values() {
         // Similar values compress well
-        let values = vec![100.0, 100.5, 100.2, 100.8];
+        let values = [100.0, 100.5, 100.2, 100.8];
 
         let mut writer = BitWriter::new();
         let mut compressor = ValueCompressor::new(values[0]);
@@ -200,7 +502,7 @@ mod tests {
     fn test_integer_values() {
         // Integer values stored as floats compress extremely well
         // because only the mantissa changes in predictable patterns
-        let values = vec![8192.0, 8192.0, 8192.0, 8193.0, 8192.0];
+        let values = [8192.0, 8192.0, 8192.0, 8193.0, 8192.0];
 
         let mut writer = BitWriter::new();
         let mut compressor = ValueCompressor::new(values[0]);
@@ -222,4 +524,171 @@ mod tests {
             total_bits as f64 / values.len() as f64
         );
     }
+
+    #[test]
+    fn test_value_round_trip() {
+        // Mix of repeats, small changes, sign flips and extreme values
+        let values = [
+            12.0,
+            12.0,
+            24.0,
+            11.5,
+            -11.5,
+            0.0,
+            1e300,
+            f64::MIN_POSITIVE,
+            1.0,
+            1.0000000001,
+            f64::MAX,
+            -0.0,
+        ];
+
+        let mut writer = BitWriter::new();
+        let mut compressor = ValueCompressor::new(values[0]);
+        for &val in &values[1..] {
+            compressor.add_value(&mut writer, val);
+        }
+        let buffer = writer.finish();
+
+        let mut reader = BitReader::new(&buffer);
+        let mut decompressor = ValueDecompressor::new(values[0]);
+        for &val in &values[1..] {
+            let decoded = decompressor.next_value(&mut reader).unwrap();
+            assert_eq!(decoded.to_bits(), val.to_bits());
+        }
+
+        // Skipping lands on the same bit positions as decoding
+        let mut skipper = BitReader::new(&buffer);
+        let mut decompressor = ValueDecompressor::new(values[0]);
+        for _ in &values[1..] {
+            decompressor.skip_value(&mut skipper).unwrap();
+        }
+        assert!(skipper.skip_bits(8).is_none());
+        let remaining = |reader: &mut BitReader| std::iter::from_fn(|| reader.read_bit()).count();
+        assert_eq!(remaining(&mut skipper), remaining(&mut reader));
+    }
+
+    #[test]
+    fn test_value_codecs() {
+        // Integers with small and huge steps, then floats and specials
+        let values = [
+            100.0,
+            101.0,
+            99.0,
+            99.0,
+            -5e9,
+            4e15,
+            2.5,
+            -0.0,
+            f64::NAN,
+            7.0,
+        ];
+        for codec in [ValueCodec::Xor, ValueCodec::IntegerDelta, ValueCodec::Raw] {
+            let mut writer = BitWriter::new();
+            let mut compressor = ValueCompressor::with_codec(values[0], codec);
+            for window in values.windows(2) {
+                let bits = compressor.add_value(&mut writer, window[1]);
+                if codec != ValueCodec::Xor {
+                    assert_eq!(bits, codec.value_bits(window[0], window[1]));
+                }
+            }
+            let buffer = writer.finish();
+
+            let mut reader = BitReader::new(&buffer);
+            let mut decompressor = ValueDecompressor::with_codec(values[0], codec);
+            for &val in &values[1..] {
+                let decoded = decompressor.next_value(&mut reader).unwrap();
+                assert_eq!(decoded.to_bits(), val.to_bits(), "{codec:?}");
+            }
+
+            let mut skipper = BitReader::new(&buffer);
+            let mut decompressor = ValueDecompressor::with_codec(values[0], codec);
+            for _ in &values[1..] {
+                decompressor.skip_value(&mut skipper).unwrap();
+            }
+            let remaining =
+                |reader: &mut BitReader| std::iter::from_fn(|| reader.read_bit()).count();
+            assert_eq!(remaining(&mut skipper), remaining(&mut reader));
+        }
+
+        // Small integer steps cost ten bits, other values 65
+        assert_eq!(ValueCodec::IntegerDelta.value_bits(100.0, 101.0), 1 + 9);
+        assert_eq!(ValueCodec::IntegerDelta.value_bits(100.0, 100.5), 65);
+        assert_eq!(ValueCodec::IntegerDelta.value_bits(0.0, 4e15), 65);
+
+        assert_eq!(
+            ValueCodec::detect(&[1.0, 2.0, -3.0]),
+            ValueCodec::IntegerDelta
+        );
+        assert_eq!(ValueCodec::detect(&[1.0, 2.5]), ValueCodec::Xor);
+        assert_eq!(ValueCodec::detect(&[-0.0]), ValueCodec::Xor);
+        assert_eq!(ValueCodec::detect(&[]), ValueCodec::Xor);
+        for codec in [ValueCodec::Xor, ValueCodec::IntegerDelta, ValueCodec::Raw] {
+            assert_eq!(ValueCodec::from_byte(codec.to_byte()), Some(codec));
+        }
+        assert_eq!(ValueCodec::from_byte(3), None);
+    }
+
+    #[test]
+    fn test_corrupt_windows_decode_to_none() {
+        let decode = |bits: &[(u64, u8)]| {
+            let mut writer = BitWriter::new();
+            for &(value, width) in bits {
+                writer.write_bits(value, width);
+            }
+            let buffer = writer.finish();
+            let decoded = ValueDecompressor::new(1.0).next_value(&mut BitReader::new(&buffer));
+            let skipped = ValueDecompressor::new(1.0).skip_value(&mut BitReader::new(&buffer));
+            assert_eq!(decoded.is_some(), skipped.is_some());
+            decoded
+        };
+
+        // 31 leading zeros and 40 meaningful bits don't fit in 64
+        assert_eq!(decode(&[(0b11, 2), (31, 5), (40, 6), (0, 40)]), None);
+        // Reusing a window before any was set
+        assert_eq!(decode(&[(0b10, 2), (0, 64)]), None);
+        // A full 64-bit window (length 0) cut short
+        assert_eq!(decode(&[(0b11, 2), (0, 5), (0, 6), (1, 32)]), None);
+        // Right at the limit: 31 leading zeros, 33 meaningful bits
+        assert_eq!(
+            decode(&[(0b11, 2), (31, 5), (33, 6), (1, 33)]),
+            Some(f64::from_bits(1.0f64.to_bits() ^ 1))
+        );
+
+        // Arbitrary bytes end the stream cleanly instead of panicking
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        for _ in 0..200 {
+            let garbage: Vec<u8> = (0..32)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect();
+            for codec in [ValueCodec::Xor, ValueCodec::IntegerDelta] {
+                let mut reader = BitReader::new(&garbage);
+                let mut decompressor = ValueDecompressor::with_codec(0.5, codec);
+                while decompressor.next_value(&mut reader).is_some() {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_first_value_hint() {
+        for value in [0.0, -0.0, 7.0, -32768.0, 32767.0, 32768.0, 1.5, f64::NAN] {
+            for hint in [ValueHint::Float, ValueHint::IntegerSmall] {
+                let mut writer = BitWriter::new();
+                hint.write_first_value(&mut writer, value);
+                assert_eq!(writer.bit_count(), hint.first_value_bits(value) as usize);
+
+                let buffer = writer.finish();
+                let read = hint.read_first_value(&mut BitReader::new(&buffer)).unwrap();
+                assert_eq!(read.to_bits(), value.to_bits());
+            }
+        }
+        assert_eq!(ValueHint::IntegerSmall.first_value_bits(-12.0), 17);
+        assert_eq!(ValueHint::IntegerSmall.first_value_bits(-0.0), 65);
+        assert_eq!(ValueHint::IntegerSmall.first_value_bits(40000.0), 65);
+    }
 }
@@ -0,0 +1,65 @@
+//! Gorilla: an in-memory time series database
+//!
+//! An implementation of Facebook's Gorilla paper (VLDB 2015): points are
+//! kept in two-hour blocks compressed with delta-of-delta timestamps and
+//! XOR'd values, in a sharded map of series with a write-ahead log,
+//! snapshots and on-disk checkpoints behind it.
+//!
+//! ```
+//! # #[cfg(feature = "std")] {
+//! use tsdb::Gorilla;
+//!
+//! let gorilla = Gorilla::new();
+//! gorilla.insert("server1.cpu", 1_700_000_000, 45.2);
+//! gorilla.insert("server1.cpu", 1_700_000_060, 46.1);
+//! let points = gorilla.query("server1.cpu", 0, u64::MAX).unwrap();
+//! assert_eq!(points, vec![(1_700_000_000, 45.2), (1_700_000_060, 46.1)]);
+//! # }
+//! ```
+//!
+//! [`Gorilla`] is the entry point; [`compression`] has the codecs on
+//! their own, and [`storage`] the series, options and persistence types
+//! the engine is configured with. Series are read through
+//! [`SeriesHandle`]s and only changed through the engine, so every write
+//! goes through its checks and WAL.
+//!
+//! Without the default `std` feature only [`compression`] and the error
+//! types are built, for `no_std` crates with `alloc`.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+pub mod compression;
+#[cfg(feature = "std")]
+pub mod storage;
+#[cfg(feature = "std")]
+mod tsdb;
+
+// The codecs report errors as TsdbError; the error types need only alloc
+#[cfg(not(feature = "std"))]
+mod tsdb {
+    #[path = "error.rs"]
+    mod error;
+
+    pub use error::{InsertError, QueryError, TsdbError};
+}
+
+pub use tsdb::{InsertError, QueryError, TsdbError};
+
+#[cfg(feature = "std")]
+pub use storage::{
+    DEFAULT_TOMBSTONE_GRACE_SECS, DataPoint, DuplicatePolicy, QueryPlan, SeriesHandle, SeriesMeta,
+    SeriesOptions, TimeSeries, downsample::Aggregation,
+};
+#[cfg(all(feature = "std", feature = "arrow"))]
+pub use tsdb::arrow;
+#[cfg(all(feature = "std", feature = "tokio"))]
+pub use tsdb::ingest;
+#[cfg(feature = "std")]
+pub use tsdb::{
+    BatchOutcome, CompressionStats, DEFAULT_EXACT_QUANTILE_POINTS, DEFAULT_FUTURE_TOLERANCE_SECS,
+    EmptyBuckets, EngineMetrics, Gorilla, GorillaConfig, InsertOutcome, MergeReport, Namespace,
+    NanValues, Order, QuantileMethod, QuantileResult, QueryOpts, QueryResult, RollupReport, Sample,
+    SelectedSeries, SeriesListing, Smoothing, WarmUp, closer,
+};
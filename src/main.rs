@@ -1,12 +1,10 @@
 // Gorilla Time Series Database - Educational Implementation
+//
+// A tour of the library's API (see src/lib.rs), used as an external crate
+// would use it.
 
-// Core modules that implement Gorilla's architecture
-mod compression; // Timestamp and value compression algorithms
-mod storage; // In-memory data structures
-mod tsdb; // Main database interface
-
-use std::time::{SystemTime, UNIX_EPOCH};
-use tsdb::Gorilla;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tsdb::{Gorilla, SeriesMeta};
 
 fn main() {
     println!("=== Gorilla Time Series Database ===\n");
@@ -16,7 +14,8 @@ fn main() {
 
     // Example 1: Store CPU metrics (simulating regular intervals)
     println!("Example 1: Storing CPU metrics at regular 60-second intervals");
-    let base_time = get_current_timestamp();
+    // Start an hour back so the demo never writes past the future tolerance
+    let base_time = get_current_timestamp() - 3600;
 
     let cpu_values = vec![
         (base_time, 45.2),
@@ -32,9 +31,16 @@ fn main() {
 
     // Show compression efficiency
     let stats = gorilla.get_stats("server1.cpu.usage");
-    println!("Original size: {} bytes", stats.original_size);
+    println!("Logical size: {} bytes", stats.logical_uncompressed_bytes);
     println!("Compressed size: {} bytes", stats.compressed_size);
-    println!("Compression ratio: {:.2}x\n", stats.compression_ratio);
+    println!(
+        "Logical compression ratio: {:.2}x",
+        stats.logical_compression_ratio
+    );
+    println!(
+        "Resident: {} bytes raw + {} bytes compressed (open blocks keep raw points)\n",
+        stats.resident_raw_bytes, stats.resident_compressed_bytes
+    );
 
     // Example 2: Query the data back
     println!("Example 2: Querying data");
@@ -64,11 +70,30 @@ fn main() {
     let mem_stats = gorilla.get_stats("server1.memory.used");
     println!("Memory metrics compression:");
     println!(
-        "Original: {} bytes -> Compressed: {} bytes",
-        mem_stats.original_size, mem_stats.compressed_size
+        "Logical: {} bytes -> Compressed: {} bytes",
+        mem_stats.logical_uncompressed_bytes, mem_stats.compressed_size
+    );
+    println!(
+        "Logical compression ratio: {:.2}x",
+        mem_stats.logical_compression_ratio
     );
-    println!("Compression ratio: {:.2}x", mem_stats.compression_ratio);
-    println!("(Notice how similar values compress extremely well!)\n");
+    println!("(Notice how similar values compress extremely well!)");
+
+    // Attach a unit so readers know what the numbers mean
+    let meta = SeriesMeta {
+        unit: Some("bytes".to_string()),
+        description: Some("Resident memory of server1".to_string()),
+        ..SeriesMeta::default()
+    };
+    if gorilla.set_meta("server1.memory.used", meta).is_ok()
+        && let Some(meta) = gorilla.get_meta("server1.memory.used")
+    {
+        println!(
+            "Unit: {} ({})\n",
+            meta.unit.unwrap_or_default(),
+            meta.description.unwrap_or_default()
+        );
+    }
 
     // Example 4: Demonstrate delta-of-delta timestamp compression
     println!("Example 4: Timestamp compression visualization");
@@ -78,6 +103,10 @@ fn main() {
     println!("\nExample 5: Value compression visualization");
     demonstrate_value_compression();
 
+    // Example 5b: The same encoding, read back from a stored block
+    println!("\nExample 5b: Bit layout of the memory block");
+    demonstrate_block_layout(&gorilla);
+
     // Example 6: Advanced features
     println!("\nExample 6: Advanced features");
     demonstrate_advanced_features(&mut gorilla, base_time);
@@ -86,7 +115,7 @@ fn main() {
 fn get_current_timestamp() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
-        .unwrap()
+        .unwrap_or(Duration::ZERO)
         .as_secs()
 }
 
@@ -96,11 +125,11 @@ fn format_timestamp(ts: u64) -> String {
 }
 
 fn demonstrate_timestamp_compression() {
-    use compression::timestamp::compress_timestamp;
+    use tsdb::compression::timestamp::compress_timestamp;
 
     println!("  Regular 60-second intervals:");
     let t0 = 1000u64;
-    let timestamps = vec![t0, t0 + 60, t0 + 120, t0 + 180];
+    let timestamps = [t0, t0 + 60, t0 + 120, t0 + 180];
 
     let mut prev_ts = t0;
     let mut prev_delta = 0i64;
@@ -156,6 +185,20 @@ fn demonstrate_value_compression() {
     }
 }
 
+fn demonstrate_block_layout(gorilla: &Gorilla) {
+    gorilla.for_each_block(|key, block| {
+        if key != "server1.memory.used" {
+            return;
+        }
+        for field in block.describe_encoding() {
+            println!(
+                "    point {} @ bit {:>3}: {:<17} {:>2} bits  {:?}",
+                field.point, field.offset, field.name, field.bits, field.value
+            );
+        }
+    });
+}
+
 fn demonstrate_advanced_features(gorilla: &mut Gorilla, base_time: u64) {
     // Add some correlated metrics for demonstration
     println!("  Adding correlated metrics:");
@@ -185,40 +228,26 @@ fn demonstrate_advanced_features(gorilla: &mut Gorilla, base_time: u64) {
     });
     println!("    Total data points across all series: {}", count);
 
-    // Demonstrate delete
-    gorilla.delete("server1.memory.used");
-    println!("    Deleted series: server1.memory.used");
-}
-
-#[cfg(test)]
-mod integration_tests {
-    use super::*;
-
-    #[test]
-    fn test_all_methods_used() {
-        let mut gorilla = Gorilla::new();
-        let base_time = 1000u64;
-
-        // Insert data
-        gorilla.insert("test.metric", base_time, 100.0);
-        gorilla.insert("test.metric2", base_time, 200.0);
-
-        // Test scan
-        let mut count = 0;
-        gorilla.scan(|_key, _ts, _val| {
-            count += 1;
-        });
-        assert!(count > 0);
-
-        // Test delete
-        gorilla.delete("test.metric");
-        assert!(
-            gorilla
-                .query("test.metric", base_time, base_time + 100)
-                .is_none()
-        );
+    // Demonstrate rename (history is kept under the new key)
+    match gorilla.rename("web01.response_time", "web01.latency") {
+        Ok(()) => println!("    Renamed series: web01.response_time -> web01.latency"),
+        Err(e) => println!("    Rename failed: {}", e),
+    }
 
-        // Test find_correlated
-        let _correlations = gorilla.find_correlated("test.metric2", base_time, base_time + 100, 5);
+    // Demonstrate delete
+    match gorilla.delete("server1.memory.used") {
+        Ok(()) => println!("    Deleted series: server1.memory.used"),
+        Err(e) => println!("    Delete failed: {}", e),
     }
+    println!("    Compacted {} tombstoned slot(s)", gorilla.compact());
+
+    // Engine self-monitoring counters
+    let metrics = gorilla.metrics();
+    println!(
+        "    Engine metrics: {} points, {} bytes compressed, {} blocks closed, {} rejected",
+        metrics.points_inserted,
+        metrics.bytes_compressed,
+        metrics.blocks_closed,
+        metrics.inserts_rejected
+    );
 }
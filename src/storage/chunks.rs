@@ -0,0 +1,350 @@
+// Streaming blocks' compressed bytes to per-shard chunk files
+//
+// Paper Section 4.3: Gorilla flushes compressed data to disk in pieces
+// (about 64KB) so a crash loses at most a small tail. Here each shard has
+// an append-only chunk file. Once a series' open block holds
+// `flush_bytes` compressed bytes that aren't on disk yet, they are written
+// as a chunk; a block's remaining bytes follow when it closes. In-order
+// points only ever append to a block's stream, so a chunk normally starts
+// where the previous one ended (less the partly filled last byte). A
+// write that rewrites earlier bytes (an out-of-order point, a replaced
+// duplicate) makes the next chunk start further back.
+//
+// Recovery replays each file in order: a chunk cuts its block's stream at
+// its offset and appends its bytes, which rebuilds the stream as it stood
+// at the block's last flush; the chunk's point count says how much of it
+// decodes. A record that is incomplete or fails its checksum (torn by a
+// crash) ends its file: it and anything after it are cut off.
+//
+// Record layout (all integers little-endian):
+//   length u32 (of what follows the checksum), CRC-32 u32 of it, key
+//   length u32, key bytes, block start u64, timestamp codec u8, value
+//   hint u8, value codec u8, offset u32, point count u32, compressed
+//   bytes
+//
+// Only points are recorded: deletes, renames and series options aren't,
+// so recovery recreates every series ever flushed with the default
+// options. Chunk files are never compacted.
+
+use super::snapshot::{SnapshotReader, invalid, write_len, write_str};
+use super::wal::crc32;
+use super::{
+    BlockEncoding, DEFAULT_BLOCK_DURATION_SECS, SeriesMeta, SeriesOptions, TimeSeries,
+    TimeSeriesBlock, TimeSeriesMap,
+};
+use crate::compression::timestamp::TimestampCodec;
+use crate::compression::value::{ValueCodec, ValueHint};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+/// Where and how often blocks are streamed to chunk files
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkConfig {
+    /// Directory holding one chunk file per shard (created if missing)
+    pub dir: PathBuf,
+
+    /// Compressed bytes an open block may hold unflushed; at most this
+    /// much of each open block is lost in a crash
+    pub flush_bytes: usize,
+}
+
+/// Compressed bytes of one block, from `offset` to the end of its stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub key: String,
+    pub start_time: u64,
+    pub encoding: BlockEncoding,
+    pub offset: usize,
+    pub point_count: usize, // Points in the whole stream up to this chunk's end
+    pub bytes: Vec<u8>,
+}
+
+/// Outcome of rebuilding series from chunk files
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ChunkReplay {
+    pub chunks: usize,     // Chunk records read
+    pub blocks: usize,     // Blocks rebuilt from them
+    pub points: usize,     // Points in those blocks
+    pub torn_bytes: u64,   // Bytes cut off the ends of files
+    pub bad_blocks: usize, // Blocks whose rebuilt stream didn't decode (skipped)
+}
+
+/// The open chunk files of a map's shards
+pub(crate) struct ChunkFiles {
+    files: Vec<Mutex<File>>,
+    flush_bytes: usize,
+}
+
+impl ChunkFiles {
+    /// Start empty chunk files for `shard_count` shards, replacing any
+    /// found in the directory
+    pub fn create(config: &ChunkConfig, shard_count: usize) -> io::Result<ChunkFiles> {
+        fs::create_dir_all(&config.dir)?;
+        let files = (0..shard_count)
+            .map(|shard| File::create(shard_path(&config.dir, shard)).map(Mutex::new))
+            .collect::<io::Result<_>>()?;
+        Ok(ChunkFiles {
+            files,
+            flush_bytes: config.flush_bytes,
+        })
+    }
+
+    /// Open the chunk files for appending after a replay cut them clean
+    fn append(config: &ChunkConfig, shard_count: usize) -> io::Result<ChunkFiles> {
+        let files = (0..shard_count)
+            .map(|shard| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(shard_path(&config.dir, shard))
+                    .map(Mutex::new)
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(ChunkFiles {
+            files,
+            flush_bytes: config.flush_bytes,
+        })
+    }
+
+    pub fn flush_bytes(&self) -> usize {
+        self.flush_bytes
+    }
+
+    /// Append a chunk to a shard's file and sync it
+    pub fn write(&self, shard: usize, chunk: &Chunk) -> io::Result<()> {
+        let mut body = Vec::with_capacity(chunk.key.len() + chunk.bytes.len() + 26);
+        write_str(&mut body, &chunk.key)?;
+        body.extend_from_slice(&chunk.start_time.to_le_bytes());
+        body.push(chunk.encoding.timestamp_codec.to_byte());
+        body.push(chunk.encoding.value_hint.to_byte());
+        body.push(chunk.encoding.value_codec.to_byte());
+        write_len(&mut body, chunk.offset)?;
+        write_len(&mut body, chunk.point_count)?;
+        body.extend_from_slice(&chunk.bytes);
+
+        let mut record = Vec::with_capacity(body.len() + 8);
+        write_len(&mut record, body.len())?;
+        record.extend_from_slice(&crc32(&body).to_le_bytes());
+        record.extend_from_slice(&body);
+
+        let mut file = self.files[shard]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        file.write_all(&record)?;
+        file.sync_data()
+    }
+}
+
+/// Rebuild series from the chunk files in `config.dir`
+///
+/// Each key's newest block becomes its open block, and every block
+/// counts as flushed. Files are cut at their first torn record and
+/// returned open for appending. A record that reads back but doesn't fit
+/// (unknown encoding, offset past its block's stream) is an
+/// `InvalidData` error.
+pub(crate) fn replay(
+    config: &ChunkConfig,
+    options: &SeriesOptions,
+) -> io::Result<(TimeSeriesMap, ChunkFiles, ChunkReplay)> {
+    fs::create_dir_all(&config.dir)?;
+    let mut map = TimeSeriesMap::new();
+    let mut report = ChunkReplay::default();
+
+    // Streams per key and block start, as of each block's last chunk
+    let mut streams: BTreeMap<String, BTreeMap<u64, Stream>> = BTreeMap::new();
+    for shard in 0..map.shard_count() {
+        let path = shard_path(&config.dir, shard);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        let mut pos = 0;
+        while pos < data.len() {
+            let Some((chunk, len)) = read_record(&data[pos..])? else {
+                report.torn_bytes += (data.len() - pos) as u64;
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(pos as u64)?;
+                break;
+            };
+            let (encoding, point_count, stream) = streams
+                .entry(chunk.key)
+                .or_default()
+                .entry(chunk.start_time)
+                .or_insert_with(|| (chunk.encoding, 0, Vec::new()));
+            if chunk.offset > stream.len() {
+                return Err(invalid(format!(
+                    "chunk at offset {} past the end of block {}",
+                    chunk.offset, chunk.start_time
+                )));
+            }
+            stream.truncate(chunk.offset);
+            stream.extend_from_slice(&chunk.bytes);
+            *encoding = chunk.encoding;
+            *point_count = chunk.point_count;
+            report.chunks += 1;
+            pos += len;
+        }
+    }
+
+    for (key, blocks) in streams {
+        let mut rebuilt: Vec<TimeSeriesBlock> = Vec::new();
+        for (start_time, (encoding, point_count, stream)) in blocks {
+            match TimeSeriesBlock::from_compressed(start_time, point_count, encoding, &stream) {
+                Some(mut block) => {
+                    block.flushed = stream.len();
+                    report.points += block.len();
+                    rebuilt.push(block);
+                }
+                None => report.bad_blocks += 1,
+            }
+        }
+        report.blocks += rebuilt.len();
+        let open = rebuilt.pop();
+        let block_duration = options
+            .block_duration
+            .unwrap_or(DEFAULT_BLOCK_DURATION_SECS);
+        let series = TimeSeries::from_parts(
+            key,
+            options.clone(),
+            block_duration,
+            rebuilt,
+            open,
+            SeriesMeta::default(),
+            None,
+        );
+        map.insert_series(series)
+            .map_err(|e| invalid(format!("duplicate series in chunk files: {}", e)))?;
+    }
+
+    let files = ChunkFiles::append(config, map.shard_count())?;
+    Ok((map, files, report))
+}
+
+// A block's encoding, point count and compressed bytes during replay
+type Stream = (BlockEncoding, usize, Vec<u8>);
+
+/// One record from the front of `data` and its length; None if it is
+/// incomplete or fails its checksum (a torn write)
+fn read_record(data: &[u8]) -> io::Result<Option<(Chunk, usize)>> {
+    let mut reader = SnapshotReader::new(data, "chunk file");
+    let (Ok(len), Ok(checksum)) = (reader.u32("record length"), reader.u32("record checksum"))
+    else {
+        return Ok(None);
+    };
+    let Ok(body) = reader.take(len as usize, "record") else {
+        return Ok(None);
+    };
+    if crc32(body) != checksum {
+        return Ok(None);
+    }
+
+    let mut reader = SnapshotReader::new(body, "chunk record");
+    let key = reader.string("key")?;
+    let start_time = reader.u64("block start")?;
+    let codec = reader.u8("timestamp codec")?;
+    let hint = reader.u8("value hint")?;
+    let value_codec = reader.u8("value codec")?;
+    let encoding = match (
+        TimestampCodec::from_byte(codec),
+        ValueHint::from_byte(hint),
+        ValueCodec::from_byte(value_codec),
+    ) {
+        (Some(timestamp_codec), Some(value_hint), Some(value_codec)) => BlockEncoding {
+            timestamp_codec,
+            value_hint,
+            value_codec,
+        },
+        _ => {
+            return Err(invalid(format!(
+                "unknown block encoding for series {}",
+                key
+            )));
+        }
+    };
+    let offset = reader.u32("offset")? as usize;
+    let point_count = reader.u32("point count")? as usize;
+    let bytes = reader.take(reader.remaining(), "chunk bytes")?.to_vec();
+
+    let chunk = Chunk {
+        key,
+        start_time,
+        encoding,
+        offset,
+        point_count,
+        bytes,
+    };
+    Ok(Some((chunk, 8 + len as usize)))
+}
+
+fn shard_path(dir: &Path, shard: usize) -> PathBuf {
+    dir.join(format!("shard-{}.chunks", shard))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunks_follow_appends_and_rewrites() {
+        let mut series = TimeSeries::new("cpu");
+        let base_time = 7200 * 100;
+        for i in 0..40 {
+            series.insert(base_time + i * 60, (i % 7) as f64 * 1.5);
+        }
+        let first = series.take_chunks(16);
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].offset, 0);
+        assert!(series.take_chunks(16).is_empty());
+
+        // An in-order point resends at most the last, partly filled byte
+        series.insert(base_time + 40 * 60, 100.0);
+        let next = series.take_chunks(1);
+        assert_eq!(next[0].offset, first[0].bytes.len() - 1);
+
+        // An out-of-order point rewrites the stream from the start
+        series.insert(base_time + 30, 5.0);
+        assert_eq!(series.take_chunks(1)[0].offset, 0);
+
+        // Closing the block flushes whatever it has left, however small
+        series.insert(base_time + 41 * 60, 3.0);
+        series.insert(base_time + 7200, 1.0);
+        series.insert(base_time + 7201, 2.0);
+        let closed = series.take_chunks(usize::MAX);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].start_time, base_time);
+        assert_eq!(closed[0].point_count, 43);
+    }
+
+    #[test]
+    fn test_record_round_trip_and_torn_tail() {
+        let dir = std::env::temp_dir().join(format!("tsdb_chunks_{}", std::process::id()));
+        let config = ChunkConfig {
+            dir: dir.clone(),
+            flush_bytes: 1,
+        };
+        let files = ChunkFiles::create(&config, 1).unwrap();
+        let chunk = Chunk {
+            key: "cpu".to_string(),
+            start_time: 7200,
+            encoding: BlockEncoding::default(),
+            offset: 3,
+            point_count: 9,
+            bytes: vec![1, 2, 3],
+        };
+        files.write(0, &chunk).unwrap();
+        let data = fs::read(shard_path(&dir, 0)).unwrap();
+        assert_eq!(read_record(&data).unwrap(), Some((chunk, data.len())));
+
+        for len in 0..data.len() {
+            assert_eq!(read_record(&data[..len]).unwrap(), None);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+// Raw points of a block as parallel columns (structure of arrays)
+//
+// Timestamps and values live in two vectors instead of one Vec<DataPoint>,
+// so a scan that only needs values (sums, averages, correlation) reads
+// half the bytes and gets contiguous f64 slices for numeric work.
+// DataPoint stays the item type; iteration builds them on the fly.
+
+use super::DataPoint;
+use std::ops::Range;
+
+/// Time-ordered points kept as a timestamp column and a value column
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PointColumns {
+    timestamps: Vec<u64>,
+    values: Vec<f64>,
+}
+
+impl PointColumns {
+    pub fn new() -> Self {
+        PointColumns::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        PointColumns {
+            timestamps: Vec::with_capacity(capacity),
+            values: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Columns from parallel vectors; panics if their lengths differ
+    pub fn from_columns(timestamps: Vec<u64>, values: Vec<f64>) -> Self {
+        assert_eq!(timestamps.len(), values.len(), "columns differ in length");
+        PointColumns { timestamps, values }
+    }
+
+    pub fn len(&self) -> usize {
+        self.timestamps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.timestamps.is_empty()
+    }
+
+    pub fn timestamps(&self) -> &[u64] {
+        &self.timestamps
+    }
+
+    pub fn values(&self) -> &[f64] {
+        &self.values
+    }
+
+    /// The point at `index`; panics if out of bounds
+    pub fn point(&self, index: usize) -> DataPoint {
+        DataPoint {
+            timestamp: self.timestamps[index],
+            value: self.values[index],
+        }
+    }
+
+    pub fn last(&self) -> Option<DataPoint> {
+        self.len().checked_sub(1).map(|index| self.point(index))
+    }
+
+    pub fn push(&mut self, point: DataPoint) {
+        self.timestamps.push(point.timestamp);
+        self.values.push(point.value);
+    }
+
+    /// Append parallel slices; panics if their lengths differ
+    pub fn extend_from_slices(&mut self, timestamps: &[u64], values: &[f64]) {
+        assert_eq!(timestamps.len(), values.len(), "columns differ in length");
+        self.timestamps.extend_from_slice(timestamps);
+        self.values.extend_from_slice(values);
+    }
+
+    pub fn insert(&mut self, index: usize, point: DataPoint) {
+        self.timestamps.insert(index, point.timestamp);
+        self.values.insert(index, point.value);
+    }
+
+    pub fn set_value(&mut self, index: usize, value: f64) {
+        self.values[index] = value;
+    }
+
+    /// Binary search for a timestamp (see slice::binary_search)
+    pub fn search(&self, timestamp: u64) -> Result<usize, usize> {
+        self.timestamps.binary_search(&timestamp)
+    }
+
+    /// Indices of the points with timestamps in [start, end]
+    pub fn range(&self, start: u64, end: u64) -> Range<usize> {
+        let from = self.timestamps.partition_point(|&ts| ts < start);
+        let to = self.timestamps.partition_point(|&ts| ts <= end);
+        from..to.max(from)
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = DataPoint> + '_ {
+        (0..self.len()).map(|index| self.point(index))
+    }
+
+    /// Merge in another set of points; ours win on equal timestamps
+    pub fn merge(&mut self, other: &PointColumns) {
+        let mut merged = PointColumns::with_capacity(self.len() + other.len());
+        let (mut i, mut j) = (0, 0);
+        while i < self.len() || j < other.len() {
+            let take_ours =
+                j == other.len() || (i < self.len() && self.timestamps[i] <= other.timestamps[j]);
+            if take_ours {
+                if j < other.len() && other.timestamps[j] == self.timestamps[i] {
+                    j += 1;
+                }
+                merged.push(self.point(i));
+                i += 1;
+            } else {
+                merged.push(other.point(j));
+                j += 1;
+            }
+        }
+        *self = merged;
+    }
+
+    /// Points that fit without reallocating
+    pub fn capacity(&self) -> usize {
+        self.timestamps.capacity().min(self.values.capacity())
+    }
+
+    /// Bytes allocated for both columns
+    pub fn capacity_bytes(&self) -> usize {
+        self.timestamps.capacity() * size_of::<u64>() + self.values.capacity() * size_of::<f64>()
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.timestamps.shrink_to_fit();
+        self.values.shrink_to_fit();
+    }
+}
+
+impl FromIterator<DataPoint> for PointColumns {
+    fn from_iter<I: IntoIterator<Item = DataPoint>>(iter: I) -> Self {
+        let mut columns = PointColumns::new();
+        for point in iter {
+            columns.push(point);
+        }
+        columns
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns(points: &[(u64, f64)]) -> PointColumns {
+        points
+            .iter()
+            .map(|&(timestamp, value)| DataPoint { timestamp, value })
+            .collect()
+    }
+
+    #[test]
+    fn test_range_and_merge() {
+        let mut ours = columns(&[(10, 1.0), (20, 2.0), (30, 3.0)]);
+        assert_eq!(ours.range(15, 30), 1..3);
+        assert_eq!(ours.range(0, 5), 0..0);
+        assert_eq!(ours.range(31, 40), 3..3);
+        assert_eq!(ours.range(25, 15), 2..2);
+
+        ours.merge(&columns(&[(5, 0.5), (20, -2.0), (40, 4.0)]));
+        assert_eq!(ours.timestamps(), &[5, 10, 20, 30, 40]);
+        assert_eq!(ours.values(), &[0.5, 1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(ours.last().map(|p| p.timestamp), Some(40));
+        assert_eq!(ours.search(30), Ok(3));
+        assert_eq!(ours.search(35), Err(4));
+    }
+
+    #[test]
+    fn test_value_scan_matches_row_layout() {
+        let rows: Vec<DataPoint> = (0..200_000u64)
+            .map(|i| DataPoint {
+                timestamp: 1000 + i * 10,
+                value: (i as f64 * 0.01).sin(),
+            })
+            .collect();
+        let columns: PointColumns = rows.iter().copied().collect();
+
+        // Same points either way, and the same aggregate results
+        assert!(columns.iter().eq(rows.iter().copied()));
+        let start = std::time::Instant::now();
+        let row_sum: f64 = rows.iter().map(|p| p.value).sum();
+        let row_time = start.elapsed();
+        let start = std::time::Instant::now();
+        let column_sum: f64 = columns.values().iter().sum();
+        let column_time = start.elapsed();
+        assert_eq!(row_sum.to_bits(), column_sum.to_bits());
+
+        let range = columns.range(1000 + 50_000 * 10, 1000 + 150_000 * 10 - 1);
+        let row_max = rows[range.clone()]
+            .iter()
+            .map(|p| p.value)
+            .fold(f64::NEG_INFINITY, f64::max);
+        let column_max = columns.values()[range]
+            .iter()
+            .copied()
+            .fold(f64::NEG_INFINITY, f64::max);
+        assert_eq!(row_max, column_max);
+
+        // Timing is informational only; it varies too much to assert on
+        println!(
+            "value scan over {} points: rows {:?}, columns {:?}",
+            rows.len(),
+            row_time,
+            column_time
+        );
+    }
+}
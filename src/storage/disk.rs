@@ -0,0 +1,672 @@
+// On-disk shard directory, after the paper's persistence layout
+//
+// Paper Section 4.3: a shard's directory holds a key list (series key to
+// integer id), append-only files of compressed blocks, and checkpoint
+// files marking when the blocks written so far are complete. Here every
+// persist writes a new generation of all three:
+//
+//   {generation}.keys        key list
+//   {generation}.blocks      block records, appended as they are written
+//   {generation}.checkpoint  marker; a generation without one is incomplete
+//
+// The marker comes last: the data files are synced, then the marker is
+// renamed into place and the directory synced. Loading picks the newest
+// generation with a marker, so a persist that dies midway leaves the
+// previous state readable. Only once the new marker is durable are the
+// superseded generations removed (all but the new one and the previous
+// complete one).
+//
+// Layouts (all integers little-endian):
+//   keys: magic "TSDBKEYS", version u32 (DISK_VERSION), options encoding
+//     u32 (the SNAPSHOT_VERSION whose options layout is used), entry
+//     count u32, per entry: key length u32, key bytes, key id u32, options
+//   blocks: magic "TSDBBLKS", version u32, format version u8
+//     (FORMAT_VERSION), then per block: key id u32, start time u64, point
+//     count u32, open flag u8, timestamp codec u8, value hint u8, value
+//     codec u8 (version 2+; XOR before), compressed length u32, CRC-32 of the compressed bytes u32,
+//     compressed bytes
+//   checkpoint: magic "TSDBCKPT", version u32, block count u64, key list
+//     length u64, blocks file length u64
+//
+// Only keys, options and blocks are kept; labels and metadata need a
+// snapshot.
+
+use super::snapshot::{
+    SNAPSHOT_VERSION, SnapshotReader, invalid, sync_dir, unsupported, write_len, write_options,
+    write_str,
+};
+use super::wal::crc32;
+use super::{
+    BlockEncoding, DEFAULT_BLOCK_DURATION_SECS, SeriesMeta, SeriesOptions, TimeSeries,
+    TimeSeriesBlock, TimeSeriesMap,
+};
+use crate::compression::FORMAT_VERSION;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const KEYS_MAGIC: &[u8; 8] = b"TSDBKEYS";
+const BLOCKS_MAGIC: &[u8; 8] = b"TSDBBLKS";
+const CHECKPOINT_MAGIC: &[u8; 8] = b"TSDBCKPT";
+
+/// Current shard directory format version
+pub const DISK_VERSION: u32 = 2;
+
+/// What a block record says about its compressed bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockHeader {
+    pub start_time: u64,
+    pub point_count: usize,
+    pub encoding: BlockEncoding,
+    pub open: bool, // The series' open block, as it stood when written
+}
+
+/// Summary of a completed generation
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CheckpointInfo {
+    pub generation: u64,
+    pub series: usize, // Entries in the key list
+    pub blocks: usize, // Block records written
+    pub points: usize, // Points covered by those blocks
+    pub bytes: u64,    // Size of the key list and blocks file
+}
+
+/// A shard directory, written one generation at a time
+pub(crate) struct ShardDir {
+    dir: PathBuf,
+
+    // Generation the next writes go to, past every file in the directory
+    generation: u64,
+
+    // Created by the first append (or by mark_checkpoint if none came)
+    blocks: Option<BufWriter<File>>,
+    block_count: usize,
+    point_count: usize,
+
+    // Entries of the key list written for this generation, if any
+    key_count: Option<usize>,
+}
+
+impl ShardDir {
+    /// Open (creating if needed) a shard directory for writing and loading
+    ///
+    /// Writes go to a new generation after any found in the directory,
+    /// including incomplete ones.
+    pub fn open(dir: &Path) -> io::Result<ShardDir> {
+        fs::create_dir_all(dir)?;
+        let generation = generations(dir)?
+            .iter()
+            .map(|&(generation, _)| generation + 1)
+            .max()
+            .unwrap_or(1);
+        Ok(ShardDir {
+            dir: dir.to_path_buf(),
+            generation,
+            blocks: None,
+            block_count: 0,
+            point_count: 0,
+            key_count: None,
+        })
+    }
+
+    /// Append one block's compressed bytes to this generation
+    ///
+    /// Blocks of a series are expected oldest first, its open block last.
+    pub fn append_block(
+        &mut self,
+        key_id: u32,
+        header: BlockHeader,
+        bytes: &[u8],
+    ) -> io::Result<()> {
+        let out = match &mut self.blocks {
+            Some(out) => out,
+            None => self
+                .blocks
+                .insert(create_blocks_file(&self.path("blocks"))?),
+        };
+        out.write_all(&key_id.to_le_bytes())?;
+        out.write_all(&header.start_time.to_le_bytes())?;
+        write_len(out, header.point_count)?;
+        out.write_all(&[header.open as u8])?;
+        out.write_all(&[header.encoding.timestamp_codec.to_byte()])?;
+        out.write_all(&[header.encoding.value_hint.to_byte()])?;
+        out.write_all(&[header.encoding.value_codec.to_byte()])?;
+        write_len(out, bytes.len())?;
+        out.write_all(&crc32(bytes).to_le_bytes())?;
+        out.write_all(bytes)?;
+
+        self.block_count += 1;
+        self.point_count += header.point_count;
+        Ok(())
+    }
+
+    /// Append every block holding points of a series, open block last
+    ///
+    /// Spilled blocks are read back from their files. Returns the number
+    /// of blocks written.
+    pub fn append_series(&mut self, key_id: u32, series: &TimeSeries) -> io::Result<usize> {
+        let open = Some(&series.open_block).filter(|block| !block.is_empty());
+        let blocks = series
+            .closed_blocks
+            .iter()
+            .map(|block| (block, false))
+            .chain(open.map(|block| (block, true)));
+
+        let mut written = 0;
+        for (block, open) in blocks {
+            let header = BlockHeader {
+                start_time: block.start_time,
+                point_count: block.len(),
+                encoding: block.encoding,
+                open,
+            };
+            self.append_block(key_id, header, &block.read_compressed()?)?;
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    /// Write this generation's key list: (key, key id, options) per series
+    ///
+    /// Every series in the generation needs an entry, even one without
+    /// blocks. Writing it again replaces it.
+    pub fn write_key_list(&mut self, keys: &[(String, u32, SeriesOptions)]) -> io::Result<()> {
+        let file = File::create(self.path("keys"))?;
+        let mut out = BufWriter::new(file);
+        out.write_all(KEYS_MAGIC)?;
+        out.write_all(&DISK_VERSION.to_le_bytes())?;
+        out.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        write_len(&mut out, keys.len())?;
+        for (key, key_id, options) in keys {
+            write_str(&mut out, key)?;
+            out.write_all(&key_id.to_le_bytes())?;
+            write_options(&mut out, options)?;
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        self.key_count = Some(keys.len());
+        Ok(())
+    }
+
+    /// Mark this generation complete and start the next one
+    ///
+    /// Syncs the key list and blocks file, then writes the marker under a
+    /// temporary name, syncs it and renames it into place (syncing the
+    /// directory too), so the marker never names data that isn't on disk.
+    /// Only then are earlier generations other than the previous complete
+    /// one removed. Fails with `InvalidInput` if no key list was written.
+    pub fn mark_checkpoint(&mut self) -> io::Result<CheckpointInfo> {
+        let Some(series) = self.key_count else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("generation {} has no key list", self.generation),
+            ));
+        };
+        let blocks = match self.blocks.take() {
+            Some(out) => out,
+            None => create_blocks_file(&self.path("blocks"))?,
+        };
+        let blocks = blocks.into_inner().map_err(|e| e.into_error())?;
+        blocks.sync_all()?;
+        let blocks_len = blocks.metadata()?.len();
+        let keys_len = fs::metadata(self.path("keys"))?.len();
+
+        let mut marker = Vec::new();
+        marker.extend_from_slice(CHECKPOINT_MAGIC);
+        marker.extend_from_slice(&DISK_VERSION.to_le_bytes());
+        marker.extend_from_slice(&(self.block_count as u64).to_le_bytes());
+        marker.extend_from_slice(&keys_len.to_le_bytes());
+        marker.extend_from_slice(&blocks_len.to_le_bytes());
+        let temp = self.path("checkpoint.tmp");
+        let mut file = File::create(&temp)?;
+        file.write_all(&marker)?;
+        file.sync_all()?;
+        fs::rename(&temp, self.path("checkpoint"))?;
+        sync_dir(&self.dir)?;
+
+        let info = CheckpointInfo {
+            generation: self.generation,
+            series,
+            blocks: self.block_count,
+            points: self.point_count,
+            bytes: keys_len + blocks_len,
+        };
+        self.prune()?;
+
+        self.generation += 1;
+        self.block_count = 0;
+        self.point_count = 0;
+        self.key_count = None;
+        Ok(info)
+    }
+
+    /// Rebuild a map from the newest complete generation
+    ///
+    /// Generations without a checkpoint marker are skipped. An empty map
+    /// comes back if there is no complete generation. A complete one that
+    /// is truncated or corrupt is an `InvalidData` error, not a reason to
+    /// fall back.
+    pub fn load(&self) -> io::Result<TimeSeriesMap> {
+        let mut map = TimeSeriesMap::new();
+        for stored in self.load_series()? {
+            map.insert_series(stored.into_series())
+                .map_err(|e| invalid(format!("duplicate series in key list: {}", e)))?;
+        }
+        Ok(map)
+    }
+
+    /// The series of the newest complete generation, as loaded by `load`
+    /// but not yet gathered into a map
+    pub(super) fn load_series(&self) -> io::Result<Vec<StoredSeries>> {
+        let newest = generations(&self.dir)?
+            .into_iter()
+            .filter(|(_, name)| name.ends_with(".checkpoint"))
+            .map(|(generation, _)| generation)
+            .max();
+        match newest {
+            Some(generation) => self.read_generation(generation),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn read_generation(&self, generation: u64) -> io::Result<Vec<StoredSeries>> {
+        let path = |ext: &str| self.dir.join(format!("{}.{}", generation, ext));
+
+        let marker = fs::read(path("checkpoint"))?;
+        let mut reader = SnapshotReader::new(&marker, "checkpoint");
+        if reader.take(CHECKPOINT_MAGIC.len(), "magic")? != CHECKPOINT_MAGIC {
+            return Err(invalid("not a checkpoint file (bad magic)".to_string()));
+        }
+        check_version(reader.u32("version")?)?;
+        let block_count = reader.u64("block count")?;
+        let keys_len = reader.u64("key list length")?;
+        let blocks_len = reader.u64("blocks file length")?;
+
+        let keys = fs::read(path("keys"))?;
+        let blocks = fs::read(path("blocks"))?;
+        if keys.len() as u64 != keys_len || blocks.len() as u64 != blocks_len {
+            return Err(invalid(format!(
+                "generation {} files don't match its checkpoint",
+                generation
+            )));
+        }
+
+        let entries = read_key_list(&keys)?;
+        let mut series_blocks: HashMap<u32, SeriesBlocks> = entries
+            .keys()
+            .map(|&key_id| (key_id, SeriesBlocks::default()))
+            .collect();
+
+        let mut reader = SnapshotReader::new(&blocks, "blocks file");
+        let version = read_blocks_header(&mut reader)?;
+        let mut records = 0;
+        while reader.remaining() > 0 {
+            let key_id = reader.u32("key id")?;
+            let start_time = reader.u64("block start")?;
+            let point_count = reader.u32("point count")? as usize;
+            let open = reader.u8("open flag")? != 0;
+            let entry = entries
+                .get(&key_id)
+                .ok_or_else(|| invalid(format!("block for unknown key id {}", key_id)))?;
+            let mut encoding = BlockEncoding {
+                timestamp_codec: reader.codec(&entry.0)?,
+                value_hint: reader.value_hint(&entry.0)?,
+                ..BlockEncoding::default()
+            };
+            if version >= 2 {
+                encoding.value_codec = reader.value_codec(&entry.0)?;
+            }
+            let data_len = reader.u32("block length")? as usize;
+            let checksum = reader.u32("block checksum")?;
+            let bytes = reader.take(data_len, "block data")?;
+
+            let corrupt = || {
+                invalid(format!(
+                    "corrupt block at {} in series {}",
+                    start_time, entry.0
+                ))
+            };
+            if crc32(bytes) != checksum {
+                return Err(corrupt());
+            }
+            let block = TimeSeriesBlock::from_compressed(start_time, point_count, encoding, bytes)
+                .ok_or_else(corrupt)?;
+
+            let target = series_blocks
+                .get_mut(&key_id)
+                .expect("entry for every key id");
+            if open {
+                target.open = Some(block);
+            } else {
+                target.closed.push(block);
+            }
+            records += 1;
+        }
+        if records != block_count {
+            return Err(invalid(format!(
+                "generation {} holds {} blocks, its checkpoint says {}",
+                generation, records, block_count
+            )));
+        }
+
+        let stored = entries
+            .into_iter()
+            .map(|(key_id, (key, options))| {
+                let SeriesBlocks { closed, open } = series_blocks
+                    .remove(&key_id)
+                    .expect("entry for every key id");
+                StoredSeries {
+                    key,
+                    options,
+                    closed,
+                    open,
+                }
+            })
+            .collect();
+        Ok(stored)
+    }
+
+    fn path(&self, ext: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", self.generation, ext))
+    }
+
+    /// Remove every generation before the current one except the newest
+    /// complete one among them
+    fn prune(&self) -> io::Result<()> {
+        let files = generations(&self.dir)?;
+        let keep_from = files
+            .iter()
+            .filter(|(generation, name)| {
+                *generation < self.generation && name.ends_with(".checkpoint")
+            })
+            .map(|&(generation, _)| generation)
+            .max()
+            .unwrap_or(self.generation);
+        for (generation, name) in files {
+            if generation < self.generation && generation != keep_from {
+                fs::remove_file(self.dir.join(name))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Blocks read back for one key id
+#[derive(Default)]
+struct SeriesBlocks {
+    closed: Vec<TimeSeriesBlock>,
+    open: Option<TimeSeriesBlock>,
+}
+
+/// A series read back from a generation: its key list entry and blocks
+pub(super) struct StoredSeries {
+    pub key: String,
+    pub options: SeriesOptions,
+    pub closed: Vec<TimeSeriesBlock>,
+    pub open: Option<TimeSeriesBlock>,
+}
+
+impl StoredSeries {
+    /// The series these blocks make up, closed blocks in time order
+    pub fn into_series(mut self) -> TimeSeries {
+        self.closed.sort_by_key(|block| block.start_time);
+        let block_duration = self
+            .options
+            .block_duration
+            .unwrap_or(DEFAULT_BLOCK_DURATION_SECS);
+        TimeSeries::from_parts(
+            self.key,
+            self.options,
+            block_duration,
+            self.closed,
+            self.open,
+            SeriesMeta::default(),
+            None,
+        )
+    }
+}
+
+/// (generation, file name) of every generation file in `dir`
+fn generations(dir: &Path) -> io::Result<Vec<(u64, String)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        let generation = name
+            .split_once('.')
+            .and_then(|(generation, _)| generation.parse::<u64>().ok());
+        if let Some(generation) = generation {
+            files.push((generation, name));
+        }
+    }
+    Ok(files)
+}
+
+fn create_blocks_file(path: &Path) -> io::Result<BufWriter<File>> {
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    let mut out = BufWriter::new(file);
+    out.write_all(BLOCKS_MAGIC)?;
+    out.write_all(&DISK_VERSION.to_le_bytes())?;
+    out.write_all(&[FORMAT_VERSION])?;
+    Ok(out)
+}
+
+/// Check a blocks file's header and return its version
+fn read_blocks_header(reader: &mut SnapshotReader<'_>) -> io::Result<u32> {
+    if reader.take(BLOCKS_MAGIC.len(), "magic")? != BLOCKS_MAGIC {
+        return Err(invalid("not a blocks file (bad magic)".to_string()));
+    }
+    let version = reader.u32("version")?;
+    check_version(version)?;
+    let format = reader.u8("format version")?;
+    if format == 0 || format > FORMAT_VERSION {
+        return Err(unsupported(format.into(), FORMAT_VERSION.into()));
+    }
+    Ok(version)
+}
+
+/// Key id to (key, options) from a key list file
+fn read_key_list(data: &[u8]) -> io::Result<HashMap<u32, (String, SeriesOptions)>> {
+    let mut reader = SnapshotReader::new(data, "key list");
+    if reader.take(KEYS_MAGIC.len(), "magic")? != KEYS_MAGIC {
+        return Err(invalid("not a key list (bad magic)".to_string()));
+    }
+    check_version(reader.u32("version")?)?;
+    let options_version = reader.u32("options encoding")?;
+    if options_version == 0 || options_version > SNAPSHOT_VERSION {
+        return Err(unsupported(options_version, SNAPSHOT_VERSION));
+    }
+
+    let mut entries = HashMap::new();
+    for _ in 0..reader.u32("entry count")? {
+        let key = reader.string("key")?;
+        let key_id = reader.u32("key id")?;
+        let options = reader.options(&key, options_version)?;
+        if entries.insert(key_id, (key, options)).is_some() {
+            return Err(invalid(format!("key id {} listed twice", key_id)));
+        }
+    }
+    if reader.remaining() > 0 {
+        return Err(invalid(format!(
+            "{} trailing bytes after the key list",
+            reader.remaining()
+        )));
+    }
+    Ok(entries)
+}
+
+fn check_version(version: u32) -> io::Result<()> {
+    if version == 0 || version > DISK_VERSION {
+        return Err(unsupported(version, DISK_VERSION));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tsdb_disk_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = generations(dir).unwrap().into_iter().map(|f| f.1).collect();
+        names.sort();
+        names
+    }
+
+    fn persist(shard: &mut ShardDir, map: &TimeSeriesMap) -> CheckpointInfo {
+        let mut keys = Vec::new();
+        for (key_id, handle) in map.iter().enumerate() {
+            let series = handle.read();
+            shard.append_series(key_id as u32, &series).unwrap();
+            keys.push((
+                series.key.to_string(),
+                key_id as u32,
+                series.options().clone(),
+            ));
+        }
+        shard.write_key_list(&keys).unwrap();
+        shard.mark_checkpoint().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_and_fallback() {
+        let dir = temp_dir("round_trip");
+        let mut shard = ShardDir::open(&dir).unwrap();
+        assert_eq!(shard.load().unwrap().series_count(), 0);
+
+        let map = TimeSeriesMap::new();
+        let base_time = 7200 * 100;
+        for i in 0..300 {
+            map.insert("cpu", base_time + i * 60, i as f64);
+        }
+        let first = persist(&mut shard, &map);
+        assert_eq!((first.generation, first.series, first.blocks), (1, 1, 3));
+        assert_eq!(first.points, 300);
+
+        map.insert("mem", base_time, 1.0);
+        let second = persist(&mut shard, &map);
+        assert_eq!((second.generation, second.series), (2, 2));
+
+        let loaded = ShardDir::open(&dir).unwrap().load().unwrap();
+        assert_eq!(loaded.series_count(), 2);
+        assert_eq!(
+            loaded.get("cpu").unwrap().read().query(0, u64::MAX),
+            map.get("cpu").unwrap().read().query(0, u64::MAX)
+        );
+
+        // Without its marker the newest generation is incomplete
+        fs::remove_file(dir.join("2.checkpoint")).unwrap();
+        let fallback = ShardDir::open(&dir).unwrap().load().unwrap();
+        assert_eq!(fallback.series_count(), 1);
+        assert!(fallback.get("mem").is_none());
+
+        // A third checkpoint keeps only itself and the complete one before,
+        // dropping the incomplete generation
+        let mut shard = ShardDir::open(&dir).unwrap();
+        assert_eq!(persist(&mut shard, &map).generation, 3);
+        assert_eq!(
+            file_names(&dir),
+            [
+                "1.blocks",
+                "1.checkpoint",
+                "1.keys",
+                "3.blocks",
+                "3.checkpoint",
+                "3.keys"
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_crash_before_marker() {
+        let dir = temp_dir("crash");
+        let map = TimeSeriesMap::new();
+        for i in 0..200 {
+            map.insert("cpu", 7200 * 100 + i * 60, i as f64);
+        }
+        persist(&mut ShardDir::open(&dir).unwrap(), &map);
+        persist(&mut ShardDir::open(&dir).unwrap(), &map);
+
+        // Generation 3 gets all its data but dies before its marker
+        map.insert("mem", 7200 * 100, 1.0);
+        let mut crashed = ShardDir::open(&dir).unwrap();
+        for (key_id, handle) in map.iter().enumerate() {
+            crashed
+                .append_series(key_id as u32, &handle.read())
+                .unwrap();
+        }
+        crashed.write_key_list(&[]).unwrap();
+        drop(crashed);
+        assert!(dir.join("3.keys").exists());
+        assert_eq!(
+            ShardDir::open(&dir).unwrap().load().unwrap().series_count(),
+            1
+        );
+
+        // Nothing is collected until the next marker is in place
+        let mut shard = ShardDir::open(&dir).unwrap();
+        let mut keys = Vec::new();
+        for (key_id, handle) in map.iter().enumerate() {
+            let series = handle.read();
+            shard.append_series(key_id as u32, &series).unwrap();
+            keys.push((
+                series.key.to_string(),
+                key_id as u32,
+                series.options().clone(),
+            ));
+        }
+        shard.write_key_list(&keys).unwrap();
+        assert_eq!(file_names(&dir).len(), 3 + 3 + 2 + 2);
+        assert_eq!(shard.mark_checkpoint().unwrap().generation, 4);
+        assert_eq!(
+            file_names(&dir),
+            [
+                "2.blocks",
+                "2.checkpoint",
+                "2.keys",
+                "4.blocks",
+                "4.checkpoint",
+                "4.keys"
+            ]
+        );
+        assert_eq!(shard.load().unwrap().series_count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_checkpoint_needs_key_list() {
+        let dir = temp_dir("no_keys");
+        let mut shard = ShardDir::open(&dir).unwrap();
+        let err = shard.mark_checkpoint().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_block_record_is_refused() {
+        let dir = temp_dir("corrupt");
+        let mut shard = ShardDir::open(&dir).unwrap();
+        let map = TimeSeriesMap::new();
+        for i in 0..50 {
+            map.insert("cpu", 7200 * 100 + i * 60, i as f64);
+        }
+        persist(&mut shard, &map);
+
+        let path = dir.join("1.blocks");
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        fs::write(&path, bytes).unwrap();
+
+        let err = shard.load().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("corrupt block"), "{}", err);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
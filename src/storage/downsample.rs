@@ -0,0 +1,221 @@
+// Downsample tiers: coarser companion series kept up to date on block close
+//
+// A series whose options list tiers feeds, for each tier, a companion
+// series named `key:<width>s:<aggregation>` (e.g. `cpu:300s:avg`) holding
+// one point per bucket of `width` seconds, stamped with the bucket start.
+// When a raw block closes, every bucket that ends at or before the new
+// open block is complete and gets rolled up from all raw points in it, so
+// buckets spanning several blocks come out right. Each bucket is rolled
+// up once; points backfilled into it later don't update the companion.
+// Companions are ordinary series without tiers of their own, so they are
+// never downsampled again, and deletes and idle expiry treat them apart
+// from the raw series (which can go long before its companions).
+//
+// Tiered retention: the raw series and each tier can keep a limited span
+// (SeriesOptions::retention, DownsampleTier::retention), applied by
+// Gorilla::enforce_tiers. Raw blocks are only dropped once every tier has
+// rolled them up, so their points live on in coarser form, and queries
+// reaching back past the raw series' oldest block read that part from the
+// finest tier that still keeps it, then raw points from there on. A
+// companion's retention comes from its raw series' tier.
+
+use super::{DataPoint, align_down};
+
+/// How the points of a bucket are combined into one value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    Avg,
+    Min,
+    Max,
+    Sum,
+    Count,
+    Last,
+    First,
+}
+
+impl Aggregation {
+    /// Name used in companion keys
+    pub fn name(self) -> &'static str {
+        match self {
+            Aggregation::Avg => "avg",
+            Aggregation::Min => "min",
+            Aggregation::Max => "max",
+            Aggregation::Sum => "sum",
+            Aggregation::Count => "count",
+            Aggregation::Last => "last",
+            Aggregation::First => "first",
+        }
+    }
+
+    pub(super) fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    pub(super) fn from_byte(byte: u8) -> Option<Self> {
+        [
+            Aggregation::Avg,
+            Aggregation::Min,
+            Aggregation::Max,
+            Aggregation::Sum,
+            Aggregation::Count,
+            Aggregation::Last,
+            Aggregation::First,
+        ]
+        .into_iter()
+        .find(|aggregation| aggregation.to_byte() == byte)
+    }
+}
+
+/// One downsampled resolution maintained for a series
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DownsampleTier {
+    /// Bucket width in seconds; buckets are aligned to the series'
+    /// align_origin (multiples of the width by default)
+    pub width: u64,
+    pub aggregation: Aggregation,
+    /// Seconds of the companion Gorilla::enforce_tiers keeps; None keeps
+    /// every bucket
+    pub retention: Option<u64>,
+}
+
+impl DownsampleTier {
+    /// Panics if `width` is zero.
+    pub fn new(width: u64, aggregation: Aggregation) -> Self {
+        assert!(width > 0, "downsample width must be positive");
+        DownsampleTier {
+            width,
+            aggregation,
+            retention: None,
+        }
+    }
+
+    /// The same tier, with its companion kept for `secs` seconds
+    pub fn with_retention(self, secs: u64) -> Self {
+        DownsampleTier {
+            retention: Some(secs),
+            ..self
+        }
+    }
+
+    /// Key of the companion series holding this tier for `key`
+    pub fn companion_key(&self, key: &str) -> String {
+        format!("{}:{}s:{}", key, self.width, self.aggregation.name())
+    }
+
+    /// Aggregate time-ordered points into (bucket start, value) pairs,
+    /// buckets laid out from `origin` (see align_down)
+    pub fn rollup(&self, points: impl Iterator<Item = DataPoint>, origin: u64) -> Vec<(u64, f64)> {
+        let mut rolled = Vec::new();
+        let mut current: Option<(u64, Bucket)> = None;
+        for point in points {
+            let start = align_down(point.timestamp, self.width, origin);
+            match &mut current {
+                Some((bucket_start, bucket)) if *bucket_start == start => bucket.add(point.value),
+                _ => {
+                    if let Some((bucket_start, bucket)) = current.take() {
+                        rolled.push((bucket_start, bucket.finish(self.aggregation)));
+                    }
+                    current = Some((start, Bucket::new(point.value)));
+                }
+            }
+        }
+        if let Some((bucket_start, bucket)) = current {
+            rolled.push((bucket_start, bucket.finish(self.aggregation)));
+        }
+        rolled
+    }
+}
+
+/// A bucket aggregate waiting to be written to its companion series
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rollup {
+    pub key: String,
+    pub timestamp: u64,
+    pub value: f64,
+}
+
+/// Running summary of one bucket
+///
+/// Also built from a block's value summary, so a query can aggregate
+/// whole blocks without decoding them (TimeSeries::downsample).
+pub(super) struct Bucket {
+    pub(super) count: u64,
+    pub(super) sum: f64,
+    pub(super) min: f64,
+    pub(super) max: f64,
+    pub(super) first: f64,
+    pub(super) last: f64,
+}
+
+impl Bucket {
+    pub(super) fn new(value: f64) -> Self {
+        Bucket {
+            count: 1,
+            sum: value,
+            min: value,
+            max: value,
+            first: value,
+            last: value,
+        }
+    }
+
+    pub(super) fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.last = value;
+    }
+
+    /// Fold in the summary of later points
+    pub(super) fn merge(&mut self, later: &Bucket) {
+        self.count += later.count;
+        self.sum += later.sum;
+        self.min = self.min.min(later.min);
+        self.max = self.max.max(later.max);
+        self.last = later.last;
+    }
+
+    pub(super) fn finish(&self, aggregation: Aggregation) -> f64 {
+        match aggregation {
+            Aggregation::Avg => self.sum / self.count as f64,
+            Aggregation::Min => self.min,
+            Aggregation::Max => self.max,
+            Aggregation::Sum => self.sum,
+            Aggregation::Count => self.count as f64,
+            Aggregation::Last => self.last,
+            Aggregation::First => self.first,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rollup_buckets() {
+        let points = [(0, 1.0), (100, 3.0), (299, 2.0), (300, 10.0), (900, 4.0)]
+            .into_iter()
+            .map(|(timestamp, value)| DataPoint { timestamp, value });
+
+        let avg = DownsampleTier::new(300, Aggregation::Avg);
+        assert_eq!(
+            avg.rollup(points.clone(), 0),
+            vec![(0, 2.0), (300, 10.0), (900, 4.0)]
+        );
+        let count = DownsampleTier::new(300, Aggregation::Count);
+        assert_eq!(
+            count.rollup(points.clone(), 0),
+            vec![(0, 3.0), (300, 1.0), (900, 1.0)]
+        );
+        // Buckets from an origin: [0, 50), [50, 350), [350, 650), ...
+        assert_eq!(
+            count.rollup(points.clone(), 950),
+            vec![(0, 1.0), (50, 3.0), (650, 1.0)]
+        );
+        let max = DownsampleTier::new(3600, Aggregation::Max);
+        assert_eq!(max.rollup(points, 0), vec![(0, 10.0)]);
+        assert_eq!(max.companion_key("cpu"), "cpu:3600s:max");
+    }
+}
@@ -0,0 +1,196 @@
+// Series labels and the inverted index used by label selectors
+//
+// A labeled series is stored under a canonical key built from its metric
+// name and sorted labels, e.g. `http.requests{host="web01",region="eu"}`,
+// so every key-based API keeps working for it. The index maps each
+// label name/value pair to the slots holding matching series; the metric
+// name is indexed as the `__name__` label (plain keys are indexed under
+// their key).
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Label name under which the metric name is indexed
+pub const NAME_LABEL: &str = "__name__";
+
+/// Metric name plus label pairs identifying a series
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SeriesLabels {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+}
+
+impl SeriesLabels {
+    pub fn new(name: &str, labels: &[(&str, &str)]) -> Self {
+        SeriesLabels {
+            name: name.to_string(),
+            labels: labels
+                .iter()
+                .map(|&(label, value)| (label.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    /// Canonical key: the name alone, or `name{a="x",b="y"}` with labels
+    /// in sorted order and `"`/`\` escaped in values
+    pub fn series_key(&self) -> String {
+        if self.labels.is_empty() {
+            return self.name.clone();
+        }
+
+        let pairs: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(label, value)| {
+                let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+                format!("{}=\"{}\"", label, escaped)
+            })
+            .collect();
+        format!("{}{{{}}}", self.name, pairs.join(","))
+    }
+}
+
+/// How a selector compares one label's value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Matcher {
+    /// Label is present with exactly this value
+    Eq(String),
+
+    /// Label is absent or has a different value
+    Neq(String),
+
+    /// Label is present and its value starts with this prefix
+    Prefix(String),
+}
+
+/// Inverted index from label name/value to slot indices (one per shard)
+#[derive(Debug, Default)]
+pub(crate) struct LabelIndex {
+    postings: HashMap<String, HashMap<String, HashSet<usize>>>,
+}
+
+impl LabelIndex {
+    /// Index the series in `slot`
+    pub fn add(&mut self, slot: usize, name: &str, labels: &BTreeMap<String, String>) {
+        for (label, value) in Self::pairs(name, labels) {
+            self.postings
+                .entry(label.to_string())
+                .or_default()
+                .entry(value.to_string())
+                .or_default()
+                .insert(slot);
+        }
+    }
+
+    /// Drop the series in `slot` from the index
+    pub fn remove(&mut self, slot: usize, name: &str, labels: &BTreeMap<String, String>) {
+        for (label, value) in Self::pairs(name, labels) {
+            let Some(values) = self.postings.get_mut(label) else {
+                continue;
+            };
+            if let Some(slots) = values.get_mut(value) {
+                slots.remove(&slot);
+                if slots.is_empty() {
+                    values.remove(value);
+                }
+            }
+            if values.is_empty() {
+                self.postings.remove(label);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.postings.clear();
+    }
+
+    /// Slots of series named `name` that satisfy every matcher
+    pub fn select(&self, name: &str, matchers: &[(&str, Matcher)]) -> HashSet<usize> {
+        let mut selected = self.slots(NAME_LABEL, |value| value == name);
+
+        for (label, matcher) in matchers {
+            if selected.is_empty() {
+                break;
+            }
+            match matcher {
+                Matcher::Eq(expected) => {
+                    let matching = self.slots(label, |value| value == expected);
+                    selected.retain(|slot| matching.contains(slot));
+                }
+                Matcher::Neq(excluded) => {
+                    let matching = self.slots(label, |value| value == excluded);
+                    selected.retain(|slot| !matching.contains(slot));
+                }
+                Matcher::Prefix(prefix) => {
+                    let matching = self.slots(label, |value| value.starts_with(prefix.as_str()));
+                    selected.retain(|slot| matching.contains(slot));
+                }
+            }
+        }
+        selected
+    }
+
+    /// Union of the postings of `label` values accepted by `accept`
+    fn slots<F>(&self, label: &str, accept: F) -> HashSet<usize>
+    where
+        F: Fn(&str) -> bool,
+    {
+        self.postings
+            .get(label)
+            .into_iter()
+            .flat_map(|values| values.iter())
+            .filter(|(value, _)| accept(value))
+            .flat_map(|(_, slots)| slots.iter().copied())
+            .collect()
+    }
+
+    fn pairs<'a>(
+        name: &'a str,
+        labels: &'a BTreeMap<String, String>,
+    ) -> impl Iterator<Item = (&'a str, &'a str)> {
+        std::iter::once((NAME_LABEL, name)).chain(
+            labels
+                .iter()
+                .map(|(label, value)| (label.as_str(), value.as_str())),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_series_key_is_canonical() {
+        let a = SeriesLabels::new("http.requests", &[("region", "eu"), ("host", "web01")]);
+        let b = SeriesLabels::new("http.requests", &[("host", "web01"), ("region", "eu")]);
+        assert_eq!(
+            a.series_key(),
+            "http.requests{host=\"web01\",region=\"eu\"}"
+        );
+        assert_eq!(a.series_key(), b.series_key());
+
+        assert_eq!(SeriesLabels::new("cpu", &[]).series_key(), "cpu");
+        let quoted = SeriesLabels::new("m", &[("path", "a\"b\\c")]);
+        assert_eq!(quoted.series_key(), "m{path=\"a\\\"b\\\\c\"}");
+    }
+
+    #[test]
+    fn test_index_add_remove() {
+        let mut index = LabelIndex::default();
+        let web = SeriesLabels::new("cpu", &[("host", "web01")]);
+        let db = SeriesLabels::new("cpu", &[("host", "db01")]);
+        index.add(0, &web.name, &web.labels);
+        index.add(1, &db.name, &db.labels);
+
+        let eq = [("host", Matcher::Eq("web01".to_string()))];
+        assert_eq!(index.select("cpu", &eq), HashSet::from([0]));
+
+        index.remove(0, &web.name, &web.labels);
+        assert!(index.select("cpu", &eq).is_empty());
+        assert_eq!(index.select("cpu", &[]), HashSet::from([1]));
+
+        // Emptied postings are dropped entirely
+        index.remove(1, &db.name, &db.labels);
+        assert!(index.postings.is_empty());
+    }
+}
@@ -1,16 +1,262 @@
 // In-memory data structures for time series storage
 // Paper Section 4.2: In-memory data structures
 
-use crate::compression::{BitWriter, timestamp::TimestampCompressor, value::ValueCompressor};
-use std::collections::HashMap;
+pub mod chunks;
+pub mod clock;
+pub mod columns;
+pub mod disk;
+pub mod downsample;
+pub mod labels;
+pub mod partition;
+pub mod snapshot;
+pub mod spill;
+pub mod wal;
+
+use crate::compression::{
+    BitReader, BitWriter,
+    inspect::{EncodedField, describe_block},
+    timestamp::{
+        TimestampCodec, TimestampCompressor, TimestampDecompressor, compress_timestamp,
+        decode_first_delta, encode_first_delta, first_delta_bits,
+    },
+    value::{ValueCodec, ValueCompressor, ValueDecompressor, ValueHint},
+};
+use crate::tsdb::TsdbError;
+use chunks::Chunk;
+use clock::{Clock, SystemClock};
+use columns::PointColumns;
+use downsample::{Aggregation, Bucket, DownsampleTier, Rollup};
+use labels::{LabelIndex, Matcher, SeriesLabels};
+use spill::{SpillConfig, SpillCounters, SpillFile, SpillReport};
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use wal::crc32;
 
 /// A single data point in a time series
+///
+/// Points order by timestamp. Equal timestamps are broken on the value
+/// in IEEE total order (`f64::total_cmp`), so equality means identical
+/// value bits: NaN equals a NaN with the same bits, and 0.0 and -0.0
+/// differ. Use `cmp_by_time` to compare timestamps alone.
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DataPoint {
     pub timestamp: u64,
     pub value: f64,
 }
 
+impl DataPoint {
+    /// Compare by timestamp only, ignoring values
+    ///
+    /// For stable sorts that should keep points with equal timestamps in
+    /// their original order.
+    pub fn cmp_by_time(&self, other: &Self) -> std::cmp::Ordering {
+        self.timestamp.cmp(&other.timestamp)
+    }
+}
+
+impl From<(u64, f64)> for DataPoint {
+    fn from((timestamp, value): (u64, f64)) -> Self {
+        DataPoint { timestamp, value }
+    }
+}
+
+impl From<DataPoint> for (u64, f64) {
+    fn from(point: DataPoint) -> Self {
+        (point.timestamp, point.value)
+    }
+}
+
+impl PartialEq for DataPoint {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for DataPoint {}
+
+impl PartialOrd for DataPoint {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DataPoint {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.timestamp
+            .cmp(&other.timestamp)
+            .then_with(|| self.value.total_cmp(&other.value))
+    }
+}
+
+/// Seconds since the UNIX epoch for a given instant
+///
+/// A clock set before 1970 yields 0 instead of panicking, so a badly
+/// configured host can still ingest data.
+pub fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+/// Current wall-clock time in seconds since the UNIX epoch
+pub fn current_time() -> u64 {
+    unix_seconds(SystemTime::now())
+}
+
+/// What to do when a point arrives for a timestamp that is already stored
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum DuplicatePolicy {
+    /// Overwrite the stored value with the new one
+    #[default]
+    KeepLast,
+
+    /// Keep the stored value and drop the new one
+    KeepFirst,
+
+    /// Refuse the new point
+    Reject,
+}
+
+/// Block window used when SeriesOptions::block_duration is None
+pub const DEFAULT_BLOCK_DURATION_SECS: u64 = 7200;
+
+/// Start of the `width`-second window holding `timestamp`, with windows
+/// laid out from `origin` (only `origin % width` matters)
+///
+/// Timestamps before the first such window fall in a shorter one
+/// starting at 0.
+pub fn align_down(timestamp: u64, width: u64, origin: u64) -> u64 {
+    let phase = origin % width;
+    if timestamp < phase {
+        0
+    } else {
+        timestamp - (timestamp - phase) % width
+    }
+}
+
+/// Per-series options, fixed when the series is created
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SeriesOptions {
+    pub duplicate_policy: DuplicatePolicy,
+
+    /// Free a block's raw points when it closes, keeping only the
+    /// compressed stream (queries decode it on demand)
+    pub drop_raw_on_close: bool,
+
+    /// Also close the open block once it holds this many points, even
+    /// within its time window
+    pub max_points_per_block: Option<usize>,
+
+    /// Length of a block's time window in seconds; None for the paper's
+    /// two hours (DEFAULT_BLOCK_DURATION_SECS). Must not be zero.
+    pub block_duration: Option<u64>,
+
+    /// Coarser companion series rolled up as blocks close (see
+    /// downsample.rs)
+    pub downsample: Vec<DownsampleTier>,
+
+    /// Seconds of raw points Gorilla::enforce_tiers keeps, back from the
+    /// `now` it is given; older blocks go once every tier has rolled them
+    /// up, and ranges before the oldest kept block are read from the
+    /// companions. None keeps every point.
+    pub retention: Option<u64>,
+
+    /// How timestamps are encoded in new blocks; each block remembers
+    /// the codec it was written with
+    pub timestamp_codec: TimestampCodec,
+
+    /// Expected shape of values, letting new block headers store their
+    /// first value compactly (e.g. IntegerSmall for small counts)
+    pub value_hint: ValueHint,
+
+    /// Seconds after a block closes (by the newest point's timestamp)
+    /// during which points late for it are appended to it directly, its
+    /// raw points still at hand; later ones take the backfill path.
+    /// None (or 0) for no grace period.
+    pub late_grace: Option<u64>,
+
+    /// How values after a block's first are encoded, fixed or detected
+    /// per block; each block records the codec it was written with
+    pub value_codec: CodecChoice,
+
+    /// Instant (seconds since epoch) block windows and downsample
+    /// buckets are laid out from, e.g. a local midnight so daily ones
+    /// follow calendar days there; 0 aligns them to the UNIX epoch
+    pub align_origin: u64,
+}
+
+impl SeriesOptions {
+    /// Encoding for blocks created under these options (Auto starts out
+    /// as Xor until the block has points to look at)
+    fn encoding(&self) -> BlockEncoding {
+        let value_codec = match self.value_codec {
+            CodecChoice::Fixed(codec) => codec,
+            CodecChoice::Auto => ValueCodec::Xor,
+        };
+        BlockEncoding {
+            timestamp_codec: self.timestamp_codec,
+            value_hint: self.value_hint,
+            value_codec,
+        }
+    }
+}
+
+/// Points an Auto block looks at to pick its value codec
+pub const AUTO_CODEC_POINTS: usize = 8;
+
+/// How a series picks the value codec of its blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodecChoice {
+    /// Every block uses this codec
+    Fixed(ValueCodec),
+
+    /// Each block takes IntegerDelta if its first AUTO_CODEC_POINTS
+    /// values are whole numbers, else Xor (see ValueCodec::detect)
+    Auto,
+}
+
+impl Default for CodecChoice {
+    fn default() -> Self {
+        CodecChoice::Fixed(ValueCodec::Xor)
+    }
+}
+
+/// How a block's stream was encoded; needed to decode it
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BlockEncoding {
+    pub timestamp_codec: TimestampCodec,
+    pub value_hint: ValueHint,
+    pub value_codec: ValueCodec,
+}
+
+/// Descriptive metadata kept alongside a series
+///
+/// `unit`, `description` and `pinned` are set by users; `created_at`
+/// and `last_write` (seconds since epoch, from the map's clock) are
+/// maintained by the engine.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SeriesMeta {
+    pub unit: Option<String>,
+    pub description: Option<String>,
+    pub created_at: u64,
+    pub last_write: u64,
+
+    /// Never expired for idleness (see Gorilla::expire_idle)
+    pub pinned: bool,
+}
+
+// Block header: aligned start time (64 bits)
+const BLOCK_HEADER_BITS: u32 = 64;
+
 /// A time series holds all data points for a single metric
 ///
 /// Architecture (from paper Figure 7):
@@ -20,270 +266,4166 @@ pub struct DataPoint {
 /// Each time series has a spinlock in production, but we use
 /// Rust's ownership system instead for this educational version
 pub struct TimeSeries {
-    // Key is public and used in scan operations for correlation analysis
-    pub key: String,
+    // Key is public and used in scan operations for correlation analysis.
+    // Shared with the shard index, so the key bytes are stored once.
+    pub key: Arc<str>,
 
     // Open block - actively being written
     open_block: TimeSeriesBlock,
 
-    // Closed blocks - immutable compressed data
+    // Closed blocks - immutable compressed data, ordered by start time
     closed_blocks: Vec<TimeSeriesBlock>,
 
     // Block duration in seconds (paper uses 2 hours = 7200 seconds)
     block_duration: u64,
+
+    options: SeriesOptions,
+
+    // Unit, description and write times
+    meta: SeriesMeta,
+
+    // Metric name and labels, for series created through insert_labeled
+    labels: Option<SeriesLabels>,
+
+    // Number of blocks whose points were read by queries (instrumentation)
+    blocks_read: AtomicUsize,
+
+    // When the series was last queried (or created), for eviction
+    last_access: AtomicU64,
+
+    // Read-only: the engine refuses writes (see Gorilla::freeze)
+    frozen: bool,
+
+    // Per downsample tier, the end of the buckets already rolled up
+    rollup_marks: Vec<u64>,
+
+    // Rolled-up buckets for the engine to write to companion series
+    pending_rollups: Vec<Rollup>,
 }
 
 impl TimeSeries {
-    pub fn new(key: String) -> Self {
-        let block_duration = 7200; // 2 hours
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+    pub fn new(key: impl Into<Arc<str>>) -> Self {
+        Self::with_options(key, SeriesOptions::default())
+    }
+
+    pub fn with_options(key: impl Into<Arc<str>>, options: SeriesOptions) -> Self {
+        Self::with_options_at(key, options, current_time())
+    }
 
-        // Align to 2-hour window (as paper describes)
-        let block_start = (now / block_duration) * block_duration;
+    /// Create a series as if the clock read `now` (seconds since epoch)
+    ///
+    /// The open block starts at the window containing `now`. Panics if
+    /// `options.block_duration` is zero.
+    pub fn with_options_at(key: impl Into<Arc<str>>, options: SeriesOptions, now: u64) -> Self {
+        let block_duration = options
+            .block_duration
+            .unwrap_or(DEFAULT_BLOCK_DURATION_SECS);
+        assert!(block_duration > 0, "block duration must be positive");
+
+        // Align to the block window (2 hours in the paper)
+        let block_start = align_down(now, block_duration, options.align_origin);
 
         TimeSeries {
-            key,
-            open_block: TimeSeriesBlock::new(block_start),
+            key: key.into(),
+            rollup_marks: vec![0; options.downsample.len()],
+            pending_rollups: Vec::new(),
+            open_block: TimeSeriesBlock::for_options(block_start, &options),
             closed_blocks: Vec::new(),
             block_duration,
+            options,
+            meta: SeriesMeta {
+                created_at: now,
+                ..SeriesMeta::default()
+            },
+            labels: None,
+            blocks_read: AtomicUsize::new(0),
+            last_access: AtomicU64::new(now),
+            frozen: false,
+        }
+    }
+
+    /// Rebuild a series from previously stored blocks
+    fn from_parts(
+        key: impl Into<Arc<str>>,
+        options: SeriesOptions,
+        block_duration: u64,
+        closed_blocks: Vec<TimeSeriesBlock>,
+        open_block: Option<TimeSeriesBlock>,
+        meta: SeriesMeta,
+        labels: Option<SeriesLabels>,
+    ) -> Self {
+        let drop_raw = options.drop_raw_on_close;
+        let mut series = Self::with_options(key, options);
+        series.meta = meta;
+        series.labels = labels;
+        series.block_duration = block_duration;
+        series.closed_blocks = closed_blocks;
+        if drop_raw {
+            series
+                .closed_blocks
+                .iter_mut()
+                .for_each(TimeSeriesBlock::drop_raw);
+        }
+        if let Some(block) = open_block {
+            series.open_block = block;
+        } else if let Some(last) = series.closed_blocks.last() {
+            // Empty open block right after the newest stored block's window
+            let next_window = series.window_end(last.start_time);
+            series.open_block = TimeSeriesBlock::for_options(next_window, &series.options);
         }
+        // Closed blocks were rolled up before they were stored
+        let rolled_up_to = series.open_block.start_time;
+        for (mark, tier) in series
+            .rollup_marks
+            .iter_mut()
+            .zip(&series.options.downsample)
+        {
+            *mark = rolled_up_to - rolled_up_to % tier.width;
+        }
+        series
     }
 
     /// Insert a data point into the time series
-    pub fn insert(&mut self, timestamp: u64, value: f64) {
+    ///
+    /// Points normally land in the open block. Points older than the open
+    /// block are backfilled into the closed block covering their window
+    /// (creating it if needed), and points that arrive out of order are
+    /// placed in timestamp order. Duplicate timestamps are resolved with
+    /// the series' duplicate policy.
+    ///
+    /// The open block closes when a point falls in a later window, or,
+    /// with `max_points_per_block`, when it is full and the point comes
+    /// after its last one; the next block then starts at that point, so
+    /// a window may hold several blocks.
+    pub fn insert(&mut self, timestamp: u64, value: f64) -> InsertEffect {
+        if self.open_block_realigns(timestamp) {
+            self.open_block.start_time = self.window_start(timestamp);
+        }
+
+        if timestamp < self.open_block.start_time {
+            if self.in_grace()
+                && self.closed_block_for(timestamp) == Ok(self.closed_blocks.len() - 1)
+            {
+                return self.append_late(timestamp, value);
+            }
+            return self.backfill(timestamp, value);
+        }
+
+        let mut effect = InsertEffect::default();
+
         // Check if we need to close the current block
-        if timestamp >= self.open_block.start_time + self.block_duration {
-            // Close current block and start a new one
-            let old_block = std::mem::replace(
-                &mut self.open_block,
-                TimeSeriesBlock::new((timestamp / self.block_duration) * self.block_duration),
-            );
-            self.closed_blocks.push(old_block);
+        if let Some(next_start) = self.next_block_start(timestamp) {
+            self.close_open_block(next_start);
+            self.roll_up(next_start);
+            effect.closed_block = true;
         }
 
         // Add point to open block
-        self.open_block.add_point(timestamp, value);
+        let size_before = self.open_block.compressed_size;
+        effect.write = self
+            .open_block
+            .add_point(timestamp, value, self.options.duplicate_policy);
+        effect.compressed_bytes = self.open_block.compressed_size.saturating_sub(size_before);
+        if !self.in_grace() {
+            self.end_grace();
+        }
+
+        effect
     }
 
-    /// Query data points within a time range
-    pub fn query(&self, start: u64, end: u64) -> Vec<DataPoint> {
-        let mut results = Vec::new();
+    /// Insert several points, encoding each touched block once
+    ///
+    /// Points that append to the open block (after its last point) are
+    /// added without re-encoding it; the block is encoded when it closes,
+    /// before any other point is written, and at the end. Every other
+    /// point goes through insert. The result is the same as inserting the
+    /// points one by one, in order; the open block's compressed growth is
+    /// reported on the last point appended to it. Records `now` as the
+    /// last write if any point is stored.
+    pub fn insert_batch(&mut self, points: &[(u64, f64)], now: u64) -> Vec<InsertEffect> {
+        let mut effects: Vec<InsertEffect> = Vec::with_capacity(points.len());
+        // Effect of the latest deferred append, and the open block's
+        // compressed size before the first one
+        let mut deferred: Option<(usize, usize)> = None;
 
-        // Query closed blocks
-        for block in &self.closed_blocks {
-            if block.overlaps(start, end) {
-                results.extend(block.get_points(start, end));
+        for &(timestamp, value) in points {
+            if self.open_block_realigns(timestamp) {
+                self.open_block.start_time = self.window_start(timestamp);
+            }
+            let appends = timestamp >= self.open_block.start_time
+                && !self.open_block.raw_dropped()
+                && self
+                    .open_block
+                    .points
+                    .last()
+                    .is_none_or(|last| last.timestamp < timestamp);
+            if !appends {
+                self.encode_deferred(&mut deferred, &mut effects);
+                effects.push(self.insert_at(timestamp, value, now));
+                continue;
             }
-        }
 
-        // Query open block
-        if self.open_block.overlaps(start, end) {
-            results.extend(self.open_block.get_points(start, end));
+            let mut effect = InsertEffect::default();
+            if let Some(next_start) = self.next_block_start(timestamp) {
+                self.encode_deferred(&mut deferred, &mut effects);
+                self.close_open_block(next_start);
+                self.roll_up(next_start);
+                effect.closed_block = true;
+            }
+            if !self.open_block.append_deferred(timestamp, value) {
+                effect.write = PointWrite::Rejected;
+                effects.push(effect);
+                continue;
+            }
+            let size_before = deferred.map_or(self.open_block.compressed_size, |(_, size)| size);
+            deferred = Some((effects.len(), size_before));
+            effects.push(effect);
+            self.meta.last_write = now;
+            if !self.in_grace() {
+                self.end_grace();
+            }
         }
+        self.encode_deferred(&mut deferred, &mut effects);
+        effects
+    }
 
-        results
+    /// Encode the open block after deferred appends, crediting its growth
+    /// to the last of them
+    fn encode_deferred(
+        &mut self,
+        deferred: &mut Option<(usize, usize)>,
+        effects: &mut [InsertEffect],
+    ) {
+        if let Some((last, size_before)) = deferred.take() {
+            self.open_block.recompress();
+            effects[last].compressed_bytes =
+                self.open_block.compressed_size.saturating_sub(size_before);
+        }
     }
 
-    /// Get storage statistics
-    pub fn get_stats(&self) -> StorageStats {
-        let mut stats = StorageStats::default();
+    /// Insert, recording `now` as the last write if the point is stored
+    fn insert_at(&mut self, timestamp: u64, value: f64, now: u64) -> InsertEffect {
+        let effect = self.insert(timestamp, value);
+        if effect.write.stored() {
+            self.meta.last_write = now;
+        }
+        effect
+    }
 
-        // Count points and calculate sizes
-        let mut total_points = 0;
+    /// Move the open block to the closed blocks, opening a new one at
+    /// `next_start`
+    fn close_open_block(&mut self, next_start: u64) {
+        self.end_grace();
+        let mut old_block = std::mem::replace(
+            &mut self.open_block,
+            TimeSeriesBlock::for_options(next_start, &self.options),
+        );
+        if self.options.drop_raw_on_close && !self.has_grace() {
+            old_block.drop_raw();
+        } else {
+            // Closed blocks rarely grow again; give back the spare capacity
+            old_block.points.shrink_to_fit();
+        }
+        self.closed_blocks.push(old_block);
+    }
 
-        for block in &self.closed_blocks {
-            total_points += block.points.len();
-            stats.compressed_size += block.compressed_size;
+    /// Queue the aggregates of every tier bucket that ends by `until`
+    ///
+    /// Called when a block closes: all points before `until` are then in
+    /// closed blocks, so those buckets are complete.
+    fn roll_up(&mut self, until: u64) {
+        let mut rollups = Vec::new();
+        let origin = self.options.align_origin;
+        for (tier, mark) in self.options.downsample.iter().zip(&self.rollup_marks) {
+            let end = align_down(until, tier.width, origin);
+            if end <= *mark {
+                continue;
+            }
+            let key = tier.companion_key(&self.key);
+            for (timestamp, value) in tier.rollup(self.iter_range(*mark, end - 1), origin) {
+                rollups.push(Rollup {
+                    key: key.clone(),
+                    timestamp,
+                    value,
+                });
+            }
+        }
+        for (mark, tier) in self.rollup_marks.iter_mut().zip(&self.options.downsample) {
+            *mark = (*mark).max(align_down(until, tier.width, origin));
         }
+        self.pending_rollups.extend(rollups);
+    }
 
-        total_points += self.open_block.points.len();
-        stats.compressed_size += self.open_block.compressed_size;
+    /// Take the rolled-up buckets waiting for their companion series
+    pub fn take_rollups(&mut self) -> Vec<Rollup> {
+        std::mem::take(&mut self.pending_rollups)
+    }
 
-        // Original size: 16 bytes per point (8 bytes timestamp + 8 bytes value)
-        stats.original_size = total_points * 16;
+    /// Options the series was created with
+    pub fn options(&self) -> &SeriesOptions {
+        &self.options
+    }
 
-        stats
+    /// Options for this series' downsample companions: the same, minus
+    /// the tiers, so companions are never downsampled again, and minus
+    /// the retention (each tier has its own)
+    pub fn companion_options(&self) -> SeriesOptions {
+        SeriesOptions {
+            downsample: Vec::new(),
+            retention: None,
+            ..self.options.clone()
+        }
     }
-}
 
-/// A block represents a 2-hour chunk of compressed time series data
-/// Paper describes this as the fundamental storage unit
-pub struct TimeSeriesBlock {
-    pub start_time: u64,
+    /// Close the open block before its window ends
+    ///
+    /// The next block starts right after the sealed block's last point,
+    /// so later points in the same window don't reopen it. Returns false
+    /// (and does nothing) if the open block is empty.
+    pub fn seal_open_block(&mut self) -> bool {
+        let Some(last) = self.open_block.points.last() else {
+            return false;
+        };
+        self.close_open_block(last.timestamp.saturating_add(1));
+        true
+    }
+
+    /// Close the open block if its window has fully elapsed by `now`
+    ///
+    /// Without this a series that stops receiving points keeps its last
+    /// block open until a newer point arrives. The block closes (and its
+    /// rollups are queued) exactly as a point in the next window would
+    /// close it. Returns false if the block is empty or its window is
+    /// still running, so calling it again is harmless.
+    pub fn close_stale_block(&mut self, now: u64) -> bool {
+        let window_end = self.window_end(self.open_block.start_time);
+        if self.open_block.is_empty() || now < window_end {
+            return false;
+        }
+        self.close_open_block(window_end);
+        self.roll_up(window_end);
+        true
+    }
 
-    // Uncompressed points (for demo purposes)
-    // In production, only compressed data would be kept
-    points: Vec<DataPoint>,
+    /// Nothing written yet: let the open block follow the first point
+    /// instead of the wall clock, as long as it stays the newest block
+    fn open_block_realigns(&self, timestamp: u64) -> bool {
+        let window_start = self.window_start(timestamp);
+        self.open_block.points.is_empty()
+            && self
+                .closed_blocks
+                .last()
+                .is_none_or(|block| self.window_start(block.start_time) < window_start)
+    }
 
-    // Compressed representation
-    compressed_data: Vec<u8>,
-    compressed_size: usize,
-}
+    /// Start of the block that replaces the open block, if a point at
+    /// `timestamp` (not older than the open block) closes it
+    fn next_block_start(&self, timestamp: u64) -> Option<u64> {
+        let window_end = self.window_end(self.open_block.start_time);
+        if timestamp >= window_end {
+            return Some(self.window_start(timestamp));
+        }
 
-impl TimeSeriesBlock {
-    pub fn new(start_time: u64) -> Self {
-        TimeSeriesBlock {
-            start_time,
-            points: Vec::new(),
-            compressed_data: Vec::new(),
-            compressed_size: 0,
+        let full = self
+            .options
+            .max_points_per_block
+            .is_some_and(|max| self.open_block.len() >= max);
+        let appends = self
+            .open_block
+            .points
+            .last()
+            .is_some_and(|last| timestamp > last.timestamp);
+        (full && appends).then_some(timestamp)
+    }
+
+    /// Closed block a point older than the open block belongs in
+    ///
+    /// That is the last block starting at or before the point, if it is
+    /// in the point's window; Err holds where a new block would go.
+    fn closed_block_for(&self, timestamp: u64) -> Result<usize, usize> {
+        let after = self
+            .closed_blocks
+            .partition_point(|block| block.start_time <= timestamp);
+        match after.checked_sub(1) {
+            Some(position)
+                if self.window_start(self.closed_blocks[position].start_time)
+                    == self.window_start(timestamp) =>
+            {
+                Ok(position)
+            }
+            _ => Err(after),
         }
     }
 
-    /// Add a point and compress it
-    pub fn add_point(&mut self, timestamp: u64, value: f64) {
-        self.points.push(DataPoint { timestamp, value });
+    fn has_grace(&self) -> bool {
+        self.options.late_grace.is_some_and(|grace| grace > 0)
+    }
 
-        // Recompress the entire block (simplified for demo)
-        // In production, this would append to existing compressed data
-        self.compress();
+    /// Whether the newest closed block is still in its grace period: the
+    /// open block's points don't yet reach `late_grace` past its start
+    fn in_grace(&self) -> bool {
+        let Some(grace) = self.options.late_grace else {
+            return false;
+        };
+        let newest = self
+            .open_block
+            .points
+            .last()
+            .map_or(self.open_block.start_time, |point| point.timestamp);
+        !self.closed_blocks.is_empty() && newest < self.open_block.start_time.saturating_add(grace)
     }
 
-    /// Compress all points in this block
-    fn compress(&mut self) {
-        if self.points.is_empty() {
-            return;
+    /// Drop the raw points the newest closed block kept for its grace
+    /// period, if the series drops them on close
+    fn end_grace(&mut self) {
+        if self.options.drop_raw_on_close
+            && self.has_grace()
+            && let Some(block) = self.closed_blocks.last_mut()
+            && !block.raw_dropped()
+        {
+            block.drop_raw();
+        }
+    }
+
+    /// Add a point to the newest closed block during its grace period
+    ///
+    /// Unlike a backfill the block's raw points are still in memory, so
+    /// nothing is decoded; a point after the block's last one appends to
+    /// its stream.
+    fn append_late(&mut self, timestamp: u64, value: f64) -> InsertEffect {
+        let block = self
+            .closed_blocks
+            .last_mut()
+            .expect("grace period needs a closed block");
+        let size_before = block.compressed_size;
+        let write = block.add_point(timestamp, value, self.options.duplicate_policy);
+        InsertEffect {
+            closed_block: false,
+            compressed_bytes: block.compressed_size.saturating_sub(size_before),
+            write,
+            created_series: false,
         }
+    }
 
-        let mut writer = BitWriter::new();
+    /// Insert a point older than the open block into its closed block
+    fn backfill(&mut self, timestamp: u64, value: f64) -> InsertEffect {
+        let position = match self.closed_block_for(timestamp) {
+            Ok(position) => position,
+            Err(position) => {
+                let block =
+                    TimeSeriesBlock::for_options(self.window_start(timestamp), &self.options);
+                self.closed_blocks.insert(position, block);
+                position
+            }
+        };
 
-        // Write header: aligned start time (64 bits)
-        writer.write_bits(self.start_time, 64);
+        let block = &mut self.closed_blocks[position];
+        let size_before = block.compressed_size;
+        let write = block.add_point(timestamp, value, self.options.duplicate_policy);
+        if self.options.drop_raw_on_close {
+            block.drop_raw();
+        }
 
-        // Write first timestamp delta (14 bits, as per paper)
-        let first_delta = (self.points[0].timestamp as i64) - (self.start_time as i64);
-        writer.write_bits(first_delta as u64, 14);
+        InsertEffect {
+            closed_block: false,
+            compressed_bytes: block.compressed_size.saturating_sub(size_before),
+            write,
+            created_series: false,
+        }
+    }
 
-        // Write first value (64 bits)
-        writer.write_bits(self.points[0].value.to_bits(), 64);
+    /// Name and labels, if the series was created with labels
+    pub fn labels(&self) -> Option<&SeriesLabels> {
+        self.labels.as_ref()
+    }
 
-        // Compress subsequent points
-        if self.points.len() > 1 {
-            let mut ts_compressor = TimestampCompressor::new(self.points[0].timestamp);
-            let mut val_compressor = ValueCompressor::new(self.points[0].value);
+    /// Metric name the label index files this series under
+    fn metric_name(&self) -> &str {
+        self.labels
+            .as_ref()
+            .map_or(&self.key, |labels| &labels.name)
+    }
 
-            for point in &self.points[1..] {
-                ts_compressor.add_timestamp(&mut writer, point.timestamp);
-                val_compressor.add_value(&mut writer, point.value);
+    fn label_map(&self) -> &BTreeMap<String, String> {
+        static EMPTY: BTreeMap<String, String> = BTreeMap::new();
+        self.labels.as_ref().map_or(&EMPTY, |labels| &labels.labels)
+    }
+
+    /// Unit, description and write times of this series
+    pub fn meta(&self) -> &SeriesMeta {
+        &self.meta
+    }
+
+    /// Whether the series is read-only (see Gorilla::freeze)
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// Mark the series read-only or writable again
+    pub fn set_frozen(&mut self, frozen: bool) {
+        self.frozen = frozen;
+    }
+
+    /// When the series was last queried, or created if never queried
+    pub fn last_access(&self) -> u64 {
+        self.last_access.load(Ordering::Relaxed)
+    }
+
+    /// Record a query at `now`; only needs a read lock
+    pub fn mark_accessed(&self, now: u64) {
+        self.last_access.fetch_max(now, Ordering::Relaxed);
+    }
+
+    /// Time window covered by each block, in seconds
+    pub fn block_duration(&self) -> u64 {
+        self.block_duration
+    }
+
+    /// Replace the user-set metadata (unit, description and pinned)
+    ///
+    /// `created_at` and `last_write` are engine-maintained and kept.
+    pub fn set_meta(&mut self, meta: SeriesMeta) {
+        self.meta.unit = meta.unit;
+        self.meta.description = meta.description;
+        self.meta.pinned = meta.pinned;
+    }
+
+    /// Estimated bits a point would add to this series, without storing it
+    ///
+    /// Costs the point against the block it would land in, using the
+    /// same routing as insert. A point that starts a new block pays the
+    /// block header; otherwise it is costed after the point that would
+    /// precede it (the re-encoding of the point after it is ignored).
+    pub fn estimate_insert_bits(&self, timestamp: u64, value: f64) -> u32 {
+        let block = if self.open_block_realigns(timestamp) {
+            None // The empty open block would realign to this point
+        } else if timestamp < self.open_block.start_time {
+            self.closed_block_for(timestamp)
+                .ok()
+                .map(|position| &self.closed_blocks[position])
+        } else if self.next_block_start(timestamp).is_some() {
+            None
+        } else {
+            Some(&self.open_block)
+        };
+
+        match block {
+            Some(block) => block.estimate_point_bits(timestamp, value),
+            None => {
+                BLOCK_HEADER_BITS
+                    + first_delta_bits(timestamp - self.window_start(timestamp))
+                    + self.options.value_hint.first_value_bits(value)
             }
         }
+    }
 
-        self.compressed_data = writer.finish();
-        self.compressed_size = self.compressed_data.len();
+    /// Start of the block window a timestamp belongs to
+    fn window_start(&self, timestamp: u64) -> u64 {
+        align_down(timestamp, self.block_duration, self.options.align_origin)
     }
 
-    /// Check if this block overlaps with a time range
-    fn overlaps(&self, start: u64, end: u64) -> bool {
-        let block_end = self.start_time + 7200; // 2 hours
-        !(end < self.start_time || start > block_end)
+    /// End (exclusive) of the block window a timestamp belongs to,
+    /// saturating for the last window before u64::MAX
+    fn window_end(&self, timestamp: u64) -> u64 {
+        let phase = self.options.align_origin % self.block_duration;
+        if timestamp < phase {
+            return phase; // The short window before the first aligned one
+        }
+        self.window_start(timestamp)
+            .saturating_add(self.block_duration)
     }
 
-    /// Get points within a time range
-    fn get_points(&self, start: u64, end: u64) -> Vec<DataPoint> {
-        self.points
+    /// Whether a point with exactly this timestamp is stored
+    pub fn contains_timestamp(&self, timestamp: u64) -> bool {
+        self.query(timestamp, timestamp)
             .iter()
-            .filter(|p| p.timestamp >= start && p.timestamp <= end)
-            .copied()
-            .collect()
+            .any(|p| p.timestamp == timestamp)
     }
-}
 
-/// Storage statistics for compression analysis
-#[derive(Default, Debug)]
-pub struct StorageStats {
-    pub original_size: usize,   // Uncompressed size in bytes
-    pub compressed_size: usize, // Compressed size in bytes
-}
+    /// Query data points within a time range
+    pub fn query(&self, start: u64, end: u64) -> Vec<DataPoint> {
+        self.iter_range(start, end).collect()
+    }
 
-impl StorageStats {
-    pub fn compression_ratio(&self) -> f64 {
-        if self.compressed_size == 0 {
-            return 0.0;
+    /// Points of the open block only (the newest data)
+    ///
+    /// Reads one block whatever the number of closed blocks.
+    pub fn query_open(&self) -> Vec<DataPoint> {
+        self.open_block.touch();
+        self.blocks_read.fetch_add(1, Ordering::Relaxed);
+        self.open_block.points().iter().collect()
+    }
+
+    /// Query a time range, reporting blocks that fail verification
+    ///
+    /// Returns the points of every readable block, plus the start times
+    /// of blocks whose compressed stream couldn't be read back or didn't
+    /// match its checksum (their points are left out).
+    pub fn query_verified(&self, start: u64, end: u64) -> (Vec<DataPoint>, Vec<u64>) {
+        let mut points = Vec::new();
+        let mut corrupt = Vec::new();
+        for block in self.blocks_in_range(start, end) {
+            match block.verified_points() {
+                Some(block_points) => points.extend(
+                    block_points
+                        .range(start, end)
+                        .map(|i| block_points.point(i)),
+                ),
+                None => corrupt.push(block.start_time),
+            }
         }
-        self.original_size as f64 / self.compressed_size as f64
+        (points, corrupt)
     }
-}
 
-/// Time Series Map (TSmap) - main data structure
-/// Paper Section 4.2 and Figure 7
-///
-/// Contains:
-/// - Vector of time series for efficient scanning
-/// - HashMap for O(1) lookups by key
-pub struct TimeSeriesMap {
-    // Vector allows efficient paged scans
-    series_vector: Vec<Option<TimeSeries>>,
+    /// Remove closed blocks whose points can't be read back
+    ///
+    /// These are the blocks query_verified reports: a corrupt compressed
+    /// stream or an unreadable spill file. Returns their start times.
+    pub fn drop_corrupt_blocks(&mut self) -> Vec<u64> {
+        let mut dropped = Vec::new();
+        self.closed_blocks.retain(|block| {
+            let corrupt = block.verified_points().is_none();
+            if corrupt {
+                dropped.push(block.start_time);
+            }
+            !corrupt
+        });
+        dropped
+    }
 
-    // Map from key to index in vector
-    key_to_index: HashMap<String, usize>,
+    /// Drop the closed blocks whose points are all before `horizon` and
+    /// rolled up by every downsample tier
+    ///
+    /// Returns the number of blocks dropped.
+    pub fn drop_blocks_before(&mut self, horizon: u64) -> usize {
+        let horizon = self
+            .rollup_marks
+            .iter()
+            .fold(horizon, |horizon, &mark| horizon.min(mark));
+        let before = self.closed_blocks.len();
+        self.closed_blocks
+            .retain(|block| block.is_empty() || block.last_timestamp >= horizon);
+        before - self.closed_blocks.len()
+    }
 
-    // Free list for reusing tombstoned entries
-    free_indices: Vec<usize>,
-}
+    /// Lazily iterate data points within a time range
+    ///
+    /// Closed blocks are visited first (oldest to newest), then the open
+    /// block. Blocks that don't overlap the range are skipped entirely.
+    pub fn iter_range(&self, start: u64, end: u64) -> impl Iterator<Item = DataPoint> + '_ {
+        self.blocks_in_range(start, end)
+            .flat_map(move |block| block.iter_points(start, end))
+    }
 
-impl TimeSeriesMap {
-    pub fn new() -> Self {
-        TimeSeriesMap {
-            series_vector: Vec::new(),
-            key_to_index: HashMap::new(),
-            free_indices: Vec::new(),
-        }
+    /// Lazily iterate data points within a time range, newest first
+    ///
+    /// Blocks are visited newest to oldest and only decoded when the
+    /// iterator reaches them, so taking the last few points reads just
+    /// the newest blocks.
+    pub fn iter_range_desc(&self, start: u64, end: u64) -> impl Iterator<Item = DataPoint> + '_ {
+        self.blocks_in_range(start, end)
+            .rev()
+            .flat_map(move |block| block.iter_points(start, end).rev())
     }
 
-    /// Insert or update a time series
-    pub fn insert(&mut self, key: String, timestamp: u64, value: f64) {
-        if let Some(&index) = self.key_to_index.get(&key) {
-            // Time series exists, update it
-            if let Some(ref mut series) = self.series_vector[index] {
-                series.insert(timestamp, value);
-            }
-        } else {
-            // Create new time series
-            let mut series = TimeSeries::new(key.clone());
-            series.insert(timestamp, value);
-
-            let index = if let Some(free_idx) = self.free_indices.pop() {
-                // Reuse a tombstoned slot
-                self.series_vector[free_idx] = Some(series);
-                free_idx
-            } else {
-                // Append new slot
-                self.series_vector.push(Some(series));
-                self.series_vector.len() - 1
-            };
+    /// Visit a time range block by block as column slices
+    ///
+    /// `f` gets each block's timestamps and values in [start, end] as
+    /// parallel slices, oldest block first, so scans that only need one
+    /// column never touch the other.
+    pub fn for_each_columns(&self, start: u64, end: u64, mut f: impl FnMut(&[u64], &[f64])) {
+        for block in self.blocks_in_range(start, end) {
+            block.with_columns(start, end, &mut f);
+        }
+    }
 
-            self.key_to_index.insert(key, index);
+    /// Timestamps in a time range, oldest first
+    ///
+    /// Blocks holding only their compressed stream decode the timestamps
+    /// and skip over the values without rebuilding them.
+    pub fn timestamps(&self, start: u64, end: u64) -> Vec<u64> {
+        let mut timestamps = Vec::new();
+        for block in self.blocks_in_range(start, end) {
+            let block_timestamps = block.timestamps();
+            let from = block_timestamps.partition_point(|&ts| ts < start);
+            let to = block_timestamps.partition_point(|&ts| ts <= end);
+            timestamps.extend_from_slice(&block_timestamps[from..to.max(from)]);
         }
+        timestamps
     }
 
-    /// Get a time series by key
-    pub fn get(&self, key: &str) -> Option<&TimeSeries> {
-        self.key_to_index
-            .get(key)
-            .and_then(|&idx| self.series_vector[idx].as_ref())
+    /// Values in a time range, oldest first
+    ///
+    /// The timestamps are still decoded (they decide what is in range),
+    /// but never copied out.
+    pub fn values(&self, start: u64, end: u64) -> Vec<f64> {
+        let mut values = Vec::new();
+        self.for_each_columns(start, end, |_, block_values| {
+            values.extend_from_slice(block_values)
+        });
+        values
     }
 
-    /// Delete a time series (tombstoning)
-    pub fn delete(&mut self, key: &str) {
-        if let Some(&index) = self.key_to_index.get(key) {
-            self.series_vector[index] = None; // Tombstone
-            self.free_indices.push(index);
-            self.key_to_index.remove(key);
+    /// Number of times queries decoded a block's value stream (blocks
+    /// whose raw points were dropped or spilled)
+    pub fn value_decodes(&self) -> usize {
+        self.closed_blocks
+            .iter()
+            .chain([&self.open_block])
+            .map(|block| block.value_decodes.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// The points in a time range, gathered into columns
+    pub fn columns(&self, start: u64, end: u64) -> PointColumns {
+        let mut columns = PointColumns::new();
+        self.for_each_columns(start, end, |timestamps, values| {
+            columns.extend_from_slices(timestamps, values)
+        });
+        columns
+    }
+
+    /// Aggregate a time range in buckets of `step` seconds aligned to
+    /// `start`, one (bucket start, value) per non-empty bucket
+    ///
+    /// A block whose points in range all fall in one bucket is folded in
+    /// from its summary without decoding; other blocks contribute only
+    /// their points in range. Panics if `step` is zero.
+    pub fn downsample(
+        &self,
+        start: u64,
+        end: u64,
+        step: u64,
+        aggregation: Aggregation,
+    ) -> Vec<(u64, f64)> {
+        assert!(step > 0, "downsample step must be positive");
+        let bucket_of = |timestamp: u64| start + (timestamp - start) / step * step;
+        let mut buckets: Vec<(u64, Bucket)> = Vec::new();
+        let mut fold = |bucket_start: u64, bucket: Bucket| match buckets.last_mut() {
+            Some((last_start, last)) if *last_start == bucket_start => last.merge(&bucket),
+            _ => buckets.push((bucket_start, bucket)),
+        };
+
+        for block in self.blocks_in_range(start, end) {
+            let inside = block.first_timestamp >= start && block.last_timestamp <= end;
+            if inside && bucket_of(block.first_timestamp) == bucket_of(block.last_timestamp) {
+                fold(bucket_of(block.first_timestamp), block.summary());
+                continue;
+            }
+            for point in block.iter_points(start, end) {
+                fold(bucket_of(point.timestamp), Bucket::new(point.value));
+            }
         }
+        buckets
+            .into_iter()
+            .map(|(bucket_start, bucket)| (bucket_start, bucket.finish(aggregation)))
+            .collect()
     }
 
-    /// Scan all time series (for background jobs)
-    pub fn scan<F>(&self, mut f: F)
-    where
-        F: FnMut(&TimeSeries),
-    {
-        for entry in &self.series_vector {
-            if let Some(series) = entry {
-                f(series);
+    /// One aggregate over the points in [start, end]; None if there are
+    /// none (with `skip_nan`, none besides NaN)
+    ///
+    /// Blocks wholly inside the range are folded in from their summaries,
+    /// unless their sum is NaN: min and max pass over NaN, so only then
+    /// can a block hold one, and it is decoded to find out. Without
+    /// `skip_nan`, a NaN point makes the aggregate NaN, except Count,
+    /// which counts it like any other.
+    pub fn aggregate(
+        &self,
+        start: u64,
+        end: u64,
+        aggregation: Aggregation,
+        skip_nan: bool,
+    ) -> Option<f64> {
+        let mut total: Option<Bucket> = None;
+        let mut fold = |bucket: Bucket| match &mut total {
+            Some(total) => total.merge(&bucket),
+            None => total = Some(bucket),
+        };
+        let mut saw_nan = false;
+
+        for block in self.blocks_in_range(start, end) {
+            let inside = block.first_timestamp >= start && block.last_timestamp <= end;
+            if inside && !block.sum_value.is_nan() {
+                fold(block.summary());
+                continue;
             }
+            for point in block.iter_points(start, end) {
+                if point.value.is_nan() {
+                    saw_nan = true;
+                    if skip_nan {
+                        continue;
+                    }
+                }
+                fold(Bucket::new(point.value));
+            }
+        }
+        let total = total?;
+        if saw_nan && !skip_nan && aggregation != Aggregation::Count {
+            return Some(f64::NAN);
         }
+        Some(total.finish(aggregation))
+    }
+
+    /// Lazily iterate points in a time range whose value lies in [min, max]
+    ///
+    /// Uses each block's min/max summary (a zone map) to skip blocks that
+    /// cannot contain a qualifying value without reading their points.
+    pub fn iter_value_range(
+        &self,
+        start: u64,
+        end: u64,
+        min: f64,
+        max: f64,
+    ) -> impl Iterator<Item = DataPoint> + '_ {
+        self.closed_blocks
+            .iter()
+            .chain(std::iter::once(&self.open_block))
+            .filter(move |block| block.overlaps(start, end) && block.may_contain(min, max))
+            .inspect(|block| {
+                block.touch();
+                self.blocks_read.fetch_add(1, Ordering::Relaxed);
+            })
+            .flat_map(move |block| block.iter_points(start, end))
+            .filter(move |p| p.value >= min && p.value <= max)
+    }
+
+    /// Blocks overlapping a time range, oldest first, counted as read
+    /// when the iterator reaches them (from either end)
+    fn blocks_in_range(
+        &self,
+        start: u64,
+        end: u64,
+    ) -> impl DoubleEndedIterator<Item = &TimeSeriesBlock> {
+        self.closed_blocks
+            .iter()
+            .chain(std::iter::once(&self.open_block))
+            .filter(move |block| block.overlaps(start, end))
+            .inspect(|block| {
+                block.touch();
+                self.blocks_read.fetch_add(1, Ordering::Relaxed);
+            })
+    }
+
+    /// Merge runs of adjacent closed blocks spanning less than `target_span`
+    ///
+    /// A run starts at a block's start time and ends at the last point of
+    /// its newest block. Merged blocks are decoded and re-encoded into the
+    /// run's first block, which rebuilds their value summaries; spilled
+    /// blocks are read back first (one that can't be read is left alone).
+    /// Points of the earlier block win on duplicate timestamps. Returns
+    /// the number of blocks merged away.
+    pub fn compact_blocks(&mut self, target_span: u64) -> usize {
+        let mut merged = 0;
+        let mut i = 0;
+        while i + 1 < self.closed_blocks.len() {
+            let run_start = self.closed_blocks[i].start_time;
+            let fits = self.closed_blocks[i + 1]
+                .points()
+                .last()
+                .is_some_and(|last| last.timestamp.saturating_sub(run_start) < target_span);
+            if !fits
+                || !self.closed_blocks[i].restore_raw()
+                || !self.closed_blocks[i + 1].restore_raw()
+            {
+                i += 1;
+                continue;
+            }
+
+            let next = self.closed_blocks.remove(i + 1);
+            let block = &mut self.closed_blocks[i];
+            block.points.merge(&next.points);
+            block.compress();
+            block.flushed = 0;
+            block.last_read = block.last_read.max(next.last_read);
+            if next.read_since_pass.load(Ordering::Relaxed) {
+                block.touch();
+            }
+            if self.options.drop_raw_on_close {
+                block.drop_raw();
+            } else {
+                block.points.shrink_to_fit();
+            }
+            merged += 1;
+        }
+        merged
+    }
+
+    /// The closed blocks compact_blocks(target_span) would merge, as runs
+    /// of indices; a block it would leave alone is a run of one
+    fn block_runs(&self, target_span: u64) -> Vec<Range<usize>> {
+        let mut runs: Vec<Range<usize>> = Vec::new();
+        for (i, block) in self.closed_blocks.iter().enumerate() {
+            match runs.last_mut() {
+                Some(run)
+                    if !block.is_empty()
+                        && block
+                            .last_timestamp
+                            .saturating_sub(self.closed_blocks[run.start].start_time)
+                            < target_span =>
+                {
+                    run.end = i + 1;
+                }
+                _ => runs.push(i..i + 1),
+            }
+        }
+        runs
+    }
+
+    /// A new block holding the points of the closed blocks in `run`,
+    /// which are left as they are
+    ///
+    /// Fails with InvalidData if one of them can't be decoded.
+    fn merged_block(&self, run: Range<usize>) -> io::Result<TimeSeriesBlock> {
+        let blocks = &self.closed_blocks[run];
+        let first = &blocks[0];
+        let mut block = TimeSeriesBlock::new(first.start_time, first.encoding);
+        block.auto_codec = first.auto_codec;
+        for source in blocks {
+            let points = source.verified_points().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("block {}: unreadable", source.start_time),
+                )
+            })?;
+            block.points.merge(&points);
+        }
+        block.compress();
+        Ok(block)
+    }
+
+    /// (start, end, open) of each block holding points, oldest first
+    ///
+    /// `end` is exclusive: the start of the next window, or of the next
+    /// block when a window holds several.
+    pub fn block_boundaries(&self) -> Vec<(u64, u64, bool)> {
+        let blocks: Vec<(&TimeSeriesBlock, bool)> = self
+            .closed_blocks
+            .iter()
+            .map(|block| (block, false))
+            .chain(std::iter::once((&self.open_block, true)))
+            .filter(|(block, _)| !block.is_empty())
+            .collect();
+
+        blocks
+            .iter()
+            .enumerate()
+            .map(|(i, &(block, open))| {
+                let window_end = self.window_end(block.start_time);
+                let end = blocks
+                    .get(i + 1)
+                    .map_or(window_end, |(next, _)| next.start_time.min(window_end));
+                (block.start_time, end, open)
+            })
+            .collect()
+    }
+
+    /// How a query over [start, end] would read this series, without
+    /// reading it
+    ///
+    /// Blocks are judged as iter_range judges them: a block is read if
+    /// its points' extent overlaps the range, and counted as pruned if
+    /// only its nominal span (see block_boundaries) does. Nothing is
+    /// decoded or counted as read.
+    pub fn explain(&self, start: u64, end: u64) -> QueryPlan {
+        let mut plan = QueryPlan::default();
+        let boundaries = self.block_boundaries();
+        let blocks = self
+            .closed_blocks
+            .iter()
+            .chain(std::iter::once(&self.open_block))
+            .filter(|block| !block.is_empty());
+        for (block, &(block_start, block_end, open)) in blocks.zip(&boundaries) {
+            if block.overlaps(start, end) {
+                if open {
+                    plan.open_block = true;
+                } else {
+                    plan.closed_blocks += 1;
+                }
+                plan.estimated_points += block.len();
+            } else if start < block_end && end >= block_start {
+                plan.pruned_blocks += 1;
+            }
+        }
+        plan
+    }
+
+    /// Take the compressed bytes due for a chunk file
+    ///
+    /// Closed blocks give all their unflushed bytes; the open block only
+    /// once `flush_bytes` of them are pending. The bytes are counted as
+    /// flushed when taken, so the caller must write them (in order)
+    /// before anything else touches the series.
+    pub fn take_chunks(&mut self, flush_bytes: usize) -> Vec<Chunk> {
+        let open_pending = self.open_block.compressed_size - self.open_block.flushed;
+        let open = Some(&mut self.open_block).filter(|_| open_pending >= flush_bytes.max(1));
+        let key = &self.key;
+        self.closed_blocks
+            .iter_mut()
+            .chain(open)
+            .filter(|block| block.flushed < block.compressed_size)
+            .filter_map(|block| {
+                let data = block.read_compressed().ok()?.into_owned();
+                let chunk = Chunk {
+                    key: key.to_string(),
+                    start_time: block.start_time,
+                    encoding: block.encoding,
+                    offset: block.flushed,
+                    point_count: block.len(),
+                    bytes: data[block.flushed..].to_vec(),
+                };
+                block.flushed = data.len();
+                Some(chunk)
+            })
+            .collect()
+    }
+
+    /// Blocks holding points, oldest first; the open block comes last
+    pub fn blocks(&self) -> impl Iterator<Item = &TimeSeriesBlock> {
+        self.closed_blocks
+            .iter()
+            .chain(std::iter::once(&self.open_block))
+            .filter(|block| !block.is_empty())
+    }
+
+    /// Size and point count of each block holding points, oldest first
+    pub fn block_stats(&self) -> Vec<BlockStats> {
+        self.closed_blocks
+            .iter()
+            .map(|block| (block, false))
+            .chain(std::iter::once((&self.open_block, true)))
+            .filter(|(block, _)| !block.is_empty())
+            .map(|(block, open)| BlockStats {
+                start_time: block.start_time,
+                points: block.len(),
+                compressed_bytes: block.compressed_size,
+                open,
+            })
+            .collect()
+    }
+
+    /// Points in a time range annotated with where they are stored
+    ///
+    /// Each point comes as (block index, offset in block, timestamp,
+    /// value); closed blocks are numbered oldest first and the open block
+    /// comes last. For debugging, so it doesn't count as a block read.
+    pub fn debug_points(&self, start: u64, end: u64) -> Vec<(usize, usize, u64, f64)> {
+        self.closed_blocks
+            .iter()
+            .chain(std::iter::once(&self.open_block))
+            .enumerate()
+            .filter(|(_, block)| block.overlaps(start, end))
+            .flat_map(|(block_index, block)| {
+                block
+                    .points()
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, p)| p.timestamp >= start && p.timestamp <= end)
+                    .map(|(offset, p)| (block_index, offset, p.timestamp, p.value))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Number of blocks whose points have been read by queries so far
+    pub fn blocks_read(&self) -> usize {
+        self.blocks_read.load(Ordering::Relaxed)
+    }
+
+    /// Get storage statistics
+    pub fn get_stats(&self) -> StorageStats {
+        let mut stats = StorageStats::default();
+
+        // Count points and calculate sizes
+        let mut total_points = 0;
+
+        for block in self.closed_blocks.iter().chain([&self.open_block]) {
+            total_points += block.len();
+            stats.compressed_size += block.compressed_size;
+
+            // What the process really holds: raw columns are kept next
+            // to the compressed buffer unless dropped or spilled
+            stats.resident_raw_bytes += block.points.capacity_bytes();
+            if let BlockRef::InMemory(data) = &block.data {
+                stats.resident_compressed_bytes += data.capacity();
+            }
+        }
+
+        // Logical size: 16 bytes per point (8 bytes timestamp + 8 bytes value)
+        stats.logical_uncompressed_bytes = total_points * 16;
+
+        stats
+    }
+
+    /// Spill closed blocks that went unread for `config.idle_secs`
+    ///
+    /// A block's idle time is measured from the last pass that found it
+    /// read (or from the first pass that saw it), so it has the
+    /// resolution of the pass interval.
+    fn spill_idle(
+        &mut self,
+        config: &SpillConfig,
+        counters: &Arc<SpillCounters>,
+        now: u64,
+    ) -> io::Result<SpillReport> {
+        let mut report = SpillReport::default();
+        for block in &mut self.closed_blocks {
+            if block.is_spilled() {
+                continue;
+            }
+            if block.read_since_pass.swap(false, Ordering::Relaxed) || block.last_read == 0 {
+                block.last_read = now;
+            } else if now.saturating_sub(block.last_read) >= config.idle_secs {
+                report.bytes_spilled += block.spill(&config.dir, &self.key, counters)?;
+                report.blocks_spilled += 1;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Heap and inline memory held by this series
+    ///
+    /// Computed from vector capacities rather than lengths, so it reflects
+    /// what is allocated, not just what is in use.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage {
+            // Key bytes plus the Arc's two reference counts
+            index_bytes: self.key.len() + 2 * size_of::<usize>(),
+            overhead_bytes: size_of::<TimeSeries>()
+                + self.closed_blocks.capacity() * size_of::<TimeSeriesBlock>(),
+            ..MemoryUsage::default()
+        };
+
+        for block in self.closed_blocks.iter().chain([&self.open_block]) {
+            usage.raw_points_bytes += block.points.capacity_bytes();
+            if let BlockRef::InMemory(data) = &block.data {
+                usage.compressed_bytes += data.capacity();
+            }
+        }
+
+        if let Some(labels) = &self.labels {
+            usage.index_bytes += labels.name.capacity()
+                + labels
+                    .labels
+                    .iter()
+                    .map(|(label, value)| {
+                        label.capacity() + value.capacity() + 2 * size_of::<String>()
+                    })
+                    .sum::<usize>();
+        }
+        for text in [&self.meta.unit, &self.meta.description]
+            .into_iter()
+            .flatten()
+        {
+            usage.overhead_bytes += text.capacity();
+        }
+
+        usage
+    }
+}
+
+/// Approximate memory held by a series or a whole map, in bytes
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MemoryUsage {
+    pub raw_points_bytes: usize, // Uncompressed points kept in blocks
+    pub compressed_bytes: usize, // Compressed block buffers
+    pub index_bytes: usize,      // Keys, labels and lookup structures
+    pub overhead_bytes: usize,   // Structs, slots and other bookkeeping
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.raw_points_bytes + self.compressed_bytes + self.index_bytes + self.overhead_bytes
+    }
+}
+
+impl std::ops::AddAssign for MemoryUsage {
+    fn add_assign(&mut self, other: MemoryUsage) {
+        self.raw_points_bytes += other.raw_points_bytes;
+        self.compressed_bytes += other.compressed_bytes;
+        self.index_bytes += other.index_bytes;
+        self.overhead_bytes += other.overhead_bytes;
+    }
+}
+
+/// What a single insert did to the storage layout
+///
+/// Returned by the insert path so the engine can keep cheap counters
+/// without re-walking blocks.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InsertEffect {
+    // Whether the insert sealed the open block and started a new one
+    pub closed_block: bool,
+
+    // Growth of the compressed representation caused by this point
+    pub compressed_bytes: usize,
+
+    // How the block handled the point (added, or a duplicate outcome)
+    pub write: PointWrite,
+
+    // Whether the series didn't exist and was created for this point
+    pub created_series: bool,
+}
+
+/// How a block handled a point, given the duplicate policy
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum PointWrite {
+    /// New timestamp, point stored
+    #[default]
+    Added,
+
+    /// Timestamp existed, value overwritten (KeepLast)
+    Replaced,
+
+    /// Timestamp existed, new point dropped (KeepFirst)
+    Ignored,
+
+    /// Timestamp existed, new point refused (Reject)
+    Rejected,
+}
+
+impl PointWrite {
+    /// Whether the point's value is now the stored one
+    pub fn stored(self) -> bool {
+        matches!(self, PointWrite::Added | PointWrite::Replaced)
+    }
+}
+
+/// A block represents a 2-hour chunk of compressed time series data
+/// Paper describes this as the fundamental storage unit
+pub struct TimeSeriesBlock {
+    pub start_time: u64,
+
+    // Timestamp codec and first-value hint the stream is written with
+    encoding: BlockEncoding,
+
+    // Uncompressed points (for demo purposes), as parallel columns
+    // In production, only compressed data would be kept; with
+    // drop_raw_on_close this is emptied when the block closes
+    points: PointColumns,
+
+    // Compressed representation, in memory or spilled to disk
+    data: BlockRef,
+    compressed_size: usize,
+
+    // CRC-32 of the compressed stream, checked whenever it is decoded
+    checksum: u32,
+
+    // Leading bytes of the stream already in a chunk file and unchanged
+    // since (see chunks.rs)
+    flushed: usize,
+
+    // Points encoded in the compressed stream
+    point_count: usize,
+
+    // Value summary (zone map), computed at compress time
+    pub min_value: f64,
+    pub max_value: f64,
+
+    // Rest of the summary, for aggregating a whole block undecoded
+    sum_value: f64,
+    first_value: f64,
+    last_value: f64,
+
+    // Timestamps of the first and last stored points, which a sparse
+    // block may leave well inside its nominal window
+    first_timestamp: u64,
+    last_timestamp: u64,
+
+    // Set when a query reads the block; cleared by spill passes, which
+    // then record the pass time in last_read
+    read_since_pass: AtomicBool,
+    last_read: u64,
+
+    // Times the value stream was decoded (raw points dropped or spilled)
+    value_decodes: AtomicUsize,
+
+    // Pick the value codec from the first points at each compress
+    // (CodecChoice::Auto)
+    auto_codec: bool,
+}
+
+/// Where a block's compressed bytes live
+enum BlockRef {
+    InMemory(Vec<u8>),
+
+    /// Spilled to disk; the raw points are dropped too
+    OnDisk(SpillFile),
+}
+
+impl TimeSeriesBlock {
+    pub fn new(start_time: u64, encoding: BlockEncoding) -> Self {
+        TimeSeriesBlock {
+            start_time,
+            encoding,
+            points: PointColumns::new(),
+            data: BlockRef::InMemory(Vec::new()),
+            compressed_size: 0,
+            checksum: crc32(&[]),
+            flushed: 0,
+            point_count: 0,
+            min_value: f64::INFINITY,
+            max_value: f64::NEG_INFINITY,
+            sum_value: 0.0,
+            first_value: 0.0,
+            last_value: 0.0,
+            first_timestamp: u64::MAX,
+            last_timestamp: 0,
+            read_since_pass: AtomicBool::new(false),
+            last_read: 0,
+            value_decodes: AtomicUsize::new(0),
+            auto_codec: false,
+        }
+    }
+
+    /// An empty block written the way a series' options ask
+    fn for_options(start_time: u64, options: &SeriesOptions) -> Self {
+        let mut block = TimeSeriesBlock::new(start_time, options.encoding());
+        block.auto_codec = options.value_codec == CodecChoice::Auto;
+        block
+    }
+
+    /// Add a point and compress it
+    ///
+    /// Points are kept in timestamp order; an out-of-order point is
+    /// inserted at its position rather than appended. A spilled block is
+    /// brought back into memory first (the point is rejected if its spill
+    /// file can't be read).
+    pub fn add_point(&mut self, timestamp: u64, value: f64, policy: DuplicatePolicy) -> PointWrite {
+        let point = DataPoint { timestamp, value };
+        if !self.restore_raw() {
+            return PointWrite::Rejected;
+        }
+        let appends = self
+            .points
+            .last()
+            .is_none_or(|last| last.timestamp < timestamp);
+
+        let write = match self.points.last() {
+            // Fast path: in-order append
+            None => {
+                self.points.push(point);
+                PointWrite::Added
+            }
+            Some(last) if last.timestamp < timestamp => {
+                self.points.push(point);
+                PointWrite::Added
+            }
+            _ => match self.points.search(timestamp) {
+                Err(position) => {
+                    self.points.insert(position, point);
+                    PointWrite::Added
+                }
+                Ok(position) => match policy {
+                    DuplicatePolicy::KeepLast => {
+                        self.points.set_value(position, value);
+                        PointWrite::Replaced
+                    }
+                    DuplicatePolicy::KeepFirst => PointWrite::Ignored,
+                    DuplicatePolicy::Reject => PointWrite::Rejected,
+                },
+            },
+        };
+
+        if !write.stored() {
+            return write;
+        }
+
+        // An append leaves all but the stream's last, partly filled byte
+        // as it was; an insert or replacement may rewrite every byte
+        let unchanged = if appends {
+            self.compressed_size.saturating_sub(1)
+        } else {
+            0
+        };
+        self.flushed = self.flushed.min(unchanged);
+
+        // Recompress the entire block (simplified for demo)
+        // In production, this would append to existing compressed data
+        self.recompress();
+        write
+    }
+
+    /// Append a point after the last one without re-encoding the block
+    ///
+    /// The stream, summary and checksum lag behind the points until
+    /// recompress is called, so a batch of appends is encoded once.
+    /// Returns false if the block's raw points can't be restored.
+    fn append_deferred(&mut self, timestamp: u64, value: f64) -> bool {
+        if !self.restore_raw() {
+            return false;
+        }
+        self.points.push(DataPoint { timestamp, value });
+        self.point_count += 1;
+        self.flushed = self.flushed.min(self.compressed_size.saturating_sub(1));
+        true
+    }
+
+    /// Re-encode the block from its points
+    ///
+    /// If an auto codec picks a different value codec the whole stream
+    /// changes, so none of it counts as flushed any more.
+    fn recompress(&mut self) {
+        let codec_before = self.encoding.value_codec;
+        self.compress();
+        if self.encoding.value_codec != codec_before {
+            self.flushed = 0;
+        }
+    }
+
+    /// Compress all points in this block
+    fn compress(&mut self) {
+        if self.points.is_empty() {
+            return;
+        }
+
+        if self.auto_codec {
+            let sample = self.points.len().min(AUTO_CODEC_POINTS);
+            self.encoding.value_codec = ValueCodec::detect(&self.points.values()[..sample]);
+        }
+
+        let mut writer = BitWriter::new();
+
+        // Write header: aligned start time (64 bits)
+        writer.write_bits(self.start_time, 64);
+
+        // Write first timestamp delta (14 bits as per paper, escaped to 64
+        // for long blocks)
+        let first = self.points.point(0);
+        encode_first_delta(&mut writer, first.timestamp - self.start_time);
+
+        // Write first value (64 bits, fewer if the value hint applies)
+        let BlockEncoding {
+            timestamp_codec,
+            value_hint,
+            value_codec,
+        } = self.encoding;
+        value_hint.write_first_value(&mut writer, first.value);
+
+        // Compress subsequent points
+        if self.points.len() > 1 {
+            let mut ts_compressor =
+                TimestampCompressor::with_codec(first.timestamp, timestamp_codec);
+            let mut val_compressor = ValueCompressor::with_codec(first.value, value_codec);
+
+            for point in self.points.iter().skip(1) {
+                ts_compressor.add_timestamp(&mut writer, point.timestamp);
+                val_compressor.add_value(&mut writer, point.value);
+            }
+        }
+
+        let data = writer.finish();
+        self.compressed_size = data.len();
+        self.checksum = crc32(&data);
+        self.point_count = self.points.len();
+        self.data = BlockRef::InMemory(data);
+
+        // Refresh the value summary used for pruning
+        let (min, max) = self
+            .points
+            .values()
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &value| {
+                (min.min(value), max.max(value))
+            });
+        self.min_value = min;
+        self.max_value = max;
+        let values = self.points.values();
+        self.sum_value = values.iter().sum();
+        self.first_value = values[0];
+        self.last_value = values[values.len() - 1];
+
+        // Points are in timestamp order, so the ends give the extent
+        let timestamps = self.points.timestamps();
+        self.first_timestamp = timestamps[0];
+        self.last_timestamp = timestamps[timestamps.len() - 1];
+    }
+
+    /// Rebuild a block from its compressed stream
+    ///
+    /// Returns None if the stream is truncated, doesn't match the given
+    /// header, or doesn't re-encode to the same bytes (i.e. is corrupt).
+    fn from_compressed(
+        start_time: u64,
+        point_count: usize,
+        encoding: BlockEncoding,
+        data: &[u8],
+    ) -> Option<Self> {
+        let mut block = TimeSeriesBlock::new(start_time, encoding);
+        block.points = decode_points(data, point_count, start_time, encoding)?;
+        block.compress();
+
+        if !matches!(&block.data, BlockRef::InMemory(bytes) if bytes == data) {
+            return None;
+        }
+        Some(block)
+    }
+
+    /// Codecs the compressed stream is written with
+    pub fn encoding(&self) -> BlockEncoding {
+        self.encoding
+    }
+
+    /// Number of points stored, whether or not the raw points are kept
+    pub fn len(&self) -> usize {
+        self.point_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.point_count == 0
+    }
+
+    /// The block's points, oldest first
+    ///
+    /// Decodes the compressed stream if the raw points were dropped or
+    /// spilled. None if that stream can't be read back or fails its
+    /// checksum.
+    pub fn decode(&self) -> Option<Vec<DataPoint>> {
+        self.verified_points().map(|points| points.iter().collect())
+    }
+
+    /// The fields of the compressed stream, each with its bit offset,
+    /// length, name and decoded value (see compression/inspect.rs)
+    ///
+    /// Empty if the stream can't be read back or fails its checksum.
+    pub fn describe_encoding(&self) -> Vec<EncodedField> {
+        let Ok(data) = self.read_compressed() else {
+            return Vec::new();
+        };
+        let BlockEncoding {
+            timestamp_codec,
+            value_hint,
+            value_codec,
+        } = self.encoding;
+        describe_block(
+            &data,
+            self.point_count,
+            timestamp_codec,
+            value_hint,
+            value_codec,
+        )
+    }
+
+    /// Cut the block in two: points before `at`, and the rest
+    ///
+    /// Both halves keep this block's start time and encoding and are
+    /// re-encoded from the points, so each has its own header, summary
+    /// and checksum. A cut before the first point or after the last
+    /// leaves one half empty. A block whose stream can't be read back
+    /// splits as empty (see points).
+    pub fn split(&self, at: u64) -> (TimeSeriesBlock, TimeSeriesBlock) {
+        let points = self.points();
+        let cut = points.timestamps().partition_point(|&ts| ts < at);
+        let half = |range: Range<usize>| {
+            let mut block = TimeSeriesBlock::new(self.start_time, self.encoding);
+            block.points = PointColumns::from_columns(
+                points.timestamps()[range.clone()].to_vec(),
+                points.values()[range].to_vec(),
+            );
+            block.compress();
+            block
+        };
+        (half(0..cut), half(cut..points.len()))
+    }
+
+    /// Whether the raw points were dropped in favor of the compressed stream
+    fn raw_dropped(&self) -> bool {
+        self.points.len() < self.point_count
+    }
+
+    /// Free the raw points, keeping only the compressed stream
+    fn drop_raw(&mut self) {
+        self.points = PointColumns::new();
+        if let BlockRef::InMemory(data) = &mut self.data {
+            data.shrink_to_fit();
+        }
+    }
+
+    /// Bring a dropped or spilled block back into memory (before a write)
+    ///
+    /// Returns false if the spill file can't be read or the stream fails
+    /// its checksum; the block is left as it was.
+    fn restore_raw(&mut self) -> bool {
+        if !self.raw_dropped() {
+            return true;
+        }
+        let Ok(data) = self.read_compressed() else {
+            return false;
+        };
+        let data = data.into_owned();
+        let Some(points) = decode_points(&data, self.point_count, self.start_time, self.encoding)
+        else {
+            return false;
+        };
+        self.value_decodes.fetch_add(1, Ordering::Relaxed);
+        if self.is_spilled() {
+            // Replacing the handle deletes the file
+            self.data = BlockRef::InMemory(data);
+        }
+        self.points = points;
+        true
+    }
+
+    /// Whether the compressed bytes are in a spill file
+    fn is_spilled(&self) -> bool {
+        matches!(self.data, BlockRef::OnDisk(_))
+    }
+
+    /// Move the compressed bytes to a spill file and drop the raw points
+    ///
+    /// Returns the number of compressed bytes written.
+    fn spill(&mut self, dir: &Path, key: &str, counters: &Arc<SpillCounters>) -> io::Result<usize> {
+        let BlockRef::InMemory(data) = &self.data else {
+            return Ok(0);
+        };
+        let file = SpillFile::write(
+            dir,
+            key,
+            self.start_time,
+            self.point_count,
+            data,
+            counters.clone(),
+        )?;
+        let written = data.len();
+        self.points = PointColumns::new();
+        self.data = BlockRef::OnDisk(file);
+        Ok(written)
+    }
+
+    /// The compressed stream, read back from disk if the block is spilled
+    ///
+    /// Fails with InvalidData if the bytes don't match the checksum taken
+    /// when the block was compressed.
+    fn read_compressed(&self) -> io::Result<Cow<'_, [u8]>> {
+        let data = match &self.data {
+            BlockRef::InMemory(data) => Cow::Borrowed(data.as_slice()),
+            BlockRef::OnDisk(file) => Cow::Owned(file.read(self.start_time, self.point_count)?),
+        };
+        if crc32(&data) != self.checksum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("block {}: checksum mismatch", self.start_time),
+            ));
+        }
+        Ok(data)
+    }
+
+    /// The block's value summary, as one downsampling bucket
+    fn summary(&self) -> Bucket {
+        Bucket {
+            count: self.point_count as u64,
+            sum: self.sum_value,
+            min: self.min_value,
+            max: self.max_value,
+            first: self.first_value,
+            last: self.last_value,
+        }
+    }
+
+    /// Mark the block as read, for the next spill pass
+    fn touch(&self) {
+        self.read_since_pass.store(true, Ordering::Relaxed);
+    }
+
+    /// The block's points, decoded from the compressed stream if needed
+    ///
+    /// A stream that can't be read or fails its checksum reads as empty;
+    /// see verified_points to tell that apart.
+    fn points(&self) -> Cow<'_, PointColumns> {
+        self.verified_points().unwrap_or_default()
+    }
+
+    /// The block's points, or None if they had to be decoded from a
+    /// stream that is unreadable or corrupt
+    fn verified_points(&self) -> Option<Cow<'_, PointColumns>> {
+        if self.raw_dropped() {
+            self.decoded_points().map(Cow::Owned)
+        } else {
+            Some(Cow::Borrowed(&self.points))
+        }
+    }
+
+    fn decoded_points(&self) -> Option<PointColumns> {
+        // An unreadable spill file is counted as a read error
+        let data = self.readable_compressed()?;
+        self.value_decodes.fetch_add(1, Ordering::Relaxed);
+        decode_points(&data, self.point_count, self.start_time, self.encoding)
+    }
+
+    /// The block's timestamps, decoding only those if the raw points
+    /// were dropped
+    fn timestamps(&self) -> Cow<'_, [u64]> {
+        if !self.raw_dropped() {
+            return Cow::Borrowed(self.points.timestamps());
+        }
+        let Some(data) = self.readable_compressed() else {
+            return Cow::Owned(Vec::new());
+        };
+        Cow::Owned(
+            decode_timestamps(&data, self.point_count, self.start_time, self.encoding)
+                .unwrap_or_default(),
+        )
+    }
+
+    /// read_compressed, counting a spill file that can't be read
+    fn readable_compressed(&self) -> Option<Cow<'_, [u8]>> {
+        let data = self.read_compressed();
+        if data.is_err()
+            && let BlockRef::OnDisk(file) = &self.data
+        {
+            file.counters().record_read_error();
+        }
+        data.ok()
+    }
+
+    /// Estimated bits for a point placed in this block (see
+    /// TimeSeries::estimate_insert_bits)
+    fn estimate_point_bits(&self, timestamp: u64, value: f64) -> u32 {
+        let points = self.points();
+        let index = points.timestamps().partition_point(|&ts| ts < timestamp);
+        // First point: its delta from the start, then the value as written
+        // by ValueHint::write_first_value
+        let first_point_bits = first_delta_bits(timestamp.saturating_sub(self.start_time))
+            + self.encoding.value_hint.first_value_bits(value);
+        if points.is_empty() {
+            return BLOCK_HEADER_BITS + first_point_bits;
+        }
+        if index == 0 {
+            return first_point_bits; // Becomes the block's first point
+        }
+
+        // Same state the compressors would hold when reaching this point
+        let prev = points.point(index - 1);
+        let prev_delta = match index {
+            1 => 0,
+            _ => prev.timestamp as i64 - points.timestamps()[index - 2] as i64,
+        };
+        let delta = timestamp as i64 - prev.timestamp as i64;
+        let encoded = match self.encoding.timestamp_codec {
+            TimestampCodec::DeltaOfDelta => delta - prev_delta,
+            TimestampCodec::Delta => delta,
+        };
+        let value_bits = self.encoding.value_codec.value_bits(prev.value, value);
+
+        (compress_timestamp(encoded) + value_bits) as u32
+    }
+
+    /// Whether any value in this block could lie in [min, max]
+    fn may_contain(&self, min: f64, max: f64) -> bool {
+        self.max_value >= min && self.min_value <= max
+    }
+
+    /// Check if this block holds points that could lie in a time range
+    ///
+    /// Uses the stored points' actual extent rather than the nominal
+    /// window, so a sparse block is skipped when the range falls in the
+    /// part of its window it holds no data for. An empty block never
+    /// overlaps.
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.point_count > 0 && start <= self.last_timestamp && end >= self.first_timestamp
+    }
+
+    /// Iterate points within a time range
+    fn iter_points(&self, start: u64, end: u64) -> impl DoubleEndedIterator<Item = DataPoint> + '_ {
+        let points = self.points();
+        points.range(start, end).map(move |i| points.point(i))
+    }
+
+    /// This block's timestamps and values in [start, end], as slices
+    fn with_columns<R>(&self, start: u64, end: u64, f: impl FnOnce(&[u64], &[f64]) -> R) -> R {
+        let points = self.points();
+        let range = points.range(start, end);
+        f(&points.timestamps()[range.clone()], &points.values()[range])
+    }
+}
+
+/// Decode a block's compressed stream (as written by compress) into points
+///
+/// The stream has no end marker, so the number of points comes from the
+/// block header. Returns None on truncated or inconsistent data.
+fn decode_points(
+    data: &[u8],
+    point_count: usize,
+    start_time: u64,
+    encoding: BlockEncoding,
+) -> Option<PointColumns> {
+    let (timestamps, values) = decode_stream(data, point_count, start_time, encoding, true)?;
+    Some(PointColumns::from_columns(timestamps, values))
+}
+
+/// Decode only the timestamps of a block's compressed stream
+///
+/// Value bits are skipped rather than rebuilt (see skip_value_xor).
+fn decode_timestamps(
+    data: &[u8],
+    point_count: usize,
+    start_time: u64,
+    encoding: BlockEncoding,
+) -> Option<Vec<u64>> {
+    decode_stream(data, point_count, start_time, encoding, false).map(|(timestamps, _)| timestamps)
+}
+
+/// Shared body of decode_points and decode_timestamps; the values come
+/// back empty unless `with_values` is set
+fn decode_stream(
+    data: &[u8],
+    point_count: usize,
+    start_time: u64,
+    encoding: BlockEncoding,
+    with_values: bool,
+) -> Option<(Vec<u64>, Vec<f64>)> {
+    let mut timestamps = Vec::with_capacity(point_count);
+    let mut values = Vec::with_capacity(if with_values { point_count } else { 0 });
+    if point_count == 0 {
+        return Some((timestamps, values));
+    }
+
+    let mut reader = BitReader::new(data);
+
+    // Header: aligned start time, first delta, first value
+    if reader.read_bits(64)? != start_time {
+        return None;
+    }
+    let first_timestamp = start_time.checked_add(decode_first_delta(&mut reader)?)?;
+    let first_value = encoding.value_hint.read_first_value(&mut reader)?;
+    timestamps.push(first_timestamp);
+    if with_values {
+        values.push(first_value);
+    }
+
+    let mut ts_decompressor =
+        TimestampDecompressor::with_codec(first_timestamp, encoding.timestamp_codec);
+    let mut val_decompressor = ValueDecompressor::with_codec(first_value, encoding.value_codec);
+
+    for _ in 1..point_count {
+        let timestamp = ts_decompressor.next_timestamp(&mut reader)?;
+        if with_values {
+            values.push(val_decompressor.next_value(&mut reader)?);
+        } else {
+            val_decompressor.skip_value(&mut reader)?;
+        }
+
+        // Blocks are always stored in strictly increasing time order
+        if timestamp <= *timestamps.last()? {
+            return None;
+        }
+        timestamps.push(timestamp);
+    }
+
+    Some((timestamps, values))
+}
+
+/// Compression figures for one block (see TimeSeries::block_stats)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockStats {
+    pub start_time: u64,
+    pub points: usize,
+    pub compressed_bytes: usize, // Whole stream, header included
+    pub open: bool,              // Still taking points, so not final
+}
+
+impl BlockStats {
+    /// Compressed bits per stored point (raw points take 128)
+    pub fn bits_per_point(&self) -> f64 {
+        (self.compressed_bytes * 8) as f64 / self.points.max(1) as f64
+    }
+}
+
+/// How a query over a time range would read a series (see
+/// TimeSeries::explain)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QueryPlan {
+    pub closed_blocks: usize,    // Closed blocks whose points overlap the range
+    pub open_block: bool,        // Whether the open block is read
+    pub estimated_points: usize, // Points in the blocks read; an upper bound on those returned
+    pub pruned_blocks: usize,    // Blocks spanning the range skipped by their first/last timestamps
+}
+
+/// Storage statistics for compression analysis
+///
+/// The logical figures describe the encoding (16 bytes per point versus
+/// the compressed streams); the resident ones what is actually allocated,
+/// which includes raw points kept alongside the compressed buffers.
+#[derive(Default, Debug)]
+pub struct StorageStats {
+    pub logical_uncompressed_bytes: usize, // 16 bytes per stored point
+    pub compressed_size: usize,            // Compressed streams, wherever they live
+    pub resident_raw_bytes: usize,         // Raw point columns held in memory
+    pub resident_compressed_bytes: usize,  // Compressed buffers held in memory
+}
+
+impl StorageStats {
+    /// Logical size over compressed size; the encoding's efficiency, not
+    /// the memory saved (see the resident fields for that)
+    pub fn logical_compression_ratio(&self) -> f64 {
+        if self.compressed_size == 0 {
+            return 0.0;
+        }
+        self.logical_uncompressed_bytes as f64 / self.compressed_size as f64
+    }
+}
+
+impl std::ops::AddAssign for StorageStats {
+    fn add_assign(&mut self, other: StorageStats) {
+        self.logical_uncompressed_bytes += other.logical_uncompressed_bytes;
+        self.compressed_size += other.compressed_size;
+        self.resident_raw_bytes += other.resident_raw_bytes;
+        self.resident_compressed_bytes += other.resident_compressed_bytes;
+    }
+}
+
+/// Default number of shards in a TimeSeriesMap (must be a power of two)
+pub const DEFAULT_SHARD_COUNT: usize = 16;
+
+/// Default time a deleted series' slot stays unavailable for reuse
+pub const DEFAULT_TOMBSTONE_GRACE_SECS: u64 = 3600;
+
+/// Time Series Map (TSmap) - main data structure
+/// Paper Section 4.2 and Figure 7
+///
+/// Contains:
+/// - Vector of time series for efficient scanning
+/// - HashMap for O(1) lookups by key
+///
+/// The map is split into shards routed by a hash of the key, so each
+/// shard keeps its own vector, index and free list. This keeps the
+/// per-shard structures small (the paper uses one lock per TSmap, with
+/// many TSmaps per host).
+///
+/// Locking: each shard's structures sit behind an RwLock and each series
+/// behind its own RwLock. Writing a point to an existing series takes the
+/// shard lock for reading and the series lock for writing, so different
+/// series are written concurrently; only creating, deleting or moving a
+/// series takes a shard's write lock. The order is always shard, then
+/// series, and no code holds two series locks at once, so readers that
+/// visit many series (scans, correlation search) lock one at a time and
+/// can't deadlock with writers.
+pub(crate) struct TimeSeriesMap {
+    shards: Vec<RwLock<Shard>>,
+
+    // shard_count - 1, used to route a key hash to its shard
+    shard_mask: usize,
+
+    // Options applied to series created by insert
+    default_options: SeriesOptions,
+
+    // Seconds a tombstoned slot is kept before it may be reused
+    tombstone_grace: u64,
+
+    // Source of "now" for new series and tombstones
+    clock: Arc<dyn Clock>,
+
+    // Where and when cold closed blocks are spilled; None keeps all in memory
+    spill: Option<SpillConfig>,
+    spill_counters: Arc<SpillCounters>,
+}
+
+/// One shard of the TSmap: a vector, its index and its free list
+struct Shard {
+    // Vector allows efficient paged scans
+    series_vector: Vec<Slot>,
+
+    // Map from key to index in vector; keys share the series' Arc<str>
+    key_to_index: HashMap<Arc<str>, usize>,
+
+    // Free list for reusing reaped tombstones
+    free_indices: Vec<usize>,
+
+    // Label name/value -> slot indices, for label selectors
+    label_index: LabelIndex,
+}
+
+/// A slot in a shard's series vector
+///
+/// Deleting a series leaves a tombstone that remembers when it happened.
+/// The slot only becomes reusable (Free) once the tombstone is reaped
+/// after the grace period, so in-flight readers holding an index never
+/// see a different series appear under it. Live series sit behind a
+/// shared handle so tombstones and free slots stay small.
+enum Slot {
+    Live(SeriesHandle),
+    Tombstone { deleted_at: u64 },
+    Free,
+}
+
+impl Slot {
+    fn as_series(&self) -> Option<&SeriesHandle> {
+        match self {
+            Slot::Live(series) => Some(series),
+            _ => None,
+        }
+    }
+}
+
+/// Shared handle to a series stored in a TimeSeriesMap
+///
+/// Cloning is cheap. A handle keeps working after the series is deleted
+/// or renamed, but then no longer reflects what the map holds. Locks are
+/// not poisoned by a panicking writer; the next caller simply proceeds.
+#[derive(Clone)]
+pub struct SeriesHandle(Arc<RwLock<TimeSeries>>);
+
+impl SeriesHandle {
+    fn new(series: TimeSeries) -> Self {
+        SeriesHandle(Arc::new(RwLock::new(series)))
+    }
+
+    /// Lock the series for reading; readers never block each other
+    pub fn read(&self) -> RwLockReadGuard<'_, TimeSeries> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock the series for writing; only the engine writes, so outside
+    /// the crate handles are read-only
+    pub(crate) fn write(&self) -> RwLockWriteGuard<'_, TimeSeries> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Whether both handles lock the same series
+    pub(crate) fn same_series(&self, other: &SeriesHandle) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+fn read_shard(shard: &RwLock<Shard>) -> RwLockReadGuard<'_, Shard> {
+    shard.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write_shard(shard: &RwLock<Shard>) -> RwLockWriteGuard<'_, Shard> {
+    shard.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn shard_mut(shard: &mut RwLock<Shard>) -> &mut Shard {
+    shard.get_mut().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Shard {
+    fn new() -> Self {
+        Shard {
+            series_vector: Vec::new(),
+            key_to_index: HashMap::new(),
+            free_indices: Vec::new(),
+            label_index: LabelIndex::default(),
+        }
+    }
+
+    /// Write a point to an existing series; None if the key is new
+    ///
+    /// Only needs the shard for reading: the series has its own lock.
+    fn update(&self, key: &str, timestamp: u64, value: f64, now: u64) -> Option<InsertEffect> {
+        Some(self.get(key)?.write().insert_at(timestamp, value, now))
+    }
+
+    /// The series under `key`, creating an empty one if it doesn't exist
+    ///
+    /// New series take a reaped slot when one is free and are added to
+    /// the key and label indexes here, so every creation path goes
+    /// through this one place.
+    fn get_or_create(
+        &mut self,
+        key: &str,
+        labels: Option<&SeriesLabels>,
+        options: &SeriesOptions,
+        now: u64,
+    ) -> &SeriesHandle {
+        if !self.key_to_index.contains_key(key) {
+            let mut series = TimeSeries::with_options_at(key, options.clone(), now);
+            series.labels = labels.cloned();
+            self.put(series);
+        }
+        self.get(key).expect("indexed keys map to live slots")
+    }
+
+    fn get(&self, key: &str) -> Option<&SeriesHandle> {
+        self.key_to_index
+            .get(key)
+            .and_then(|&idx| self.series_vector[idx].as_series())
+    }
+
+    fn delete(&mut self, key: &str, now: u64) {
+        self.take(key, now);
+    }
+
+    /// Remove a series from this shard, tombstoning its slot
+    fn take(&mut self, key: &str, now: u64) -> Option<SeriesHandle> {
+        let index = self.key_to_index.remove(key)?;
+        let slot = std::mem::replace(
+            &mut self.series_vector[index],
+            Slot::Tombstone { deleted_at: now },
+        );
+        match slot {
+            Slot::Live(handle) => {
+                let series = handle.read();
+                self.label_index
+                    .remove(index, series.metric_name(), series.label_map());
+                drop(series);
+                Some(handle)
+            }
+            _ => None,
+        }
+    }
+
+    /// Free tombstones older than the grace period, returning how many
+    fn reap_tombstones(&mut self, now: u64, grace_period: u64) -> usize {
+        let mut reaped = 0;
+        for (index, slot) in self.series_vector.iter_mut().enumerate() {
+            if let Slot::Tombstone { deleted_at } = *slot
+                && now.saturating_sub(deleted_at) >= grace_period
+            {
+                *slot = Slot::Free;
+                self.free_indices.push(index);
+                reaped += 1;
+            }
+        }
+        reaped
+    }
+
+    /// Rebuild the vector without tombstones, returning slots reclaimed
+    fn compact(&mut self) -> usize {
+        let reclaimed = self.series_vector.len() - self.key_to_index.len();
+        if reclaimed == 0 {
+            return 0;
+        }
+
+        let old_vector = std::mem::take(&mut self.series_vector);
+        self.series_vector = Vec::with_capacity(self.key_to_index.len());
+        self.label_index.clear();
+
+        for slot in old_vector {
+            if let Slot::Live(handle) = slot {
+                let index = self.series_vector.len();
+                let series = handle.read();
+                self.key_to_index.insert(series.key.clone(), index);
+                self.label_index
+                    .add(index, series.metric_name(), series.label_map());
+                drop(series);
+                self.series_vector.push(Slot::Live(handle));
+            }
+        }
+
+        self.free_indices.clear();
+        reclaimed
+    }
+
+    /// Check the key index and free list against the series vector
+    ///
+    /// Every indexed key must map to a live slot holding that key, every
+    /// free index to a Free slot listed once, and no index may be both.
+    /// Every live and Free slot must be reachable that way.
+    fn check_invariants(&self) -> Result<(), String> {
+        let slots = self.series_vector.len();
+        let mut free = HashSet::new();
+        for &index in &self.free_indices {
+            match self.series_vector.get(index) {
+                Some(Slot::Free) => {}
+                Some(_) => return Err(format!("free index {} is not a free slot", index)),
+                None => {
+                    return Err(format!(
+                        "free index {} is past the end ({} slots)",
+                        index, slots
+                    ));
+                }
+            }
+            if !free.insert(index) {
+                return Err(format!("free index {} is listed twice", index));
+            }
+        }
+
+        for (key, &index) in &self.key_to_index {
+            if free.contains(&index) {
+                return Err(format!(
+                    "index {} of key {:?} is also on the free list",
+                    index, key
+                ));
+            }
+            match self.series_vector.get(index) {
+                Some(Slot::Live(handle)) => {
+                    let held = handle.read().key.clone();
+                    if held != *key {
+                        return Err(format!(
+                            "key {:?} maps to index {}, which holds {:?}",
+                            key, index, held
+                        ));
+                    }
+                }
+                Some(_) => {
+                    return Err(format!(
+                        "key {:?} maps to index {}, which holds no series",
+                        key, index
+                    ));
+                }
+                None => {
+                    return Err(format!(
+                        "key {:?} maps to index {}, past the end ({} slots)",
+                        key, index, slots
+                    ));
+                }
+            }
+        }
+
+        // Distinct keys map to distinct live slots, so equal counts mean
+        // no live slot is missing from the index
+        let live = self
+            .series_vector
+            .iter()
+            .filter(|slot| matches!(slot, Slot::Live(_)))
+            .count();
+        if live != self.key_to_index.len() {
+            return Err(format!(
+                "{} live slots but {} indexed keys",
+                live,
+                self.key_to_index.len()
+            ));
+        }
+        let unlisted = self
+            .series_vector
+            .iter()
+            .filter(|slot| matches!(slot, Slot::Free))
+            .count();
+        if unlisted != free.len() {
+            return Err(format!(
+                "{} free slots but {} on the free list",
+                unlisted,
+                free.len()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Place an existing series into this shard under its current key
+    fn put(&mut self, series: TimeSeries) {
+        self.put_handle(SeriesHandle::new(series));
+    }
+
+    fn put_handle(&mut self, handle: SeriesHandle) {
+        let series = handle.read();
+        let key = series.key.clone();
+        let name = series.metric_name().to_string();
+        let labels = series.label_map().clone();
+        drop(series);
+
+        let index = if let Some(free_idx) = self.free_indices.pop() {
+            // Reuse a reaped slot
+            self.series_vector[free_idx] = Slot::Live(handle);
+            free_idx
+        } else {
+            // Append new slot
+            self.series_vector.push(Slot::Live(handle));
+            self.series_vector.len() - 1
+        };
+        self.key_to_index.insert(key, index);
+        self.label_index.add(index, &name, &labels);
+    }
+
+    /// Live series matching a label selector
+    fn select(&self, name: &str, matchers: &[(&str, Matcher)]) -> Vec<SeriesHandle> {
+        self.label_index
+            .select(name, matchers)
+            .into_iter()
+            .filter_map(|index| self.series_vector[index].as_series().cloned())
+            .collect()
+    }
+}
+
+impl TimeSeriesMap {
+    pub fn new() -> Self {
+        Self::with_shards(DEFAULT_SHARD_COUNT)
+    }
+
+    /// Create a map with a specific number of shards
+    ///
+    /// Panics if `shard_count` is not a power of two.
+    pub fn with_shards(shard_count: usize) -> Self {
+        assert!(
+            shard_count.is_power_of_two(),
+            "shard count must be a power of two, got {}",
+            shard_count
+        );
+
+        TimeSeriesMap {
+            shards: (0..shard_count)
+                .map(|_| RwLock::new(Shard::new()))
+                .collect(),
+            shard_mask: shard_count - 1,
+            default_options: SeriesOptions::default(),
+            tombstone_grace: DEFAULT_TOMBSTONE_GRACE_SECS,
+            clock: Arc::new(SystemClock),
+            spill: None,
+            spill_counters: Arc::default(),
+        }
+    }
+
+    /// Replace the clock used for new series and tombstones
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Current time according to the map's clock
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Enable (or disable, with None) spilling of cold closed blocks
+    ///
+    /// The spill directory must exist. Blocks already spilled stay on
+    /// disk until they are written to or dropped.
+    pub fn set_spill(&mut self, config: Option<SpillConfig>) {
+        self.spill = config;
+    }
+
+    /// Reload and read-error counts for spilled blocks
+    pub fn spill_counters(&self) -> &SpillCounters {
+        &self.spill_counters
+    }
+
+    /// Spill cold closed blocks to disk
+    ///
+    /// First spills blocks idle for the configured time, then, if a memory
+    /// budget is set and still exceeded, the least recently read closed
+    /// blocks until usage fits. Does nothing unless spilling is enabled.
+    pub fn spill_cold(&mut self) -> io::Result<SpillReport> {
+        let Some(config) = self.spill.clone() else {
+            return Ok(SpillReport::default());
+        };
+        let now = self.now();
+        let mut report = SpillReport::default();
+
+        for series in self.iter() {
+            let pass = series
+                .write()
+                .spill_idle(&config, &self.spill_counters, now)?;
+            report.blocks_spilled += pass.blocks_spilled;
+            report.bytes_spilled += pass.bytes_spilled;
+        }
+
+        let Some(budget) = config.memory_budget else {
+            return Ok(report);
+        };
+        let mut usage = self.memory_usage().total();
+        if usage <= budget {
+            return Ok(report);
+        }
+
+        // (last read, series, block) of every in-memory closed block
+        let series: Vec<SeriesHandle> = self.iter().collect();
+        let mut candidates = Vec::new();
+        for (series_index, handle) in series.iter().enumerate() {
+            for (block_index, block) in handle.read().closed_blocks.iter().enumerate() {
+                if !block.is_spilled() {
+                    candidates.push((block.last_read, series_index, block_index));
+                }
+            }
+        }
+        candidates.sort_unstable();
+
+        for (_, series_index, block_index) in candidates {
+            if usage <= budget {
+                break;
+            }
+            let mut guard = series[series_index].write();
+            let series = &mut *guard;
+            let block = &mut series.closed_blocks[block_index];
+            let freed = block.points.capacity_bytes()
+                + match &block.data {
+                    BlockRef::InMemory(data) => data.capacity(),
+                    BlockRef::OnDisk(_) => 0,
+                };
+            report.bytes_spilled += block.spill(&config.dir, &series.key, &self.spill_counters)?;
+            report.blocks_spilled += 1;
+            usage = usage.saturating_sub(freed);
+        }
+        Ok(report)
+    }
+
+    /// Set how long deleted slots stay tombstoned before reuse
+    pub fn set_tombstone_grace(&mut self, seconds: u64) {
+        self.tombstone_grace = seconds;
+    }
+
+    /// Set the options used for series created from now on
+    pub fn set_default_options(&mut self, options: SeriesOptions) {
+        self.default_options = options;
+    }
+
+    /// Options new series get unless created with their own
+    pub fn default_options(&self) -> &SeriesOptions {
+        &self.default_options
+    }
+
+    /// Number of live series
+    pub fn series_count(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| read_shard(shard).key_to_index.len())
+            .sum()
+    }
+
+    /// Number of shards this map routes keys across
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Route a key to its shard by hashing it
+    pub fn shard_index(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() as usize) & self.shard_mask
+    }
+
+    /// Insert or update a time series
+    pub fn insert(&self, key: &str, timestamp: u64, value: f64) -> InsertEffect {
+        self.insert_point(key, None, &self.default_options, timestamp, value)
+    }
+
+    /// Insert into the series identified by a name and labels
+    ///
+    /// The series is stored under `labels.series_key()` and indexed by
+    /// its labels when first created.
+    pub fn insert_labeled(
+        &self,
+        labels: &SeriesLabels,
+        timestamp: u64,
+        value: f64,
+    ) -> InsertEffect {
+        self.insert_point(
+            &labels.series_key(),
+            Some(labels),
+            &self.default_options,
+            timestamp,
+            value,
+        )
+    }
+
+    /// Insert several points into one series, locking it once
+    ///
+    /// Creates the series if needed, then writes the points under a
+    /// single series lock with TimeSeries::insert_batch. Returns each
+    /// point's effect, in order.
+    pub fn insert_many(&self, key: &str, points: &[(u64, f64)]) -> Vec<InsertEffect> {
+        if points.is_empty() {
+            return Vec::new();
+        }
+        let shard = &self.shards[self.shard_index(key)];
+        let now = self.clock.now();
+        if let Some(series) = read_shard(shard).get(key) {
+            return series.write().insert_batch(points, now);
+        }
+
+        // New series: another writer may have created it since the check
+        let mut shard = write_shard(shard);
+        let created_series = !shard.key_to_index.contains_key(key);
+        let mut effects = shard
+            .get_or_create(key, None, &self.default_options, now)
+            .write()
+            .insert_batch(points, now);
+        effects[0].created_series = created_series;
+        effects
+    }
+
+    /// Insert, creating the series with `options` if it doesn't exist
+    pub fn insert_with_options(
+        &self,
+        key: &str,
+        options: &SeriesOptions,
+        timestamp: u64,
+        value: f64,
+    ) -> InsertEffect {
+        self.insert_point(key, None, options, timestamp, value)
+    }
+
+    fn insert_point(
+        &self,
+        key: &str,
+        labels: Option<&SeriesLabels>,
+        options: &SeriesOptions,
+        timestamp: u64,
+        value: f64,
+    ) -> InsertEffect {
+        let shard = &self.shards[self.shard_index(key)];
+        let now = self.clock.now();
+        if let Some(effect) = read_shard(shard).update(key, timestamp, value, now) {
+            return effect;
+        }
+
+        // New series: another writer may have created it since the check
+        let mut shard = write_shard(shard);
+        let created_series = !shard.key_to_index.contains_key(key);
+        let effect = shard
+            .get_or_create(key, labels, options, now)
+            .write()
+            .insert_at(timestamp, value, now);
+        InsertEffect {
+            created_series,
+            ..effect
+        }
+    }
+
+    /// Every live series named `name` whose labels satisfy all matchers
+    ///
+    /// Series created with a plain key match on their key as the name.
+    pub fn select(&self, name: &str, matchers: &[(&str, Matcher)]) -> Vec<SeriesHandle> {
+        self.shards
+            .iter()
+            .flat_map(|shard| read_shard(shard).select(name, matchers))
+            .collect()
+    }
+
+    /// The series under `key`, creating it with `options` if it doesn't
+    /// exist, in one step under the shard lock
+    pub fn get_or_insert(&self, key: &str, options: &SeriesOptions) -> SeriesHandle {
+        let now = self.clock.now();
+        write_shard(&self.shards[self.shard_index(key)])
+            .get_or_create(key, None, options, now)
+            .clone()
+    }
+
+    /// Add an already-built series (e.g. one loaded from disk)
+    pub fn insert_series(&mut self, series: TimeSeries) -> Result<(), TsdbError> {
+        let index = self.shard_index(&series.key);
+        let shard = shard_mut(&mut self.shards[index]);
+        if shard.key_to_index.contains_key(&series.key) {
+            return Err(TsdbError::SeriesExists(series.key.to_string()));
+        }
+        shard.put(series);
+        Ok(())
+    }
+
+    /// Get a time series by key
+    pub fn get(&self, key: &str) -> Option<SeriesHandle> {
+        read_shard(&self.shards[self.shard_index(key)])
+            .get(key)
+            .cloned()
+    }
+
+    /// Get a time series by key for modification
+    pub fn get_mut(&mut self, key: &str) -> Option<RwLockWriteGuard<'_, TimeSeries>> {
+        let shard = self.shard_index(key);
+        shard_mut(&mut self.shards[shard])
+            .get(key)
+            .map(SeriesHandle::write)
+    }
+
+    /// Get a series for modification, creating it with `options` if needed
+    ///
+    /// A new series is empty and indexed under `key` straight away, so
+    /// lookups and scans see it even before it holds any points.
+    #[cfg(test)]
+    pub fn get_or_create(
+        &mut self,
+        key: &str,
+        options: &SeriesOptions,
+    ) -> RwLockWriteGuard<'_, TimeSeries> {
+        let now = self.clock.now();
+        let shard = self.shard_index(key);
+        shard_mut(&mut self.shards[shard])
+            .get_or_create(key, None, options, now)
+            .write()
+    }
+
+    /// Delete a time series (tombstoning)
+    ///
+    /// The key disappears immediately (a later insert recreates the series
+    /// in a fresh slot), but the old slot is only reused after
+    /// reap_tombstones() runs past the grace period.
+    pub fn delete(&mut self, key: &str, now: u64) {
+        let shard = self.shard_index(key);
+        shard_mut(&mut self.shards[shard]).delete(key, now);
+    }
+
+    /// Delete a series without exclusive access to the map
+    ///
+    /// Locks only the key's shard, so it can run alongside inserts and
+    /// queries. Returns the removed series, if there was one.
+    pub fn remove(&self, key: &str, now: u64) -> Option<SeriesHandle> {
+        let shard = self.shard_index(key);
+        write_shard(&self.shards[shard]).take(key, now)
+    }
+
+    /// Free tombstoned slots whose grace period has elapsed
+    ///
+    /// Returns the number of slots made available for reuse.
+    pub fn reap_tombstones(&mut self, now: u64) -> usize {
+        let grace_period = self.tombstone_grace;
+        self.shards
+            .iter_mut()
+            .map(|shard| shard_mut(shard).reap_tombstones(now, grace_period))
+            .sum()
+    }
+
+    /// Rename a time series in place
+    ///
+    /// Only the key changes: the series (and all of its blocks) is moved
+    /// as a whole, nothing is decompressed or re-encoded. If the new key
+    /// routes to a different shard, the series moves to that shard.
+    pub fn rename(&mut self, old_key: &str, new_key: &str) -> Result<(), TsdbError> {
+        let old_shard = self.shard_index(old_key);
+        let new_shard = self.shard_index(new_key);
+
+        if !shard_mut(&mut self.shards[old_shard])
+            .key_to_index
+            .contains_key(old_key)
+        {
+            return Err(TsdbError::SeriesNotFound(old_key.to_string()));
+        }
+        if shard_mut(&mut self.shards[new_shard])
+            .key_to_index
+            .contains_key(new_key)
+        {
+            return Err(TsdbError::SeriesExists(new_key.to_string()));
+        }
+
+        if old_shard == new_shard {
+            // Same shard: keep the slot, only re-key the index
+            let shard = shard_mut(&mut self.shards[old_shard]);
+            let index = shard.key_to_index.remove(old_key).unwrap();
+            if let Some(handle) = shard.series_vector[index].as_series() {
+                let mut series = handle.write();
+                shard
+                    .label_index
+                    .remove(index, series.metric_name(), series.label_map());
+                series.key = Arc::from(new_key);
+                shard
+                    .label_index
+                    .add(index, series.metric_name(), series.label_map());
+                shard.key_to_index.insert(series.key.clone(), index);
+            }
+        } else if let Some(handle) =
+            shard_mut(&mut self.shards[old_shard]).take(old_key, self.clock.now())
+        {
+            handle.write().key = Arc::from(new_key);
+            shard_mut(&mut self.shards[new_shard]).put_handle(handle);
+        }
+
+        Ok(())
+    }
+
+    /// Remove every series, keeping the shards' allocations for reuse
+    ///
+    /// Unlike delete, nothing is tombstoned: the vectors, indices and
+    /// free lists are emptied in place, so new series start at slot 0.
+    pub fn clear(&mut self) {
+        for shard in &mut self.shards {
+            let shard = shard_mut(shard);
+            shard.series_vector.clear();
+            shard.key_to_index.clear();
+            shard.free_indices.clear();
+            shard.label_index.clear();
+        }
+    }
+
+    /// Compact every shard's vector after heavy deletion
+    ///
+    /// Tombstoned slots are dropped and live series are packed densely,
+    /// so scans stop skipping holes. The free list is cleared, meaning new
+    /// series append rather than reuse stale indices. Returns the number
+    /// of slots reclaimed. Safe to call at any time, but since it renumbers
+    /// every slot it also ends the grace period of pending tombstones.
+    pub fn compact(&mut self) -> usize {
+        self.shards
+            .iter_mut()
+            .map(|shard| shard_mut(shard).compact())
+            .sum()
+    }
+
+    /// Check every shard's key index and free list for consistency
+    ///
+    /// Also checks that each key sits in the shard it hashes to. Returns
+    /// a description of the first problem found. Each shard is read-locked
+    /// while it is checked.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        for (number, shard) in self.shards.iter().enumerate() {
+            let shard = read_shard(shard);
+            shard
+                .check_invariants()
+                .map_err(|problem| format!("shard {}: {}", number, problem))?;
+            if let Some(key) = shard
+                .key_to_index
+                .keys()
+                .find(|key| self.shard_index(key) != number)
+            {
+                return Err(format!(
+                    "shard {}: key {:?} belongs in shard {}",
+                    number,
+                    key,
+                    self.shard_index(key)
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Memory held by every live series plus the shards' own structures
+    ///
+    /// The label index postings are not counted.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage::default();
+        for shard in &self.shards {
+            let shard = read_shard(shard);
+            usage.index_bytes +=
+                shard.key_to_index.capacity() * (size_of::<(Arc<str>, usize)>() + 1);
+            usage.overhead_bytes += shard.series_vector.capacity() * size_of::<Slot>()
+                + shard.free_indices.capacity() * size_of::<usize>();
+        }
+        self.scan(|series| usage += series.memory_usage());
+        usage
+    }
+
+    /// Iterate over every live series, skipping tombstones and free slots
+    ///
+    /// Shards are visited in order, and each shard's vector in index order.
+    /// A shard is locked only while its handles are collected, so series
+    /// created or deleted during iteration may or may not show up.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            shards: self.shards.iter(),
+            series: Vec::new().into_iter(),
+        }
+    }
+
+    /// Scan all time series (for background jobs)
+    ///
+    /// Each series is read-locked only while `f` runs on it, so `f` must
+    /// not write to the map.
+    pub fn scan<F>(&self, mut f: F)
+    where
+        F: FnMut(&TimeSeries),
+    {
+        for handle in self {
+            f(&handle.read());
+        }
+    }
+}
+
+/// Iterator over the live series of a TimeSeriesMap
+pub struct Iter<'a> {
+    shards: std::slice::Iter<'a, RwLock<Shard>>,
+    series: std::vec::IntoIter<SeriesHandle>,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = SeriesHandle;
+
+    fn next(&mut self) -> Option<SeriesHandle> {
+        loop {
+            if let Some(handle) = self.series.next() {
+                return Some(handle);
+            }
+            let shard = read_shard(self.shards.next()?);
+            let handles: Vec<SeriesHandle> = shard
+                .series_vector
+                .iter()
+                .filter_map(|slot| slot.as_series().cloned())
+                .collect();
+            self.series = handles.into_iter();
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a TimeSeriesMap {
+    type Item = SeriesHandle;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sharded_lookups_and_scan() {
+        let mut map = TimeSeriesMap::new();
+        assert_eq!(map.shard_count(), DEFAULT_SHARD_COUNT);
+
+        for i in 0..5000 {
+            map.insert(&format!("host{}.cpu", i), 1000, i as f64);
+        }
+
+        // Every key is routed back to the shard it was inserted into
+        for i in 0..5000 {
+            let handle = map.get(&format!("host{}.cpu", i)).unwrap();
+            let series = handle.read();
+            assert_eq!(&*series.key, format!("host{}.cpu", i));
+        }
+
+        // Scan sees every series exactly once
+        let mut seen = std::collections::HashSet::new();
+        map.scan(|series| {
+            assert!(seen.insert(series.key.clone()), "duplicate in scan");
+        });
+        assert_eq!(seen.len(), 5000);
+
+        // Deletes route the same way as lookups
+        for i in (0..5000).step_by(2) {
+            map.delete(&format!("host{}.cpu", i), 0);
+        }
+        let mut count = 0;
+        map.scan(|_| count += 1);
+        assert_eq!(count, 2500);
+        assert!(map.get("host0.cpu").is_none());
+        assert!(map.get("host1.cpu").is_some());
+    }
+
+    #[test]
+    fn test_iter_skips_tombstones() {
+        let mut map = TimeSeriesMap::with_shards(4);
+        for i in 0..100 {
+            map.insert(&format!("host{}.cpu", i), 1000, i as f64);
+        }
+        for i in (0..100).filter(|i| i % 3 == 0) {
+            map.delete(&format!("host{}.cpu", i), 0);
+        }
+
+        // Tombstoned series never show up
+        assert_eq!(map.iter().count(), 66);
+        assert!(map.iter().all(|series| {
+            let series = series.read();
+            let id = series
+                .key
+                .trim_start_matches("host")
+                .trim_end_matches(".cpu");
+            id.parse::<usize>().unwrap() % 3 != 0
+        }));
+
+        // Adapters and early exit work as usual
+        let found = map
+            .iter()
+            .find(|series| &*series.read().key == "host4.cpu")
+            .unwrap();
+        assert_eq!(found.read().query(0, u64::MAX)[0].value, 4.0);
+        assert_eq!(map.iter().take(5).count(), 5);
+        let high: Vec<SeriesHandle> = map
+            .iter()
+            .filter(|series| series.read().query(0, u64::MAX)[0].value >= 90.0)
+            .collect();
+        assert_eq!(high.len(), 6); // 91, 92, 94, 95, 97, 98
+
+        // `for` over a reference matches iter()
+        let mut count = 0;
+        for _ in &map {
+            count += 1;
+        }
+        assert_eq!(count, 66);
+    }
+
+    #[test]
+    fn test_shard_distribution() {
+        let map = TimeSeriesMap::with_shards(16);
+        let total = 4000;
+
+        for i in 0..total {
+            map.insert(&format!("metric.{}", i), 1000, 1.0);
+        }
+
+        let average = total / map.shard_count();
+        for (i, shard) in map.shards.iter().map(read_shard).enumerate() {
+            let size = shard.key_to_index.len();
+            assert!(
+                size <= average * 2,
+                "shard {} holds {} series (average {})",
+                i,
+                size,
+                average
+            );
+        }
+    }
+
+    #[test]
+    fn test_rename_across_shards() {
+        let mut map = TimeSeriesMap::new();
+        for i in 0..64 {
+            map.insert(&format!("old.{}", i), 1000, i as f64);
+        }
+
+        // With 16 shards, most of these renames change shard
+        for i in 0..64 {
+            map.rename(&format!("old.{}", i), &format!("new.{}", i))
+                .unwrap();
+        }
+
+        for i in 0..64 {
+            assert!(map.get(&format!("old.{}", i)).is_none());
+            let handle = map.get(&format!("new.{}", i)).unwrap();
+            let series = handle.read();
+            assert_eq!(&*series.key, format!("new.{}", i));
+            assert_eq!(series.query(0, u64::MAX)[0].value, i as f64);
+        }
+
+        let mut count = 0;
+        map.scan(|_| count += 1);
+        assert_eq!(count, 64);
+    }
+
+    #[test]
+    fn test_series_key_is_shared_with_index() {
+        let mut map = TimeSeriesMap::with_shards(1);
+        for i in 0..100 {
+            map.insert(&format!("svc.{}", i), 1000, i as f64);
+        }
+        map.delete("svc.0", 0);
+        map.compact();
+        map.rename("svc.1", "svc.renamed").unwrap();
+
+        // Index and series hold the same allocation; nothing else does
+        let shard = read_shard(&map.shards[0]);
+        for slot in &shard.series_vector {
+            let series = slot.as_series().unwrap().read();
+            let (index_key, _) = shard.key_to_index.get_key_value(&*series.key).unwrap();
+            assert!(Arc::ptr_eq(index_key, &series.key));
+            assert_eq!(Arc::strong_count(&series.key), 2);
+        }
+
+        let mut keys = Vec::new();
+        map.scan(|series| keys.push(series.key.to_string()));
+        keys.sort();
+        assert_eq!(keys.len(), 99);
+        assert!(keys.contains(&"svc.renamed".to_string()));
+        assert!(!keys.contains(&"svc.1".to_string()));
+    }
+
+    #[test]
+    fn test_compact_after_heavy_deletion() {
+        let mut map = TimeSeriesMap::new();
+        for i in 0..1000 {
+            map.insert(&format!("container.{}", i), 1000, i as f64);
+        }
+        for i in 0..900 {
+            map.delete(&format!("container.{}", i), 0);
+        }
+
+        let slots_before: usize = map
+            .shards
+            .iter()
+            .map(read_shard)
+            .map(|s| s.series_vector.len())
+            .sum();
+        assert_eq!(slots_before, 1000);
+
+        assert_eq!(map.compact(), 900);
+        assert_eq!(map.compact(), 0, "second compact has nothing to do");
+
+        for shard in map.shards.iter().map(read_shard) {
+            assert!(shard.free_indices.is_empty());
+            assert_eq!(shard.series_vector.len(), shard.key_to_index.len());
+            assert!(
+                shard
+                    .series_vector
+                    .iter()
+                    .all(|slot| slot.as_series().is_some())
+            );
+        }
+
+        // Lookups still resolve to the right series
+        for i in 900..1000 {
+            let handle = map.get(&format!("container.{}", i)).unwrap();
+            let series = handle.read();
+            assert_eq!(series.query(0, u64::MAX)[0].value, i as f64);
+        }
+        assert!(map.get("container.0").is_none());
+
+        let mut count = 0;
+        map.scan(|_| count += 1);
+        assert_eq!(count, 100);
+
+        // New series append to the dense vector
+        map.insert("container.new", 1000, 1.0);
+        let shard = read_shard(&map.shards[map.shard_index("container.new")]);
+        assert_eq!(
+            shard.key_to_index["container.new"],
+            shard.series_vector.len() - 1
+        );
+        assert_eq!(
+            &*map.get("container.new").unwrap().read().key,
+            "container.new"
+        );
+    }
+
+    #[test]
+    fn test_clear_keeps_capacity() {
+        let mut map = TimeSeriesMap::with_shards(2);
+        for i in 0..200 {
+            map.insert(&format!("job.{}", i), 1000, i as f64);
+        }
+        map.delete("job.0", 0);
+        map.reap_tombstones(u64::MAX);
+        let capacities: Vec<usize> = map
+            .shards
+            .iter()
+            .map(read_shard)
+            .map(|shard| shard.series_vector.capacity())
+            .collect();
+
+        map.clear();
+        assert_eq!(map.series_count(), 0);
+        assert_eq!(map.iter().count(), 0);
+        for (shard, capacity) in map.shards.iter().map(read_shard).zip(capacities) {
+            assert!(shard.series_vector.is_empty());
+            assert!(shard.key_to_index.is_empty());
+            assert!(shard.free_indices.is_empty());
+            assert_eq!(shard.series_vector.capacity(), capacity);
+        }
+
+        // The map is usable again, starting from the first slot
+        map.insert("job.0", 2000, 1.0);
+        let shard = read_shard(&map.shards[map.shard_index("job.0")]);
+        assert_eq!(shard.key_to_index["job.0"], 0);
+        drop(shard);
+        assert_eq!(map.get("job.0").unwrap().read().query(0, u64::MAX).len(), 1);
+        assert!(map.get("job.1").is_none());
+    }
+
+    #[test]
+    fn test_memory_usage_tracks_block_close() {
+        let mut series = TimeSeries::new("cpu");
+        let base_time = 7200 * 100;
+        for i in 0..700 {
+            series.insert(base_time + i * 10, i as f64);
+        }
+        let open = series.memory_usage();
+        assert!(open.raw_points_bytes >= 700 * size_of::<DataPoint>());
+
+        // Closing trims the block's raw points to exactly what is stored
+        series.insert(base_time + 7200, 0.0);
+        assert_eq!(series.closed_blocks[0].points.capacity(), 700);
+        let closed = series.memory_usage();
+        assert_eq!(
+            closed.raw_points_bytes,
+            (700 + series.open_block.points.capacity()) * size_of::<DataPoint>()
+        );
+        assert!(closed.raw_points_bytes < open.raw_points_bytes);
+        assert_eq!(closed.index_bytes, open.index_bytes);
+    }
+
+    #[test]
+    fn test_day_long_block_late_first_point() {
+        use crate::compression::inspect::FieldValue;
+
+        let options = SeriesOptions {
+            block_duration: Some(86400),
+            drop_raw_on_close: true,
+            ..SeriesOptions::default()
+        };
+        let mut series = TimeSeries::with_options("cpu", options);
+        let window = 86400 * 100;
+        // More than 16383s in, past what the paper's 14-bit delta holds
+        series.insert(window + 20000, 1.0);
+        series.insert(window + 20060, 2.0);
+        series.insert(window + 86400, 3.0);
+        assert!(series.closed_blocks[0].points.is_empty());
+
+        let expected = [
+            DataPoint {
+                timestamp: window + 20000,
+                value: 1.0,
+            },
+            DataPoint {
+                timestamp: window + 20060,
+                value: 2.0,
+            },
+        ];
+        assert_eq!(series.query(window, window + 86399), expected);
+        let fields = series.closed_blocks[0].describe_encoding();
+        assert_eq!(fields[2].name, "first_delta_wide");
+        assert_eq!(fields[2].value, FieldValue::Delta(20000));
+    }
+
+    #[test]
+    fn test_drop_raw_on_close() {
+        let options = SeriesOptions {
+            drop_raw_on_close: true,
+            ..SeriesOptions::default()
+        };
+        let mut series = TimeSeries::with_options("cpu", options);
+        let base_time = 7200 * 100;
+        for i in 0..200 {
+            series.insert(base_time + i * 60, i as f64 * 0.5);
+        }
+        assert_eq!(series.closed_blocks.len(), 1);
+
+        // The closed block only keeps its compressed stream
+        let closed = &series.closed_blocks[0];
+        assert!(closed.points.is_empty());
+        assert_eq!(closed.points.capacity(), 0);
+        assert_eq!(closed.len(), 120);
+        let stats = series.get_stats();
+        assert_eq!(stats.logical_uncompressed_bytes, 200 * 16);
+
+        // Only the open block's 80 points are resident uncompressed now,
+        // against all 200 when closed blocks keep theirs
+        let mut kept = TimeSeries::new("kept");
+        for i in 0..200 {
+            kept.insert(base_time + i * 60, i as f64 * 0.5);
+        }
+        let kept_stats = kept.get_stats();
+        assert_eq!(kept_stats.logical_uncompressed_bytes, 200 * 16);
+        assert_eq!(
+            stats.resident_raw_bytes,
+            series.open_block.points.capacity_bytes()
+        );
+        assert!(stats.resident_raw_bytes < 80 * 16 * 2);
+        assert!(kept_stats.resident_raw_bytes >= 200 * 16);
+        assert_eq!(stats.compressed_size, kept_stats.compressed_size);
+        assert!(stats.resident_compressed_bytes <= kept_stats.resident_compressed_bytes);
+
+        // ...but still answers queries by decoding it
+        let points = series.query(base_time, base_time + 200 * 60);
+        assert_eq!(points.len(), 200);
+        for (i, point) in points.iter().enumerate() {
+            assert_eq!(point.timestamp, base_time + i as u64 * 60);
+            assert_eq!(point.value, i as f64 * 0.5);
+        }
+        assert_eq!(series.iter_value_range(0, u64::MAX, 10.0, 10.0).count(), 1);
+
+        // A backfilled point is merged in and the raw points dropped again
+        series.insert(base_time + 30, -1.0);
+        assert!(series.closed_blocks[0].points.is_empty());
+        assert_eq!(series.closed_blocks[0].len(), 121);
+        assert!(series.contains_timestamp(base_time + 30));
+        assert_eq!(series.query(0, u64::MAX).len(), 201);
+    }
+
+    #[test]
+    fn test_checksum_catches_corrupt_stream() {
+        let options = SeriesOptions {
+            drop_raw_on_close: true,
+            ..SeriesOptions::default()
+        };
+        let mut series = TimeSeries::with_options("cpu", options);
+        let base_time = 7200 * 100;
+        for i in 0..360 {
+            series.insert(base_time + i * 60, i as f64);
+        }
+        assert_eq!(series.closed_blocks.len(), 2);
+        let (points, corrupt) = series.query_verified(0, u64::MAX);
+        assert_eq!(points.len(), 360);
+        assert!(corrupt.is_empty());
+
+        // Flip one bit of the first block's stream
+        let BlockRef::InMemory(data) = &mut series.closed_blocks[0].data else {
+            panic!("block should be in memory");
+        };
+        let last = data.len() - 1;
+        data[last] ^= 1;
+
+        let (points, corrupt) = series.query_verified(0, u64::MAX);
+        assert_eq!(points.len(), 240);
+        assert_eq!(points[0].timestamp, base_time + 7200);
+        assert_eq!(corrupt, vec![base_time]);
+        assert_eq!(series.query(0, u64::MAX).len(), 240);
+        assert!(series.timestamps(base_time, base_time + 7199).is_empty());
+
+        // The block can't be restored for a write either
+        assert!(!series.closed_blocks[0].restore_raw());
+
+        assert_eq!(series.drop_corrupt_blocks(), vec![base_time]);
+        assert_eq!(series.closed_blocks.len(), 1);
+        assert!(series.drop_corrupt_blocks().is_empty());
+        assert!(series.query_verified(0, u64::MAX).1.is_empty());
+    }
+
+    #[test]
+    fn test_block_stats() {
+        let mut series = TimeSeries::new("cpu");
+        let base_time = 7200 * 100;
+        for i in 0..150 {
+            series.insert(base_time + i * 60, 1.0);
+        }
+
+        let stats = series.block_stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(
+            (stats[0].start_time, stats[0].points, stats[0].open),
+            (base_time, 120, false)
+        );
+        assert_eq!((stats[1].points, stats[1].open), (30, true));
+        assert_eq!(
+            stats[0].compressed_bytes,
+            series.closed_blocks[0].compressed_size
+        );
+
+        // Regular timestamps and a constant value take about 2 bits a
+        // point, plus the header
+        assert!(stats[0].bits_per_point() < 4.0);
+        assert!(TimeSeries::new("empty").block_stats().is_empty());
+    }
+
+    #[test]
+    fn test_align_down() {
+        assert_eq!(align_down(7300, 7200, 0), 7200);
+        assert_eq!(align_down(7300, 7200, 100), 7300);
+        assert_eq!(align_down(7299, 7200, 100), 100);
+        // Only the origin's phase matters, whichever side of `timestamp`
+        assert_eq!(align_down(7299, 7200, 100 + 7200 * 5), 100);
+        // Before the first aligned window: the short window from 0
+        assert_eq!(align_down(99, 7200, 100), 0);
+        assert_eq!(align_down(u64::MAX, 10, 3), u64::MAX - 2);
+
+        // An empty series' open block starts on an aligned window too
+        let options = SeriesOptions {
+            align_origin: 1800,
+            ..SeriesOptions::default()
+        };
+        let series = TimeSeries::with_options_at("cpu", options, 7200 * 100);
+        assert_eq!(series.open_block.start_time, 7200 * 100 - 5400);
+    }
+
+    #[test]
+    fn test_max_points_per_block_splits_window() {
+        let options = SeriesOptions {
+            max_points_per_block: Some(100),
+            ..SeriesOptions::default()
+        };
+        let mut series = TimeSeries::with_options("cpu", options);
+        let base_time = 7200 * 100;
+        for i in 0..250 {
+            series.insert(base_time + i * 10, i as f64);
+        }
+
+        // Three blocks in one window, each picking up where the last ended
+        assert_eq!(series.closed_blocks.len(), 2);
+        assert_eq!(series.closed_blocks[0].start_time, base_time);
+        assert_eq!(series.closed_blocks[1].start_time, base_time + 1000);
+        assert_eq!(series.open_block.start_time, base_time + 2000);
+        assert_eq!(series.open_block.len(), 50);
+        assert_eq!(
+            series.block_boundaries(),
+            vec![
+                (base_time, base_time + 1000, false),
+                (base_time + 1000, base_time + 2000, false),
+                (base_time + 2000, base_time + 7200, true),
+            ]
+        );
+
+        // Late points go to the block covering them, full or not
+        series.insert(base_time + 1005, -1.0);
+        series.insert(base_time + 2005, -2.0);
+        assert_eq!(series.closed_blocks[1].len(), 101);
+        assert_eq!(series.open_block.len(), 51);
+        assert_eq!(series.query(0, u64::MAX).len(), 252);
+
+        // The time window still closes blocks on its own
+        series.insert(base_time + 7200, 0.0);
+        assert_eq!(series.closed_blocks.len(), 3);
+        assert_eq!(series.open_block.start_time, base_time + 7200);
+    }
+
+    #[test]
+    fn test_insert_batch_matches_insert() {
+        let options = SeriesOptions {
+            max_points_per_block: Some(100),
+            drop_raw_on_close: true,
+            late_grace: Some(120),
+            ..SeriesOptions::default()
+        };
+        let base_time = 7200 * 100;
+        let mut points: Vec<(u64, f64)> =
+            (0..1000).map(|i| (base_time + i * 30, i as f64)).collect();
+        // Late, out-of-order and duplicate points among the appends
+        points.insert(150, (base_time + 7200 - 30, -1.0));
+        points.insert(400, (base_time + 2015, -2.0));
+        points.insert(600, (base_time + 900, -3.0));
+        points.push((base_time + 100, -4.0));
+
+        let mut single = TimeSeries::with_options_at("cpu", options.clone(), 0);
+        let single_effects: Vec<InsertEffect> = points
+            .iter()
+            .map(|&(ts, value)| single.insert_at(ts, value, 1))
+            .collect();
+        let mut batch = TimeSeries::with_options_at("cpu", options, 0);
+        let batch_effects = batch.insert_batch(&points, 1);
+
+        assert_eq!(batch.block_boundaries(), single.block_boundaries());
+        assert_eq!(batch.query(0, u64::MAX), single.query(0, u64::MAX));
+        let blocks = |series: &TimeSeries| {
+            let mut blocks: Vec<(u64, Vec<u8>, bool)> = series
+                .closed_blocks
+                .iter()
+                .map(|block| {
+                    let data = block.read_compressed().unwrap().into_owned();
+                    (block.start_time, data, block.raw_dropped())
+                })
+                .collect();
+            let data = series.open_block.read_compressed().unwrap().into_owned();
+            blocks.push((series.open_block.start_time, data, false));
+            blocks
+        };
+        assert_eq!(blocks(&batch), blocks(&single));
+
+        for (batch, single) in batch_effects.iter().zip(&single_effects) {
+            assert_eq!(batch.write, single.write);
+            assert_eq!(batch.closed_block, single.closed_block);
+        }
+        let growth = |effects: &[InsertEffect]| -> usize {
+            effects.iter().map(|effect| effect.compressed_bytes).sum()
+        };
+        assert_eq!(growth(&batch_effects), growth(&single_effects));
+        assert_eq!(batch.meta.last_write, 1);
+    }
+
+    #[test]
+    fn test_count_closed_blocks_query_and_stats() {
+        let options = SeriesOptions {
+            max_points_per_block: Some(100),
+            ..SeriesOptions::default()
+        };
+        let mut series = TimeSeries::with_options("cpu", options);
+        let base_time = 7200 * 100;
+        for i in 0..350 {
+            series.insert(base_time + i * 2, (i % 17) as f64);
+        }
+        assert_eq!(series.block_boundaries().len(), 4);
+        let sizes: Vec<usize> = series
+            .closed_blocks
+            .iter()
+            .chain(std::iter::once(&series.open_block))
+            .map(|block| block.len())
+            .collect();
+        assert_eq!(sizes, vec![100, 100, 100, 50]);
+
+        // A range spanning every block boundary
+        let points = series.query(base_time + 150, base_time + 650);
+        assert_eq!(points.len(), 251);
+        for (i, point) in points.iter().enumerate() {
+            let n = 75 + i as u64;
+            assert_eq!(point.timestamp, base_time + n * 2);
+            assert_eq!(point.value, (n % 17) as f64);
+        }
+
+        // Stats add up over all four blocks
+        let stats = series.get_stats();
+        assert_eq!(stats.logical_uncompressed_bytes, 350 * 16);
+        let compressed: usize = series
+            .closed_blocks
+            .iter()
+            .chain(std::iter::once(&series.open_block))
+            .map(|block| block.read_compressed().unwrap().len())
+            .sum();
+        assert_eq!(stats.compressed_size, compressed);
+    }
+
+    #[test]
+    fn test_compact_blocks() {
+        let options = SeriesOptions {
+            max_points_per_block: Some(5),
+            ..SeriesOptions::default()
+        };
+        let mut series = TimeSeries::with_options("cpu", options.clone());
+        let base_time = 7200 * 100;
+        for i in 0..105 {
+            series.insert(base_time + i * 60, (i % 7) as f64);
+        }
+        assert_eq!(series.closed_blocks.len(), 20);
+        let points = series.query(0, u64::MAX);
+        let stats = series.get_stats();
+
+        // Runs of five blocks (240s each, 300s apart) fit in 1500s
+        let mut short = TimeSeries::with_options("short", options);
+        for point in &points {
+            short.insert(point.timestamp, point.value);
+        }
+        assert_eq!(short.compact_blocks(1500), 16);
+        assert_eq!(short.closed_blocks.len(), 4);
+        assert!(short.closed_blocks.iter().all(|block| block.len() == 25));
+
+        assert_eq!(series.compact_blocks(7200), 19);
+        assert_eq!(series.closed_blocks.len(), 1);
+        assert_eq!(series.compact_blocks(7200), 0, "nothing left to merge");
+
+        let block = &series.closed_blocks[0];
+        assert_eq!(block.start_time, base_time);
+        assert_eq!(block.len(), 100);
+        assert_eq!((block.min_value, block.max_value), (0.0, 6.0));
+
+        let after = series.query(0, u64::MAX);
+        assert_eq!(after.len(), points.len());
+        assert!(
+            after
+                .iter()
+                .zip(&points)
+                .all(|(a, b)| { a.timestamp == b.timestamp && a.value == b.value })
+        );
+        let compacted = series.get_stats();
+        assert_eq!(
+            compacted.logical_uncompressed_bytes,
+            stats.logical_uncompressed_bytes
+        );
+        assert!(compacted.compressed_size < stats.compressed_size);
+    }
+
+    #[test]
+    fn test_debug_points_show_placement() {
+        let mut series = TimeSeries::new("cpu");
+        let base_time = 7200 * 100;
+        series.insert(base_time + 120, 2.0);
+        series.insert(base_time + 60, 1.0); // Out of order, same block
+        series.insert(base_time + 7200, 3.0); // Closes the first block
+        series.insert(base_time + 180, 4.0); // Backfilled into it
+        series.insert(base_time + 7260, 5.0);
+
+        assert_eq!(
+            series.debug_points(0, u64::MAX),
+            vec![
+                (0, 0, base_time + 60, 1.0),
+                (0, 1, base_time + 120, 2.0),
+                (0, 2, base_time + 180, 4.0),
+                (1, 0, base_time + 7200, 3.0),
+                (1, 1, base_time + 7260, 5.0),
+            ]
+        );
+
+        // The range filters points but keeps their block and offset
+        assert_eq!(
+            series.debug_points(base_time + 100, base_time + 7230),
+            vec![
+                (0, 1, base_time + 120, 2.0),
+                (0, 2, base_time + 180, 4.0),
+                (1, 0, base_time + 7200, 3.0),
+            ]
+        );
+        assert_eq!(series.blocks_read(), 0);
+    }
+
+    #[test]
+    fn test_tombstone_reuse_waits_for_grace_period() {
+        let mut map = TimeSeriesMap::with_shards(1);
+        map.insert("a", 1000, 1.0);
+        map.insert("b", 1000, 2.0);
+
+        map.delete("a", 10_000);
+
+        // Within the grace period a new series never takes slot 0
+        map.insert("c", 1000, 3.0);
+        assert_eq!(read_shard(&map.shards[0]).key_to_index["c"], 2);
+        assert_eq!(
+            map.reap_tombstones(10_000 + DEFAULT_TOMBSTONE_GRACE_SECS - 1),
+            0
+        );
+
+        // Once reaped, the slot is reused
+        assert_eq!(
+            map.reap_tombstones(10_000 + DEFAULT_TOMBSTONE_GRACE_SECS),
+            1
+        );
+        assert_eq!(map.reap_tombstones(u64::MAX), 0, "already reaped");
+        map.insert("d", 1000, 4.0);
+        assert_eq!(read_shard(&map.shards[0]).key_to_index["d"], 0);
+        assert_eq!(
+            map.get("d").unwrap().read().query(0, u64::MAX)[0].value,
+            4.0
+        );
+    }
+
+    #[test]
+    fn test_reinsert_after_delete() {
+        let mut map = TimeSeriesMap::with_shards(1);
+        map.set_tombstone_grace(60);
+        map.insert("a", 1000, 1.0);
+        map.delete("a", 5000);
+
+        // A late write recreates the series in a fresh slot, without the
+        // deleted history
+        map.insert("a", 1060, 2.0);
+        assert_eq!(read_shard(&map.shards[0]).key_to_index["a"], 1);
+        let points = map.get("a").unwrap().read().query(0, u64::MAX);
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].value, 2.0);
+
+        // Reaping frees the old slot only
+        assert_eq!(map.reap_tombstones(5060), 1);
+        assert!(map.get("a").is_some());
+        assert_eq!(read_shard(&map.shards[0]).free_indices, vec![0]);
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let mut map = TimeSeriesMap::with_shards(1);
+        map.set_tombstone_grace(60);
+        for key in ["a", "b", "c", "d"] {
+            map.insert(key, 1000, 1.0);
+        }
+        map.delete("b", 2000);
+        map.delete("c", 2000);
+        map.reap_tombstones(2030);
+        assert_eq!(map.check_invariants(), Ok(()), "tombstones pending");
+        map.reap_tombstones(2060);
+        map.insert("e", 1000, 1.0);
+        assert_eq!(map.check_invariants(), Ok(()), "a reaped slot reused");
+
+        // Each kind of drift is reported with what and where
+        let check = |corrupt: fn(&mut Shard)| {
+            let mut map = TimeSeriesMap::with_shards(1);
+            for key in ["a", "b", "c"] {
+                map.insert(key, 1000, 1.0);
+            }
+            map.delete("c", 1000);
+            map.reap_tombstones(u64::MAX);
+            corrupt(shard_mut(&mut map.shards[0]));
+            map.check_invariants().unwrap_err()
+        };
+        assert_eq!(
+            check(|shard| shard.free_indices.push(0)),
+            "shard 0: free index 0 is not a free slot"
+        );
+        assert_eq!(
+            check(|shard| shard.free_indices.push(2)),
+            "shard 0: free index 2 is listed twice"
+        );
+        assert_eq!(
+            check(|shard| shard.free_indices.push(7)),
+            "shard 0: free index 7 is past the end (3 slots)"
+        );
+        assert_eq!(
+            check(|shard| {
+                shard.key_to_index.insert("a".into(), 1);
+            }),
+            "shard 0: key \"a\" maps to index 1, which holds \"b\""
+        );
+        assert_eq!(
+            check(|shard| {
+                shard.key_to_index.insert("c".into(), 2);
+            }),
+            "shard 0: index 2 of key \"c\" is also on the free list"
+        );
+        assert_eq!(
+            check(|shard| {
+                shard.key_to_index.remove("b");
+            }),
+            "shard 0: 2 live slots but 1 indexed keys"
+        );
+        assert_eq!(
+            check(|shard| shard.free_indices.clear()),
+            "shard 0: 1 free slots but 0 on the free list"
+        );
+
+        // Keys must sit in the shard they hash to
+        let mut map = TimeSeriesMap::with_shards(2);
+        map.insert("a", 1000, 1.0);
+        let home = map.shard_index("a");
+        let handle = shard_mut(&mut map.shards[home]).take("a", 0).unwrap();
+        shard_mut(&mut map.shards[1 - home]).put_handle(handle);
+        assert_eq!(
+            map.check_invariants().unwrap_err(),
+            format!("shard {}: key \"a\" belongs in shard {}", 1 - home, home)
+        );
+    }
+
+    #[test]
+    fn test_get_or_create() {
+        let mut map = TimeSeriesMap::with_shards(1);
+        let options = SeriesOptions {
+            max_points_per_block: Some(10),
+            ..SeriesOptions::default()
+        };
+
+        // A created series is indexed even before it holds points
+        assert!(
+            map.get_or_create("a", &options)
+                .query(0, u64::MAX)
+                .is_empty()
+        );
+        assert!(map.get("a").is_some());
+        for i in 0..25 {
+            map.get_or_create("a", &options).insert(1000 + i, i as f64);
+        }
+        assert_eq!(map.get_mut("a").unwrap().query(0, u64::MAX).len(), 25);
+
+        // An existing series is returned as is, options untouched
+        let series = map.get_or_create("a", &SeriesOptions::default());
+        assert_eq!(series.options.max_points_per_block, Some(10));
+        assert_eq!(series.closed_blocks.len(), 2);
+        drop(series);
+
+        let mut scanned = Vec::new();
+        map.scan(|series| {
+            let values = series
+                .query(0, u64::MAX)
+                .iter()
+                .map(|p| p.value)
+                .sum::<f64>();
+            scanned.push((series.key.to_string(), values));
+        });
+        assert_eq!(scanned, vec![("a".to_string(), 300.0)]);
+
+        // Creation reuses reaped slots like insert does
+        map.delete("a", 0);
+        map.reap_tombstones(u64::MAX);
+        map.get_or_create("b", &options).insert(1000, 1.0);
+        assert_eq!(read_shard(&map.shards[0]).key_to_index["b"], 0);
+        assert_eq!(
+            map.get("b").unwrap().read().query(0, u64::MAX)[0].value,
+            1.0
+        );
+    }
+
+    #[test]
+    fn test_value_range_pruning() {
+        let mut series = TimeSeries::new("pruned".to_string());
+
+        // Four blocks; only the third contains values above 100
+        for block in 0..4u64 {
+            for i in 0..10u64 {
+                let value = if block == 2 {
+                    150.0 + i as f64
+                } else {
+                    i as f64
+                };
+                series.insert(7200 * (10 + block) + i * 60, value);
+            }
+        }
+        assert_eq!(series.closed_blocks.len(), 3);
+        assert_eq!(series.closed_blocks[2].min_value, 150.0);
+        assert_eq!(series.closed_blocks[2].max_value, 159.0);
+
+        let before = series.blocks_read();
+        let high: Vec<DataPoint> = series
+            .iter_value_range(0, u64::MAX, 155.0, 1000.0)
+            .collect();
+        assert_eq!(high.len(), 5);
+        assert!(high.iter().all(|p| p.value >= 155.0));
+        assert_eq!(series.blocks_read() - before, 1, "other blocks pruned");
+
+        // A plain range query reads every block
+        let before = series.blocks_read();
+        assert_eq!(series.query(0, u64::MAX).len(), 40);
+        assert_eq!(series.blocks_read() - before, 4);
+    }
+
+    #[test]
+    fn test_downsample_uses_block_summaries() {
+        let options = SeriesOptions {
+            drop_raw_on_close: true,
+            ..SeriesOptions::default()
+        };
+        let mut series = TimeSeries::with_options_at("cpu", options, 0);
+        let base_time = 7200 * 100;
+        for i in 0..4 * 120 {
+            series.insert(base_time + i * 60, (i % 10) as f64);
+        }
+        assert_eq!(series.closed_blocks.len(), 3);
+
+        // One bucket per block: the closed blocks aren't decoded
+        let daily = series.downsample(base_time, base_time + 4 * 7200, 7200, Aggregation::Sum);
+        assert_eq!(daily.len(), 4);
+        assert!(daily.iter().all(|&(_, sum)| sum == 12.0 * 45.0));
+        assert_eq!(series.value_decodes(), 0);
+        let count = series.downsample(base_time, u64::MAX, 86_400, Aggregation::Count);
+        assert_eq!(count, vec![(base_time, 480.0)]);
+        assert_eq!(series.value_decodes(), 0);
+
+        // Buckets cutting through blocks decode them
+        let hourly = series.downsample(base_time, base_time + 4 * 7200, 3600, Aggregation::First);
+        assert_eq!(hourly.len(), 8);
+        assert_eq!(hourly[1], (base_time + 3600, 0.0));
+        assert_eq!(series.value_decodes(), 3);
+    }
+
+    #[test]
+    fn test_describe_encoding() {
+        use crate::compression::inspect::FieldValue;
+
+        let mut series = TimeSeries::new("cpu");
+        let base_time = 7200 * 100;
+        for i in 0..50 {
+            series.insert(
+                base_time + 30 + i * 60 + i % 3,
+                20.0 + (i % 7) as f64 * 0.25,
+            );
+        }
+        let block = &series.open_block;
+        let fields = block.describe_encoding();
+
+        let layout: Vec<(&str, usize, u8)> = fields[..3]
+            .iter()
+            .map(|field| (field.name, field.offset, field.bits))
+            .collect();
+        assert_eq!(
+            layout,
+            [
+                ("header", 0, 64),
+                ("first_delta", 64, 14),
+                ("first_value", 78, 64)
+            ]
+        );
+        assert_eq!(fields[0].value, FieldValue::Timestamp(base_time));
+        assert_eq!(fields[1].value, FieldValue::Delta(30));
+        assert_eq!(fields[2].value, FieldValue::Value(20.0));
+
+        // The fields run to the end of the stream, through every point
+        let last = fields.last().unwrap();
+        let end = last.offset + last.bits as usize;
+        assert_eq!(end.div_ceil(8), block.read_compressed().unwrap().len());
+        assert_eq!(last.point, 49);
+    }
+
+    #[test]
+    fn test_close_stale_block_rolls_up() {
+        let options = SeriesOptions {
+            downsample: vec![DownsampleTier::new(3600, downsample::Aggregation::Max)],
+            ..SeriesOptions::default()
+        };
+        let mut series = TimeSeries::with_options("cpu", options);
+        let base_time = 7200 * 100;
+        for i in 0..10 {
+            series.insert(base_time + i * 600, i as f64);
+        }
+
+        assert!(!series.close_stale_block(base_time + 7199));
+        assert!(series.close_stale_block(base_time + 7200));
+        assert!(
+            !series.close_stale_block(base_time + 7200),
+            "already closed"
+        );
+        assert_eq!(series.closed_blocks.len(), 1);
+        assert_eq!(series.open_block.len(), 0);
+        assert_eq!(series.open_block.start_time, base_time + 7200);
+
+        // Both hourly buckets are complete once the block closes
+        let maxima: Vec<(u64, f64)> = series
+            .take_rollups()
+            .into_iter()
+            .map(|rollup| (rollup.timestamp, rollup.value))
+            .collect();
+        assert_eq!(maxima, vec![(base_time, 5.0), (base_time + 3600, 9.0)]);
+
+        series.insert(base_time + 7260, 10.0);
+        assert_eq!(series.closed_blocks.len(), 1);
+        assert_eq!(series.open_block.len(), 1);
+    }
+
+    #[test]
+    fn test_sparse_block_pruned_outside_extent() {
+        let mut series = TimeSeries::new("sparse");
+        let base_time = 7200 * 100;
+
+        // The first window only holds points in its middle ten minutes
+        for i in 0..10 {
+            series.insert(base_time + 3000 + i * 60, i as f64);
+        }
+        series.insert(base_time + 7200, 10.0);
+        assert_eq!(series.closed_blocks.len(), 1);
+
+        // Inside the nominal window but just outside the stored points
+        for (start, end) in [
+            (base_time, base_time + 2999),
+            (base_time + 3541, base_time + 7199),
+        ] {
+            let before = series.blocks_read();
+            assert!(series.query(start, end).is_empty());
+            assert_eq!(
+                series.blocks_read() - before,
+                0,
+                "{}..{} pruned",
+                start,
+                end
+            );
+        }
+
+        // Touching either end of the extent still reads the block
+        for (start, end) in [
+            (base_time, base_time + 3000),
+            (base_time + 3540, base_time + 3600),
+        ] {
+            let before = series.blocks_read();
+            assert_eq!(series.query(start, end).len(), 1);
+            assert_eq!(series.blocks_read() - before, 1);
+        }
+    }
+
+    #[test]
+    fn test_column_scans() {
+        let mut series = TimeSeries::new("cpu");
+        let base_time = 7200 * 100;
+        for i in 0..2000u64 {
+            series.insert(base_time + i * 3, (i as f64 * 0.01).sin());
+        }
+        assert_eq!(series.closed_blocks.len(), 0);
+
+        // Summing through the value column reads 8 bytes a point...
+        let (mut sum, mut bytes) = (0.0, 0);
+        series.for_each_columns(base_time, base_time + 2999, |timestamps, values| {
+            assert_eq!(timestamps.len(), values.len());
+            sum += values.iter().sum::<f64>();
+            bytes += size_of_val(values);
+        });
+
+        // ...half of what whole points take, for the same result
+        let points = series.query(base_time, base_time + 2999);
+        assert_eq!(points.len(), 1000);
+        assert_eq!(sum, points.iter().map(|p| p.value).sum::<f64>());
+        assert_eq!(bytes * 2, size_of_val(points.as_slice()));
+
+        let columns = series.columns(base_time + 30, base_time + 59);
+        assert_eq!(
+            columns.timestamps(),
+            &(10..20).map(|i| base_time + i * 3).collect::<Vec<_>>()[..]
+        );
+        assert!(
+            columns
+                .iter()
+                .zip(&points[10..20])
+                .all(|(a, b)| a.value == b.value)
+        );
+    }
+
+    #[test]
+    fn test_descending_iteration() {
+        let mut series = TimeSeries::new("tail".to_string());
+        for block in 0..4u64 {
+            for i in 0..10u64 {
+                series.insert(7200 * (10 + block) + i * 60, (block * 10 + i) as f64);
+            }
+        }
+
+        let mut ascending = series.query(0, u64::MAX);
+        ascending.reverse();
+        let descending: Vec<DataPoint> = series.iter_range_desc(0, u64::MAX).collect();
+        assert_eq!(descending.len(), 40);
+        assert!(
+            ascending
+                .iter()
+                .zip(&descending)
+                .all(|(a, d)| a.timestamp == d.timestamp && a.value == d.value)
+        );
+
+        // Within a range, and only the newest block is read for a tail
+        let window: Vec<u64> = series
+            .iter_range_desc(7200 * 11 + 120, 7200 * 12 + 60)
+            .map(|p| p.timestamp)
+            .collect();
+        assert_eq!(window[0], 7200 * 12 + 60);
+        assert_eq!(*window.last().unwrap(), 7200 * 11 + 120);
+        assert_eq!(window.len(), 10);
+
+        let before = series.blocks_read();
+        let tail: Vec<f64> = series
+            .iter_range_desc(0, u64::MAX)
+            .take(5)
+            .map(|p| p.value)
+            .collect();
+        assert_eq!(tail, vec![39.0, 38.0, 37.0, 36.0, 35.0]);
+        assert_eq!(series.blocks_read() - before, 1);
+    }
+
+    #[test]
+    fn test_small_integer_value_hint() {
+        let small_ints = BlockEncoding {
+            value_hint: ValueHint::IntegerSmall,
+            ..BlockEncoding::default()
+        };
+        let mut plain = TimeSeriesBlock::new(7200 * 10, BlockEncoding::default());
+        let mut hinted = TimeSeriesBlock::new(7200 * 10, small_ints);
+        for i in 0..100u64 {
+            let value = -3.0 + (i % 7) as f64;
+            plain.add_point(7200 * 10 + i * 60, value, DuplicatePolicy::KeepLast);
+            hinted.add_point(7200 * 10 + i * 60, value, DuplicatePolicy::KeepLast);
+        }
+
+        // The first value takes 17 bits instead of 64
+        let plain_data = plain.read_compressed().unwrap();
+        let hinted_data = hinted.read_compressed().unwrap();
+        assert!(hinted_data.len() + 5 <= plain_data.len());
+
+        let decoded =
+            TimeSeriesBlock::from_compressed(hinted.start_time, 100, small_ints, &hinted_data)
+                .unwrap();
+        assert_eq!(decoded.points, hinted.points);
+        assert_eq!(decoded.points, plain.points);
+
+        // Decoding needs the hint the block was written with
+        let misread = TimeSeriesBlock::from_compressed(
+            hinted.start_time,
+            100,
+            BlockEncoding::default(),
+            &hinted_data,
+        );
+        assert!(misread.is_none_or(|block| block.points != hinted.points));
+    }
+
+    #[test]
+    fn test_block_split() {
+        let start = 7200 * 10;
+        let mut block = TimeSeriesBlock::new(start, BlockEncoding::default());
+        for i in 0..100u64 {
+            block.add_point(start + i * 60, (i % 11) as f64, DuplicatePolicy::KeepLast);
+        }
+        block.drop_raw();
+        let all = block.decode().unwrap();
+
+        // On a point, between points, and at or past either end
+        for (at, cut) in [
+            (start + 40 * 60, 40),
+            (start + 40 * 60 + 1, 41),
+            (start, 0),
+            (start + 99 * 60 + 1, 100),
+            (u64::MAX, 100),
+        ] {
+            let (left, right) = block.split(at);
+            assert_eq!((left.len(), right.len()), (cut, 100 - cut));
+            assert_eq!(left.decode().unwrap(), all[..cut]);
+            assert_eq!(right.decode().unwrap(), all[cut..]);
+            for half in [&left, &right] {
+                assert_eq!(half.start_time, start);
+                let data = half.read_compressed().unwrap();
+                let rebuilt =
+                    TimeSeriesBlock::from_compressed(start, half.len(), half.encoding, &data)
+                        .unwrap();
+                assert_eq!(rebuilt.min_value, half.min_value);
+                assert_eq!(rebuilt.max_value, half.max_value);
+            }
+            if cut > 0 {
+                assert_eq!(left.last_timestamp, all[cut - 1].timestamp);
+                assert!(!left.overlaps(at, u64::MAX));
+            }
+            if cut < 100 {
+                assert_eq!(right.first_timestamp, all[cut].timestamp);
+                assert!(!right.overlaps(0, at - 1));
+            }
+        }
+    }
+
+    #[test]
+    fn test_block_decode_round_trip() {
+        let mut block = TimeSeriesBlock::new(7200 * 10, BlockEncoding::default());
+        for i in 0..500u64 {
+            // Irregular timestamps and noisy values
+            let timestamp = 7200 * 10 + i * 13 + (i % 7);
+            let value = (i as f64 * 0.37).sin() * 1000.0 + (i % 3) as f64;
+            block.add_point(timestamp, value, DuplicatePolicy::KeepLast);
+        }
+
+        let data = block.read_compressed().unwrap();
+        let decoded =
+            TimeSeriesBlock::from_compressed(block.start_time, 500, block.encoding, &data).unwrap();
+        assert_eq!(decoded.points.len(), 500);
+        for (a, b) in decoded.points.iter().zip(block.points.iter()) {
+            assert_eq!(a.timestamp, b.timestamp);
+            assert_eq!(a.value.to_bits(), b.value.to_bits());
+        }
+        assert_eq!(decoded.min_value, block.min_value);
+
+        // Wrong header and truncation are rejected
+        assert!(TimeSeriesBlock::from_compressed(0, 500, block.encoding, &data).is_none());
+        assert!(
+            TimeSeriesBlock::from_compressed(
+                block.start_time,
+                500,
+                block.encoding,
+                &data[..data.len() / 2]
+            )
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn test_data_point_ordering() {
+        let point = |timestamp, value| DataPoint { timestamp, value };
+
+        // A fixed shuffle of 100 points: 37 is coprime with 100
+        let mut points: Vec<DataPoint> = (0..100u64)
+            .map(|i| point(1000 + (i * 37 % 100) * 60, i as f64))
+            .collect();
+        points.sort();
+        assert!(points.windows(2).all(|w| w[0].timestamp < w[1].timestamp));
+        assert_eq!(points[0], point(1000, 0.0));
+
+        // Equal timestamps fall back to the value's total order
+        let mut tied = vec![
+            point(5, 2.0),
+            point(5, -1.0),
+            point(5, f64::NAN),
+            point(5, -0.0),
+        ];
+        tied.sort();
+        let values: Vec<u64> = tied.iter().map(|p| p.value.to_bits()).collect();
+        let expected: Vec<u64> = [-1.0, -0.0, 2.0, f64::NAN]
+            .iter()
+            .map(|v| v.to_bits())
+            .collect();
+        assert_eq!(values, expected);
+        assert_ne!(point(5, 0.0), point(5, -0.0));
+        assert_eq!(point(5, f64::NAN), point(5, f64::NAN));
+
+        tied.dedup_by_key(|p| p.timestamp);
+        assert_eq!(tied, vec![point(5, -1.0)]);
+    }
+
+    #[test]
+    fn test_data_point_conversions() {
+        let point = DataPoint::from((60, 1.5));
+        assert_eq!(
+            point,
+            DataPoint {
+                timestamp: 60,
+                value: 1.5
+            }
+        );
+        assert_eq!(<(u64, f64)>::from(point), (60, 1.5));
+
+        // A stable sort by time keeps tied points in arrival order
+        let mut points: Vec<DataPoint> = [(120, 3.0), (60, 2.0), (60, 1.0)]
+            .into_iter()
+            .map(DataPoint::from)
+            .collect();
+        points.sort_by(DataPoint::cmp_by_time);
+        let pairs: Vec<(u64, f64)> = points.into_iter().map(Into::into).collect();
+        assert_eq!(pairs, vec![(60, 2.0), (60, 1.0), (120, 3.0)]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_data_point_serde_round_trip() {
+        let points = vec![
+            DataPoint {
+                timestamp: 60,
+                value: 1.5,
+            },
+            DataPoint {
+                timestamp: 120,
+                value: -0.25,
+            },
+        ];
+        let json = serde_json::to_string(&points).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"timestamp":60,"value":1.5},{"timestamp":120,"value":-0.25}]"#
+        );
+        let decoded: Vec<DataPoint> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, points);
+    }
+
+    #[test]
+    fn test_pre_epoch_clock_does_not_panic() {
+        let before_epoch = UNIX_EPOCH - Duration::from_secs(86_400);
+        assert_eq!(unix_seconds(before_epoch), 0);
+
+        let mut series = TimeSeries::with_options_at(
+            "skewed".to_string(),
+            SeriesOptions::default(),
+            unix_seconds(before_epoch),
+        );
+        assert_eq!(series.open_block.start_time, 0);
+
+        series.insert(7200 * 10 + 5, 1.0);
+        series.insert(7200 * 10 + 65, 2.0);
+        assert_eq!(series.query(0, u64::MAX).len(), 2);
+    }
+
+    #[test]
+    fn test_late_grace() {
+        let options = SeriesOptions {
+            drop_raw_on_close: true,
+            late_grace: Some(120),
+            ..SeriesOptions::default()
+        };
+        let mut series = TimeSeries::with_options_at("cpu", options, 0);
+        let boundary = 7200 * 101;
+        for i in 0..120 {
+            series.insert(boundary - 7200 + i * 60, i as f64);
+        }
+        assert!(series.insert(boundary, 120.0).closed_block);
+        let decodes = |series: &TimeSeries| {
+            series.closed_blocks[0]
+                .value_decodes
+                .load(Ordering::Relaxed)
+        };
+
+        // 30 seconds late, within the grace period: appended to the
+        // closed block, which still has its raw points
+        series.insert(boundary + 30, 121.0);
+        assert!(series.insert(boundary - 30, 119.5).write.stored());
+        assert!(!series.closed_blocks[0].raw_dropped());
+        assert_eq!(decodes(&series), 0);
+        assert_eq!(series.closed_blocks[0].len(), 121);
+        assert_eq!(series.open_block.len(), 2);
+
+        // Past the grace period the block drops its raw points, and a point
+        // 10 minutes late is backfilled (decoding the block again)
+        series.insert(boundary + 600, 122.0);
+        assert!(series.closed_blocks[0].raw_dropped());
+        assert!(series.insert(boundary - 90, 119.0).write.stored());
+        assert_eq!(decodes(&series), 1);
+        assert!(series.closed_blocks[0].raw_dropped());
+
+        // Everything comes back once, in order, across the boundary
+        let points = series.query(boundary - 7200, u64::MAX);
+        assert_eq!(points.len(), 125);
+        assert!(points.windows(2).all(|w| w[0].timestamp < w[1].timestamp));
+        let near: Vec<u64> = series
+            .query(boundary - 120, boundary + 600)
+            .iter()
+            .map(|p| p.timestamp)
+            .collect();
+        assert_eq!(
+            near,
+            vec![
+                boundary - 120,
+                boundary - 90,
+                boundary - 60,
+                boundary - 30,
+                boundary,
+                boundary + 30,
+                boundary + 600
+            ]
+        );
+    }
+
+    #[test]
+    fn test_out_of_order_and_backfill() {
+        let mut series = TimeSeries::new("backfill".to_string());
+
+        // Two windows of data, written newest window first
+        series.insert(7200 * 10 + 60, 1.0);
+        series.insert(7200 * 10 + 180, 3.0);
+        series.insert(7200 * 10 + 120, 2.0); // out of order in the open block
+        series.insert(7200 * 9 + 60, 0.5); // older window, backfilled
+        series.insert(7200 * 8 + 60, 0.25); // even older, new closed block
+
+        let points: Vec<u64> = series
+            .query(0, u64::MAX)
+            .iter()
+            .map(|p| p.timestamp)
+            .collect();
+        assert_eq!(
+            points,
+            vec![
+                7200 * 8 + 60,
+                7200 * 9 + 60,
+                7200 * 10 + 60,
+                7200 * 10 + 120,
+                7200 * 10 + 180
+            ]
+        );
+        assert_eq!(series.closed_blocks.len(), 2);
+        assert!(series.closed_blocks[0].start_time < series.closed_blocks[1].start_time);
+    }
+
+    #[test]
+    fn test_duplicate_policies() {
+        let ts = 7200 * 10;
+        for (policy, expected_write, expected_value) in [
+            (DuplicatePolicy::KeepLast, PointWrite::Replaced, 2.0),
+            (DuplicatePolicy::KeepFirst, PointWrite::Ignored, 1.0),
+            (DuplicatePolicy::Reject, PointWrite::Rejected, 1.0),
+        ] {
+            let options = SeriesOptions {
+                duplicate_policy: policy,
+                ..SeriesOptions::default()
+            };
+            let mut series = TimeSeries::with_options("dup".to_string(), options);
+            assert_eq!(series.insert(ts, 1.0).write, PointWrite::Added);
+            assert_eq!(series.insert(ts, 2.0).write, expected_write);
+
+            let points = series.query(0, u64::MAX);
+            assert_eq!(points.len(), 1);
+            assert_eq!(points[0].value, expected_value);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "power of two")]
+    fn test_shard_count_must_be_power_of_two() {
+        TimeSeriesMap::with_shards(12);
     }
 }
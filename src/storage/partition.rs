@@ -0,0 +1,459 @@
+// Time-partitioned persistence: one directory per UTC day
+//
+// Blocks are grouped by the day their start time falls in and written to
+// `<root>/<YYYY-MM-DD>/<shard>/`, each a shard directory (see disk.rs)
+// holding that day's blocks of the shard's series. A small manifest per
+// day records the timestamps its blocks cover, so retention drops a whole
+// day directory once its newest point is past the horizon (no file is
+// rewritten), and a range load skips days outside the range after
+// reading only their manifests. Loading unions the days: a series' blocks
+// are gathered from every day they landed in.
+//
+// A day's manifest is written after its shard checkpoints, under a
+// temporary name renamed into place, so a day without one is incomplete
+// and never loaded. Each persist rewrites every day it has blocks for and
+// then removes the day directories it no longer has blocks for.
+//
+// Manifest layout (all integers little-endian):
+//   magic "TSDBDAYM", version u32 (DISK_VERSION), first timestamp u64,
+//   last timestamp u64, series u32, blocks u64, points u64
+
+use super::disk::{BlockHeader, DISK_VERSION, ShardDir, StoredSeries};
+use super::snapshot::{SnapshotReader, invalid, sync_dir, unsupported, write_len};
+use super::{SeriesOptions, TimeSeriesBlock, TimeSeriesMap};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const MANIFEST_MAGIC: &[u8; 8] = b"TSDBDAYM";
+const MANIFEST_FILE: &str = "MANIFEST";
+
+const SECS_PER_DAY: u64 = 86400;
+
+/// What a day directory holds, from its manifest
+#[derive(Debug, Clone, PartialEq)]
+pub struct DayManifest {
+    pub day: String,          // Directory name, YYYY-MM-DD
+    pub first_timestamp: u64, // Oldest point in the day's blocks
+    pub last_timestamp: u64,  // Newest point, which may fall on a later day
+    pub series: usize,
+    pub blocks: usize,
+    pub points: usize,
+}
+
+impl DayManifest {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        start <= self.last_timestamp && end >= self.first_timestamp
+    }
+}
+
+/// A root directory of per-day shard directories
+pub(crate) struct PartitionedDir {
+    root: PathBuf,
+}
+
+impl PartitionedDir {
+    /// Open (creating if needed) a partitioned root directory
+    pub fn open(root: &Path) -> io::Result<PartitionedDir> {
+        fs::create_dir_all(root)?;
+        Ok(PartitionedDir {
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// Write every block holding points into its day's shard directory
+    ///
+    /// Spilled blocks are read back from their files; open blocks are
+    /// written as they stand. Returns the manifests written, oldest day
+    /// first. Series without points aren't recorded.
+    pub fn persist(&self, map: &TimeSeriesMap) -> io::Result<Vec<DayManifest>> {
+        let mut days: BTreeMap<String, DayWriter> = BTreeMap::new();
+        for handle in map.iter() {
+            let series = handle.read();
+            let shard = map.shard_index(&series.key);
+            let open = Some(&series.open_block).filter(|block| !block.is_empty());
+            let blocks = series
+                .closed_blocks
+                .iter()
+                .filter(|block| !block.is_empty())
+                .map(|block| (block, false))
+                .chain(open.map(|block| (block, true)));
+            for (block, open) in blocks {
+                let day = day_name(block.start_time);
+                let writer = days
+                    .entry(day.clone())
+                    .or_insert_with(|| DayWriter::new(self.root.join(&day), day));
+                writer.append(shard, &series.key, series.options(), block, open)?;
+            }
+        }
+
+        let mut manifests = Vec::new();
+        for writer in days.into_values() {
+            manifests.push(writer.finish()?);
+        }
+        for day in self.day_dirs()? {
+            if !manifests.iter().any(|manifest| manifest.day == day) {
+                fs::remove_dir_all(self.root.join(&day))?;
+            }
+        }
+        sync_dir(&self.root)?;
+        Ok(manifests)
+    }
+
+    /// Manifests of the complete days, oldest first
+    pub fn manifests(&self) -> io::Result<Vec<DayManifest>> {
+        let mut manifests = Vec::new();
+        for day in self.day_dirs()? {
+            match fs::read(self.root.join(&day).join(MANIFEST_FILE)) {
+                Ok(data) => manifests.push(read_manifest(day, &data)?),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(manifests)
+    }
+
+    /// Rebuild a map from every complete day
+    pub fn load(&self) -> io::Result<TimeSeriesMap> {
+        self.load_range(0, u64::MAX)
+    }
+
+    /// Rebuild a map from the days holding points in [start, end]
+    ///
+    /// Other days are skipped without opening anything but their
+    /// manifests, so series come back with only the blocks of the days
+    /// read (and not at all if they have none there). Such a partial map
+    /// is for querying; persisting it would drop the skipped days.
+    pub fn load_range(&self, start: u64, end: u64) -> io::Result<TimeSeriesMap> {
+        let mut series: BTreeMap<String, StoredSeries> = BTreeMap::new();
+        for manifest in self.manifests()? {
+            if !manifest.overlaps(start, end) {
+                continue;
+            }
+            let day_dir = self.root.join(&manifest.day);
+            for entry in fs::read_dir(&day_dir)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_dir() {
+                    continue;
+                }
+                for stored in ShardDir::open(&entry.path())?.load_series()? {
+                    match series.get_mut(&stored.key) {
+                        Some(gathered) => {
+                            gathered.closed.extend(stored.closed);
+                            gathered.open = gathered.open.take().or(stored.open);
+                        }
+                        None => {
+                            series.insert(stored.key.clone(), stored);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut map = TimeSeriesMap::new();
+        for stored in series.into_values() {
+            map.insert_series(stored.into_series())
+                .map_err(|e| invalid(format!("duplicate series across days: {}", e)))?;
+        }
+        Ok(map)
+    }
+
+    /// Remove every day whose newest point is older than `horizon`
+    ///
+    /// Whole directories are deleted; nothing else is rewritten. Returns
+    /// the days removed, oldest first. Incomplete days (no manifest) are
+    /// left alone.
+    pub fn prune_before(&self, horizon: u64) -> io::Result<Vec<String>> {
+        let mut removed = Vec::new();
+        for manifest in self.manifests()? {
+            if manifest.last_timestamp < horizon {
+                fs::remove_dir_all(self.root.join(&manifest.day))?;
+                removed.push(manifest.day);
+            }
+        }
+        if !removed.is_empty() {
+            sync_dir(&self.root)?;
+        }
+        Ok(removed)
+    }
+
+    /// Names of the day directories under the root, oldest first
+    fn day_dirs(&self) -> io::Result<Vec<String>> {
+        let mut days = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_dir() && is_day_name(&name) {
+                days.push(name);
+            }
+        }
+        days.sort();
+        Ok(days)
+    }
+}
+
+/// One day's shard directories and manifest, while a persist writes them
+struct DayWriter {
+    dir: PathBuf,
+    manifest: DayManifest,
+
+    // Per shard: its directory, key list so far and key ids by key
+    shards: BTreeMap<usize, ShardWriter>,
+}
+
+struct ShardWriter {
+    dir: ShardDir,
+    keys: Vec<(String, u32, SeriesOptions)>,
+    ids: HashMap<String, u32>,
+}
+
+impl DayWriter {
+    fn new(dir: PathBuf, day: String) -> DayWriter {
+        DayWriter {
+            dir,
+            manifest: DayManifest {
+                day,
+                first_timestamp: u64::MAX,
+                last_timestamp: 0,
+                series: 0,
+                blocks: 0,
+                points: 0,
+            },
+            shards: BTreeMap::new(),
+        }
+    }
+
+    fn append(
+        &mut self,
+        shard: usize,
+        key: &str,
+        options: &SeriesOptions,
+        block: &TimeSeriesBlock,
+        open: bool,
+    ) -> io::Result<()> {
+        let writer = match self.shards.get_mut(&shard) {
+            Some(writer) => writer,
+            None => self.shards.entry(shard).or_insert(ShardWriter {
+                dir: ShardDir::open(&self.dir.join(shard.to_string()))?,
+                keys: Vec::new(),
+                ids: HashMap::new(),
+            }),
+        };
+        let key_id = match writer.ids.get(key) {
+            Some(&key_id) => key_id,
+            None => {
+                let key_id = u32::try_from(writer.keys.len())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many series"))?;
+                writer.keys.push((key.to_string(), key_id, options.clone()));
+                writer.ids.insert(key.to_string(), key_id);
+                self.manifest.series += 1;
+                key_id
+            }
+        };
+
+        let header = BlockHeader {
+            start_time: block.start_time,
+            point_count: block.len(),
+            encoding: block.encoding,
+            open,
+        };
+        writer
+            .dir
+            .append_block(key_id, header, &block.read_compressed()?)?;
+
+        let manifest = &mut self.manifest;
+        manifest.first_timestamp = manifest.first_timestamp.min(block.first_timestamp);
+        manifest.last_timestamp = manifest.last_timestamp.max(block.last_timestamp);
+        manifest.blocks += 1;
+        manifest.points += block.len();
+        Ok(())
+    }
+
+    /// Checkpoint every shard written and write the manifest, then drop
+    /// the day's other shard directories
+    fn finish(self) -> io::Result<DayManifest> {
+        let mut written = Vec::new();
+        for (shard, mut writer) in self.shards {
+            writer.dir.write_key_list(&writer.keys)?;
+            writer.dir.mark_checkpoint()?;
+            written.push(shard.to_string());
+        }
+        write_manifest(&self.dir, &self.manifest)?;
+
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_dir() && !written.contains(&name) {
+                fs::remove_dir_all(entry.path())?;
+            }
+        }
+        Ok(self.manifest)
+    }
+}
+
+/// The directory name of the UTC day containing `timestamp`
+pub fn day_name(timestamp: u64) -> String {
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = (timestamp / SECS_PER_DAY) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn is_day_name(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    bytes.len() == 10
+        && bytes.iter().enumerate().all(|(i, &b)| match i {
+            4 | 7 => b == b'-',
+            _ => b.is_ascii_digit(),
+        })
+}
+
+fn write_manifest(dir: &Path, manifest: &DayManifest) -> io::Result<()> {
+    let mut data = Vec::new();
+    data.extend_from_slice(MANIFEST_MAGIC);
+    data.extend_from_slice(&DISK_VERSION.to_le_bytes());
+    data.extend_from_slice(&manifest.first_timestamp.to_le_bytes());
+    data.extend_from_slice(&manifest.last_timestamp.to_le_bytes());
+    write_len(&mut data, manifest.series)?;
+    data.extend_from_slice(&(manifest.blocks as u64).to_le_bytes());
+    data.extend_from_slice(&(manifest.points as u64).to_le_bytes());
+
+    let temp = dir.join(format!("{}.tmp", MANIFEST_FILE));
+    let mut file = File::create(&temp)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    fs::rename(&temp, dir.join(MANIFEST_FILE))?;
+    sync_dir(dir)
+}
+
+fn read_manifest(day: String, data: &[u8]) -> io::Result<DayManifest> {
+    let mut reader = SnapshotReader::new(data, "day manifest");
+    if reader.take(MANIFEST_MAGIC.len(), "magic")? != MANIFEST_MAGIC {
+        return Err(invalid(format!("day {}: not a manifest (bad magic)", day)));
+    }
+    let version = reader.u32("version")?;
+    if version == 0 || version > DISK_VERSION {
+        return Err(unsupported(version, DISK_VERSION));
+    }
+    Ok(DayManifest {
+        first_timestamp: reader.u64("first timestamp")?,
+        last_timestamp: reader.u64("last timestamp")?,
+        series: reader.u32("series count")? as usize,
+        blocks: reader.u64("block count")? as usize,
+        points: reader.u64("point count")? as usize,
+        day,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY0: u64 = SECS_PER_DAY * 19000; // 2022-01-08
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tsdb_days_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// cpu every 10 minutes over three days, mem on the last day only
+    fn three_days() -> TimeSeriesMap {
+        let map = TimeSeriesMap::new();
+        for i in 0..3 * 144 {
+            map.insert("cpu", DAY0 + i * 600, (i % 13) as f64);
+        }
+        map.insert("mem", DAY0 + 2 * SECS_PER_DAY + 60, 1.0);
+        map
+    }
+
+    fn points(map: &TimeSeriesMap, key: &str) -> Vec<(u64, f64)> {
+        let series = map.get(key).unwrap();
+        let points = series.read().query(0, u64::MAX);
+        points.into_iter().map(Into::into).collect()
+    }
+
+    #[test]
+    fn test_day_name() {
+        assert_eq!(day_name(0), "1970-01-01");
+        assert_eq!(day_name(951_782_400), "2000-02-29");
+        assert_eq!(day_name(1_700_000_000), "2023-11-14");
+        assert_eq!(day_name(DAY0 - 1), "2022-01-07");
+        assert!(is_day_name(&day_name(DAY0)));
+        assert!(!is_day_name("2022-01-8") && !is_day_name("2022/01/08"));
+    }
+
+    #[test]
+    fn test_three_days_and_prune() {
+        let root = temp_dir("prune");
+        let days = PartitionedDir::open(&root).unwrap();
+        let map = three_days();
+        let manifests = days.persist(&map).unwrap();
+        let names: Vec<&str> = manifests.iter().map(|m| m.day.as_str()).collect();
+        assert_eq!(names, ["2022-01-08", "2022-01-09", "2022-01-10"]);
+        assert_eq!(manifests[0].first_timestamp, DAY0);
+        assert_eq!(manifests[0].last_timestamp, DAY0 + SECS_PER_DAY - 600);
+        assert_eq!((manifests[0].series, manifests[0].blocks), (1, 12));
+        assert_eq!((manifests[2].series, manifests[2].points), (2, 145));
+        assert_eq!(days.manifests().unwrap(), manifests);
+
+        // Every day together gives back the whole map
+        let loaded = days.load().unwrap();
+        assert_eq!(points(&loaded, "cpu"), points(&map, "cpu"));
+        assert_eq!(points(&loaded, "mem"), points(&map, "mem"));
+
+        // The oldest day goes as a whole directory
+        let removed = days.prune_before(DAY0 + SECS_PER_DAY).unwrap();
+        assert_eq!(removed, ["2022-01-08"]);
+        assert!(!root.join("2022-01-08").exists());
+        assert_eq!(days.manifests().unwrap(), manifests[1..]);
+        let pruned = days.load().unwrap();
+        let cpu = points(&pruned, "cpu");
+        assert_eq!(cpu.len(), 2 * 144);
+        assert_eq!(cpu[..], points(&map, "cpu")[144..]);
+        assert!(days.prune_before(DAY0 + SECS_PER_DAY).unwrap().is_empty());
+
+        // Persisting the pruned map writes the same two days again
+        assert_eq!(days.persist(&pruned).unwrap(), manifests[1..]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_load_range_skips_days() {
+        let root = temp_dir("range");
+        let days = PartitionedDir::open(&root).unwrap();
+        days.persist(&three_days()).unwrap();
+
+        // Break every file of the last day but its manifest
+        for shard in fs::read_dir(root.join("2022-01-10")).unwrap() {
+            let shard = shard.unwrap().path();
+            if shard.is_dir() {
+                for file in fs::read_dir(&shard).unwrap() {
+                    fs::write(file.unwrap().path(), b"garbage").unwrap();
+                }
+            }
+        }
+        assert!(days.load().is_err());
+
+        // A range within the first two days never opens them
+        let map = days
+            .load_range(DAY0 + 3600, DAY0 + SECS_PER_DAY + 3600)
+            .unwrap();
+        assert_eq!(points(&map, "cpu").len(), 2 * 144);
+        assert!(map.get("mem").is_none());
+
+        // A day without a manifest is incomplete and ignored
+        fs::remove_file(root.join("2022-01-10").join(MANIFEST_FILE)).unwrap();
+        assert_eq!(days.manifests().unwrap().len(), 2);
+        assert_eq!(points(&days.load().unwrap(), "cpu").len(), 2 * 144);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,625 @@
+// Snapshot persistence for the TSmap
+// Writes every live series to a single versioned binary file
+//
+// The file is written under a temporary name beside the target, synced,
+// then renamed over it, so a crash mid-write leaves the previous snapshot
+// (or none) rather than a partial one.
+//
+// Layout (all integers little-endian):
+//   magic "TSDBSNAP", version u32
+//   format version u8 (version 8+; FORMAT_VERSION of the block data,
+//     which is 1 in earlier snapshots)
+//   WAL segment u64, WAL offset u64 (version 2+; see wal.rs)
+//   series count u64
+//   per series:
+//     key length u32, key bytes (UTF-8)
+//     options u8 (duplicate policy; high bit set for drop_raw_on_close),
+//       block duration u64,
+//     max points per block u32 (version 6+; 0 for no limit)
+//     timestamp codec u8 (version 10+; 0 delta-of-delta, 1 delta)
+//     value hint u8 (version 11+; 0 float, 1 small integer)
+//     late grace u64 (version 12+; seconds, 0 for none)
+//     value codec choice u8 (version 13+; 0 XOR, 1 integer delta, 2 raw,
+//       255 auto)
+//     align origin u64 (version 14+; seconds since epoch)
+//     retention u64 (version 15+; seconds, 0 for none)
+//     downsample tiers (version 9+): count u8, per tier width u64,
+//       aggregation u8 and retention u64 (version 15+; 0 for none)
+//     metadata (version 3+): unit, description (each a present flag u8,
+//       then length u32 and UTF-8 bytes if present), created_at u64,
+//       last_write u64, flags u8 (version 5+; bit 0 pinned, bit 1
+//       frozen from version 7)
+//     labels (version 4+): present flag u8, then if present the metric
+//       name (length u32, bytes), label count u32 and per label the
+//       name and value (each length u32, bytes)
+//     block count u32
+//     per block:
+//       start time u64, point count u32, open flag u8,
+//       timestamp codec u8 (version 10+; delta-of-delta before),
+//       value hint u8 (version 11+; float before),
+//       value codec u8 (version 13+; XOR before),
+//       compressed length u32, compressed bytes
+
+use super::downsample::{Aggregation, DownsampleTier};
+use super::labels::SeriesLabels;
+use super::wal::WalPosition;
+use super::{
+    BlockEncoding, CodecChoice, DEFAULT_BLOCK_DURATION_SECS, DuplicatePolicy, SeriesHandle,
+    SeriesMeta, SeriesOptions, TimeSeries, TimeSeriesBlock, TimeSeriesMap,
+};
+use crate::compression::FORMAT_VERSION;
+use crate::compression::timestamp::TimestampCodec;
+use crate::compression::value::{ValueCodec, ValueHint};
+use crate::tsdb::TsdbError;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"TSDBSNAP";
+
+// Set in the options byte when the series drops raw points on close
+const DROP_RAW_FLAG: u8 = 0x80;
+
+// Bits of the metadata flags byte
+const PINNED_FLAG: u8 = 0x01;
+const FROZEN_FLAG: u8 = 0x02;
+
+/// Current snapshot format version
+pub const SNAPSHOT_VERSION: u32 = 15;
+
+/// Summary of a written snapshot
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SnapshotInfo {
+    pub series: usize, // Series written
+    pub blocks: usize, // Blocks written (open blocks included)
+    pub points: usize, // Points covered by those blocks
+    pub bytes: u64,    // Size of the snapshot file
+}
+
+/// Write every live series in the map to `path`
+///
+/// Open blocks are written as they currently stand; in-memory state is
+/// not modified. With `compact`, closed blocks are written merged as
+/// compact_blocks would merge them over each series' block duration.
+/// `wal_position` marks the point in the write-ahead log the snapshot
+/// covers. The file only appears at `path` once fully written and
+/// synced; a failed write can leave `<path>.tmp` behind, which the next
+/// write replaces.
+pub(crate) fn write_snapshot(
+    map: &TimeSeriesMap,
+    wal_position: WalPosition,
+    path: &Path,
+    compact: bool,
+) -> io::Result<SnapshotInfo> {
+    let mut info = SnapshotInfo::default();
+    let temp = temp_path(path);
+    let mut out = BufWriter::new(File::create(&temp)?);
+
+    // Fix the set of series up front so the count matches what follows
+    let series: Vec<SeriesHandle> = map.iter().collect();
+
+    out.write_all(SNAPSHOT_MAGIC)?;
+    out.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+    out.write_all(&[FORMAT_VERSION])?;
+    out.write_all(&wal_position.segment.to_le_bytes())?;
+    out.write_all(&wal_position.offset.to_le_bytes())?;
+    out.write_all(&(series.len() as u64).to_le_bytes())?;
+
+    for handle in series {
+        let series = handle.read();
+        write_len(&mut out, series.key.len())?;
+        out.write_all(series.key.as_bytes())?;
+        write_options(&mut out, &series.options)?;
+        write_opt_str(&mut out, series.meta.unit.as_deref())?;
+        write_opt_str(&mut out, series.meta.description.as_deref())?;
+        out.write_all(&series.meta.created_at.to_le_bytes())?;
+        out.write_all(&series.meta.last_write.to_le_bytes())?;
+        let mut flags = 0;
+        if series.meta.pinned {
+            flags |= PINNED_FLAG;
+        }
+        if series.frozen {
+            flags |= FROZEN_FLAG;
+        }
+        out.write_all(&[flags])?;
+        write_labels(&mut out, series.labels.as_ref())?;
+
+        let runs = if compact {
+            series.block_runs(series.block_duration())
+        } else {
+            (0..series.closed_blocks.len()).map(|i| i..i + 1).collect()
+        };
+        let open = Some(&series.open_block).filter(|block| !block.is_empty());
+        write_len(&mut out, runs.len() + open.is_some() as usize)?;
+
+        for run in runs {
+            if run.len() == 1 {
+                write_block(&mut out, &series.closed_blocks[run.start], false, &mut info)?;
+            } else {
+                let merged = series.merged_block(run)?;
+                write_block(&mut out, &merged, false, &mut info)?;
+            }
+        }
+        if let Some(block) = open {
+            write_block(&mut out, block, true, &mut info)?;
+        }
+        info.series += 1;
+    }
+
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    info.bytes = file.metadata()?.len();
+    fs::rename(&temp, path)?;
+    sync_parent(path)?;
+    Ok(info)
+}
+
+fn write_block(
+    out: &mut impl Write,
+    block: &TimeSeriesBlock,
+    is_open: bool,
+    info: &mut SnapshotInfo,
+) -> io::Result<()> {
+    out.write_all(&block.start_time.to_le_bytes())?;
+    write_len(out, block.len())?;
+    out.write_all(&[is_open as u8])?;
+    out.write_all(&[block.encoding.timestamp_codec.to_byte()])?;
+    out.write_all(&[block.encoding.value_hint.to_byte()])?;
+    out.write_all(&[block.encoding.value_codec.to_byte()])?;
+    let data = block.read_compressed()?;
+    write_len(out, data.len())?;
+    out.write_all(&data)?;
+
+    info.blocks += 1;
+    info.points += block.len();
+    Ok(())
+}
+
+/// Where write_snapshot writes before renaming into place
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Sync a directory, making renames and new files in it durable
+pub(super) fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => sync_dir(dir),
+        _ => sync_dir(Path::new(".")),
+    }
+}
+
+/// Rebuild a map from a snapshot written by write_snapshot
+///
+/// Also returns the WAL position the snapshot covers (the start of the
+/// log for version 1 files). Truncated or corrupt files produce an
+/// `InvalidData` error describing what could not be read; this never
+/// panics on bad input.
+pub(crate) fn read_snapshot(path: &Path) -> io::Result<(TimeSeriesMap, WalPosition)> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    let mut reader = SnapshotReader::new(&data, "snapshot");
+
+    if reader.take(SNAPSHOT_MAGIC.len(), "magic")? != SNAPSHOT_MAGIC {
+        return Err(invalid("not a snapshot file (bad magic)".to_string()));
+    }
+    let version = reader.u32("version")?;
+    if version == 0 || version > SNAPSHOT_VERSION {
+        return Err(unsupported(version, SNAPSHOT_VERSION));
+    }
+    // Version 1 blocks have the same bit layout, so they need no rewrite
+    let format = if version >= 8 {
+        reader.u8("format version")?
+    } else {
+        1
+    };
+    if format == 0 || format > FORMAT_VERSION {
+        return Err(unsupported(format.into(), FORMAT_VERSION.into()));
+    }
+    let wal_position = if version >= 2 {
+        WalPosition {
+            segment: reader.u64("WAL segment")?,
+            offset: reader.u64("WAL offset")?,
+        }
+    } else {
+        WalPosition::default()
+    };
+
+    let mut map = TimeSeriesMap::new();
+    let series_count = reader.u64("series count")?;
+
+    for _ in 0..series_count {
+        let key_len = reader.u32("key length")? as usize;
+        let key = String::from_utf8(reader.take(key_len, "key")?.to_vec())
+            .map_err(|_| invalid("series key is not valid UTF-8".to_string()))?;
+        let options = reader.options(&key, version)?;
+        let block_duration = options
+            .block_duration
+            .unwrap_or(DEFAULT_BLOCK_DURATION_SECS);
+        let mut flags = 0;
+        let meta = if version >= 3 {
+            let mut meta = SeriesMeta {
+                unit: reader.opt_string("unit")?,
+                description: reader.opt_string("description")?,
+                created_at: reader.u64("created_at")?,
+                last_write: reader.u64("last_write")?,
+                pinned: false,
+            };
+            if version >= 5 {
+                flags = reader.u8("flags")?;
+                if version < 7 {
+                    // Earlier versions stored only the pinned flag
+                    flags = (flags != 0) as u8;
+                }
+            }
+            meta.pinned = flags & PINNED_FLAG != 0;
+            meta
+        } else {
+            SeriesMeta::default()
+        };
+        let labels = if version >= 4 { reader.labels()? } else { None };
+
+        let block_count = reader.u32("block count")?;
+        let mut closed_blocks = Vec::new();
+        let mut open_block = None;
+
+        for _ in 0..block_count {
+            let start_time = reader.u64("block start")?;
+            let point_count = reader.u32("point count")? as usize;
+            let is_open = reader.u8("open flag")? != 0;
+            let mut encoding = BlockEncoding::default();
+            if version >= 10 {
+                encoding.timestamp_codec = reader.codec(&key)?;
+            }
+            if version >= 11 {
+                encoding.value_hint = reader.value_hint(&key)?;
+            }
+            if version >= 13 {
+                encoding.value_codec = reader.value_codec(&key)?;
+            }
+            let data_len = reader.u32("block length")? as usize;
+            let bytes = reader.take(data_len, "block data")?;
+
+            let block = TimeSeriesBlock::from_compressed(start_time, point_count, encoding, bytes)
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "corrupt block at {} in series {} (offset {})",
+                        start_time, key, reader.pos
+                    ))
+                })?;
+
+            if is_open {
+                open_block = Some(block);
+            } else {
+                closed_blocks.push(block);
+            }
+        }
+
+        let mut series = TimeSeries::from_parts(
+            key,
+            options,
+            block_duration,
+            closed_blocks,
+            open_block,
+            meta,
+            labels,
+        );
+        series.frozen = flags & FROZEN_FLAG != 0;
+        map.insert_series(series)
+            .map_err(|e| invalid(format!("duplicate series in snapshot: {}", e)))?;
+    }
+
+    if reader.pos != data.len() {
+        return Err(invalid(format!(
+            "{} trailing bytes after last series",
+            data.len() - reader.pos
+        )));
+    }
+
+    Ok((map, wal_position))
+}
+
+/// Bounds-checked cursor over the snapshot bytes
+///
+/// Also reads the shard directory files (see disk.rs), which share the
+/// snapshot's encoding of series options.
+pub(super) struct SnapshotReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    kind: &'static str, // What is being read, for error messages
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub(super) fn new(data: &'a [u8], kind: &'static str) -> Self {
+        SnapshotReader { data, pos: 0, kind }
+    }
+
+    /// Bytes not read yet
+    pub(super) fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    pub(super) fn take(&mut self, len: usize, what: &str) -> io::Result<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            return Err(invalid(format!(
+                "{} truncated while reading {} at offset {}",
+                self.kind, what, self.pos
+            )));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub(super) fn u8(&mut self, what: &str) -> io::Result<u8> {
+        Ok(self.take(1, what)?[0])
+    }
+
+    pub(super) fn u32(&mut self, what: &str) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4, what)?.try_into().unwrap()))
+    }
+
+    pub(super) fn u64(&mut self, what: &str) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8, what)?.try_into().unwrap()))
+    }
+
+    /// Series options as written by write_options in a given snapshot
+    /// version
+    pub(super) fn options(&mut self, key: &str, version: u32) -> io::Result<SeriesOptions> {
+        let mut options = options_from_byte(self.u8("options")?)
+            .ok_or_else(|| invalid(format!("unknown options for series {}", key)))?;
+        let block_duration = self.u64("block duration")?;
+        if block_duration == 0 {
+            return Err(invalid(format!("zero block duration for series {}", key)));
+        }
+        options.block_duration =
+            (block_duration != DEFAULT_BLOCK_DURATION_SECS).then_some(block_duration);
+        if version >= 6 {
+            let max_points = self.u32("max points per block")? as usize;
+            options.max_points_per_block = (max_points > 0).then_some(max_points);
+        }
+        if version >= 10 {
+            options.timestamp_codec = self.codec(key)?;
+        }
+        if version >= 11 {
+            options.value_hint = self.value_hint(key)?;
+        }
+        if version >= 12 {
+            let grace = self.u64("late grace")?;
+            options.late_grace = (grace > 0).then_some(grace);
+        }
+        if version >= 13 {
+            let byte = self.u8("value codec choice")?;
+            options.value_codec = codec_choice_from_byte(byte).ok_or_else(|| {
+                invalid(format!(
+                    "unknown value codec choice {} for series {}",
+                    byte, key
+                ))
+            })?;
+        }
+        if version >= 14 {
+            options.align_origin = self.u64("align origin")?;
+        }
+        if version >= 15 {
+            options.retention = self.retention("retention")?;
+        }
+        if version >= 9 {
+            options.downsample = self.tiers(key, version)?;
+        }
+        Ok(options)
+    }
+
+    pub(super) fn string(&mut self, what: &str) -> io::Result<String> {
+        let len = self.u32(what)? as usize;
+        let bytes = self.take(len, what)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| invalid(format!("{} is not valid UTF-8", what)))
+    }
+
+    fn labels(&mut self) -> io::Result<Option<SeriesLabels>> {
+        if self.u8("labels flag")? == 0 {
+            return Ok(None);
+        }
+
+        let mut labels = SeriesLabels {
+            name: self.string("metric name")?,
+            ..SeriesLabels::default()
+        };
+        for _ in 0..self.u32("label count")? {
+            let label = self.string("label name")?;
+            let value = self.string("label value")?;
+            labels.labels.insert(label, value);
+        }
+        Ok(Some(labels))
+    }
+
+    fn tiers(&mut self, key: &str, version: u32) -> io::Result<Vec<DownsampleTier>> {
+        let mut tiers = Vec::new();
+        for _ in 0..self.u8("tier count")? {
+            let width = self.u64("tier width")?;
+            let aggregation = Aggregation::from_byte(self.u8("tier aggregation")?);
+            let retention = if version >= 15 {
+                self.retention("tier retention")?
+            } else {
+                None
+            };
+            match aggregation {
+                Some(aggregation) if width > 0 => tiers.push(DownsampleTier {
+                    width,
+                    aggregation,
+                    retention,
+                }),
+                _ => return Err(invalid(format!("bad downsample tier for series {}", key))),
+            }
+        }
+        Ok(tiers)
+    }
+
+    /// A retention in seconds, 0 standing for none
+    fn retention(&mut self, what: &str) -> io::Result<Option<u64>> {
+        let secs = self.u64(what)?;
+        Ok((secs > 0).then_some(secs))
+    }
+
+    pub(super) fn codec(&mut self, key: &str) -> io::Result<TimestampCodec> {
+        let byte = self.u8("timestamp codec")?;
+        TimestampCodec::from_byte(byte).ok_or_else(|| {
+            invalid(format!(
+                "unknown timestamp codec {} for series {}",
+                byte, key
+            ))
+        })
+    }
+
+    pub(super) fn value_hint(&mut self, key: &str) -> io::Result<ValueHint> {
+        let byte = self.u8("value hint")?;
+        ValueHint::from_byte(byte)
+            .ok_or_else(|| invalid(format!("unknown value hint {} for series {}", byte, key)))
+    }
+
+    pub(super) fn value_codec(&mut self, key: &str) -> io::Result<ValueCodec> {
+        let byte = self.u8("value codec")?;
+        ValueCodec::from_byte(byte)
+            .ok_or_else(|| invalid(format!("unknown value codec {} for series {}", byte, key)))
+    }
+
+    fn opt_string(&mut self, what: &str) -> io::Result<Option<String>> {
+        match self.u8(what)? {
+            0 => Ok(None),
+            1 => self.string(what).map(Some),
+            flag => Err(invalid(format!("bad {} flag {}", what, flag))),
+        }
+    }
+}
+
+/// Series options in the current snapshot encoding (the options byte
+/// through the downsample tiers)
+pub(super) fn write_options(out: &mut impl Write, options: &SeriesOptions) -> io::Result<()> {
+    let block_duration = options
+        .block_duration
+        .unwrap_or(DEFAULT_BLOCK_DURATION_SECS);
+    out.write_all(&[options_to_byte(options)])?;
+    out.write_all(&block_duration.to_le_bytes())?;
+    write_len(out, options.max_points_per_block.unwrap_or(0))?;
+    out.write_all(&[options.timestamp_codec.to_byte()])?;
+    out.write_all(&[options.value_hint.to_byte()])?;
+    out.write_all(&options.late_grace.unwrap_or(0).to_le_bytes())?;
+    out.write_all(&[codec_choice_to_byte(options.value_codec)])?;
+    out.write_all(&options.align_origin.to_le_bytes())?;
+    out.write_all(&options.retention.unwrap_or(0).to_le_bytes())?;
+    write_tiers(out, &options.downsample)
+}
+
+pub(super) fn write_len(out: &mut impl Write, len: usize) -> io::Result<()> {
+    let len = u32::try_from(len).map_err(|_| invalid(format!("length {} too large", len)))?;
+    out.write_all(&len.to_le_bytes())
+}
+
+pub(super) fn write_str(out: &mut impl Write, value: &str) -> io::Result<()> {
+    write_len(out, value.len())?;
+    out.write_all(value.as_bytes())
+}
+
+fn write_labels(out: &mut impl Write, labels: Option<&SeriesLabels>) -> io::Result<()> {
+    let Some(labels) = labels else {
+        return out.write_all(&[0]);
+    };
+
+    out.write_all(&[1])?;
+    write_str(out, &labels.name)?;
+    write_len(out, labels.labels.len())?;
+    for (label, value) in &labels.labels {
+        write_str(out, label)?;
+        write_str(out, value)?;
+    }
+    Ok(())
+}
+
+fn write_tiers(out: &mut impl Write, tiers: &[DownsampleTier]) -> io::Result<()> {
+    let count = u8::try_from(tiers.len())
+        .map_err(|_| invalid(format!("too many downsample tiers ({})", tiers.len())))?;
+    out.write_all(&[count])?;
+    for tier in tiers {
+        out.write_all(&tier.width.to_le_bytes())?;
+        out.write_all(&[tier.aggregation.to_byte()])?;
+        out.write_all(&tier.retention.unwrap_or(0).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn write_opt_str(out: &mut impl Write, value: Option<&str>) -> io::Result<()> {
+    match value {
+        Some(value) => {
+            out.write_all(&[1])?;
+            write_str(out, value)
+        }
+        None => out.write_all(&[0]),
+    }
+}
+
+pub(super) fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// An `InvalidData` error wrapping TsdbError::UnsupportedVersion
+pub(super) fn unsupported(found: u32, supported: u32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        TsdbError::UnsupportedVersion { found, supported },
+    )
+}
+
+fn options_to_byte(options: &SeriesOptions) -> u8 {
+    let mut byte = policy_to_byte(options.duplicate_policy);
+    if options.drop_raw_on_close {
+        byte |= DROP_RAW_FLAG;
+    }
+    byte
+}
+
+fn options_from_byte(byte: u8) -> Option<SeriesOptions> {
+    Some(SeriesOptions {
+        duplicate_policy: policy_from_byte(byte & !DROP_RAW_FLAG)?,
+        drop_raw_on_close: byte & DROP_RAW_FLAG != 0,
+        ..SeriesOptions::default()
+    })
+}
+
+fn policy_to_byte(policy: DuplicatePolicy) -> u8 {
+    match policy {
+        DuplicatePolicy::KeepLast => 0,
+        DuplicatePolicy::KeepFirst => 1,
+        DuplicatePolicy::Reject => 2,
+    }
+}
+
+fn policy_from_byte(byte: u8) -> Option<DuplicatePolicy> {
+    match byte {
+        0 => Some(DuplicatePolicy::KeepLast),
+        1 => Some(DuplicatePolicy::KeepFirst),
+        2 => Some(DuplicatePolicy::Reject),
+        _ => None,
+    }
+}
+
+// Stands for CodecChoice::Auto; fixed codecs are stored as their own byte
+const AUTO_CODEC_BYTE: u8 = 0xFF;
+
+fn codec_choice_to_byte(choice: CodecChoice) -> u8 {
+    match choice {
+        CodecChoice::Fixed(codec) => codec.to_byte(),
+        CodecChoice::Auto => AUTO_CODEC_BYTE,
+    }
+}
+
+fn codec_choice_from_byte(byte: u8) -> Option<CodecChoice> {
+    match byte {
+        AUTO_CODEC_BYTE => Some(CodecChoice::Auto),
+        _ => ValueCodec::from_byte(byte).map(CodecChoice::Fixed),
+    }
+}
@@ -0,0 +1,190 @@
+// Spilling cold closed blocks to disk
+//
+// A closed block that hasn't been read for a while (or that has to go to
+// keep the map under its memory budget) gets its header and compressed
+// bytes written to a file in the spill directory. The in-memory copy is
+// dropped and queries read the file back when they touch the block.
+// Spill files are a cache of in-memory state, not a persistence format:
+// a file is removed as soon as the block that owns it is dropped or
+// brought back into memory.
+//
+// File layout (all integers little-endian):
+//   magic "TSDBSPIL", format version u8 (FORMAT_VERSION), start time
+//   u64, point count u32, compressed length u32, compressed bytes
+//
+// Spill files never outlive the process that wrote them, so there is
+// nothing to migrate: any other version is refused.
+
+use crate::compression::FORMAT_VERSION;
+use crate::tsdb::TsdbError;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const SPILL_MAGIC: &[u8; 8] = b"TSDBSPIL";
+const SPILL_HEADER_LEN: usize = 8 + 1 + 8 + 4 + 4;
+
+/// When and where closed blocks are spilled
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpillConfig {
+    /// Directory holding spill files (created if missing)
+    pub dir: PathBuf,
+
+    /// Seconds a closed block may go unread before it is spilled
+    pub idle_secs: u64,
+
+    /// Spill least recently read blocks while the map uses more than
+    /// this many bytes (see TimeSeriesMap::memory_usage)
+    pub memory_budget: Option<usize>,
+}
+
+/// Outcome of one spill pass
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SpillReport {
+    pub blocks_spilled: usize, // Blocks moved to disk by this pass
+    pub bytes_spilled: usize,  // Compressed bytes written for them
+}
+
+/// Counters shared by a map and every file it spilled
+#[derive(Debug, Default)]
+pub(crate) struct SpillCounters {
+    reloads: AtomicU64,
+    read_errors: AtomicU64,
+}
+
+impl SpillCounters {
+    /// Spilled blocks read back from disk by queries or writes
+    pub fn reloads(&self) -> u64 {
+        self.reloads.load(Ordering::Relaxed)
+    }
+
+    /// Spill files that could not be read back
+    pub fn read_errors(&self) -> u64 {
+        self.read_errors.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn record_read_error(&self) {
+        self.read_errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A block's spill file; deleting the handle deletes the file
+#[derive(Debug)]
+pub(crate) struct SpillFile {
+    path: PathBuf,
+    counters: Arc<SpillCounters>,
+}
+
+impl SpillFile {
+    /// Write a block's compressed bytes to a new file in `dir`
+    ///
+    /// Files are named by the key's hash and the block start; a numeric
+    /// suffix keeps names unique when a key is reused.
+    pub fn write(
+        dir: &Path,
+        key: &str,
+        start_time: u64,
+        point_count: usize,
+        data: &[u8],
+        counters: Arc<SpillCounters>,
+    ) -> io::Result<SpillFile> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let stem = format!("{:016x}-{}", hasher.finish(), start_time);
+
+        let mut bytes = Vec::with_capacity(SPILL_HEADER_LEN + data.len());
+        bytes.extend_from_slice(SPILL_MAGIC);
+        bytes.push(FORMAT_VERSION);
+        bytes.extend_from_slice(&start_time.to_le_bytes());
+        bytes.extend_from_slice(&len_u32(point_count)?.to_le_bytes());
+        bytes.extend_from_slice(&len_u32(data.len())?.to_le_bytes());
+        bytes.extend_from_slice(data);
+
+        for attempt in 0.. {
+            let name = match attempt {
+                0 => format!("{}.blk", stem),
+                n => format!("{}-{}.blk", stem, n),
+            };
+            let path = dir.join(name);
+            let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            };
+
+            // Own the path before writing, so a failed write cleans up
+            let spilled = SpillFile { path, counters };
+            file.write_all(&bytes)?;
+            return Ok(spilled);
+        }
+        unreachable!("unbounded attempts")
+    }
+
+    /// Read the compressed bytes back, checking them against the block
+    pub fn read(&self, start_time: u64, point_count: usize) -> io::Result<Vec<u8>> {
+        self.counters.reloads.fetch_add(1, Ordering::Relaxed);
+
+        let mut bytes = Vec::new();
+        File::open(&self.path)?.read_to_end(&mut bytes)?;
+
+        let header = bytes
+            .get(..SPILL_HEADER_LEN)
+            .ok_or_else(|| self.invalid("truncated header"))?;
+        let field = |at: usize, len: usize| {
+            let mut value = [0u8; 8];
+            value[..len].copy_from_slice(&header[at..at + len]);
+            u64::from_le_bytes(value)
+        };
+        if &header[..8] != SPILL_MAGIC {
+            return Err(self.invalid("bad magic"));
+        }
+        if header[8] != FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                TsdbError::UnsupportedVersion {
+                    found: header[8].into(),
+                    supported: FORMAT_VERSION.into(),
+                },
+            ));
+        }
+        if field(9, 8) != start_time || field(17, 4) != point_count as u64 {
+            return Err(self.invalid("header does not match the block"));
+        }
+        if field(21, 4) as usize != bytes.len() - SPILL_HEADER_LEN {
+            return Err(self.invalid("length mismatch"));
+        }
+
+        bytes.drain(..SPILL_HEADER_LEN);
+        Ok(bytes)
+    }
+
+    pub(super) fn counters(&self) -> &SpillCounters {
+        &self.counters
+    }
+
+    fn invalid(&self, reason: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("spill file {}: {}", self.path.display(), reason),
+        )
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn len_u32(len: usize) -> io::Result<u32> {
+    u32::try_from(len).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("length {} too large", len),
+        )
+    })
+}
@@ -0,0 +1,918 @@
+// Write-ahead log (WAL) for inserts
+// Complements snapshots: everything written since the last snapshot can
+// be replayed after a restart.
+//
+// The log is a directory of numbered segment files (wal-00000001.log, ...).
+// Each segment starts with a header and is self-contained: keys are
+// interned per segment, so a key-definition record precedes the first
+// record that refers to it.
+//
+// Segment layout (all integers little-endian):
+//   magic "TSDBWAL\0", version u32
+//   records, each framed as payload length u32, CRC-32 of payload u32,
+//   then the payload, which starts with a tag byte:
+//     KEY    key id u32, key length u32, key bytes
+//     LABELS key id u32, metric name (length u32, bytes), label count u32,
+//            per label its name and value (each length u32, bytes);
+//            defines the key `SeriesLabels::series_key()` with its labels
+//     INSERT key id u32, timestamp u64, value bits u64
+//     DELETE key id u32
+//     RENAME old key id u32, new key id u32
+//     FREEZE key id u32, frozen u8 (1 to freeze, 0 to unfreeze)
+//     CREATE key id u32, snapshot version u32, then the series options as
+//            snapshots of that version write them (version 3+)
+
+use super::SeriesOptions;
+use super::labels::SeriesLabels;
+use super::snapshot::{SNAPSHOT_VERSION, SnapshotReader, write_options};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const WAL_MAGIC: &[u8; 8] = b"TSDBWAL\0";
+
+/// Current WAL segment format version
+///
+/// Version 2 segments only lack CREATE records, so they are still read.
+pub const WAL_VERSION: u32 = 3;
+
+const HEADER_LEN: u64 = 12;
+
+// Length prefix + CRC in front of every record
+const FRAME_LEN: usize = 8;
+
+/// Largest record payload the writer produces (bounds key length)
+pub const MAX_RECORD_BYTES: usize = 1 << 20;
+
+const TAG_KEY: u8 = 1;
+const TAG_INSERT: u8 = 2;
+const TAG_DELETE: u8 = 3;
+const TAG_RENAME: u8 = 4;
+const TAG_LABELED_KEY: u8 = 5;
+const TAG_FREEZE: u8 = 6;
+const TAG_CREATE: u8 = 7;
+
+/// A position in the log: records before it are already reflected
+/// elsewhere (e.g. in a snapshot)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WalPosition {
+    pub segment: u64,
+    pub offset: u64,
+}
+
+/// A logged operation, as handed to the replay callback
+#[derive(Debug, Clone, PartialEq)]
+pub enum WalRecord {
+    Insert {
+        key: String,
+        labels: Option<SeriesLabels>, // Set for series created with labels
+        timestamp: u64,
+        value: f64,
+    },
+    Delete {
+        key: String,
+    },
+    Rename {
+        old_key: String,
+        new_key: String,
+    },
+    Freeze {
+        key: String,
+        frozen: bool, // False when the series was unfrozen
+    },
+    CreateSeries {
+        key: String,
+        options: SeriesOptions,
+    },
+}
+
+/// What a replay went through
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct WalReplay {
+    pub segments: usize,        // Segment files read
+    pub records_applied: usize, // Records handed to the callback
+    pub records_skipped: usize, // Records before the start position
+    pub torn_segments: usize,   // Segments that ended in a torn record
+    pub bytes_discarded: u64,   // Bytes of torn records dropped
+}
+
+/// A record in the middle of a segment failed to parse or verify
+///
+/// Returned inside an `InvalidData` io::Error (reach it with
+/// `err.get_ref()` and `downcast_ref`). Nothing after `offset` in
+/// `segment` was applied, so the caller can repair or move the segment
+/// aside and replay again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalCorruption {
+    pub segment: PathBuf,
+    pub offset: u64,
+    pub reason: String,
+}
+
+impl fmt::Display for WalCorruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "corrupt WAL segment {} at offset {}: {}",
+            self.segment.display(),
+            self.offset,
+            self.reason
+        )
+    }
+}
+
+impl Error for WalCorruption {}
+
+/// Appends records to the current segment, rotating by size
+pub(crate) struct WalWriter {
+    dir: PathBuf,
+    segment: u64,
+    out: BufWriter<File>,
+
+    // Bytes written to the current segment (including buffered ones)
+    segment_len: u64,
+    max_segment_bytes: u64,
+
+    // Records appended since the last fsync
+    unsynced: usize,
+    sync_every: usize,
+
+    // Key interning for the current segment: id, and whether the
+    // definition carried labels
+    key_ids: HashMap<String, (u32, bool)>,
+    next_key_id: u32,
+}
+
+impl WalWriter {
+    /// Open a log in `dir`, starting a fresh segment after any existing ones
+    ///
+    /// Existing segments are never appended to, so a torn tail left by a
+    /// crash stays at the end of its own segment.
+    pub fn open(dir: &Path, max_segment_bytes: u64, sync_every: usize) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let next = list_segments(dir)?.last().map_or(1, |&(n, _)| n + 1);
+
+        Ok(WalWriter {
+            dir: dir.to_path_buf(),
+            segment: next,
+            out: create_segment(dir, next)?,
+            segment_len: HEADER_LEN,
+            max_segment_bytes,
+            unsynced: 0,
+            sync_every: sync_every.max(1),
+            key_ids: HashMap::new(),
+            next_key_id: 0,
+        })
+    }
+
+    /// Position just after the last appended record
+    pub fn position(&self) -> WalPosition {
+        WalPosition {
+            segment: self.segment,
+            offset: self.segment_len,
+        }
+    }
+
+    /// Log an insert
+    pub fn append_insert(&mut self, key: &str, timestamp: u64, value: f64) -> io::Result<()> {
+        self.prepare()?;
+        let id = self.key_id(key, None)?;
+        self.write_insert(id, timestamp, value)
+    }
+
+    /// Log an insert into a labeled series
+    pub fn append_insert_labeled(
+        &mut self,
+        labels: &SeriesLabels,
+        timestamp: u64,
+        value: f64,
+    ) -> io::Result<()> {
+        self.prepare()?;
+        let id = self.key_id(&labels.series_key(), Some(labels))?;
+        self.write_insert(id, timestamp, value)
+    }
+
+    fn write_insert(&mut self, id: u32, timestamp: u64, value: f64) -> io::Result<()> {
+        let mut record = Vec::with_capacity(21);
+        record.push(TAG_INSERT);
+        record.extend_from_slice(&id.to_le_bytes());
+        record.extend_from_slice(&timestamp.to_le_bytes());
+        record.extend_from_slice(&value.to_bits().to_le_bytes());
+        self.write_record(&record)
+    }
+
+    /// Log a series deletion
+    pub fn append_delete(&mut self, key: &str) -> io::Result<()> {
+        self.prepare()?;
+        let id = self.key_id(key, None)?;
+
+        let mut record = vec![TAG_DELETE];
+        record.extend_from_slice(&id.to_le_bytes());
+        self.write_record(&record)
+    }
+
+    /// Log a series rename
+    pub fn append_rename(&mut self, old_key: &str, new_key: &str) -> io::Result<()> {
+        self.prepare()?;
+        let old_id = self.key_id(old_key, None)?;
+        let new_id = self.key_id(new_key, None)?;
+
+        let mut record = vec![TAG_RENAME];
+        record.extend_from_slice(&old_id.to_le_bytes());
+        record.extend_from_slice(&new_id.to_le_bytes());
+        self.write_record(&record)
+    }
+
+    /// Log a series being frozen or unfrozen
+    pub fn append_freeze(&mut self, key: &str, frozen: bool) -> io::Result<()> {
+        self.prepare()?;
+        let id = self.key_id(key, None)?;
+
+        let mut record = vec![TAG_FREEZE];
+        record.extend_from_slice(&id.to_le_bytes());
+        record.push(frozen as u8);
+        self.write_record(&record)
+    }
+
+    /// Log a series created with its own options
+    pub fn append_create(&mut self, key: &str, options: &SeriesOptions) -> io::Result<()> {
+        self.prepare()?;
+        let id = self.key_id(key, None)?;
+
+        let mut record = vec![TAG_CREATE];
+        record.extend_from_slice(&id.to_le_bytes());
+        record.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        write_options(&mut record, options)?;
+        self.write_record(&record)
+    }
+
+    /// Flush buffered records and fsync the current segment
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        self.out.get_ref().sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Rotate to a new segment once the current one is full
+    fn prepare(&mut self) -> io::Result<()> {
+        if self.segment_len < self.max_segment_bytes {
+            return Ok(());
+        }
+
+        self.sync()?;
+        self.segment += 1;
+        self.out = create_segment(&self.dir, self.segment)?;
+        self.segment_len = HEADER_LEN;
+        self.key_ids.clear();
+        self.next_key_id = 0;
+        Ok(())
+    }
+
+    /// Interned id for a key, writing its definition on first use
+    ///
+    /// A key first defined without labels is defined again (under a new
+    /// id) the first time it is used with labels.
+    fn key_id(&mut self, key: &str, labels: Option<&SeriesLabels>) -> io::Result<u32> {
+        if let Some(&(id, labeled)) = self.key_ids.get(key)
+            && (labeled || labels.is_none())
+        {
+            return Ok(id);
+        }
+
+        let id = self.next_key_id;
+        let record = match labels {
+            Some(labels) => {
+                let mut record = vec![TAG_LABELED_KEY];
+                record.extend_from_slice(&id.to_le_bytes());
+                push_str(&mut record, &labels.name);
+                record.extend_from_slice(&(labels.labels.len() as u32).to_le_bytes());
+                for (label, value) in &labels.labels {
+                    push_str(&mut record, label);
+                    push_str(&mut record, value);
+                }
+                record
+            }
+            None => {
+                let mut record = vec![TAG_KEY];
+                record.extend_from_slice(&id.to_le_bytes());
+                push_str(&mut record, key);
+                record
+            }
+        };
+        self.write_frame(&record)?;
+
+        self.next_key_id += 1;
+        self.key_ids.insert(key.to_string(), (id, labels.is_some()));
+        Ok(id)
+    }
+
+    /// Write one framed record with a single write call
+    fn write_frame(&mut self, payload: &[u8]) -> io::Result<()> {
+        if payload.len() > MAX_RECORD_BYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("WAL record of {} bytes is too large", payload.len()),
+            ));
+        }
+
+        let mut frame = Vec::with_capacity(FRAME_LEN + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32(payload).to_le_bytes());
+        frame.extend_from_slice(payload);
+
+        self.out.write_all(&frame)?;
+        self.segment_len += frame.len() as u64;
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        self.write_frame(record)?;
+
+        self.unsynced += 1;
+        if self.unsynced >= self.sync_every {
+            self.sync()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WalWriter {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+/// Replay every segment in `dir` in order, starting at `from`
+///
+/// Records before `from` are skipped (key definitions are still read).
+///
+/// A torn record at the end of a segment (its length runs past the end
+/// of the file, or it is the last record and its CRC fails) is what a
+/// crash mid-write leaves behind: replay of that segment stops there and
+/// the dropped bytes are counted in the report. With `truncate_torn` the
+/// segment is also cut back to its last complete record.
+///
+/// A bad record followed by more data is real corruption: replay stops
+/// with an `InvalidData` error carrying a `WalCorruption`.
+pub(crate) fn replay<F>(
+    dir: &Path,
+    from: WalPosition,
+    truncate_torn: bool,
+    mut apply: F,
+) -> io::Result<WalReplay>
+where
+    F: FnMut(WalRecord),
+{
+    let mut report = WalReplay::default();
+
+    for (segment, path) in list_segments(dir)? {
+        if segment < from.segment {
+            continue;
+        }
+        let skip_before = if segment == from.segment {
+            from.offset
+        } else {
+            0
+        };
+
+        let data = fs::read(&path)?;
+        report.segments += 1;
+        let end = replay_segment(&data, &path, skip_before, &mut report, &mut apply)?;
+
+        if end < data.len() {
+            report.torn_segments += 1;
+            report.bytes_discarded += (data.len() - end) as u64;
+            if truncate_torn {
+                let file = OpenOptions::new().write(true).open(&path)?;
+                file.set_len(end as u64)?;
+                file.sync_all()?;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Replay one segment, returning the offset just past its last good record
+fn replay_segment<F>(
+    data: &[u8],
+    path: &Path,
+    skip_before: u64,
+    report: &mut WalReplay,
+    apply: &mut F,
+) -> io::Result<usize>
+where
+    F: FnMut(WalRecord),
+{
+    if data.len() < HEADER_LEN as usize {
+        return Ok(0); // Crashed before the header was written
+    }
+    if &data[..8] != WAL_MAGIC {
+        return Err(corrupt(path, 0, "bad magic".to_string()));
+    }
+    let version = u32_at(data, 8);
+    if !(2..=WAL_VERSION).contains(&version) {
+        return Err(corrupt(path, 8, format!("unsupported version {}", version)));
+    }
+
+    let mut keys: HashMap<u32, (String, Option<SeriesLabels>)> = HashMap::new();
+    let mut pos = HEADER_LEN as usize;
+
+    while pos < data.len() {
+        let offset = pos;
+        let remaining = data.len() - pos;
+        if remaining < FRAME_LEN {
+            return Ok(offset); // Torn inside the frame header
+        }
+
+        let len = u32_at(data, pos) as usize;
+        let crc = u32_at(data, pos + 4);
+        if len == 0 || len > MAX_RECORD_BYTES {
+            if data[pos..].iter().all(|&byte| byte == 0) {
+                return Ok(offset); // Zero-filled tail: file grew, data never landed
+            }
+            return Err(corrupt(
+                path,
+                offset as u64,
+                format!("bad record length {}", len),
+            ));
+        }
+        if len > remaining - FRAME_LEN {
+            return Ok(offset); // Torn payload: the record was only partly written
+        }
+
+        let payload = &data[pos + FRAME_LEN..pos + FRAME_LEN + len];
+        pos += FRAME_LEN + len;
+        if crc32(payload) != crc {
+            if pos == data.len() {
+                return Ok(offset); // Last record never fully reached disk
+            }
+            return Err(corrupt(path, offset as u64, "CRC mismatch".to_string()));
+        }
+
+        let record = match parse_record(payload, &mut keys) {
+            Ok(Some(record)) => record,
+            Ok(None) => continue, // Key definition
+            Err(reason) => return Err(corrupt(path, offset as u64, reason)),
+        };
+
+        if (offset as u64) < skip_before {
+            report.records_skipped += 1;
+        } else {
+            report.records_applied += 1;
+            apply(record);
+        }
+    }
+
+    Ok(data.len())
+}
+
+/// Decode a verified payload; key definitions update `keys` and yield None
+fn parse_record(
+    payload: &[u8],
+    keys: &mut HashMap<u32, (String, Option<SeriesLabels>)>,
+) -> Result<Option<WalRecord>, String> {
+    let tag = payload[0];
+    let body = &payload[1..];
+
+    if tag == TAG_LABELED_KEY {
+        let (id, labels) =
+            parse_labeled_key(body).ok_or_else(|| "malformed labeled key record".to_string())?;
+        keys.insert(id, (labels.series_key(), Some(labels)));
+        return Ok(None);
+    }
+    if tag == TAG_CREATE {
+        return parse_create(body, keys).map(Some);
+    }
+
+    let expected = match tag {
+        TAG_KEY if body.len() >= 8 => 8 + u32_at(body, 4) as usize,
+        TAG_KEY => 8,
+        TAG_INSERT => 20,
+        TAG_DELETE => 4,
+        TAG_RENAME => 8,
+        TAG_FREEZE => 5,
+        _ => return Err(format!("unknown record tag {}", tag)),
+    };
+    if body.len() != expected {
+        return Err(format!(
+            "record tag {} has {} bytes of body",
+            tag,
+            body.len()
+        ));
+    }
+
+    let entry = |id: u32| {
+        keys.get(&id)
+            .cloned()
+            .ok_or_else(|| format!("undefined key id {}", id))
+    };
+    let key = |id: u32| entry(id).map(|(key, _)| key);
+
+    let record = match tag {
+        TAG_KEY => {
+            let name = String::from_utf8(body[8..].to_vec())
+                .map_err(|_| "key is not valid UTF-8".to_string())?;
+            keys.insert(u32_at(body, 0), (name, None));
+            return Ok(None);
+        }
+        TAG_INSERT => {
+            let (key, labels) = entry(u32_at(body, 0))?;
+            WalRecord::Insert {
+                key,
+                labels,
+                timestamp: u64_at(body, 4),
+                value: f64::from_bits(u64_at(body, 12)),
+            }
+        }
+        TAG_DELETE => WalRecord::Delete {
+            key: key(u32_at(body, 0))?,
+        },
+        TAG_FREEZE => WalRecord::Freeze {
+            key: key(u32_at(body, 0))?,
+            frozen: body[4] != 0,
+        },
+        _ => WalRecord::Rename {
+            old_key: key(u32_at(body, 0))?,
+            new_key: key(u32_at(body, 4))?,
+        },
+    };
+    Ok(Some(record))
+}
+
+/// Decode a CREATE record body, which must be consumed exactly
+fn parse_create(
+    body: &[u8],
+    keys: &HashMap<u32, (String, Option<SeriesLabels>)>,
+) -> Result<WalRecord, String> {
+    let mut reader = SnapshotReader::new(body, "create record");
+    let id = reader.u32("key id").map_err(|e| e.to_string())?;
+    let key = keys
+        .get(&id)
+        .map(|(key, _)| key.clone())
+        .ok_or_else(|| format!("undefined key id {}", id))?;
+    let version = reader.u32("options version").map_err(|e| e.to_string())?;
+    if version == 0 || version > SNAPSHOT_VERSION {
+        return Err(format!("unsupported options version {}", version));
+    }
+    let options = reader.options(&key, version).map_err(|e| e.to_string())?;
+    if reader.remaining() != 0 {
+        return Err(format!(
+            "create record has {} trailing bytes",
+            reader.remaining()
+        ));
+    }
+    Ok(WalRecord::CreateSeries { key, options })
+}
+
+/// Decode a LABELS record body, which must be consumed exactly
+fn parse_labeled_key(body: &[u8]) -> Option<(u32, SeriesLabels)> {
+    let mut reader = BodyReader { body, pos: 0 };
+
+    let id = reader.u32()?;
+    let mut labels = SeriesLabels {
+        name: reader.string()?,
+        ..SeriesLabels::default()
+    };
+    for _ in 0..reader.u32()? {
+        let label = reader.string()?;
+        let value = reader.string()?;
+        labels.labels.insert(label, value);
+    }
+
+    (reader.pos == body.len()).then_some((id, labels))
+}
+
+/// Bounds-checked cursor over a variable-length record body
+struct BodyReader<'a> {
+    body: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BodyReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.body.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+}
+
+/// Segment files in `dir`, ordered by segment number
+fn list_segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    if !dir.exists() {
+        return Ok(segments);
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let number = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("wal-"))
+            .and_then(|name| name.strip_suffix(".log"))
+            .and_then(|number| number.parse::<u64>().ok());
+        if let Some(number) = number {
+            segments.push((number, path));
+        }
+    }
+
+    segments.sort();
+    Ok(segments)
+}
+
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("wal-{:08}.log", segment))
+}
+
+fn create_segment(dir: &Path, segment: u64) -> io::Result<BufWriter<File>> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(segment_path(dir, segment))?;
+
+    let mut out = BufWriter::new(file);
+    out.write_all(WAL_MAGIC)?;
+    out.write_all(&WAL_VERSION.to_le_bytes())?;
+    Ok(out)
+}
+
+fn push_str(record: &mut Vec<u8>, value: &str) {
+    record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    record.extend_from_slice(value.as_bytes());
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+fn corrupt(path: &Path, offset: u64, reason: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        WalCorruption {
+            segment: path.to_path_buf(),
+            offset,
+            reason,
+        },
+    )
+}
+
+/// CRC-32 (IEEE, as used by zlib and Ethernet)
+pub(super) fn crc32(bytes: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc = TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("tsdb-test-{}", std::process::id()))
+            .join(name);
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn collect(dir: &Path, from: WalPosition) -> (Vec<WalRecord>, WalReplay) {
+        let mut records = Vec::new();
+        let report = replay(dir, from, false, |record| records.push(record)).unwrap();
+        (records, report)
+    }
+
+    #[test]
+    fn test_rotation_and_interning() {
+        let dir = temp_dir("wal_rotation");
+        let mut wal = WalWriter::open(&dir, 256, 10).unwrap();
+        for i in 0..100u64 {
+            let key = format!("host{}.cpu", i % 3);
+            wal.append_insert(&key, 1000 + i, i as f64).unwrap();
+        }
+        wal.append_rename("host0.cpu", "host9.cpu").unwrap();
+        wal.append_delete("host1.cpu").unwrap();
+        drop(wal);
+
+        let segments = list_segments(&dir).unwrap();
+        assert!(segments.len() > 1, "expected rotation");
+
+        let (records, report) = collect(&dir, WalPosition::default());
+        assert_eq!(report.segments, segments.len());
+        assert_eq!(records.len(), 102);
+        assert_eq!(
+            records[99],
+            WalRecord::Insert {
+                key: "host0.cpu".to_string(),
+                labels: None,
+                timestamp: 1099,
+                value: 99.0
+            }
+        );
+        assert_eq!(
+            records[101],
+            WalRecord::Delete {
+                key: "host1.cpu".to_string()
+            }
+        );
+
+        // Reopening never appends to an existing segment
+        let wal = WalWriter::open(&dir, 256, 10).unwrap();
+        assert_eq!(wal.position().segment, segments.len() as u64 + 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Key definition for "cpu" and one insert, each with its frame
+    const KEY_FRAME: usize = FRAME_LEN + 1 + 8 + 3;
+    const INSERT_FRAME: usize = FRAME_LEN + 21;
+
+    fn write_cpu_segment(dir: &Path, points: u64) -> PathBuf {
+        let mut wal = WalWriter::open(dir, 1 << 20, 1000).unwrap();
+        for i in 0..points {
+            wal.append_insert("cpu", 1000 + i, i as f64).unwrap();
+        }
+        wal.sync().unwrap();
+        drop(wal);
+        list_segments(dir).unwrap().pop().unwrap().1
+    }
+
+    #[test]
+    fn test_torn_tail_recovers_complete_records() {
+        let dir = temp_dir("wal_torn");
+        let path = write_cpu_segment(&dir, 50);
+        let bytes = fs::read(&path).unwrap();
+
+        // Cut the segment at every length: replay keeps exactly the
+        // records that were completely written
+        for len in 0..bytes.len() {
+            fs::write(&path, &bytes[..len]).unwrap();
+            let (records, report) = collect(&dir, WalPosition::default());
+
+            let body = len.saturating_sub(HEADER_LEN as usize + KEY_FRAME);
+            assert_eq!(records.len(), body / INSERT_FRAME, "cut at {}", len);
+            if let Some(WalRecord::Insert { timestamp, .. }) = records.last() {
+                assert_eq!(*timestamp, 1000 + records.len() as u64 - 1);
+            }
+
+            let header = HEADER_LEN as usize;
+            let complete = if len < header {
+                0
+            } else if len < header + KEY_FRAME {
+                header
+            } else {
+                header + KEY_FRAME + records.len() * INSERT_FRAME
+            };
+            assert_eq!(
+                report.bytes_discarded,
+                (len - complete) as u64,
+                "cut at {}",
+                len
+            );
+        }
+
+        // A last record whose bytes never fully landed fails its CRC
+        let mut bad = bytes.clone();
+        let last = bad.len() - 1;
+        bad[last] ^= 0xFF;
+        fs::write(&path, &bad).unwrap();
+        let (records, report) = collect(&dir, WalPosition::default());
+        assert_eq!(records.len(), 49);
+        assert_eq!(report.torn_segments, 1);
+        assert_eq!(report.bytes_discarded, INSERT_FRAME as u64);
+
+        // Zero-filled tail
+        let mut padded = bytes.clone();
+        padded.extend_from_slice(&[0; 64]);
+        fs::write(&path, &padded).unwrap();
+        let (records, report) = collect(&dir, WalPosition::default());
+        assert_eq!(records.len(), 50);
+        assert_eq!(report.bytes_discarded, 64);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_truncate_torn_tail() {
+        let dir = temp_dir("wal_truncate");
+        let path = write_cpu_segment(&dir, 10);
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 7]).unwrap();
+
+        let report = replay(&dir, WalPosition::default(), true, |_| {}).unwrap();
+        assert_eq!(report.records_applied, 9);
+        assert_eq!(report.bytes_discarded, (INSERT_FRAME - 7) as u64);
+        assert_eq!(
+            fs::metadata(&path).unwrap().len() as usize,
+            bytes.len() - INSERT_FRAME
+        );
+
+        // The cleaned segment replays without discarding anything
+        let (records, report) = collect(&dir, WalPosition::default());
+        assert_eq!(records.len(), 9);
+        assert_eq!(report.bytes_discarded, 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_middle_record_is_an_error() {
+        let dir = temp_dir("wal_middle");
+        let path = write_cpu_segment(&dir, 50);
+        let bytes = fs::read(&path).unwrap();
+
+        // Flip a payload byte of the 11th insert
+        let offset = HEADER_LEN as usize + KEY_FRAME + 10 * INSERT_FRAME;
+        let mut bad = bytes.clone();
+        bad[offset + FRAME_LEN + 6] ^= 0x01;
+        fs::write(&path, &bad).unwrap();
+
+        let mut applied = 0;
+        let err = replay(&dir, WalPosition::default(), true, |_| applied += 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let corruption = err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<WalCorruption>())
+            .unwrap();
+        assert_eq!(corruption.segment, path);
+        assert_eq!(corruption.offset, offset as u64);
+        assert!(err.to_string().contains("CRC"), "{}", err);
+        assert!(err.to_string().contains("wal-00000001.log"), "{}", err);
+        assert_eq!(applied, 10);
+
+        // Corruption is never "repaired" by truncation
+        assert_eq!(fs::metadata(&path).unwrap().len() as usize, bytes.len());
+
+        // A garbage length in the middle is corruption too
+        let mut bad = bytes.clone();
+        bad[offset..offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, &bad).unwrap();
+        let err = replay(&dir, WalPosition::default(), false, |_| {}).unwrap_err();
+        assert!(err.to_string().contains("bad record length"), "{}", err);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_from_position() {
+        let dir = temp_dir("wal_position");
+        let mut wal = WalWriter::open(&dir, 1 << 20, 1000).unwrap();
+        for i in 0..10u64 {
+            wal.append_insert("cpu", i, i as f64).unwrap();
+        }
+        let mark = wal.position();
+        for i in 10..30u64 {
+            wal.append_insert("cpu", i, i as f64).unwrap();
+        }
+        drop(wal);
+
+        let (records, report) = collect(&dir, mark);
+        assert_eq!(records.len(), 20);
+        assert_eq!(report.records_skipped, 10);
+        assert!(matches!(
+            records[0],
+            WalRecord::Insert { timestamp: 10, .. }
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+// Arrow export of a series
+//
+// A series' points become a RecordBatch with a non-null `timestamp`
+// column (TimestampSecond) and a non-null `value` column (Float64), which
+// DataFusion and other Arrow tools can query directly.
+
+use super::Gorilla;
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, TimestampSecondArray};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use std::sync::Arc;
+
+/// The schema of every batch from Gorilla::to_record_batch
+pub fn series_schema() -> Schema {
+    Schema::new(vec![
+        Field::new(
+            "timestamp",
+            DataType::Timestamp(TimeUnit::Second, None),
+            false,
+        ),
+        Field::new("value", DataType::Float64, false),
+    ])
+}
+
+impl Gorilla {
+    /// A series' points in [start, end] as an Arrow RecordBatch
+    ///
+    /// Returns None if the series doesn't exist; an empty range gives a
+    /// batch with no rows. Timestamps past i64::MAX are clamped to it.
+    pub fn to_record_batch(&self, key: &str, start: u64, end: u64) -> Option<RecordBatch> {
+        let points = self.query(key, start, end)?;
+        let timestamps: TimestampSecondArray = points
+            .iter()
+            .map(|&(timestamp, _)| i64::try_from(timestamp).unwrap_or(i64::MAX))
+            .collect::<Vec<_>>()
+            .into();
+        let values: Float64Array = points
+            .iter()
+            .map(|&(_, value)| value)
+            .collect::<Vec<_>>()
+            .into();
+        let columns: Vec<ArrayRef> = vec![Arc::new(timestamps), Arc::new(values)];
+        let batch = RecordBatch::try_new(Arc::new(series_schema()), columns)
+            .expect("columns match the series schema");
+        Some(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::Array;
+
+    #[test]
+    fn test_to_record_batch() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        for i in 0..5 {
+            gorilla.insert("cpu", base_time + i * 60, i as f64 * 1.5);
+        }
+
+        let batch = gorilla
+            .to_record_batch("cpu", base_time + 60, base_time + 180)
+            .unwrap();
+        assert_eq!(batch.schema().as_ref(), &series_schema());
+        assert_eq!(batch.num_rows(), 3);
+        let timestamps = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<TimestampSecondArray>()
+            .unwrap();
+        let values = batch
+            .column(1)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        let base = base_time as i64;
+        assert_eq!(
+            timestamps.values().to_vec(),
+            vec![base + 60, base + 120, base + 180]
+        );
+        assert_eq!(values.values().to_vec(), vec![1.5, 3.0, 4.5]);
+        assert_eq!(timestamps.null_count() + values.null_count(), 0);
+
+        let empty = gorilla.to_record_batch("cpu", 0, base_time - 1).unwrap();
+        assert_eq!((empty.num_rows(), empty.num_columns()), (0, 2));
+        assert!(gorilla.to_record_batch("mem", 0, u64::MAX).is_none());
+    }
+}
@@ -0,0 +1,151 @@
+// LRU cache of query results (see GorillaConfig::query_cache)
+//
+// Results are keyed by series key and range, and remember the handle of
+// the series they were read from, so a series deleted and created again
+// under the same key never serves the old one's points. The engine drops
+// a key's results after each write or delete to it. A result is stored
+// while the series' read lock is still held, and writers drop results
+// only once their change is in, so a result read before a write is
+// always dropped after it.
+
+use crate::storage::SeriesHandle;
+use std::collections::{BTreeMap, HashMap};
+
+pub(super) struct QueryCache {
+    capacity: usize,
+    // Results by series key, then by (start, end)
+    entries: HashMap<String, HashMap<(u64, u64), Entry>>,
+    // Every result by its last use, least recent first
+    recency: BTreeMap<u64, (String, u64, u64)>,
+    tick: u64,
+}
+
+struct Entry {
+    series: SeriesHandle,
+    points: Vec<(u64, f64)>,
+    used: u64,
+}
+
+impl QueryCache {
+    /// A cache holding up to `capacity` results
+    pub(super) fn new(capacity: usize) -> Self {
+        QueryCache {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// The cached points of [start, end] in `series`, stored under `key`
+    pub(super) fn get(
+        &mut self,
+        key: &str,
+        series: &SeriesHandle,
+        start: u64,
+        end: u64,
+    ) -> Option<Vec<(u64, f64)>> {
+        let entry = self.entries.get_mut(key)?.get_mut(&(start, end))?;
+        if !entry.series.same_series(series) {
+            return None;
+        }
+        self.tick += 1;
+        let range = self
+            .recency
+            .remove(&entry.used)
+            .expect("every result is in the recency order");
+        entry.used = self.tick;
+        self.recency.insert(self.tick, range);
+        Some(entry.points.clone())
+    }
+
+    /// Store the points of [start, end] in `series`, evicting the least
+    /// recently used results beyond the capacity
+    pub(super) fn insert(
+        &mut self,
+        key: &str,
+        series: &SeriesHandle,
+        start: u64,
+        end: u64,
+        points: &[(u64, f64)],
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        let entry = Entry {
+            series: series.clone(),
+            points: points.to_vec(),
+            used: self.tick,
+        };
+        let ranges = self.entries.entry(key.to_string()).or_default();
+        if let Some(old) = ranges.insert((start, end), entry) {
+            self.recency.remove(&old.used);
+        }
+        self.recency
+            .insert(self.tick, (key.to_string(), start, end));
+
+        while self.recency.len() > self.capacity {
+            let Some((_, (key, start, end))) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(ranges) = self.entries.get_mut(&key) {
+                ranges.remove(&(start, end));
+                if ranges.is_empty() {
+                    self.entries.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Drop every result for `key`
+    pub(super) fn invalidate(&mut self, key: &str) {
+        for entry in self
+            .entries
+            .remove(key)
+            .into_iter()
+            .flat_map(HashMap::into_values)
+        {
+            self.recency.remove(&entry.used);
+        }
+    }
+
+    /// Drop every result
+    pub(super) fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::TimeSeriesMap;
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let map = TimeSeriesMap::new();
+        map.insert("a", 100, 1.0);
+        map.insert("b", 100, 2.0);
+        let (a, b) = (map.get("a").unwrap(), map.get("b").unwrap());
+
+        let mut cache = QueryCache::new(2);
+        cache.insert("a", &a, 0, 10, &[(1, 1.0)]);
+        cache.insert("a", &a, 0, 20, &[(1, 1.0), (15, 1.5)]);
+        assert_eq!(cache.get("a", &a, 0, 10), Some(vec![(1, 1.0)]));
+        // (0, 20) is now the least recent
+        cache.insert("b", &b, 0, 10, &[(2, 2.0)]);
+        assert_eq!(cache.get("a", &a, 0, 20), None);
+        assert!(cache.get("a", &a, 0, 10).is_some());
+        assert!(cache.get("b", &b, 0, 10).is_some());
+
+        // A result is only served for the series it was read from
+        assert_eq!(cache.get("b", &a, 0, 10), None);
+
+        cache.invalidate("a");
+        assert_eq!(cache.get("a", &a, 0, 10), None);
+        assert_eq!(cache.recency.len(), 1);
+        cache.clear();
+        assert_eq!(cache.get("b", &b, 0, 10), None);
+    }
+}
@@ -0,0 +1,90 @@
+// Background closer for open blocks of series that went quiet
+//
+// Open blocks normally close when a point in a later window arrives. A
+// BlockCloser runs Gorilla::close_stale_blocks on a std thread once a
+// minute (by the engine's clock), so idle series get their last block
+// closed and rolled up too. The thread holds only a weak reference: it
+// stops when the closer is dropped or the engine goes away.
+
+use super::Gorilla;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How often the closer checks for stale open blocks
+const TICK: Duration = Duration::from_secs(60);
+
+/// Handle to a thread closing stale open blocks; dropping it stops the
+/// thread
+pub struct BlockCloser {
+    // Dropped to wake the thread and tell it to stop
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BlockCloser {
+    /// Start closing stale blocks of a shared engine every minute
+    pub fn spawn(gorilla: &Arc<Gorilla>) -> Self {
+        Self::with_tick(gorilla, TICK)
+    }
+
+    fn with_tick(gorilla: &Arc<Gorilla>, tick: Duration) -> Self {
+        let gorilla = Arc::downgrade(gorilla);
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || run(gorilla, stopped, tick));
+        BlockCloser {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for BlockCloser {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Thread body: close stale blocks every tick until told to stop
+fn run(gorilla: Weak<Gorilla>, stopped: mpsc::Receiver<()>, tick: Duration) {
+    while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(tick) {
+        let Some(gorilla) = gorilla.upgrade() else {
+            return;
+        };
+        gorilla.close_stale_blocks(gorilla.tsmap.now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::clock::{Clock, TestClock};
+    use crate::tsdb::GorillaConfig;
+
+    #[test]
+    fn test_closer_thread_closes_idle_blocks() {
+        let clock = Arc::new(TestClock::new(7200 * 100));
+        let gorilla = Arc::new(
+            Gorilla::with_config(GorillaConfig {
+                clock: clock.clone(),
+                ..GorillaConfig::default()
+            })
+            .unwrap(),
+        );
+        gorilla.insert("cpu", clock.now(), 1.0);
+
+        let closer = BlockCloser::with_tick(&gorilla, Duration::from_millis(5));
+        clock.advance(7200);
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while gorilla.metrics().blocks_closed == 0 {
+            assert!(std::time::Instant::now() < deadline, "block never closed");
+            thread::sleep(Duration::from_millis(5));
+        }
+        drop(closer);
+        assert_eq!(gorilla.metrics().blocks_closed, 1);
+    }
+}
@@ -0,0 +1,101 @@
+// Engine configuration
+
+use crate::storage::chunks::ChunkConfig;
+use crate::storage::clock::{Clock, SystemClock};
+use crate::storage::spill::SpillConfig;
+use crate::storage::{DEFAULT_TOMBSTONE_GRACE_SECS, SeriesOptions};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Default size at which a WAL segment is rotated (64 MiB)
+pub const DEFAULT_WAL_SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Default number of WAL records between fsyncs
+pub const DEFAULT_WAL_SYNC_EVERY: usize = 1000;
+
+/// Default seconds past "now" a point's timestamp may be
+pub const DEFAULT_FUTURE_TOLERANCE_SECS: u64 = 300;
+
+/// Default size of the largest range quantiles are computed exactly for
+pub const DEFAULT_EXACT_QUANTILE_POINTS: usize = 1_000_000;
+
+/// Settings for a Gorilla instance
+#[derive(Debug, Clone)]
+pub struct GorillaConfig {
+    /// Directory for the write-ahead log; None disables the WAL
+    pub wal_dir: Option<PathBuf>,
+
+    /// Segment size (bytes) after which the WAL rotates to a new file
+    pub wal_segment_bytes: u64,
+
+    /// Records appended between fsyncs of the WAL
+    pub wal_sync_every: usize,
+
+    /// Cut torn records off the end of WAL segments during recovery
+    pub wal_truncate_torn: bool,
+
+    /// Options applied to newly created series (duplicate policy,
+    /// drop_raw_on_close, max_points_per_block, block duration,
+    /// downsample tiers, timestamp codec, value hint); see
+    /// Gorilla::create_series for per-series overrides
+    pub series_options: SeriesOptions,
+
+    /// Source of "now" for block alignment and tombstones
+    pub clock: Arc<dyn Clock>,
+
+    /// Seconds a deleted series' slot stays tombstoned before
+    /// Gorilla::reap_tombstones may reuse it
+    pub tombstone_grace: u64,
+
+    /// Spilling of cold closed blocks to disk; None keeps all in memory
+    pub spill: Option<SpillConfig>,
+
+    /// Streaming of blocks' compressed bytes to per-shard chunk files, a
+    /// lighter alternative to the WAL (see Gorilla::recover_chunks);
+    /// None disables it
+    pub chunks: Option<ChunkConfig>,
+
+    /// Most series the instance will hold; inserts that would create
+    /// more are refused (None means unlimited)
+    pub max_series: Option<usize>,
+
+    /// Seconds past the clock's "now" a point may be dated; later points
+    /// are refused (None accepts any timestamp). Imports skip the check.
+    pub future_tolerance: Option<u64>,
+
+    /// Memory ceiling in bytes (see Gorilla::memory_usage); inserts that
+    /// push usage over it evict the least recently queried series, making
+    /// the instance a bounded cache (None means unbounded)
+    pub max_memory_bytes: Option<usize>,
+
+    /// Most results the query cache holds, evicting the least recently
+    /// used; None disables it. Results are memoized by (key, start, end)
+    /// for `query` and dropped on each write or delete to the key.
+    pub query_cache: Option<usize>,
+
+    /// Most points in a range Gorilla::quantiles computes exactly,
+    /// holding all their values; larger ranges are estimated in constant
+    /// memory
+    pub exact_quantile_points: usize,
+}
+
+impl Default for GorillaConfig {
+    fn default() -> Self {
+        GorillaConfig {
+            wal_dir: None,
+            wal_segment_bytes: DEFAULT_WAL_SEGMENT_BYTES,
+            wal_sync_every: DEFAULT_WAL_SYNC_EVERY,
+            wal_truncate_torn: true,
+            series_options: SeriesOptions::default(),
+            clock: Arc::new(SystemClock),
+            tombstone_grace: DEFAULT_TOMBSTONE_GRACE_SECS,
+            spill: None,
+            chunks: None,
+            max_series: None,
+            future_tolerance: Some(DEFAULT_FUTURE_TOLERANCE_SECS),
+            max_memory_bytes: None,
+            query_cache: None,
+            exact_quantile_points: DEFAULT_EXACT_QUANTILE_POINTS,
+        }
+    }
+}
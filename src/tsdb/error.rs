@@ -0,0 +1,158 @@
+// Error types returned by the Gorilla public API
+
+use alloc::string::String;
+use core::fmt;
+
+/// Errors returned by fallible Gorilla operations
+#[derive(Debug, Clone, PartialEq)]
+pub enum TsdbError {
+    /// The requested series does not exist
+    SeriesNotFound(String),
+
+    /// A series with this key already exists
+    SeriesExists(String),
+
+    /// The series is frozen and can't be changed
+    Frozen(String),
+
+    /// Creating the series would exceed the configured `max_series`
+    CardinalityLimitExceeded { current: usize, limit: usize },
+
+    /// Serialized data was written in a format version this build can't
+    /// read (`supported` is the newest it understands)
+    UnsupportedVersion { found: u32, supported: u32 },
+
+    /// Serialized data is truncated or malformed
+    Corrupt(String),
+
+    /// A timestamp string didn't parse (see Gorilla::insert_rfc3339)
+    InvalidTimestamp(String),
+
+    /// A point was refused, for the reason given
+    Rejected(InsertError),
+
+    /// The change couldn't be written to the write-ahead log, so it
+    /// wasn't made
+    WalAppendFailed,
+}
+
+impl fmt::Display for TsdbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TsdbError::SeriesNotFound(key) => write!(f, "series not found: {}", key),
+            TsdbError::SeriesExists(key) => write!(f, "series already exists: {}", key),
+            TsdbError::Frozen(key) => write!(f, "series is frozen: {}", key),
+            TsdbError::CardinalityLimitExceeded { current, limit } => write!(
+                f,
+                "series limit reached ({} of {}); new series refused",
+                current, limit
+            ),
+            TsdbError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "unsupported format version {} (newest supported is {})",
+                found, supported
+            ),
+            TsdbError::Corrupt(reason) => write!(f, "corrupt data: {}", reason),
+            TsdbError::InvalidTimestamp(text) => {
+                write!(f, "invalid RFC 3339 timestamp: {:?}", text)
+            }
+            TsdbError::Rejected(reason) => write!(f, "point rejected: {}", reason),
+            TsdbError::WalAppendFailed => {
+                write!(f, "write-ahead log append failed; change not made")
+            }
+        }
+    }
+}
+
+impl core::error::Error for TsdbError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            TsdbError::Rejected(reason) => Some(reason),
+            _ => None,
+        }
+    }
+}
+
+impl From<InsertError> for TsdbError {
+    fn from(reason: InsertError) -> Self {
+        TsdbError::Rejected(reason)
+    }
+}
+
+/// Why a query was refused
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryError {
+    /// No series is stored under the key
+    SeriesNotFound(String),
+
+    /// The range ends before it starts (both bounds are inclusive, so
+    /// start == end is a valid one-timestamp range)
+    InvalidRange { start: u64, end: u64 },
+
+    /// A block in the range failed its checksum or couldn't be read back
+    /// from its spill file
+    CorruptBlock { key: String, block_start: u64 },
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::SeriesNotFound(key) => write!(f, "series not found: {}", key),
+            QueryError::InvalidRange { start, end } => {
+                write!(f, "invalid range: start {} is after end {}", start, end)
+            }
+            QueryError::CorruptBlock { key, block_start } => {
+                write!(f, "corrupt block at {} in series {}", block_start, key)
+            }
+        }
+    }
+}
+
+impl core::error::Error for QueryError {}
+
+/// Why an insert was refused
+#[derive(Debug, Clone, PartialEq)]
+pub enum InsertError {
+    /// NaN values can't be aggregated or correlated
+    NanValue,
+
+    /// The point couldn't be written to the write-ahead log
+    WalAppendFailed,
+
+    /// The series' duplicate policy refused a second point at `timestamp`
+    DuplicateRejected { timestamp: u64 },
+
+    /// Creating the series would exceed the configured `max_series`
+    CardinalityLimitExceeded { current: usize, limit: usize },
+
+    /// The point is dated past now plus the configured future tolerance
+    TimestampTooFarInFuture { ts: u64, max_allowed: u64 },
+
+    /// The series is frozen (see Gorilla::freeze)
+    SeriesFrozen,
+}
+
+impl fmt::Display for InsertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InsertError::NanValue => write!(f, "NaN values are not stored"),
+            InsertError::WalAppendFailed => write!(f, "write-ahead log append failed"),
+            InsertError::DuplicateRejected { timestamp } => {
+                write!(f, "duplicate point at {} rejected", timestamp)
+            }
+            InsertError::CardinalityLimitExceeded { current, limit } => write!(
+                f,
+                "series limit reached ({} of {}); new series refused",
+                current, limit
+            ),
+            InsertError::TimestampTooFarInFuture { ts, max_allowed } => write!(
+                f,
+                "timestamp {} is too far in the future (latest allowed {})",
+                ts, max_allowed
+            ),
+            InsertError::SeriesFrozen => write!(f, "series is frozen"),
+        }
+    }
+}
+
+impl core::error::Error for InsertError {}
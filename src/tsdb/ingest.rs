@@ -0,0 +1,147 @@
+// Asynchronous, batching insert queue (tokio feature)
+//
+// AsyncGorilla puts inserts on a bounded channel drained by a background
+// task. The task takes whatever has queued up (up to MAX_BATCH commands),
+// groups the points by series and commits each group with
+// Gorilla::insert_batch, so a burst of writes to one series takes the WAL
+// and series locks once instead of once per point. Within a series,
+// points are written in the order they were sent.
+
+use super::Gorilla;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+/// Most queued commands the drain task takes in one batch
+const MAX_BATCH: usize = 4096;
+
+/// Commands the queue holds before insert waits for room
+const QUEUE_CAPACITY: usize = 16 * MAX_BATCH;
+
+enum Command {
+    Insert {
+        key: String,
+        timestamp: u64,
+        value: f64,
+    },
+
+    /// Answered once everything queued before it is written
+    Flush(oneshot::Sender<()>),
+}
+
+/// A Gorilla instance fed through an asynchronous insert queue
+///
+/// Queries go straight to the engine (see `gorilla`); points become
+/// visible to them once the drain task has written them, which `flush`
+/// waits for.
+pub struct AsyncGorilla {
+    gorilla: Arc<Gorilla>,
+    queue: mpsc::Sender<Command>,
+}
+
+impl AsyncGorilla {
+    /// Start the drain task on the current tokio runtime
+    ///
+    /// Panics if called outside a runtime. The task ends once this
+    /// handle is dropped and the queue is empty.
+    pub fn spawn(gorilla: Gorilla) -> Self {
+        let gorilla = Arc::new(gorilla);
+        let (queue, commands) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(drain(gorilla.clone(), commands));
+        AsyncGorilla { gorilla, queue }
+    }
+
+    /// The engine, for queries and other synchronous calls
+    pub fn gorilla(&self) -> &Gorilla {
+        &self.gorilla
+    }
+
+    /// Queue a point, waiting only if the queue is full
+    ///
+    /// Like Gorilla::insert, refused points are dropped and counted in
+    /// the engine's metrics.
+    pub async fn insert(&self, key: impl Into<String>, timestamp: u64, value: f64) {
+        let command = Command::Insert {
+            key: key.into(),
+            timestamp,
+            value,
+        };
+        // The drain task outlives every sender, so this can't fail
+        let _ = self.queue.send(command).await;
+    }
+
+    /// Wait until every point queued before this call is written
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+        if self.queue.send(Command::Flush(done)).await.is_ok() {
+            let _ = written.await;
+        }
+    }
+}
+
+/// Background task: commit queued inserts in per-series batches
+async fn drain(gorilla: Arc<Gorilla>, mut commands: mpsc::Receiver<Command>) {
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    while commands.recv_many(&mut batch, MAX_BATCH).await > 0 {
+        let mut points: HashMap<String, Vec<(u64, f64)>> = HashMap::new();
+        let mut flushes = Vec::new();
+        for command in batch.drain(..) {
+            match command {
+                Command::Insert {
+                    key,
+                    timestamp,
+                    value,
+                } => points.entry(key).or_default().push((timestamp, value)),
+                Command::Flush(done) => flushes.push(done),
+            }
+        }
+
+        // Writing blocks on locks (and WAL fsyncs), so keep it off the
+        // runtime's worker threads
+        let engine = gorilla.clone();
+        let committed = tokio::task::spawn_blocking(move || {
+            for (key, points) in points {
+                let _ = engine.insert_batch(&key, &points);
+            }
+        });
+        let _ = committed.await;
+
+        for done in flushes {
+            let _ = done.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_async_inserts_flush() {
+        let engine = AsyncGorilla::spawn(Gorilla::new());
+        let base_time = 7200 * 100;
+        for i in 0..1000u64 {
+            let key = format!("host{}.cpu", i % 4);
+            engine.insert(key, base_time + i * 15, i as f64).await;
+        }
+        engine.flush().await;
+
+        let gorilla = engine.gorilla();
+        for host in 0..4u64 {
+            let points = gorilla
+                .query(&format!("host{}.cpu", host), 0, u64::MAX)
+                .unwrap();
+            assert_eq!(points.len(), 250);
+            for (n, &(timestamp, value)) in points.iter().enumerate() {
+                let i = host + 4 * n as u64;
+                assert_eq!((timestamp, value), (base_time + i * 15, i as f64));
+            }
+        }
+        assert_eq!(gorilla.metrics().points_inserted, 1000);
+
+        // Refused points are dropped, as with Gorilla::insert
+        engine.insert("host0.cpu", base_time, f64::NAN).await;
+        engine.flush().await;
+        assert_eq!(engine.gorilla().metrics().inserts_rejected, 1);
+    }
+}
@@ -1,7 +1,47 @@
 // Main Gorilla TSDB interface
 // Paper Section 4: Gorilla Architecture
 
-use crate::storage::{DataPoint, TimeSeriesMap};
+#[cfg(feature = "arrow")]
+pub mod arrow;
+mod cache;
+pub mod closer;
+mod config;
+mod error;
+#[cfg(feature = "tokio")]
+pub mod ingest;
+pub mod namespace;
+mod quantile;
+mod rfc3339;
+mod sample;
+mod smooth;
+
+pub use config::{DEFAULT_EXACT_QUANTILE_POINTS, DEFAULT_FUTURE_TOLERANCE_SECS, GorillaConfig};
+pub use error::{InsertError, QueryError, TsdbError};
+pub use namespace::Namespace;
+pub use quantile::{QuantileMethod, QuantileResult};
+pub use smooth::{Smoothing, WarmUp};
+
+use crate::compression::stream::{self, StreamCompressor};
+use crate::storage::chunks::{self, ChunkFiles, ChunkReplay};
+use crate::storage::columns::PointColumns;
+use crate::storage::disk::{CheckpointInfo, ShardDir};
+use crate::storage::downsample::Aggregation;
+use crate::storage::labels::{Matcher, SeriesLabels};
+use crate::storage::partition::{DayManifest, PartitionedDir};
+use crate::storage::snapshot::{self, SnapshotInfo};
+use crate::storage::spill::SpillReport;
+use crate::storage::wal::{self, WalPosition, WalRecord, WalReplay, WalWriter};
+use crate::storage::{
+    CodecChoice, DataPoint, DuplicatePolicy, InsertEffect, MemoryUsage, PointWrite, QueryPlan,
+    SeriesHandle, SeriesMeta, SeriesOptions, StorageStats, TimeSeries, TimeSeriesBlock,
+    TimeSeriesMap,
+};
+use cache::QueryCache;
+use quantile::P2;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, PoisonError};
 
 /// Design goals (from paper Section 2.2):
 /// - Store billions of time series
@@ -19,6 +59,44 @@ pub struct Gorilla {
     // The core data structure: TSmap
     // In production, this would be sharded across multiple hosts
     tsmap: TimeSeriesMap,
+
+    // Internal counters exposed through metrics()
+    metrics: Mutex<EngineMetrics>,
+
+    // Write-ahead log, if enabled in the config
+    wal: Mutex<Option<WalWriter>>,
+
+    // Chunk files blocks are streamed to, if enabled in the config
+    chunks: Option<ChunkFiles>,
+
+    // Cap on the number of series (see GorillaConfig::max_series)
+    max_series: Option<usize>,
+
+    // How far past now timestamps may go (see GorillaConfig::future_tolerance)
+    future_tolerance: Option<u64>,
+
+    // Memory ceiling enforced on insert (see GorillaConfig::max_memory_bytes)
+    max_memory_bytes: Option<usize>,
+
+    // Called with the key of each series evicted to stay under the ceiling
+    evict_callback: Option<EvictCallback>,
+
+    // Memoized query results (see GorillaConfig::query_cache)
+    query_cache: Option<Mutex<QueryCache>>,
+
+    // Largest range quantiles are exact for (see GorillaConfig)
+    exact_quantile_points: usize,
+}
+
+type EvictCallback = Box<dyn Fn(&str) + Send + Sync>;
+
+/// A key and its points, None if the series doesn't exist (query_many)
+type KeyedPoints = (String, Option<Vec<(u64, f64)>>);
+
+impl Default for Gorilla {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Gorilla {
@@ -26,6 +104,221 @@ impl Gorilla {
     pub fn new() -> Self {
         Gorilla {
             tsmap: TimeSeriesMap::new(),
+            metrics: Mutex::default(),
+            wal: Mutex::default(),
+            chunks: None,
+            max_series: None,
+            future_tolerance: Some(DEFAULT_FUTURE_TOLERANCE_SECS),
+            max_memory_bytes: None,
+            evict_callback: None,
+            query_cache: None,
+            exact_quantile_points: DEFAULT_EXACT_QUANTILE_POINTS,
+        }
+    }
+
+    /// Create a Gorilla instance whose new series use the given options
+    pub fn with_series_options(options: SeriesOptions) -> Self {
+        let mut gorilla = Self::new();
+        gorilla.tsmap.set_default_options(options);
+        gorilla
+    }
+
+    /// Create a Gorilla instance from a config
+    ///
+    /// If `wal_dir` is set, a new WAL segment is started there. Existing
+    /// segments are left alone but not replayed; use `recover` for that.
+    pub fn with_config(config: GorillaConfig) -> io::Result<Self> {
+        let mut gorilla = Self::new();
+        gorilla.apply_config(&config)?;
+        gorilla.wal = Mutex::new(Self::open_wal(&config)?);
+        Ok(gorilla)
+    }
+
+    /// Rebuild state by replaying the write-ahead log in `wal_dir`
+    ///
+    /// Logging continues in a fresh segment after the replayed ones.
+    pub fn recover(wal_dir: &Path) -> io::Result<(Self, WalReplay)> {
+        let config = GorillaConfig {
+            wal_dir: Some(wal_dir.to_path_buf()),
+            ..GorillaConfig::default()
+        };
+        Self::recover_with(config, None)
+    }
+
+    /// Load a snapshot, then replay the WAL records written after it
+    pub fn recover_from_snapshot(snapshot: &Path, wal_dir: &Path) -> io::Result<(Self, WalReplay)> {
+        let config = GorillaConfig {
+            wal_dir: Some(wal_dir.to_path_buf()),
+            ..GorillaConfig::default()
+        };
+        Self::recover_with(config, Some(snapshot))
+    }
+
+    /// Recover using an explicit config, optionally starting from a snapshot
+    ///
+    /// Records older than the snapshot's WAL position are skipped. A
+    /// record torn by a crash at the end of a segment ends replay of that
+    /// segment (everything before it is recovered, and the report counts
+    /// the bytes dropped). Corruption in the middle of a segment is an
+    /// `InvalidData` error wrapping a `WalCorruption`.
+    ///
+    /// If `config.chunks` is set, chunk files are started afresh and
+    /// everything recovered is streamed into them.
+    pub fn recover_with(
+        config: GorillaConfig,
+        snapshot: Option<&Path>,
+    ) -> io::Result<(Self, WalReplay)> {
+        let mut gorilla = Self::new();
+        let from = match snapshot {
+            Some(path) => {
+                let (tsmap, position) = snapshot::read_snapshot(path)?;
+                gorilla.tsmap = tsmap;
+                position
+            }
+            None => WalPosition::default(),
+        };
+        gorilla.apply_config(&config)?;
+        let report = match &config.wal_dir {
+            Some(dir) => wal::replay(dir, from, config.wal_truncate_torn, |record| {
+                gorilla.apply(record)
+            })?,
+            None => WalReplay::default(),
+        };
+
+        // Replayed points streamed as they were written; the snapshot's
+        // blocks still have to be
+        let mut keys = Vec::new();
+        gorilla
+            .tsmap
+            .scan(|series| keys.push(series.key.to_string()));
+        for key in keys {
+            gorilla.flush_chunks(&key);
+        }
+        gorilla.wal = Mutex::new(Self::open_wal(&config)?);
+        Ok((gorilla, report))
+    }
+
+    /// Rebuild state from the chunk files in `config.chunks`
+    ///
+    /// Each block comes back as of its last flush, so at most
+    /// `flush_bytes` of each open block's compressed data is lost; closed
+    /// blocks come back whole. Series get `config.series_options` (chunk
+    /// files don't record options). Streaming continues into the same
+    /// files. Fails with `InvalidInput` if chunk streaming isn't
+    /// configured.
+    pub fn recover_chunks(config: GorillaConfig) -> io::Result<(Self, ChunkReplay)> {
+        let Some(chunk_config) = &config.chunks else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "chunk streaming is not configured",
+            ));
+        };
+        let (tsmap, files, report) = chunks::replay(chunk_config, &config.series_options)?;
+
+        let mut gorilla = Self::new();
+        gorilla.tsmap = tsmap;
+        gorilla.chunks = Some(files);
+        gorilla.apply_config(&config)?;
+        gorilla.wal = Mutex::new(Self::open_wal(&config)?);
+        Ok((gorilla, report))
+    }
+
+    /// Apply everything in `config` but the WAL, which each constructor
+    /// opens once it's done replaying
+    ///
+    /// Chunk files are started unless some are already open (as
+    /// recover_chunks leaves them).
+    fn apply_config(&mut self, config: &GorillaConfig) -> io::Result<()> {
+        self.tsmap
+            .set_default_options(config.series_options.clone());
+        self.tsmap.set_clock(config.clock.clone());
+        self.tsmap.set_tombstone_grace(config.tombstone_grace);
+        self.enable_spill(config)?;
+        self.max_series = config.max_series;
+        self.future_tolerance = config.future_tolerance;
+        self.max_memory_bytes = config.max_memory_bytes;
+        self.query_cache = config
+            .query_cache
+            .map(|capacity| Mutex::new(QueryCache::new(capacity)));
+        self.exact_quantile_points = config.exact_quantile_points;
+        if self.chunks.is_none() {
+            self.chunks = config
+                .chunks
+                .as_ref()
+                .map(|chunks| ChunkFiles::create(chunks, self.tsmap.shard_count()))
+                .transpose()?;
+        }
+        Ok(())
+    }
+
+    fn enable_spill(&mut self, config: &GorillaConfig) -> io::Result<()> {
+        if let Some(spill) = &config.spill {
+            std::fs::create_dir_all(&spill.dir)?;
+        }
+        self.tsmap.set_spill(config.spill.clone());
+        Ok(())
+    }
+
+    fn open_wal(config: &GorillaConfig) -> io::Result<Option<WalWriter>> {
+        config
+            .wal_dir
+            .as_deref()
+            .map(|dir| WalWriter::open(dir, config.wal_segment_bytes, config.wal_sync_every))
+            .transpose()
+    }
+
+    /// Apply a replayed WAL record (the WAL is not attached yet)
+    fn apply(&mut self, record: WalRecord) {
+        match record {
+            WalRecord::Insert {
+                labels: Some(labels),
+                timestamp,
+                value,
+                ..
+            } => {
+                let _ =
+                    self.write_point(&labels.series_key(), Some(&labels), timestamp, value, false);
+            }
+            WalRecord::Insert {
+                key,
+                labels: None,
+                timestamp,
+                value,
+            } => {
+                let _ = self.import(&key, timestamp, value);
+            }
+            WalRecord::Delete { key } => {
+                let _ = self.delete(&key);
+            }
+            WalRecord::Rename { old_key, new_key } => {
+                // Only successful renames are logged
+                let _ = self.rename(&old_key, &new_key);
+            }
+            WalRecord::Freeze { key, frozen } => {
+                let _ = self.set_frozen(&key, frozen);
+            }
+            WalRecord::CreateSeries { key, options } => {
+                let _ = self.create_series(&key, options);
+            }
+        }
+    }
+
+    /// Run a WAL append if the WAL is enabled; None if it failed
+    ///
+    /// On success the WAL stays locked until the returned guard drops, so
+    /// callers can apply the change before another write gets logged and
+    /// replay sees changes in the order they were applied.
+    fn log<F>(&self, append: F) -> Option<MutexGuard<'_, Option<WalWriter>>>
+    where
+        F: FnOnce(&mut WalWriter) -> io::Result<()>,
+    {
+        let mut wal = lock(&self.wal);
+        match wal.as_mut().map(append) {
+            Some(Err(_)) => {
+                lock(&self.metrics).wal_errors += 1;
+                None
+            }
+            _ => Some(wal),
         }
     }
 
@@ -37,245 +330,6603 @@ impl Gorilla {
     /// 3. Buffer writes for 1 minute on shard reassignment
     ///
     /// Paper Section 4.4: Handling failures
-    pub fn insert(&mut self, key: &str, timestamp: u64, value: f64) {
-        self.tsmap.insert(key.to_string(), timestamp, value);
+    ///
+    /// NaN values are rejected (and counted in metrics) since they can't
+    /// be meaningfully aggregated or correlated. Points for a timestamp
+    /// that is already stored follow the series' duplicate policy.
+    ///
+    /// With the WAL enabled the point is logged before it is applied; a
+    /// point that can't be logged is rejected. Use try_insert to learn
+    /// what happened to a point.
+    ///
+    /// Refusals by policy (NaN, duplicate, frozen series, series limit,
+    /// future timestamp) are dropped silently; they only show in metrics.
+    /// A failed WAL append is a fault rather than a policy, so it is also
+    /// reported on stderr.
+    ///
+    /// Takes `&self`: different series can be written from several
+    /// threads at once, while queries keep running (see TimeSeriesMap for
+    /// the locking). With the WAL enabled, writes are serialized on it.
+    pub fn insert(&self, key: &str, timestamp: u64, value: f64) {
+        if let Err(error @ InsertError::WalAppendFailed) = self.try_insert(key, timestamp, value) {
+            eprintln!("tsdb: dropped point {} @ {}: {}", key, timestamp, error);
+        }
     }
 
-    /// Query data points within a time range
+    /// Insert a data point, reporting what happened to it
     ///
-    /// Returns all points for the given key between start and end timestamps
+    /// Refused points (NaN, WAL failure, duplicate rejected by the
+    /// series' policy, series limit reached, frozen series, future
+    /// timestamp) leave the data untouched and are counted in metrics.
+    pub fn try_insert(
+        &self,
+        key: &str,
+        timestamp: u64,
+        value: f64,
+    ) -> Result<InsertOutcome, InsertError> {
+        self.write_point(key, None, timestamp, value, true)
+    }
+
+    /// Insert a data point dated by an RFC 3339 string, e.g.
+    /// `2021-01-01T00:00:00Z` or `2021-01-01T05:30:00.250+05:30`
     ///
-    /// In production:
-    /// - Queries hit the closest regional instance
-    /// - Failed over automatically on node failure
-    /// - Can return partial results marked as such
+    /// The string is converted to seconds since the epoch, the
+    /// resolution points are stored at (fractions are truncated), then
+    /// inserted as by `try_insert`. Malformed strings and instants before
+    /// the epoch fail with `TsdbError::InvalidTimestamp`, and points
+    /// `try_insert` refuses with `TsdbError::Rejected`.
+    pub fn insert_rfc3339(&self, key: &str, timestamp: &str, value: f64) -> Result<(), TsdbError> {
+        let timestamp = rfc3339::parse(timestamp)
+            .ok_or_else(|| TsdbError::InvalidTimestamp(timestamp.to_string()))?;
+        self.try_insert(key, timestamp, value)?;
+        Ok(())
+    }
+
+    /// Insert a data point, creating the series with a value codec
     ///
-    /// Paper: Query latency reduced from ~500ms (HBase) to ~7ms (Gorilla)
-    pub fn query(&self, key: &str, start: u64, end: u64) -> Option<Vec<(u64, f64)>> {
-        self.tsmap.get(key).map(|series| {
-            series
-                .query(start, end)
-                .into_iter()
-                .map(|dp| (dp.timestamp, dp.value))
-                .collect()
-        })
+    /// A new series gets the instance's default options with `codec`
+    /// (see CodecChoice); an existing one keeps the codec it was created
+    /// with. Otherwise the same as try_insert. Like other options, the
+    /// codec isn't written to the WAL.
+    pub fn insert_with_codec(
+        &self,
+        key: &str,
+        timestamp: u64,
+        value: f64,
+        codec: CodecChoice,
+    ) -> Result<InsertOutcome, InsertError> {
+        // Create it only if the point would be accepted; a series created
+        // meanwhile by another writer keeps its own options
+        let room = self
+            .max_series
+            .is_none_or(|limit| self.tsmap.series_count() < limit);
+        if room && !value.is_nan() && self.check_timestamp(timestamp).is_ok() && !self.contains(key)
+        {
+            let options = SeriesOptions {
+                value_codec: codec,
+                ..self.tsmap.default_options().clone()
+            };
+            // Without its create record, replay would bring the series
+            // back with the defaults; the insert then fails to log too
+            if let Some(wal) = self.log(|wal| wal.append_create(key, &options)) {
+                self.tsmap.get_or_insert(key, &options);
+                drop(wal);
+            }
+            return self
+                .try_insert(key, timestamp, value)
+                .map(|outcome| match outcome {
+                    InsertOutcome::Inserted => InsertOutcome::CreatedSeries,
+                    outcome => outcome,
+                });
+        }
+        self.try_insert(key, timestamp, value)
     }
 
-    /// Get storage statistics for a time series
+    /// Insert a data point without the future-timestamp check
     ///
-    /// This shows the compression efficiency achieved by Gorilla
-    /// Paper reports average of 1.37 bytes per data point (12x compression)
-    pub fn get_stats(&self, key: &str) -> CompressionStats {
-        if let Some(series) = self.tsmap.get(key) {
-            let stats = series.get_stats();
-            CompressionStats {
-                original_size: stats.original_size,
-                compressed_size: stats.compressed_size,
-                compression_ratio: stats.compression_ratio(),
+    /// For backfills and imports of data whose timestamps are known to be
+    /// right (e.g. copied from another instance). All other checks of
+    /// try_insert still apply.
+    pub fn import(
+        &self,
+        key: &str,
+        timestamp: u64,
+        value: f64,
+    ) -> Result<InsertOutcome, InsertError> {
+        self.write_point(key, None, timestamp, value, false)
+    }
+
+    /// Insert several points into one series
+    ///
+    /// The batch is checked up front: points failing the checks of insert
+    /// (NaN, future timestamp) are skipped, the rest are put in timestamp
+    /// order, and repeated timestamps within the batch are settled with
+    /// the series' duplicate policy the way inserting them in order would.
+    /// What's left is logged under one WAL lock and written under one
+    /// series lock, encoding each touched block once (see
+    /// TimeSeries::insert_batch), which is what makes batching pay off for
+    /// busy series.
+    ///
+    /// The whole batch is refused if the series is frozen, a new series
+    /// would exceed the series limit, or its first point can't be logged;
+    /// a WAL failure later on skips the points from there. Skipped points
+    /// are counted in metrics.
+    pub fn insert_batch(
+        &self,
+        key: &str,
+        points: &[(u64, f64)],
+    ) -> Result<BatchOutcome, InsertError> {
+        let mut accepted: Vec<(u64, f64)> = Vec::with_capacity(points.len());
+        for &(timestamp, value) in points {
+            match self.check_timestamp(timestamp) {
+                Ok(()) if !value.is_nan() => accepted.push((timestamp, value)),
+                _ => lock(&self.metrics).inserts_rejected += 1,
             }
+        }
+
+        // Later points at a timestamp replace earlier ones under KeepLast
+        // (which still counts them as inserted); otherwise the first wins
+        let policy = self
+            .tsmap
+            .get(key)
+            .map_or(self.tsmap.default_options().duplicate_policy, |series| {
+                series.read().options().duplicate_policy
+            });
+        accepted.sort_by_key(|&(timestamp, _)| timestamp);
+        let before_dedup = accepted.len();
+        if policy == DuplicatePolicy::KeepLast {
+            accepted.reverse();
+            accepted.dedup_by_key(|&mut (timestamp, _)| timestamp);
+            accepted.reverse();
         } else {
-            CompressionStats::default()
+            accepted.dedup_by_key(|&mut (timestamp, _)| timestamp);
+        }
+        let superseded = before_dedup - accepted.len();
+
+        let results = self.write_series(key, &accepted);
+        let mut metrics = lock(&self.metrics);
+        let results = match results {
+            Ok(results) if policy == DuplicatePolicy::KeepLast => {
+                metrics.points_inserted += superseded as u64;
+                results
+            }
+            Ok(results) => {
+                metrics.inserts_rejected += superseded as u64;
+                results
+            }
+            Err(error) => {
+                metrics.inserts_rejected += superseded as u64;
+                return Err(error);
+            }
+        };
+        let mut inserted = results
+            .iter()
+            .filter(|result| {
+                matches!(
+                    result,
+                    Ok(InsertOutcome::Inserted
+                        | InsertOutcome::CreatedSeries
+                        | InsertOutcome::ReplacedDuplicate)
+                )
+            })
+            .count();
+        if policy == DuplicatePolicy::KeepLast {
+            inserted += superseded;
         }
+        Ok(BatchOutcome {
+            inserted,
+            skipped: points.len() - inserted,
+        })
     }
 
-    /// Scan all time series
+    /// Write samples for many series at once, e.g. one scrape of a host
     ///
-    /// Used for:
-    /// - Correlation search (Section 5.1)
-    /// - Background rollup aggregations (Section 5.3)
-    /// - Monitoring and debugging
+    /// Samples are grouped by key, and each series is looked up (or
+    /// created) once and written like a batch: under one WAL lock and one
+    /// series lock, encoding each touched block once. Unlike insert_batch
+    /// a series' samples are applied in input order, duplicates included.
     ///
-    /// Paper: Gorilla can scan all data very efficiently for these operations
-    /// Demonstrated in Example 6
-    pub fn scan<F>(&self, mut f: F)
-    where
-        F: FnMut(&str, u64, f64),
-    {
-        self.tsmap.scan(|series| {
-            for point in series.query(0, u64::MAX) {
-                f(&series.key, point.timestamp, point.value);
+    /// Returns one result per sample, in input order, as try_insert would
+    /// give it, so callers can report partial failures. A series refusing
+    /// its whole batch (frozen, series limit, WAL failure) fails each of
+    /// its samples with that error.
+    pub fn write(&self, batch: &[Sample<'_>]) -> Vec<Result<InsertOutcome, InsertError>> {
+        let mut groups: Vec<(&str, Vec<usize>)> = Vec::new();
+        let mut group_of: HashMap<&str, usize> = HashMap::new();
+        for (i, sample) in batch.iter().enumerate() {
+            let group = *group_of.entry(sample.key).or_insert_with(|| {
+                groups.push((sample.key, Vec::new()));
+                groups.len() - 1
+            });
+            groups[group].1.push(i);
+        }
+
+        let mut results = vec![None; batch.len()];
+        for (key, indices) in groups {
+            let points: Vec<(u64, f64)> = indices
+                .iter()
+                .map(|&i| (batch[i].timestamp, batch[i].value))
+                .collect();
+            match self.write_series(key, &points) {
+                Ok(series_results) => {
+                    for (i, result) in indices.into_iter().zip(series_results) {
+                        results[i] = Some(result);
+                    }
+                }
+                Err(error) => {
+                    for i in indices {
+                        results[i] = Some(Err(error.clone()));
+                    }
+                }
             }
-        });
+        }
+        results
+            .into_iter()
+            .map(|result| result.expect("every sample is in a group"))
+            .collect()
     }
 
-    /// Delete a time series
-    /// Used in Example 6 to demonstrate cleanup
-    pub fn delete(&mut self, key: &str) {
-        self.tsmap.delete(key);
+    /// Check, log and apply points to one series, in the given order
+    ///
+    /// Each point gets the checks of try_insert. The rest are logged
+    /// under one WAL lock and written under one series lock (see
+    /// TimeSeries::insert_batch). Err if the series refuses the whole
+    /// batch (frozen, series limit) or its first point can't be logged;
+    /// otherwise one result per point. Refused points are counted in
+    /// metrics.
+    fn write_series(
+        &self,
+        key: &str,
+        points: &[(u64, f64)],
+    ) -> Result<Vec<Result<InsertOutcome, InsertError>>, InsertError> {
+        let mut results: Vec<Option<Result<InsertOutcome, InsertError>>> = vec![None; points.len()];
+        let mut accepted: Vec<usize> = Vec::with_capacity(points.len());
+        for (i, &(timestamp, value)) in points.iter().enumerate() {
+            let checked = self.check_timestamp(timestamp).and_then(|()| {
+                if value.is_nan() {
+                    Err(InsertError::NanValue)
+                } else {
+                    Ok(())
+                }
+            });
+            match checked {
+                Ok(()) => accepted.push(i),
+                Err(error) => {
+                    lock(&self.metrics).inserts_rejected += 1;
+                    results[i] = Some(Err(error));
+                }
+            }
+        }
+        // Frozen and cardinality checks hold for the whole batch
+        if let Some(&first) = accepted.first()
+            && let Err(error) = self.check_insert(key, points[first].1)
+        {
+            lock(&self.metrics).inserts_rejected += accepted.len() as u64;
+            return Err(error);
+        }
+
+        // Apply only what made it into the log
+        let mut wal = lock(&self.wal);
+        let mut logged = accepted.len();
+        if let Some(writer) = wal.as_mut()
+            && let Some(failed) = accepted.iter().position(|&i| {
+                let (timestamp, value) = points[i];
+                writer.append_insert(key, timestamp, value).is_err()
+            })
+        {
+            let mut metrics = lock(&self.metrics);
+            metrics.wal_errors += 1;
+            metrics.inserts_rejected += (accepted.len() - failed) as u64;
+            if failed == 0 {
+                return Err(InsertError::WalAppendFailed);
+            }
+            for &i in &accepted[failed..] {
+                results[i] = Some(Err(InsertError::WalAppendFailed));
+            }
+            logged = failed;
+        }
+
+        let logged: Vec<usize> = accepted[..logged].to_vec();
+        let batch: Vec<(u64, f64)> = logged.iter().map(|&i| points[i]).collect();
+        let effects = self.tsmap.insert_many(key, &batch);
+        self.invalidate_cached(key);
+        effects.iter().for_each(|&effect| self.count_insert(effect));
+        drop(wal);
+        let stored = effects.iter().any(|effect| effect.write.stored());
+        if stored {
+            self.flush_chunks(key);
+        }
+        if effects.iter().any(|effect| effect.closed_block) {
+            self.write_rollups(key);
+        }
+        if stored {
+            self.enforce_memory_cap(key);
+        }
+        for (&i, &effect) in logged.iter().zip(&effects) {
+            results[i] = Some(insert_outcome(effect, points[i].0));
+        }
+        Ok(results
+            .into_iter()
+            .map(|result| result.expect("every point is checked, refused or written"))
+            .collect())
     }
-}
 
-/// Statistics about compression efficiency
-#[derive(Debug, Default)]
-pub struct CompressionStats {
-    pub original_size: usize,
-    pub compressed_size: usize,
-    pub compression_ratio: f64,
-}
+    /// Insert a data point into the series identified by name and labels
+    ///
+    /// The series is stored under a canonical key such as
+    /// `http.requests{host="web01",region="eu"}` (labels sorted), so the
+    /// key-based API works on it too, and it is indexed by its labels for
+    /// query_selector. Label order in `labels` doesn't matter.
+    pub fn insert_labeled(&self, name: &str, labels: &[(&str, &str)], timestamp: u64, value: f64) {
+        let _ = self.insert_series_labels(&SeriesLabels::new(name, labels), timestamp, value);
+    }
 
-/// Use cases enabled by Gorilla (from Section 5)
-///
-/// 1. Time series correlation (Section 5.1)
-///    - Brute-force search across 1M+ time series
-///    - Uses PPMCC (Pearson correlation)
-///    - Helps answer: "What happened when my service broke?"
-///
-/// 2. Advanced charting (Section 5.2)
-///    - Horizon charts with large datasets
-///    - Visual anomaly detection
-///    - Real-time dashboards
-///
-/// 3. Efficient aggregations (Section 5.3)
-///    - Rollup operations run directly on Gorilla
-///    - No longer need expensive HBase scans
-///    - Reduced load on persistent storage
-impl Gorilla {
-    /// Example: Find correlated time series (simplified version of Section 5.1)
+    fn insert_series_labels(
+        &self,
+        labels: &SeriesLabels,
+        timestamp: u64,
+        value: f64,
+    ) -> Result<InsertOutcome, InsertError> {
+        self.write_point(&labels.series_key(), Some(labels), timestamp, value, true)
+    }
+
+    /// Shared insert path: check, log, apply, count
     ///
-    /// In production, this calculates Pearson Product-Moment Correlation
-    /// Coefficient (PPMCC) across all time series
-    /// Demonstrated in Example 6
-    pub fn find_correlated(
+    /// `check_future` is false for imports and WAL replay, which may carry
+    /// timestamps past the future tolerance.
+    fn write_point(
         &self,
-        needle_key: &str,
-        start: u64,
-        end: u64,
-        top_n: usize,
-    ) -> Vec<(String, f64)> {
-        // Get the needle time series
-        let needle = match self.query(needle_key, start, end) {
-            Some(data) => data,
-            None => return Vec::new(),
+        key: &str,
+        labels: Option<&SeriesLabels>,
+        timestamp: u64,
+        value: f64,
+        check_future: bool,
+    ) -> Result<InsertOutcome, InsertError> {
+        let checked = if check_future {
+            self.check_timestamp(timestamp)
+        } else {
+            Ok(())
+        };
+        if let Err(error) = checked.and_then(|()| self.check_insert(key, value)) {
+            lock(&self.metrics).inserts_rejected += 1;
+            return Err(error);
+        }
+        let logged = match labels {
+            Some(labels) => self.log(|wal| wal.append_insert_labeled(labels, timestamp, value)),
+            None => self.log(|wal| wal.append_insert(key, timestamp, value)),
+        };
+        let Some(wal) = logged else {
+            lock(&self.metrics).inserts_rejected += 1;
+            return Err(InsertError::WalAppendFailed);
         };
 
-        if needle.is_empty() {
-            return Vec::new();
+        let effect = match labels {
+            Some(labels) => self.tsmap.insert_labeled(labels, timestamp, value),
+            None => self.tsmap.insert(key, timestamp, value),
+        };
+        self.invalidate_cached(key);
+        self.count_insert(effect);
+        drop(wal);
+        if effect.write.stored() {
+            self.flush_chunks(key);
         }
+        if effect.closed_block {
+            self.write_rollups(key);
+        }
+        if effect.write.stored() {
+            self.enforce_memory_cap(key);
+        }
+        insert_outcome(effect, timestamp)
+    }
 
-        let mut correlations = Vec::new();
+    /// Refuse timestamps beyond now plus the future tolerance
+    fn check_timestamp(&self, ts: u64) -> Result<(), InsertError> {
+        let Some(tolerance) = self.future_tolerance else {
+            return Ok(());
+        };
+        let max_allowed = self.tsmap.now().saturating_add(tolerance);
+        if ts > max_allowed {
+            return Err(InsertError::TimestampTooFarInFuture { ts, max_allowed });
+        }
+        Ok(())
+    }
 
-        // Scan all time series and calculate correlation
-        self.tsmap.scan(|series| {
-            if series.key == needle_key {
-                return; // Skip self
+    /// Refuse NaN values, frozen series and series beyond the
+    /// cardinality limit
+    ///
+    /// The limit is checked before the write, so concurrent writers
+    /// creating new series at the same moment may overshoot it slightly.
+    /// Freezing needs `&mut self`, so it can't race with the write.
+    fn check_insert(&self, key: &str, value: f64) -> Result<(), InsertError> {
+        if value.is_nan() {
+            return Err(InsertError::NanValue);
+        }
+        let existing = self.tsmap.get(key);
+        if existing
+            .as_ref()
+            .is_some_and(|series| series.read().is_frozen())
+        {
+            return Err(InsertError::SeriesFrozen);
+        }
+        if let Some(limit) = self.max_series {
+            let current = self.tsmap.series_count();
+            if current >= limit && existing.is_none() {
+                lock(&self.metrics).series_rejected += 1;
+                return Err(InsertError::CardinalityLimitExceeded { current, limit });
             }
+        }
+        Ok(())
+    }
 
-            let data = series.query(start, end);
-            if data.len() != needle.len() {
-                return; // Need same length for correlation
+    /// Write the buckets a block close rolled up to the companion series
+    ///
+    /// Companions aren't logged: replaying the raw points closes the same
+    /// blocks and rolls them up again. Frozen companions are left alone.
+    fn write_rollups(&self, key: &str) {
+        let Some(series) = self.tsmap.get(key) else {
+            return;
+        };
+        let (rollups, options) = {
+            let mut series = series.write();
+            (series.take_rollups(), series.companion_options())
+        };
+        for rollup in rollups {
+            let frozen = self
+                .tsmap
+                .get(&rollup.key)
+                .is_some_and(|companion| companion.read().is_frozen());
+            if !frozen {
+                self.tsmap.insert_with_options(
+                    &rollup.key,
+                    &options,
+                    rollup.timestamp,
+                    rollup.value,
+                );
+                self.invalidate_cached(&rollup.key);
+                self.flush_chunks(&rollup.key);
             }
+        }
+    }
 
-            // Simple correlation calculation (simplified)
-            let correlation = calculate_correlation(&needle, &data);
-            correlations.push((series.key.clone(), correlation));
-        });
+    /// Write a series' due chunks, if chunk streaming is enabled
+    ///
+    /// Runs under the series' write lock, so its chunks land in the file
+    /// in the order they were taken. Failed writes are counted; their
+    /// bytes aren't retried.
+    fn flush_chunks(&self, key: &str) {
+        let (Some(files), Some(series)) = (&self.chunks, self.tsmap.get(key)) else {
+            return;
+        };
+        let shard = self.tsmap.shard_index(key);
+        let mut series = series.write();
+        for chunk in series.take_chunks(files.flush_bytes()) {
+            if files.write(shard, &chunk).is_err() {
+                lock(&self.metrics).chunk_errors += 1;
+            }
+        }
+    }
 
-        // Sort by absolute correlation and take top N
-        correlations.sort_by(|a, b| b.1.abs().partial_cmp(&a.1.abs()).unwrap());
-        correlations.truncate(top_n);
+    /// Evict the least recently queried series while over max_memory_bytes
+    ///
+    /// Series that were never queried count from their creation. The
+    /// series just written to, pinned and frozen series are never evicted,
+    /// so a single oversized series can keep usage above the cap. Evictions
+    /// are logged as deletes so recovery doesn't bring them back; a
+    /// series whose delete can't be logged is kept.
+    fn enforce_memory_cap(&self, keep: &str) {
+        let Some(cap) = self.max_memory_bytes else {
+            return;
+        };
+        let mut usage = self.tsmap.memory_usage().total();
+        if usage <= cap {
+            return;
+        }
 
-        correlations
-    }
-}
+        // (last access, key, bytes held) of every series that may go
+        let mut candidates = Vec::new();
+        self.tsmap.scan(|series| {
+            if &*series.key != keep && !series.meta().pinned && !series.is_frozen() {
+                candidates.push((
+                    series.last_access(),
+                    series.key.to_string(),
+                    series.memory_usage().total(),
+                ));
+            }
+        });
+        candidates.sort_unstable();
 
-/// Calculate correlation between two time series (simplified)
-/// Used by find_correlated() in Example 6
-fn calculate_correlation(series1: &[(u64, f64)], series2: &[DataPoint]) -> f64 {
-    if series1.len() != series2.len() || series1.is_empty() {
-        return 0.0;
+        let now = self.tsmap.now();
+        for (_, key, bytes) in candidates {
+            if usage <= cap {
+                break;
+            }
+            let Some(wal) = self.log(|wal| wal.append_delete(&key)) else {
+                continue;
+            };
+            let removed = self.tsmap.remove(&key, now).is_some();
+            drop(wal);
+            self.invalidate_cached(&key);
+            if removed {
+                usage = usage.saturating_sub(bytes);
+                lock(&self.metrics).series_evicted += 1;
+                if let Some(callback) = &self.evict_callback {
+                    callback(&key);
+                }
+            }
+        }
     }
 
-    let n = series1.len() as f64;
-
-    // Calculate means
-    let mean1: f64 = series1.iter().map(|(_, v)| v).sum::<f64>() / n;
-    let mean2: f64 = series2.iter().map(|p| p.value).sum::<f64>() / n;
+    /// Drop cached query results for `key`, once a write or delete to
+    /// it is in
+    fn invalidate_cached(&self, key: &str) {
+        if let Some(cache) = &self.query_cache {
+            lock(cache).invalidate(key);
+        }
+    }
 
-    // Calculate correlation
-    let mut numerator = 0.0;
-    let mut sum_sq1 = 0.0;
-    let mut sum_sq2 = 0.0;
+    /// Update metrics for the outcome of an insert
+    fn count_insert(&self, effect: InsertEffect) {
+        let mut metrics = lock(&self.metrics);
+        if !effect.write.stored() {
+            metrics.inserts_rejected += 1;
+            return;
+        }
 
-    for i in 0..series1.len() {
-        let diff1 = series1[i].1 - mean1;
-        let diff2 = series2[i].value - mean2;
-        numerator += diff1 * diff2;
-        sum_sq1 += diff1 * diff1;
-        sum_sq2 += diff2 * diff2;
+        metrics.points_inserted += 1;
+        metrics.bytes_compressed += effect.compressed_bytes as u64;
+        if effect.closed_block {
+            metrics.blocks_closed += 1;
+        }
     }
 
-    let denominator = (sum_sq1 * sum_sq2).sqrt();
-    if denominator == 0.0 {
+    /// Query every series named `name` whose labels match all matchers
+    ///
+    /// Matchers are ANDed; `Neq` also matches series without the label.
+    /// Series inserted with a plain key match on that key as their name.
+    /// Returns matching series sorted by key, each with its points in
+    /// [start, end] (possibly none).
+    pub fn query_selector(
+        &self,
+        name: &str,
+        matchers: &[(&str, Matcher)],
+        start: u64,
+        end: u64,
+    ) -> Vec<SelectedSeries> {
+        let now = self.tsmap.now();
+        let mut selected: Vec<SelectedSeries> = self
+            .tsmap
+            .select(name, matchers)
+            .into_iter()
+            .map(|handle| {
+                let series = handle.read();
+                series.mark_accessed(now);
+                SelectedSeries {
+                    key: series.key.to_string(),
+                    labels: series
+                        .labels()
+                        .map(|labels| labels.labels.clone())
+                        .unwrap_or_default(),
+                    points: series
+                        .iter_range(start, end)
+                        .map(|dp| (dp.timestamp, dp.value))
+                        .collect(),
+                }
+            })
+            .collect();
+        selected.sort_by(|a, b| a.key.cmp(&b.key));
+        selected
+    }
+
+    /// Set the unit, description and pinned flag of an existing series
+    ///
+    /// Fails with SeriesNotFound rather than creating an empty series.
+    /// `created_at` and `last_write` in `meta` are ignored; the engine
+    /// maintains those itself.
+    pub fn set_meta(&mut self, key: &str, meta: SeriesMeta) -> Result<(), TsdbError> {
+        let mut series = self
+            .tsmap
+            .get_mut(key)
+            .ok_or_else(|| TsdbError::SeriesNotFound(key.to_string()))?;
+        series.set_meta(meta);
+        Ok(())
+    }
+
+    /// Delete series that received no writes for `idle_threshold` seconds
+    ///
+    /// Meant for ephemeral workloads (CI jobs, autoscaled pods) whose
+    /// series stop getting data. Series pinned in their metadata and
+    /// frozen series are kept, as are those whose delete can't be logged
+    /// to the WAL. Returns the expired keys in sorted order.
+    pub fn expire_idle(&mut self, now: u64, idle_threshold: u64) -> Vec<String> {
+        self.expire_idle_with(now, idle_threshold, |_, _| {})
+    }
+
+    /// Like expire_idle, handing each series' final data to `archive`
+    /// (key and all of its raw points) once it is deleted
+    pub fn expire_idle_with<F>(
+        &mut self,
+        now: u64,
+        idle_threshold: u64,
+        mut archive: F,
+    ) -> Vec<String>
+    where
+        F: FnMut(&str, Vec<(u64, f64)>),
+    {
+        let mut expired = Vec::new();
+        self.tsmap.scan(|series| {
+            let meta = series.meta();
+            if !meta.pinned
+                && !series.is_frozen()
+                && now.saturating_sub(meta.last_write) > idle_threshold
+            {
+                expired.push(series.key.to_string());
+            }
+        });
+        expired.sort();
+
+        expired.retain(|key| {
+            let points = self
+                .tsmap
+                .get(key)
+                .map(|series| series.read().query(0, u64::MAX));
+            if self.delete(key).is_err() {
+                return false;
+            }
+            if let Some(points) = points {
+                archive(key, points.into_iter().map(Into::into).collect());
+            }
+            true
+        });
+        expired
+    }
+
+    /// Look up a series for a query, recording the access for eviction
+    fn get_queried(&self, key: &str) -> Option<SeriesHandle> {
+        let handle = self.tsmap.get(key)?;
+        handle.read().mark_accessed(self.tsmap.now());
+        Some(handle)
+    }
+
+    /// A queried range as columns, for slice-based math
+    fn query_columns(&self, key: &str, start: u64, end: u64) -> Option<PointColumns> {
+        self.get_queried(key)
+            .map(|series| series.read().columns(start, end))
+    }
+
+    /// Call `callback` with the key of every series evicted by the
+    /// memory ceiling (see GorillaConfig::max_memory_bytes)
+    ///
+    /// Runs on the inserting thread after the series is gone, with no
+    /// locks held. Replaces any previous callback.
+    pub fn on_evict<F>(&mut self, callback: F)
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.evict_callback = Some(Box::new(callback));
+    }
+
+    /// Whether a series exists under `key`
+    pub fn contains(&self, key: &str) -> bool {
+        self.tsmap.get(key).is_some()
+    }
+
+    /// How a series' data is partitioned into blocks
+    ///
+    /// One `(start, end, open)` tuple per block holding points, oldest
+    /// first; `end` is exclusive. Empty if the series doesn't exist.
+    pub fn block_boundaries(&self, key: &str) -> Vec<(u64, u64, bool)> {
+        self.tsmap
+            .get(key)
+            .map(|series| series.read().block_boundaries())
+            .unwrap_or_default()
+    }
+
+    /// Dry-run a query: which blocks of a series it would read
+    ///
+    /// Like EXPLAIN in SQL, for performance debugging. Reads only the
+    /// block summaries: nothing is decoded, and the query isn't counted
+    /// as an access. An empty plan if the series doesn't exist.
+    pub fn explain_query(&self, key: &str, start: u64, end: u64) -> QueryPlan {
+        self.tsmap
+            .get(key)
+            .map(|series| series.read().explain(start, end))
+            .unwrap_or_default()
+    }
+
+    /// Points in a time range with the block and offset holding each
+    ///
+    /// Returns `(block index, offset, timestamp, value)` tuples, for
+    /// diagnosing points landing in unexpected blocks. Empty if the
+    /// series doesn't exist.
+    pub fn debug_points(&self, key: &str, start: u64, end: u64) -> Vec<(usize, usize, u64, f64)> {
+        self.tsmap
+            .get(key)
+            .map(|series| series.read().debug_points(start, end))
+            .unwrap_or_default()
+    }
+
+    /// Metadata of a series, if it exists
+    pub fn get_meta(&self, key: &str) -> Option<SeriesMeta> {
+        self.tsmap
+            .get(key)
+            .map(|series| series.read().meta().clone())
+    }
+
+    /// List every series key in sorted order
+    ///
+    /// With `include_meta`, each entry also carries the series' metadata.
+    /// Tenants' series are listed under their stored keys (see
+    /// `list_series_in` for one tenant's logical keys).
+    pub fn list_series(&self, include_meta: bool) -> Vec<SeriesListing> {
+        let mut listing = Vec::new();
+        self.tsmap.scan(|series| {
+            listing.push(SeriesListing {
+                key: series.key.to_string(),
+                meta: include_meta.then(|| series.meta().clone()),
+            });
+        });
+        listing.sort_by(|a, b| a.key.cmp(&b.key));
+        listing
+    }
+
+    /// A tenant's view of this instance, isolated from other tenants
+    ///
+    /// Its series are stored under the tenant name, a NUL and the
+    /// logical key, so the same key under two tenants names two series.
+    /// Panics if `tenant` contains a NUL.
+    pub fn namespace(&self, tenant: &str) -> Namespace<'_> {
+        Namespace::new(self, tenant)
+    }
+
+    /// The logical keys of a tenant's series, in sorted order
+    pub fn list_series_in(&self, tenant: &str) -> Vec<String> {
+        let mut keys = Vec::new();
+        self.tsmap.scan(|series| {
+            if let Some(key) = namespace::strip_tenant(tenant, &series.key) {
+                keys.push(key.to_string());
+            }
+        });
+        keys.sort();
+        keys
+    }
+
+    /// Estimate the compressed bits a would-be insert adds
+    ///
+    /// Uses the series' current compressor state (the point the new one
+    /// would follow) and changes nothing. A new series pays for a block
+    /// header and a raw first point; NaN values, which insert rejects,
+    /// cost nothing.
+    pub fn estimate_insert_bits(&self, key: &str, timestamp: u64, value: f64) -> u32 {
+        if value.is_nan() {
+            return 0;
+        }
+        match self.tsmap.get(key) {
+            Some(series) => series.read().estimate_insert_bits(timestamp, value),
+            None => TimeSeries::new(key).estimate_insert_bits(timestamp, value),
+        }
+    }
+
+    /// Snapshot of the engine's internal counters
+    ///
+    /// Lets operators graph the TSDB's own behavior (ingestion rate,
+    /// compression output, block turnover, rejected writes).
+    pub fn metrics(&self) -> EngineMetrics {
+        let spill = self.tsmap.spill_counters();
+        EngineMetrics {
+            series: self.tsmap.series_count() as u64,
+            blocks_reloaded: spill.reloads(),
+            spill_read_errors: spill.read_errors(),
+            ..*lock(&self.metrics)
+        }
+    }
+
+    /// Move cold closed blocks to the spill directory
+    ///
+    /// Meant to run periodically when spilling is configured; blocks
+    /// unread for `idle_secs`, then the least recently read ones while
+    /// over the memory budget, are written out and dropped from memory.
+    /// Queries read spilled blocks back transparently.
+    pub fn spill_cold(&mut self) -> io::Result<SpillReport> {
+        let report = self.tsmap.spill_cold()?;
+        lock(&self.metrics).blocks_spilled += report.blocks_spilled as u64;
+        Ok(report)
+    }
+
+    /// Close every open block whose window has fully elapsed by `now`
+    ///
+    /// Blocks otherwise close only when a newer point arrives, so a series
+    /// that goes quiet would keep its last block open indefinitely. Closed
+    /// blocks count in `blocks_closed` and write their rollups as usual;
+    /// empty open blocks are left alone, so running it twice is harmless.
+    /// Returns the number of blocks closed. See `closer::BlockCloser` to
+    /// run it in the background.
+    pub fn close_stale_blocks(&self, now: u64) -> usize {
+        let closed: Vec<String> = self
+            .tsmap
+            .iter()
+            .filter_map(|series| {
+                let mut series = series.write();
+                series
+                    .close_stale_block(now)
+                    .then(|| series.key.to_string())
+            })
+            .collect();
+        lock(&self.metrics).blocks_closed += closed.len() as u64;
+        for key in &closed {
+            self.write_rollups(key);
+        }
+        closed.len()
+    }
+
+    /// Apply the tiered retention of every series as of `now`
+    ///
+    /// First closes stale blocks as close_stale_blocks does, so their
+    /// buckets reach the downsample companions. Then each series with a
+    /// `retention` drops the closed blocks whose points are all older than
+    /// `now - retention` and rolled up by every tier, and each companion
+    /// of a tier with a retention is trimmed to it the same way. Frozen
+    /// series are left alone. The drops aren't logged to the WAL, so a
+    /// recovery that replays those points brings them back; run this
+    /// again after recovering. Returns the number of blocks dropped.
+    pub fn enforce_tiers(&self, now: u64) -> usize {
+        self.close_stale_blocks(now);
+
+        // (key, horizon) of every series with a retention
+        let mut trims = Vec::new();
+        self.tsmap.scan(|series| {
+            let options = series.options();
+            if let Some(keep) = options.retention {
+                trims.push((series.key.to_string(), now.saturating_sub(keep)));
+            }
+            for tier in &options.downsample {
+                if let Some(keep) = tier.retention {
+                    trims.push((tier.companion_key(&series.key), now.saturating_sub(keep)));
+                }
+            }
+        });
+
+        let mut dropped = 0;
+        for (key, horizon) in trims {
+            let Some(series) = self.tsmap.get(&key) else {
+                continue;
+            };
+            let count = {
+                let mut series = series.write();
+                if series.is_frozen() {
+                    continue;
+                }
+                series.drop_blocks_before(horizon)
+            };
+            if count > 0 {
+                self.invalidate_cached(&key);
+                dropped += count;
+            }
+        }
+        dropped
+    }
+
+    /// The companion a query of `series` from `start` reads for the
+    /// part of the range raw data no longer holds, with the start of
+    /// the oldest raw block (where raw data takes over)
+    ///
+    /// None if the series' retention (by the instance's clock) reaches
+    /// back to `start`, or its blocks still do (enforce_tiers hasn't
+    /// dropped them yet). Otherwise it is the finest tier whose retention
+    /// covers `start`, or failing that the one that keeps the longest.
+    /// Tiers whose companion doesn't exist yet are passed over.
+    fn query_tier(&self, series: &SeriesHandle, start: u64) -> Option<(String, SeriesHandle, u64)> {
+        let now = self.tsmap.now();
+        let covers =
+            |retention: Option<u64>| retention.is_none_or(|keep| start >= now.saturating_sub(keep));
+        let (key, retention, mut tiers, held_from) = {
+            let series = series.read();
+            let options = series.options();
+            let held_from = series
+                .blocks()
+                .next()
+                .map_or(u64::MAX, |block| block.start_time);
+            if covers(options.retention) || start >= held_from {
+                return None;
+            }
+            (
+                series.key.to_string(),
+                options.retention,
+                options.downsample.clone(),
+                held_from,
+            )
+        };
+
+        tiers.sort_by_key(|tier| tier.width);
+        // The tier keeping the longest so far, raw included
+        let mut longest: Option<(String, SeriesHandle, u64)> = None;
+        let mut longest_kept = retention;
+        for tier in tiers {
+            let companion_key = tier.companion_key(&key);
+            let Some(companion) = self.get_queried(&companion_key) else {
+                continue;
+            };
+            if covers(tier.retention) {
+                return Some((companion_key, companion, held_from));
+            }
+            if tier.retention > longest_kept {
+                longest_kept = tier.retention;
+                longest = Some((companion_key, companion, held_from));
+            }
+        }
+        longest
+    }
+
+    /// Query data points within a time range
+    ///
+    /// Returns all points for the given key between start and end timestamps
+    ///
+    /// In production:
+    /// - Queries hit the closest regional instance
+    /// - Failed over automatically on node failure
+    /// - Can return partial results marked as such
+    ///
+    /// Paper: Query latency reduced from ~500ms (HBase) to ~7ms (Gorilla)
+    ///
+    /// An inverted range (start > end) reads as empty; use `try_query` to
+    /// have it reported.
+    pub fn query(&self, key: &str, start: u64, end: u64) -> Option<Vec<(u64, f64)>> {
+        match self.try_query(key, start, end) {
+            Ok(points) => Some(points),
+            Err(QueryError::SeriesNotFound(_)) => None,
+            Err(QueryError::InvalidRange { .. }) => self.contains(key).then(Vec::new),
+            Err(QueryError::CorruptBlock { .. }) => unreachable!("try_query skips corrupt blocks"),
+        }
+    }
+
+    /// Query data points within a time range, reporting why it failed
+    ///
+    /// Both bounds are inclusive; start == end asks for one timestamp and
+    /// end may be u64::MAX. The range is checked before the key. Blocks
+    /// that fail verification read as empty; `query_result` reports them.
+    ///
+    /// On a series with a `retention`, the part of a range before its
+    /// oldest remaining block is read from a downsample companion (see
+    /// enforce_tiers): the finest tier whose retention covers the start,
+    /// or the one that keeps the longest. So aged ranges come back at a
+    /// coarser step, followed by the raw points still held.
+    pub fn try_query(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<(u64, f64)>, QueryError> {
+        if start > end {
+            return Err(QueryError::InvalidRange { start, end });
+        }
+        let series = self
+            .get_queried(key)
+            .ok_or_else(|| QueryError::SeriesNotFound(key.to_string()))?;
+        let mut points = Vec::new();
+        let mut raw_start = start;
+        if let Some((companion_key, companion, held_from)) = self.query_tier(&series, start) {
+            points = self.read_range(&companion_key, &companion, start, end.min(held_from - 1));
+            raw_start = held_from;
+        }
+        if raw_start <= end {
+            points.extend(self.read_range(key, &series, raw_start, end));
+        }
+        Ok(points)
+    }
+
+    /// Read a range of one series, through the query cache if enabled
+    fn read_range(
+        &self,
+        key: &str,
+        series: &SeriesHandle,
+        start: u64,
+        end: u64,
+    ) -> Vec<(u64, f64)> {
+        let Some(cache) = &self.query_cache else {
+            let points = series.read().query(start, end);
+            return points.into_iter().map(Into::into).collect();
+        };
+
+        let cached = lock(cache).get(key, series, start, end);
+        let mut metrics = lock(&self.metrics);
+        if let Some(points) = cached {
+            metrics.query_cache_hits += 1;
+            return points;
+        }
+        metrics.query_cache_misses += 1;
+        drop(metrics);
+        // Cached under the read lock, so no write lands in between
+        let guard = series.read();
+        let points: Vec<(u64, f64)> = guard
+            .query(start, end)
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        lock(cache).insert(key, series, start, end, &points);
+        points
+    }
+
+    /// Query a time range, failing on blocks that don't verify
+    ///
+    /// Every block decoded from its compressed stream is checked against
+    /// the checksum taken when it was written. A bad block fails the
+    /// query with `QueryError::CorruptBlock` (the oldest one is named),
+    /// unless `partial` is set: then the healthy blocks' points come back
+    /// with the bad blocks listed in `QueryResult::corrupt_blocks`. The
+    /// range and key are checked as in `try_query`.
+    pub fn query_result(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+        partial: bool,
+    ) -> Result<QueryResult, QueryError> {
+        if start > end {
+            return Err(QueryError::InvalidRange { start, end });
+        }
+        let series = self
+            .get_queried(key)
+            .ok_or_else(|| QueryError::SeriesNotFound(key.to_string()))?;
+        let (points, corrupt_blocks) = series.read().query_verified(start, end);
+        if let Some(&block_start) = corrupt_blocks.first()
+            && !partial
+        {
+            return Err(QueryError::CorruptBlock {
+                key: key.to_string(),
+                block_start,
+            });
+        }
+        Ok(QueryResult {
+            points: points.into_iter().map(Into::into).collect(),
+            corrupt_blocks,
+        })
+    }
+
+    /// Points of a series' open block: its newest, still-growing block
+    ///
+    /// The cheapest "recent data" query, for live dashboards: no closed
+    /// block is looked at. Covers at most the current block window (less
+    /// if `max_points_per_block` split it), and is empty right after a
+    /// block closes or if the key doesn't exist.
+    pub fn query_open(&self, key: &str) -> Vec<(u64, f64)> {
+        self.get_queried(key).map_or_else(Vec::new, |series| {
+            series
+                .read()
+                .query_open()
+                .into_iter()
+                .map(Into::into)
+                .collect()
+        })
+    }
+
+    /// Points from the last `duration_secs` seconds, up to now
+    ///
+    /// Queries [now - duration_secs, now] by the instance's clock (see
+    /// GorillaConfig::clock); empty if the key doesn't exist.
+    pub fn query_last(&self, key: &str, duration_secs: u64) -> Vec<(u64, f64)> {
+        let now = self.tsmap.now();
+        self.query(key, now.saturating_sub(duration_secs), now)
+            .unwrap_or_default()
+    }
+
+    /// Query data points within a time range, as DataPoints
+    ///
+    /// Like `query`, but keeps the storage point type (which orders,
+    /// compares and, with the `serde` feature, serializes).
+    pub fn query_points(&self, key: &str, start: u64, end: u64) -> Option<Vec<DataPoint>> {
+        self.get_queried(key)
+            .map(|series| series.read().query(start, end))
+    }
+
+    /// Timestamps of the points in a time range, without their values
+    ///
+    /// For availability checks ("did it report every minute?"); compressed
+    /// blocks skip over their value bits. None if the key doesn't exist.
+    pub fn query_timestamps(&self, key: &str, start: u64, end: u64) -> Option<Vec<u64>> {
+        self.get_queried(key)
+            .map(|series| series.read().timestamps(start, end))
+    }
+
+    /// Values of the points in a time range, oldest first
+    ///
+    /// For statistics that don't need timestamps. None if the key
+    /// doesn't exist.
+    pub fn query_values(&self, key: &str, start: u64, end: u64) -> Option<Vec<f64>> {
+        self.get_queried(key)
+            .map(|series| series.read().values(start, end))
+    }
+
+    /// Query data points within a time range, newest first
+    ///
+    /// Stops after `limit` points (if given), so tail reads such as "the
+    /// last 50 points" only decode the newest blocks. None if the key
+    /// doesn't exist.
+    pub fn query_desc(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+        limit: Option<usize>,
+    ) -> Option<Vec<(u64, f64)>> {
+        let opts = QueryOpts {
+            limit,
+            order: Order::Descending,
+            ..QueryOpts::default()
+        };
+        self.query_opts(key, start, end, opts)
+    }
+
+    /// Query one page of a time range
+    ///
+    /// Skips `opts.offset` points in `opts.order`, then returns at most
+    /// `opts.limit`. Blocks are read lazily, so blocks past the page are
+    /// never touched. `opts.end_exclusive` leaves out a point at `end`.
+    /// None if the key doesn't exist.
+    pub fn query_opts(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+        opts: QueryOpts,
+    ) -> Option<Vec<(u64, f64)>> {
+        self.get_queried(key).map(|series| {
+            let Some(end) = opts.inclusive_end(end) else {
+                return Vec::new();
+            };
+            let series = series.read();
+            match opts.order {
+                Order::Ascending => opts.page(series.iter_range(start, end)),
+                Order::Descending => opts.page(series.iter_range_desc(start, end)),
+            }
+        })
+    }
+
+    /// Encode a range of a series as a self-delimiting Gorilla stream
+    ///
+    /// The blob can be shipped elsewhere and read back with
+    /// `GorillaStreamIter` or `decode_range`. Only raw points are
+    /// encoded, never a downsample companion's. None if the key doesn't
+    /// exist.
+    pub fn encode_range(&self, key: &str, start: u64, end: u64) -> Option<Vec<u8>> {
+        let points: Vec<(u64, f64)> = self
+            .query_points(key, start, end)?
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        stream::encode_range(&points)
+    }
+
+    /// Query a range as a Gorilla stream, to be decoded by the client
+    ///
+    /// Returns the same bytes as `encode_range`, but points go straight
+    /// from the series into the encoder: no point list is built and no
+    /// f64 is ever formatted on the server. Decode with
+    /// `GorillaStreamIter`. None if the key doesn't exist or two points
+    /// are too far apart for a stream (see `stream::encode_range`).
+    pub fn query_compressed(&self, key: &str, start: u64, end: u64) -> Option<Vec<u8>> {
+        let series = self.get_queried(key)?;
+        let series = series.read();
+        let mut points = series.iter_range(start, end);
+        let Some(first) = points.next() else {
+            return stream::encode_range(&[]);
+        };
+        let mut compressor = StreamCompressor::new(first.timestamp, first.value);
+        for point in points {
+            if !compressor.accepts(point.timestamp) {
+                return None;
+            }
+            compressor.push(point.timestamp, point.value);
+        }
+        Some(compressor.finish())
+    }
+
+    /// Query several series over the same range in one call
+    ///
+    /// Returns one entry per key, in input order: None for a key with no
+    /// series, Some (possibly empty) for one that exists. Each series is
+    /// read under its own read lock, so writers to the others carry on.
+    pub fn query_many(&self, keys: &[&str], start: u64, end: u64) -> Vec<KeyedPoints> {
+        keys.iter()
+            .map(|&key| (key.to_string(), self.query(key, start, end)))
+            .collect()
+    }
+
+    /// Query every series whose key matches a glob pattern
+    ///
+    /// `*` matches any run of characters (including none) and `?` any one
+    /// character; everything else matches itself. Returns the matching
+    /// series sorted by key, each with its points in [start, end]
+    /// (possibly none).
+    pub fn query_glob(
+        &self,
+        pattern: &str,
+        start: u64,
+        end: u64,
+    ) -> Vec<(String, Vec<(u64, f64)>)> {
+        let now = self.tsmap.now();
+        let mut matched = Vec::new();
+        self.tsmap.scan(|series| {
+            if !glob_matches(pattern, &series.key) {
+                return;
+            }
+            series.mark_accessed(now);
+            let points = series
+                .iter_range(start, end)
+                .map(|dp| (dp.timestamp, dp.value))
+                .collect();
+            matched.push((series.key.to_string(), points));
+        });
+        matched.sort_by(|a, b| a.0.cmp(&b.0));
+        matched
+    }
+
+    /// Query a time range in buckets of `step_secs`, one aggregate each
+    ///
+    /// Buckets are aligned to `start` and stamped with their start; the
+    /// last one may be cut short by `end`. Blocks wholly inside one
+    /// bucket are aggregated from their summaries without being decoded
+    /// (see TimeSeries::downsample). Empty buckets are left out, or with
+    /// EmptyBuckets::Nan given NaN (which inserts never store), so a
+    /// chart can draw them as gaps; that emits every bucket of the range,
+    /// so keep it bounded. None if the series doesn't exist. Panics if
+    /// `step_secs` is zero.
+    pub fn query_downsampled(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+        step_secs: u64,
+        aggregation: Aggregation,
+        empty: EmptyBuckets,
+    ) -> Option<Vec<(u64, f64)>> {
+        let series = self.get_queried(key)?;
+        let buckets = series.read().downsample(start, end, step_secs, aggregation);
+        if empty == EmptyBuckets::Omit || start > end {
+            return Some(buckets);
+        }
+
+        let mut filled = Vec::new();
+        let mut buckets = buckets.into_iter().peekable();
+        let mut bucket_start = Some(start);
+        while let Some(timestamp) = bucket_start.filter(|&timestamp| timestamp <= end) {
+            match buckets.next_if(|&(stamp, _)| stamp == timestamp) {
+                Some(bucket) => filled.push(bucket),
+                None => filled.push((timestamp, f64::NAN)),
+            }
+            bucket_start = timestamp.checked_add(step_secs);
+        }
+        Some(filled)
+    }
+
+    /// One aggregate over a time range, as a scalar (e.g. the max of the
+    /// last ten minutes, for an alert)
+    ///
+    /// Blocks wholly inside the range are aggregated from their summaries
+    /// without being decoded. NaN values, which inserts never store but
+    /// imported or restored data may hold, are handled per `nan`. None if
+    /// the series doesn't exist or the range holds no (counted) points.
+    pub fn aggregate(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+        aggregation: Aggregation,
+        nan: NanValues,
+    ) -> Option<f64> {
+        let series = self.get_queried(key)?;
+        let skip_nan = nan == NanValues::Skip;
+        series.read().aggregate(start, end, aggregation, skip_nan)
+    }
+
+    /// Number of points in a time range, NaN values included; 0 if the
+    /// series doesn't exist
+    pub fn count(&self, key: &str, start: u64, end: u64) -> usize {
+        self.aggregate(key, start, end, Aggregation::Count, NanValues::Propagate)
+            .map_or(0, |count| count as usize)
+    }
+
+    /// `aggregate` over several series, e.g. the keys a selector matched
+    ///
+    /// Returns one entry per key, in input order, None where `aggregate`
+    /// gives None.
+    pub fn aggregate_many(
+        &self,
+        keys: &[&str],
+        start: u64,
+        end: u64,
+        aggregation: Aggregation,
+        nan: NanValues,
+    ) -> Vec<(String, Option<f64>)> {
+        keys.iter()
+            .map(|&key| {
+                let value = self.aggregate(key, start, end, aggregation, nan);
+                (key.to_string(), value)
+            })
+            .collect()
+    }
+
+    /// Query only the points whose value satisfies a predicate
+    ///
+    /// The predicate is evaluated while streaming over the series, so
+    /// only qualifying points are ever collected (e.g. "samples above
+    /// threshold" without materializing the whole range first).
+    /// Returns an empty Vec if the series does not exist.
+    pub fn query_where<P>(&self, key: &str, start: u64, end: u64, pred: P) -> Vec<(u64, f64)>
+    where
+        P: Fn(f64) -> bool,
+    {
+        match self.get_queried(key) {
+            Some(series) => series
+                .read()
+                .iter_range(start, end)
+                .filter(|dp| pred(dp.value))
+                .map(|dp| (dp.timestamp, dp.value))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Query points in a time range whose value lies in [min, max]
+    ///
+    /// Blocks whose stored min/max can't intersect the value bounds are
+    /// skipped without being read (zone-map pruning).
+    pub fn query_value_range(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+        min: f64,
+        max: f64,
+    ) -> Option<Vec<(u64, f64)>> {
+        self.get_queried(key).map(|series| {
+            series
+                .read()
+                .iter_value_range(start, end, min, max)
+                .map(|dp| (dp.timestamp, dp.value))
+                .collect()
+        })
+    }
+
+    /// Total increase of a counter over a time range
+    ///
+    /// Sums the positive steps between consecutive points. A decrease is
+    /// treated as a counter reset, so the post-reset value counts as the
+    /// increase since the reset. Returns 0.0 for missing series or ranges
+    /// with fewer than two points.
+    pub fn increase(&self, key: &str, start: u64, end: u64) -> f64 {
+        let Some(series) = self.get_queried(key) else {
+            return 0.0;
+        };
+        let series = series.read();
+
+        let mut total = 0.0;
+        let mut prev: Option<f64> = None;
+        series.for_each_columns(start, end, |_, values| {
+            for &value in values {
+                if let Some(prev) = prev {
+                    total += counter_increase(prev, value);
+                }
+                prev = Some(value);
+            }
+        });
+        total
+    }
+
+    /// Per-second rate of a counter in buckets of `step_secs`
+    ///
+    /// Buckets are aligned to `start` and stamped with their start, as in
+    /// query_downsampled. Each step between consecutive points in
+    /// [start, end] counts toward the bucket of its later point, so a
+    /// bucket holding a single point still gets the step into it. A drop
+    /// is a counter reset, with the post-reset value taken as the
+    /// increase (as in `increase`), also when the reset is a bucket's
+    /// first point. A bucket's rate is its increase over the seconds its
+    /// steps span. Buckets without a step into them (empty, or holding
+    /// just the range's first point) are left out; NaN values are
+    /// skipped. None if the series doesn't exist. Panics if `step_secs`
+    /// is zero.
+    pub fn rate(&self, key: &str, start: u64, end: u64, step_secs: u64) -> Option<Vec<(u64, f64)>> {
+        self.bucket_slopes(key, start, end, step_secs, counter_increase)
+    }
+
+    /// Per-second rate of change of a gauge in buckets of `step_secs`
+    ///
+    /// Bucketed like `rate`, but without reset handling: a drop is a
+    /// negative change.
+    pub fn derivative(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+        step_secs: u64,
+    ) -> Option<Vec<(u64, f64)>> {
+        self.bucket_slopes(key, start, end, step_secs, |prev, value| value - prev)
+    }
+
+    /// The `q` quantile (0.5 for the median, 0.99 for p99) of the values
+    /// in [start, end]
+    ///
+    /// See `quantiles`, which also reports whether it is exact.
+    pub fn quantile(&self, key: &str, start: u64, end: u64, q: f64) -> Option<f64> {
+        self.quantiles(key, start, end, &[q])
+            .map(|result| result.values[0])
+    }
+
+    /// Several quantiles of the values in [start, end], in one pass
+    ///
+    /// Ranges of up to `exact_quantile_points` points (see GorillaConfig)
+    /// are exact, interpolating between the nearest ranks; larger ones
+    /// are estimated without holding their values, to within about 1%
+    /// of rank on typical data (see quantile.rs). `method` says which.
+    /// NaN values are skipped. None if the series doesn't exist or the
+    /// range holds no values. Panics if a quantile is outside [0, 1].
+    pub fn quantiles(&self, key: &str, start: u64, end: u64, qs: &[f64]) -> Option<QuantileResult> {
+        assert!(
+            qs.iter().all(|q| (0.0..=1.0).contains(q)),
+            "quantiles must be in [0, 1]"
+        );
+        let series = self.get_queried(key)?;
+        let series = series.read();
+        let points = series
+            .aggregate(start, end, Aggregation::Count, false)
+            .map_or(0, |count| count as usize);
+
+        if points <= self.exact_quantile_points {
+            let mut values = Vec::with_capacity(points);
+            series.for_each_columns(start, end, |_, chunk| {
+                values.extend(chunk.iter().filter(|value| !value.is_nan()));
+            });
+            return Some(QuantileResult {
+                values: quantile::exact(&mut values, qs)?,
+                method: QuantileMethod::Exact,
+            });
+        }
+        let mut estimators: Vec<P2> = qs.iter().map(|&q| P2::new(q)).collect();
+        let mut seen = false;
+        series.for_each_columns(start, end, |_, chunk| {
+            for &value in chunk.iter().filter(|value| !value.is_nan()) {
+                seen = true;
+                estimators
+                    .iter_mut()
+                    .for_each(|estimator| estimator.add(value));
+            }
+        });
+        Some(QuantileResult {
+            values: estimators.iter().map(P2::estimate).collect::<Option<_>>()?,
+            method: QuantileMethod::Approximate,
+        })
+        .filter(|_| seen)
+    }
+
+    /// Points in [start, end] smoothed by `smoothing`, one per point at
+    /// its timestamp
+    ///
+    /// Points whose window isn't full yet are left out or averaged over
+    /// what it holds, per `warm_up` (see Smoothing for each warm-up).
+    /// Points are read lazily, so only one window is held beyond the
+    /// output. NaN values are skipped. None if the series doesn't exist.
+    /// Panics on a zero window, or an EWMA alpha outside (0, 1].
+    pub fn smooth(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+        smoothing: Smoothing,
+        warm_up: WarmUp,
+    ) -> Option<Vec<(u64, f64)>> {
+        let series = self.get_queried(key)?;
+        let series = series.read();
+        Some(smooth::smooth(
+            series.iter_range(start, end),
+            smoothing,
+            warm_up,
+        ))
+    }
+
+    /// Shared body of rate and derivative: per bucket, the sum of
+    /// `change(prev, value)` over the steps into it, per second they span
+    fn bucket_slopes(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+        step_secs: u64,
+        change: impl Fn(f64, f64) -> f64,
+    ) -> Option<Vec<(u64, f64)>> {
+        assert!(step_secs > 0, "rate step must be positive");
+        let series = self.get_queried(key)?;
+        let series = series.read();
+
+        // (bucket start, total change, seconds spanned)
+        let mut buckets: Vec<(u64, f64, u64)> = Vec::new();
+        let mut prev: Option<(u64, f64)> = None;
+        series.for_each_columns(start, end, |timestamps, values| {
+            for (&timestamp, &value) in timestamps.iter().zip(values) {
+                if value.is_nan() {
+                    continue;
+                }
+                if let Some((prev_timestamp, prev_value)) = prev {
+                    let bucket = start + (timestamp - start) / step_secs * step_secs;
+                    let delta = change(prev_value, value);
+                    let elapsed = timestamp - prev_timestamp;
+                    match buckets.last_mut() {
+                        Some((last, total, seconds)) if *last == bucket => {
+                            *total += delta;
+                            *seconds += elapsed;
+                        }
+                        _ => buckets.push((bucket, delta, elapsed)),
+                    }
+                }
+                prev = Some((timestamp, value));
+            }
+        });
+        Some(
+            buckets
+                .into_iter()
+                .map(|(bucket, total, seconds)| (bucket, total / seconds as f64))
+                .collect(),
+        )
+    }
+
+    /// Query data points with values clamped into [min, max]
+    ///
+    /// This is a display transform, not a filter: every point in the range
+    /// is returned (timestamp cadence is preserved), but values outside the
+    /// bounds are capped to the nearest bound. Useful for charts where a
+    /// single spike would otherwise flatten the rest of the series.
+    /// None if the series doesn't exist, or if the bounds are NaN or
+    /// inverted (min > max).
+    pub fn query_clamped(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+        min: f64,
+        max: f64,
+    ) -> Option<Vec<(u64, f64)>> {
+        // f64::clamp panics on these
+        if min.is_nan() || max.is_nan() || min > max {
+            return None;
+        }
+        self.query(key, start, end).map(|points| {
+            points
+                .into_iter()
+                .map(|(ts, value)| (ts, value.clamp(min, max)))
+                .collect()
+        })
+    }
+
+    /// Get storage statistics for a time series
+    ///
+    /// This shows the compression efficiency achieved by Gorilla
+    /// Paper reports average of 1.37 bytes per data point (12x compression)
+    pub fn get_stats(&self, key: &str) -> CompressionStats {
+        if let Some(series) = self.tsmap.get(key) {
+            CompressionStats::from(series.read().get_stats())
+        } else {
+            CompressionStats::default()
+        }
+    }
+
+    /// Storage statistics summed over every live series
+    ///
+    /// The ratio is overall (total logical over total compressed), so
+    /// large series weigh more than small ones.
+    pub fn global_stats(&self) -> CompressionStats {
+        let mut total = StorageStats::default();
+        for series in self.tsmap.iter() {
+            total += series.read().get_stats();
+        }
+        CompressionStats::from(total)
+    }
+
+    /// Approximate memory held by the whole database
+    ///
+    /// Unlike get_stats, this counts allocated capacity (raw points,
+    /// compressed buffers, keys and bookkeeping), not just payload sizes.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.tsmap.memory_usage()
+    }
+
+    /// The `n` series holding the most memory, largest first
+    pub fn heaviest_series(&self, n: usize) -> Vec<(String, MemoryUsage)> {
+        let mut usages: Vec<(String, MemoryUsage)> = self
+            .tsmap
+            .iter()
+            .map(|series| {
+                let series = series.read();
+                (series.key.to_string(), series.memory_usage())
+            })
+            .collect();
+
+        usages.sort_by(|a, b| b.1.total().cmp(&a.1.total()).then_with(|| a.0.cmp(&b.0)));
+        usages.truncate(n);
+        usages
+    }
+
+    /// The `n` closed blocks that compress worst, across all series
+    ///
+    /// Each entry is (key, block start, bits per point), highest bits per
+    /// point first, to find series that compress poorly (random noise,
+    /// jittery timestamps). Open blocks are left out: their header cost
+    /// isn't spread over a full block yet.
+    pub fn worst_compressed_blocks(&self, n: usize) -> Vec<(String, u64, f64)> {
+        let mut blocks: Vec<(String, u64, f64)> = Vec::new();
+        for series in self.tsmap.iter() {
+            let series = series.read();
+            blocks.extend(
+                series
+                    .block_stats()
+                    .into_iter()
+                    .filter(|stats| !stats.open)
+                    .map(|stats| {
+                        (
+                            series.key.to_string(),
+                            stats.start_time,
+                            stats.bits_per_point(),
+                        )
+                    }),
+            );
+        }
+
+        blocks.sort_by(|a, b| {
+            b.2.total_cmp(&a.2)
+                .then_with(|| a.0.cmp(&b.0))
+                .then_with(|| a.1.cmp(&b.1))
+        });
+        blocks.truncate(n);
+        blocks
+    }
+
+    /// Iterate over every live series
+    ///
+    /// Unlike `scan`, this supports early exit and iterator adapters.
+    /// Each item is a handle; lock it with `read()` to look inside.
+    pub fn series_iter(&self) -> impl Iterator<Item = SeriesHandle> {
+        self.tsmap.iter()
+    }
+
+    /// Scan all time series
+    ///
+    /// Used for:
+    /// - Correlation search (Section 5.1)
+    /// - Background rollup aggregations (Section 5.3)
+    /// - Monitoring and debugging
+    ///
+    /// Paper: Gorilla can scan all data very efficiently for these operations
+    /// Demonstrated in Example 6
+    pub fn scan<F>(&self, mut f: F)
+    where
+        F: FnMut(&str, u64, f64),
+    {
+        self.tsmap.scan(|series| {
+            for point in series.query(0, u64::MAX) {
+                f(&series.key, point.timestamp, point.value);
+            }
+        });
+    }
+
+    /// Call `f` with every block holding points, for export pipelines
+    ///
+    /// Series come in map order; each series' blocks come oldest first
+    /// with the open block last. Blocks are read-only: use `start_time`,
+    /// `len` and `decode` on them. Each series is read-locked while its
+    /// blocks are visited.
+    pub fn for_each_block<F>(&self, mut f: F)
+    where
+        F: FnMut(&str, &TimeSeriesBlock),
+    {
+        self.tsmap.scan(|series| {
+            for block in series.blocks() {
+                f(&series.key, block);
+            }
+        });
+    }
+
+    /// Delete a time series
+    /// Used in Example 6 to demonstrate cleanup
+    ///
+    /// Deleting a missing key does nothing. The delete is logged first;
+    /// if that fails the series is kept and `WalAppendFailed` returned,
+    /// so recovery can't disagree with what was served.
+    pub fn delete(&mut self, key: &str) -> Result<(), TsdbError> {
+        if self.tsmap.get(key).is_some() && self.log(|wal| wal.append_delete(key)).is_none() {
+            return Err(TsdbError::WalAppendFailed);
+        }
+        let now = self.tsmap.now();
+        self.tsmap.delete(key, now);
+        self.invalidate_cached(key);
+        Ok(())
+    }
+
+    /// Make slots of series deleted more than the grace period ago reusable
+    ///
+    /// Returns the number of slots freed.
+    pub fn reap_tombstones(&mut self, now: u64) -> usize {
+        self.tsmap.reap_tombstones(now)
+    }
+
+    /// Remove every series, keeping allocations for reuse
+    ///
+    /// Faster than dropping and recreating the instance. With the WAL
+    /// enabled a delete is logged for each series, so recovery doesn't
+    /// bring them back. Engine metrics are kept.
+    pub fn clear(&mut self) {
+        if lock(&self.wal).is_some() {
+            let keys: Vec<String> = self
+                .tsmap
+                .iter()
+                .map(|series| series.read().key.to_string())
+                .collect();
+            for key in &keys {
+                self.log(|wal| wal.append_delete(key));
+            }
+        }
+        self.tsmap.clear();
+        if let Some(cache) = &self.query_cache {
+            lock(cache).clear();
+        }
+    }
+
+    /// Reclaim tombstoned slots left behind by deletes
+    ///
+    /// Returns the number of slots reclaimed. Useful after churny
+    /// workloads (e.g. short-lived container metrics) so scans don't
+    /// waste time skipping holes.
+    pub fn compact(&mut self) -> usize {
+        self.tsmap.compact()
+    }
+
+    /// Check the series index for internal consistency
+    ///
+    /// Verifies that every key maps to the live slot holding it and that
+    /// the free slot lists match the slots (see
+    /// TimeSeriesMap::check_invariants). Returns a description of the
+    /// first problem found; a healthy instance always passes.
+    pub fn self_check(&self) -> Result<(), String> {
+        self.tsmap.check_invariants()
+    }
+
+    /// Merge small adjacent closed blocks in every series
+    ///
+    /// Each series' runs of blocks are merged up to its own block duration
+    /// (see TimeSeries::compact_blocks). Returns the number of blocks
+    /// merged away.
+    pub fn compact_all(&mut self) -> usize {
+        self.tsmap
+            .iter()
+            .map(|series| {
+                let mut series = series.write();
+                let span = series.block_duration();
+                series.compact_blocks(span)
+            })
+            .sum()
+    }
+
+    /// Merge the history of `src` into `dst`, then delete `src`
+    ///
+    /// Every point of `src` (across all of its blocks) is inserted into
+    /// `dst` through the normal insert path, so overlapping ranges end up
+    /// interleaved in timestamp order and colliding timestamps are
+    /// resolved by `dst`'s duplicate policy. `dst` is created if missing.
+    ///
+    /// If a point can't be written (the WAL append fails, or `dst` would
+    /// exceed the series limit), the merge stops with `Rejected` and
+    /// `src` is kept; the points already written stay in `dst`. If the
+    /// delete of `src` can't be logged, it is kept too and
+    /// `WalAppendFailed` returned.
+    pub fn merge_series(&mut self, src: &str, dst: &str) -> Result<MergeReport, TsdbError> {
+        let report = self.merge_series_dry_run(src, dst)?;
+        if src == dst {
+            return Ok(report);
+        }
+
+        let points = self.source_points(src)?;
+        for point in points {
+            match self.write_point(dst, None, point.timestamp, point.value, false) {
+                Ok(_) | Err(InsertError::DuplicateRejected { .. }) => {}
+                Err(error) => return Err(TsdbError::Rejected(error)),
+            }
+        }
+        self.delete(src)?;
+
+        Ok(report)
+    }
+
+    /// Report what merge_series would do without changing anything
+    pub fn merge_series_dry_run(&self, src: &str, dst: &str) -> Result<MergeReport, TsdbError> {
+        let points = self.source_points(src)?;
+        if src == dst {
+            return Ok(MergeReport::default());
+        }
+
+        let collisions = match self.tsmap.get(dst) {
+            Some(target) => {
+                let target = target.read();
+                if target.is_frozen() {
+                    return Err(TsdbError::Frozen(dst.to_string()));
+                }
+                points
+                    .iter()
+                    .filter(|p| target.contains_timestamp(p.timestamp))
+                    .count()
+            }
+            None => 0,
+        };
+
+        Ok(MergeReport {
+            points_moved: points.len(),
+            collisions,
+        })
+    }
+
+    fn source_points(&self, src: &str) -> Result<Vec<DataPoint>, TsdbError> {
+        self.tsmap
+            .get(src)
+            .map(|series| series.read().query(0, u64::MAX))
+            .ok_or_else(|| TsdbError::SeriesNotFound(src.to_string()))
+    }
+
+    /// Write every series to a snapshot file
+    ///
+    /// Each series is stored with its options and the compressed bytes of
+    /// all of its blocks (including the open block, as it currently
+    /// stands). In-memory state is left untouched. With the WAL enabled,
+    /// the snapshot records the current log position so recovery only
+    /// replays what comes after it.
+    pub fn snapshot(&self, path: &Path) -> io::Result<SnapshotInfo> {
+        let position = lock(&self.wal)
+            .as_ref()
+            .map_or(WalPosition::default(), |wal| wal.position());
+        snapshot::write_snapshot(&self.tsmap, position, path, false)
+    }
+
+    /// Write every series to a snapshot file, merging split blocks
+    ///
+    /// Like `snapshot`, only compressed block bytes and live series are
+    /// written (raw points and deleted slots never are), but each
+    /// series' closed blocks are also merged as `compact_all` would
+    /// merge them, so a window split by backfills or point limits is
+    /// stored as one stream. In-memory state is left untouched; `load`
+    /// reads the file as any other snapshot.
+    pub fn snapshot_compact(&self, path: &Path) -> io::Result<SnapshotInfo> {
+        let position = lock(&self.wal)
+            .as_ref()
+            .map_or(WalPosition::default(), |wal| wal.position());
+        snapshot::write_snapshot(&self.tsmap, position, path, true)
+    }
+
+    /// Rebuild a Gorilla instance from a snapshot file
+    ///
+    /// Corrupt or truncated snapshots return an `InvalidData` error.
+    /// Engine metrics start from zero.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(Gorilla {
+            tsmap: snapshot::read_snapshot(path)?.0,
+            metrics: Mutex::default(),
+            wal: Mutex::default(),
+            chunks: None,
+            max_series: None,
+            future_tolerance: Some(DEFAULT_FUTURE_TOLERANCE_SECS),
+            max_memory_bytes: None,
+            evict_callback: None,
+            query_cache: None,
+            exact_quantile_points: DEFAULT_EXACT_QUANTILE_POINTS,
+        })
+    }
+
+    /// Write every series to a shard directory as a new checkpoint
+    ///
+    /// Follows the paper's on-disk layout (see storage/disk.rs): a key
+    /// list with each series' options, an append-only file of compressed
+    /// blocks and a checkpoint marker, written last. The previous
+    /// complete checkpoint is kept as a fallback. Labels, metadata and
+    /// the WAL position aren't recorded; use `snapshot` for those.
+    pub fn persist_to(&self, dir: &Path) -> io::Result<CheckpointInfo> {
+        let mut shard = ShardDir::open(dir)?;
+        let mut keys = Vec::new();
+        for handle in self.tsmap.iter() {
+            let series = handle.read();
+            let key_id = u32::try_from(keys.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many series"))?;
+            shard.append_series(key_id, &series)?;
+            keys.push((series.key.to_string(), key_id, series.options().clone()));
+        }
+        shard.write_key_list(&keys)?;
+        shard.mark_checkpoint()
+    }
+
+    /// Rebuild a Gorilla instance from the newest complete checkpoint in
+    /// a shard directory written by `persist_to`
+    ///
+    /// A checkpoint whose marker never landed is skipped in favor of the
+    /// one before it; a directory without any starts empty. Corrupt files
+    /// return an `InvalidData` error.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let mut gorilla = Self::new();
+        gorilla.tsmap = ShardDir::open(dir)?.load()?;
+        Ok(gorilla)
+    }
+
+    /// Persist every block under `root`, one directory per UTC day of
+    /// the blocks' start times (see partition.rs)
+    ///
+    /// Returns the manifest of each day written, oldest first. Old days
+    /// are then dropped with `prune_days_before`. As with
+    /// `persist_to`, labels and metadata aren't recorded.
+    pub fn persist_by_day(&self, root: &Path) -> io::Result<Vec<DayManifest>> {
+        PartitionedDir::open(root)?.persist(&self.tsmap)
+    }
+
+    /// Rebuild a Gorilla instance from every complete day under `root`
+    /// (see `persist_by_day`)
+    pub fn open_by_day(root: &Path) -> io::Result<Self> {
+        let mut gorilla = Self::new();
+        gorilla.tsmap = PartitionedDir::open(root)?.load()?;
+        Ok(gorilla)
+    }
+
+    /// Rebuild a Gorilla instance from the days under `root` holding
+    /// points in [start, end]
+    ///
+    /// Other days are skipped without opening anything but their
+    /// manifests, so series come back with only the blocks of the days
+    /// read. Such a partial instance is for querying; persisting it by
+    /// day again would drop the skipped days.
+    pub fn open_by_day_range(root: &Path, start: u64, end: u64) -> io::Result<Self> {
+        let mut gorilla = Self::new();
+        gorilla.tsmap = PartitionedDir::open(root)?.load_range(start, end)?;
+        Ok(gorilla)
+    }
+
+    /// Delete the days under `root` whose newest point is older than
+    /// `horizon` (see `persist_by_day`)
+    ///
+    /// Whole day directories go; incomplete days are left alone. Returns
+    /// the names of the days removed, oldest first.
+    pub fn prune_days_before(root: &Path, horizon: u64) -> io::Result<Vec<String>> {
+        PartitionedDir::open(root)?.prune_before(horizon)
+    }
+
+    /// Create an empty series with its own options
+    ///
+    /// For series that need something other than the instance defaults
+    /// (e.g. 10-minute blocks for a 1-second probe, day-long blocks and
+    /// the small-integer hint for a billing counter). Later inserts use
+    /// the series' options. Creating a series that already exists is a
+    /// no-op with the same options and fails with `SeriesExists` with
+    /// different ones. A new series is logged to the WAL with its
+    /// options, so recovery recreates it as it was; if that append
+    /// fails, nothing is created and `WalAppendFailed` is returned.
+    /// Panics if `options.block_duration` is zero.
+    pub fn create_series(&self, key: &str, options: SeriesOptions) -> Result<(), TsdbError> {
+        let exists = self.contains(key);
+        if let Some(limit) = self.max_series {
+            let current = self.tsmap.series_count();
+            if current >= limit && !exists {
+                lock(&self.metrics).series_rejected += 1;
+                return Err(TsdbError::CardinalityLimitExceeded { current, limit });
+            }
+        }
+        // Logged ahead of the series' points, so replay creates it first
+        let logged = if exists {
+            None
+        } else {
+            let wal = self.log(|wal| wal.append_create(key, &options));
+            Some(wal.ok_or(TsdbError::WalAppendFailed)?)
+        };
+        let series = self.tsmap.get_or_insert(key, &options);
+        drop(logged);
+        if series.read().options() != &options {
+            return Err(TsdbError::SeriesExists(key.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Rename a time series, keeping all of its history
+    ///
+    /// Fails if `old_key` does not exist or `new_key` is already taken,
+    /// and with `WalAppendFailed` (renaming nothing) if the rename can't
+    /// be logged. Block data is never moved or recompressed; only the
+    /// key changes.
+    pub fn rename(&mut self, old_key: &str, new_key: &str) -> Result<(), TsdbError> {
+        if !self.contains(old_key) {
+            return Err(TsdbError::SeriesNotFound(old_key.to_string()));
+        }
+        if self.contains(new_key) {
+            return Err(TsdbError::SeriesExists(new_key.to_string()));
+        }
+        // Logged before the map changes, so a failed append renames nothing
+        if self
+            .log(|wal| wal.append_rename(old_key, new_key))
+            .is_none()
+        {
+            return Err(TsdbError::WalAppendFailed);
+        }
+        self.tsmap.rename(old_key, new_key)?;
+        self.invalidate_cached(old_key);
+        self.invalidate_cached(new_key);
+        Ok(())
+    }
+
+    /// Remove a series' closed blocks that fail verification
+    ///
+    /// The repair for `QueryError::CorruptBlock`: blocks whose compressed
+    /// stream doesn't match its checksum, or whose spill file can't be
+    /// read, are dropped with their points. Returns their start times.
+    /// The removal isn't logged to the WAL, so a recovery that replays
+    /// those points brings them back.
+    pub fn drop_corrupt_blocks(&mut self, key: &str) -> Result<Vec<u64>, TsdbError> {
+        let dropped = self
+            .tsmap
+            .get_mut(key)
+            .ok_or_else(|| TsdbError::SeriesNotFound(key.to_string()))?
+            .drop_corrupt_blocks();
+        self.invalidate_cached(key);
+        Ok(dropped)
+    }
+
+    /// Make a series read-only, e.g. for forensics or after archiving
+    ///
+    /// Inserts into a frozen series fail with `InsertError::SeriesFrozen`,
+    /// merging into it fails with `TsdbError::Frozen`, and idle
+    /// expiry and memory-cap eviction skip it. Queries, stats, explicit
+    /// deletes and renames work as usual. The open block is sealed so
+    /// its compression is final.
+    pub fn freeze(&mut self, key: &str) -> Result<(), TsdbError> {
+        self.set_frozen(key, true)
+    }
+
+    /// Make a frozen series writable again
+    ///
+    /// Points for the sealed block's window start a new block after it.
+    pub fn unfreeze(&mut self, key: &str) -> Result<(), TsdbError> {
+        self.set_frozen(key, false)
+    }
+
+    fn set_frozen(&mut self, key: &str, frozen: bool) -> Result<(), TsdbError> {
+        let mut series = self
+            .tsmap
+            .get_mut(key)
+            .ok_or_else(|| TsdbError::SeriesNotFound(key.to_string()))?;
+        series.set_frozen(frozen);
+        if frozen {
+            series.seal_open_block();
+        }
+        drop(series);
+        self.log(|wal| wal.append_freeze(key, frozen));
+        Ok(())
+    }
+}
+
+/// Statistics about compression efficiency
+///
+/// `logical_compression_ratio` compares 16 bytes per point with the
+/// compressed streams. Raw points kept next to them (unless
+/// `drop_raw_on_close` is set) mean the memory actually saved is less;
+/// the resident fields show what is held.
+#[derive(Debug, Default)]
+pub struct CompressionStats {
+    pub logical_uncompressed_bytes: usize,
+    pub compressed_size: usize,
+    pub resident_raw_bytes: usize,
+    pub resident_compressed_bytes: usize,
+    pub logical_compression_ratio: f64,
+}
+
+impl From<StorageStats> for CompressionStats {
+    fn from(stats: StorageStats) -> Self {
+        CompressionStats {
+            logical_uncompressed_bytes: stats.logical_uncompressed_bytes,
+            compressed_size: stats.compressed_size,
+            resident_raw_bytes: stats.resident_raw_bytes,
+            resident_compressed_bytes: stats.resident_compressed_bytes,
+            logical_compression_ratio: stats.logical_compression_ratio(),
+        }
+    }
+}
+
+/// One series returned by query_selector
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectedSeries {
+    pub key: String,
+    pub labels: BTreeMap<String, String>,
+    pub points: Vec<(u64, f64)>,
+}
+
+/// One entry returned by list_series
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeriesListing {
+    pub key: String,
+    pub meta: Option<SeriesMeta>, // Only filled in when requested
+}
+
+/// What query_downsampled does with buckets holding no points
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EmptyBuckets {
+    #[default]
+    Omit, // Left out of the result
+    Nan, // Emitted with a NaN value, as a gap
+}
+
+/// What aggregate does with NaN values in the range
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NanValues {
+    #[default]
+    Skip, // Left out, as if absent
+    Propagate, // Make the aggregate NaN (Count counts them)
+}
+
+/// Direction in which a query returns points
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    #[default]
+    Ascending, // Oldest first
+    Descending, // Newest first
+}
+
+/// Paging and range options for query_opts
+///
+/// With `end_exclusive`, a point exactly at `end` is left out, so
+/// consecutive windows like [0, 3600) and [3600, 7200) never both return
+/// the boundary point. The default keeps both bounds inclusive.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct QueryOpts {
+    pub limit: Option<usize>, // Most points returned; None for no limit
+    pub offset: usize,        // Points skipped before the page starts
+    pub order: Order,
+    pub end_exclusive: bool, // Range is [start, end) instead of [start, end]
+}
+
+impl QueryOpts {
+    /// The last timestamp the range includes; None if it includes none
+    fn inclusive_end(&self, end: u64) -> Option<u64> {
+        if self.end_exclusive {
+            end.checked_sub(1)
+        } else {
+            Some(end)
+        }
+    }
+
+    /// Cut this page out of points already in the requested order
+    fn page(&self, points: impl Iterator<Item = DataPoint>) -> Vec<(u64, f64)> {
+        points
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .map(|dp| (dp.timestamp, dp.value))
+            .collect()
+    }
+}
+
+/// Points returned by query_result
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct QueryResult {
+    pub points: Vec<(u64, f64)>,
+    pub corrupt_blocks: Vec<u64>, // Start times of blocks left out (partial results only)
+}
+
+/// What an accepted insert did (see Gorilla::try_insert)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertOutcome {
+    /// The point was added to an existing series
+    Inserted,
+
+    /// The series didn't exist; it was created holding this point
+    CreatedSeries,
+
+    /// A point at the same timestamp was overwritten (KeepLast)
+    ReplacedDuplicate,
+
+    /// A point at the same timestamp was kept and this one dropped
+    /// (KeepFirst)
+    IgnoredDuplicate,
+}
+
+/// The result try_insert reports for a point's insert effect
+fn insert_outcome(effect: InsertEffect, timestamp: u64) -> Result<InsertOutcome, InsertError> {
+    match effect.write {
+        PointWrite::Added if effect.created_series => Ok(InsertOutcome::CreatedSeries),
+        PointWrite::Added => Ok(InsertOutcome::Inserted),
+        PointWrite::Replaced => Ok(InsertOutcome::ReplacedDuplicate),
+        PointWrite::Ignored => Ok(InsertOutcome::IgnoredDuplicate),
+        PointWrite::Rejected => Err(InsertError::DuplicateRejected { timestamp }),
+    }
+}
+
+/// One point of a multi-series write (see Gorilla::write)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample<'a> {
+    pub key: &'a str,
+    pub timestamp: u64,
+    pub value: f64,
+}
+
+/// What Gorilla::insert_batch did with a batch of points
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BatchOutcome {
+    /// Points whose value was stored, including replaced duplicates
+    pub inserted: usize,
+
+    /// Points refused by a check or the duplicate policy, or not logged
+    pub skipped: usize,
+}
+
+/// What Gorilla::rollup wrote
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RollupReport {
+    pub series: usize, // Output series that received points
+    pub points: usize, // Window aggregates written
+}
+
+/// Outcome of merging one series into another
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MergeReport {
+    pub points_moved: usize, // Points taken from the source series
+    pub collisions: usize,   // Of those, timestamps already present in the destination
+}
+
+/// Counters describing the engine's own behavior
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct EngineMetrics {
+    pub points_inserted: u64,    // Points accepted by insert
+    pub bytes_compressed: u64,   // Compressed bytes produced by those points
+    pub blocks_closed: u64,      // Open blocks sealed into closed blocks
+    pub inserts_rejected: u64,   // Inserts refused (e.g. NaN values)
+    pub wal_errors: u64,         // Failed write-ahead log appends
+    pub blocks_spilled: u64,     // Closed blocks moved to the spill directory
+    pub blocks_reloaded: u64,    // Spilled blocks read back from disk
+    pub spill_read_errors: u64,  // Spill files that could not be read back
+    pub chunk_errors: u64,       // Failed writes to the chunk files
+    pub series: u64,             // Live series right now
+    pub series_rejected: u64,    // Series creations refused by max_series
+    pub series_evicted: u64,     // Series dropped to stay under max_memory_bytes
+    pub query_cache_hits: u64,   // Queries answered from the query cache
+    pub query_cache_misses: u64, // Queries the cache was checked for and lacked
+}
+
+/// Use cases enabled by Gorilla (from Section 5)
+///
+/// 1. Time series correlation (Section 5.1)
+///    - Brute-force search across 1M+ time series
+///    - Uses PPMCC (Pearson correlation)
+///    - Helps answer: "What happened when my service broke?"
+///
+/// 2. Advanced charting (Section 5.2)
+///    - Horizon charts with large datasets
+///    - Visual anomaly detection
+///    - Real-time dashboards
+///
+/// 3. Efficient aggregations (Section 5.3)
+///    - Rollup operations run directly on Gorilla
+///    - No longer need expensive HBase scans
+///    - Reduced load on persistent storage
+impl Gorilla {
+    /// Example: Find correlated time series (simplified version of Section 5.1)
+    ///
+    /// In production, this calculates Pearson Product-Moment Correlation
+    /// Coefficient (PPMCC) across all time series
+    /// Demonstrated in Example 6
+    ///
+    /// The needle's points are copied out first, then the scan read-locks
+    /// one series at a time, so no lock is held across series and this
+    /// can run alongside writers without deadlocking.
+    pub fn find_correlated(
+        &self,
+        needle_key: &str,
+        start: u64,
+        end: u64,
+        top_n: usize,
+    ) -> Vec<(String, f64)> {
+        // Get the needle time series
+        let needle = match self.query_columns(needle_key, start, end) {
+            Some(data) => data,
+            None => return Vec::new(),
+        };
+
+        if needle.is_empty() {
+            return Vec::new();
+        }
+
+        let mut correlations = Vec::new();
+
+        // Scan all time series and calculate correlation
+        self.tsmap.scan(|series| {
+            if &*series.key == needle_key {
+                return; // Skip self
+            }
+
+            let data = series.columns(start, end);
+            if data.len() != needle.len() {
+                return; // Need same length for correlation
+            }
+
+            // Simple correlation calculation (simplified)
+            let correlation = pearson(needle.values(), data.values());
+            correlations.push((series.key.to_string(), correlation));
+        });
+
+        // Sort by absolute correlation and take top N
+        correlations.sort_by(|a, b| b.1.abs().partial_cmp(&a.1.abs()).unwrap());
+        correlations.truncate(top_n);
+
+        correlations
+    }
+
+    /// Lag (in samples) at which `key_b` best follows `key_a`
+    ///
+    /// At lag `k` each point `i` of `key_a` in [start, end] is paired with
+    /// point `i + k` of `key_b`, so a positive lag means `key_b` trails
+    /// `key_a`. Every lag in [-max_lag, max_lag] leaving at least three
+    /// pairs is tried; returns the one with the largest absolute
+    /// correlation (the smallest lag on ties) and that correlation. None
+    /// if a series is missing or no lag has enough pairs.
+    pub fn best_lag(
+        &self,
+        key_a: &str,
+        key_b: &str,
+        start: u64,
+        end: u64,
+        max_lag: usize,
+    ) -> Option<(i64, f64)> {
+        let a = self.query_columns(key_a, start, end)?;
+        let b = self.query_columns(key_b, start, end)?;
+
+        let lags = (0..=max_lag as i64).flat_map(|lag| [lag, -lag]).skip(1);
+        let mut best: Option<(i64, f64)> = None;
+        for lag in lags {
+            // Point i of a pairs with point i + lag of b
+            let shift = lag.unsigned_abs() as usize;
+            let (a, b) = if lag >= 0 {
+                (a.values(), b.values().get(shift..).unwrap_or_default())
+            } else {
+                (a.values().get(shift..).unwrap_or_default(), b.values())
+            };
+            let pairs = a.len().min(b.len());
+            if pairs < 3 {
+                continue;
+            }
+
+            let correlation = pearson(&a[..pairs], &b[..pairs]);
+            if best.is_none_or(|(_, r)| correlation.abs() > r.abs()) {
+                best = Some((lag, correlation));
+            }
+        }
+        best
+    }
+
+    /// Pearson correlation of two series over a sliding window
+    ///
+    /// The series are aligned on the timestamps they share in
+    /// [start, end]. Every run of `window` consecutive aligned points gives
+    /// one coefficient, keyed by the timestamp ending that run. Empty if
+    /// either series is missing, `window` is below 2, or there are fewer
+    /// aligned points than `window`.
+    pub fn rolling_correlation(
+        &self,
+        key_a: &str,
+        key_b: &str,
+        start: u64,
+        end: u64,
+        window: usize,
+    ) -> Vec<(u64, f64)> {
+        let (Some(a), Some(b)) = (
+            self.query_columns(key_a, start, end),
+            self.query_columns(key_b, start, end),
+        ) else {
+            return Vec::new();
+        };
+        if window < 2 {
+            return Vec::new();
+        }
+
+        let (timestamps, values_a, values_b) = align_points(&a, &b);
+        (window..=timestamps.len())
+            .map(|end| {
+                let run = end - window..end;
+                (
+                    timestamps[end - 1],
+                    pearson(&values_a[run.clone()], &values_b[run]),
+                )
+            })
+            .collect()
+    }
+
+    /// Roll up every series whose key matches a glob pattern into
+    /// derived series of windowed aggregates (Section 5.3)
+    ///
+    /// Windows are `window_secs` wide and aligned to multiples of it;
+    /// each one wholly inside [start, end] becomes one point, stamped
+    /// with the window start, in the series named by `output_template`
+    /// with `{key}` replaced by the source key (e.g.
+    /// `rollup.1h.avg.{key}`). An output series' last point marks how far
+    /// it is rolled up, so calling again only processes later windows
+    /// (empty windows give no point). Keys that are themselves outputs
+    /// of this rollup are skipped, so `*` doesn't roll up its own
+    /// results. Panics if `window_secs` is zero or the template lacks
+    /// `{key}`.
+    pub fn rollup(
+        &self,
+        pattern: &str,
+        start: u64,
+        end: u64,
+        window_secs: u64,
+        aggregation: Aggregation,
+        output_template: &str,
+    ) -> RollupReport {
+        assert!(window_secs > 0, "rollup window must be positive");
+        assert!(
+            output_template.contains("{key}"),
+            "rollup output template needs {{key}}"
+        );
+        let mut report = RollupReport::default();
+        // The last window that ends by `end`, and the first one from `start`
+        let Some(latest) = end.checked_sub(window_secs - 1) else {
+            return report;
+        };
+        let last_window = latest / window_secs * window_secs;
+        let Some(first_window) = start.div_ceil(window_secs).checked_mul(window_secs) else {
+            return report;
+        };
+
+        let mut keys = Vec::new();
+        self.tsmap.scan(|series| {
+            if glob_matches(pattern, &series.key) {
+                keys.push(series.key.to_string());
+            }
+        });
+        keys.sort();
+        let output_key = |key: &str| output_template.replace("{key}", key);
+        let outputs: HashSet<String> = keys.iter().map(|key| output_key(key)).collect();
+
+        for key in keys.iter().filter(|&key| !outputs.contains(key)) {
+            let output = output_key(key);
+            let rolled_up = self.tsmap.get(&output).and_then(|series| {
+                let last = series.read().iter_range_desc(0, u64::MAX).next();
+                last.map(|point| point.timestamp.saturating_add(window_secs))
+            });
+            let from = rolled_up.map_or(first_window, |next| next.max(first_window));
+            let Some(series) = self.tsmap.get(key).filter(|_| from <= last_window) else {
+                continue;
+            };
+            let to = last_window + (window_secs - 1);
+            let points = series.read().downsample(from, to, window_secs, aggregation);
+            if points.is_empty() {
+                continue;
+            }
+            if let Ok(outcome) = self.insert_batch(&output, &points)
+                && outcome.inserted > 0
+            {
+                report.series += 1;
+                report.points += outcome.inserted;
+            }
+        }
+        report
+    }
+}
+
+/// How much a counter went up from `prev` to `value`; a drop is a
+/// reset, after which the counter restarted from zero
+fn counter_increase(prev: f64, value: f64) -> f64 {
+    if value >= prev { value - prev } else { value }
+}
+
+/// Lock a mutex, carrying on if a panicking thread poisoned it
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Whether `key` matches a glob pattern of `*` (any run) and `?` (any
+/// one character)
+///
+/// Walks both once, backtracking only to the last `*`.
+fn glob_matches(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let (mut p, mut k) = (0, 0);
+    // Position after the last '*' seen, and the key position it resumes at
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, k));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == key[k] => {
+                p += 1;
+                k += 1;
+            }
+            _ => match star {
+                // Let the last '*' take one more character
+                Some((after, from)) => {
+                    star = Some((after, from + 1));
+                    p = after;
+                    k = from + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Pearson correlation (PPMCC) of paired values; 0.0 if either side is flat
+///
+/// Used by find_correlated() in Example 6. `values1[i]` pairs with
+/// `values2[i]`; both slices must have the same length.
+fn pearson(values1: &[f64], values2: &[f64]) -> f64 {
+    debug_assert_eq!(values1.len(), values2.len());
+    if values1.is_empty() {
+        return 0.0;
+    }
+
+    let n = values1.len() as f64;
+
+    // Calculate means
+    let mean1: f64 = values1.iter().sum::<f64>() / n;
+    let mean2: f64 = values2.iter().sum::<f64>() / n;
+
+    // Calculate correlation
+    let mut numerator = 0.0;
+    let mut sum_sq1 = 0.0;
+    let mut sum_sq2 = 0.0;
+
+    for (&v1, &v2) in values1.iter().zip(values2) {
+        let diff1 = v1 - mean1;
+        let diff2 = v2 - mean2;
+        numerator += diff1 * diff2;
+        sum_sq1 += diff1 * diff1;
+        sum_sq2 += diff2 * diff2;
+    }
+
+    let denominator = (sum_sq1 * sum_sq2).sqrt();
+    if denominator == 0.0 {
         0.0
     } else {
         numerator / denominator
     }
-}
+}
+
+/// Pair up the points of two series that share a timestamp
+///
+/// Returns the shared timestamps with the matching values of `a` and
+/// of `b`, as parallel columns; points without a partner are skipped.
+fn align_points(a: &PointColumns, b: &PointColumns) -> (Vec<u64>, Vec<f64>, Vec<f64>) {
+    let mut aligned = (Vec::new(), Vec::new(), Vec::new());
+    let (mut i, mut j) = (0, 0);
+    let (a_times, b_times) = (a.timestamps(), b.timestamps());
+    while i < a_times.len() && j < b_times.len() {
+        match a_times[i].cmp(&b_times[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                aligned.0.push(a_times[i]);
+                aligned.1.push(a.values()[i]);
+                aligned.2.push(b.values()[j]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    aligned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::FORMAT_VERSION;
+    use crate::compression::timestamp::TimestampCodec;
+    use crate::compression::value::{ValueCodec, ValueHint};
+    use crate::storage::DuplicatePolicy;
+    use crate::storage::chunks::ChunkConfig;
+    use crate::storage::clock::{Clock, TestClock};
+    use crate::storage::downsample::{Aggregation, DownsampleTier};
+    use crate::storage::labels::Matcher;
+    use crate::storage::snapshot::SNAPSHOT_VERSION;
+    use crate::storage::spill::SpillConfig;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_basic_operations() {
+        let gorilla = Gorilla::new();
+
+        // Use current time to ensure we're within a valid block
+        let base_time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        // Insert some data
+        gorilla.insert("cpu.usage", base_time, 45.2);
+        gorilla.insert("cpu.usage", base_time + 60, 46.1);
+        gorilla.insert("cpu.usage", base_time + 120, 45.8);
+
+        // Query it back
+        let results = gorilla
+            .query("cpu.usage", base_time, base_time + 200)
+            .unwrap();
+        assert_eq!(
+            results.len(),
+            3,
+            "Expected 3 results, got {}",
+            results.len()
+        );
+        assert_eq!(results[0].1, 45.2);
+
+        // Check compression
+        let stats = gorilla.get_stats("cpu.usage");
+        println!("Compression: {}x", stats.logical_compression_ratio);
+        assert!(stats.logical_compression_ratio > 1.0);
+
+        // The open block still holds its raw points next to the stream
+        assert!(stats.resident_raw_bytes >= stats.logical_uncompressed_bytes);
+        assert!(stats.resident_compressed_bytes >= stats.compressed_size);
+
+        // Test that key field is accessible
+        gorilla.scan(|key, _ts, _val| {
+            println!("Scanned series: {}", key);
+        });
+    }
+
+    #[test]
+    fn test_compression_efficiency() {
+        let gorilla = Gorilla::new();
+
+        // A day back, so every point is within the future tolerance
+        let base_time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            - 86400;
+
+        // Insert identical values (should compress to ~1 bit each)
+        for i in 0..100 {
+            gorilla.insert("memory.used", base_time + i * 60, 8192.0);
+        }
+
+        let stats = gorilla.get_stats("memory.used");
+        println!("100 identical values:");
+        println!("  Logical: {} bytes", stats.logical_uncompressed_bytes);
+        println!("  Compressed: {} bytes", stats.compressed_size);
+        println!("  Resident raw: {} bytes", stats.resident_raw_bytes);
+        println!("  Ratio: {:.2}x", stats.logical_compression_ratio);
+
+        // Should achieve very high compression
+        assert!(stats.logical_compression_ratio > 10.0);
+
+        // ...of the encoding; in memory, the raw points are still kept
+        assert!(stats.resident_raw_bytes >= 100 * 16);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let gorilla = Gorilla::new();
+
+        let base_time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            - 86400;
+
+        // 50 points in the current window, then 10 more a block later
+        for i in 0..50 {
+            gorilla.insert("cpu.usage", base_time + i * 60, 40.0 + (i % 7) as f64);
+        }
+        for i in 0..10 {
+            gorilla.insert("cpu.usage", base_time + 7200 + i * 60, 41.0);
+        }
+        gorilla.insert("cpu.usage", base_time + 9000, f64::NAN);
+
+        let metrics = gorilla.metrics();
+        assert_eq!(metrics.points_inserted, 60);
+        assert_eq!(metrics.inserts_rejected, 1);
+        assert!(metrics.blocks_closed >= 1);
+        assert_eq!(
+            metrics.bytes_compressed,
+            gorilla.get_stats("cpu.usage").compressed_size as u64
+        );
+
+        // The rejected NaN was never stored
+        let stored = gorilla.query("cpu.usage", 0, u64::MAX).unwrap();
+        assert_eq!(stored.len(), 60);
+    }
+
+    #[test]
+    fn test_query_clamped() {
+        let gorilla = Gorilla::new();
+
+        let base_time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let values = [50.0, 500.0, 45.0, -20.0, 60.0];
+        for (i, &value) in values.iter().enumerate() {
+            gorilla.insert("latency.ms", base_time + i as u64 * 10, value);
+        }
+
+        let clamped = gorilla
+            .query_clamped("latency.ms", base_time, base_time + 100, 0.0, 100.0)
+            .unwrap();
+
+        // Nothing dropped, only capped
+        assert_eq!(clamped.len(), values.len());
+        let clamped_values: Vec<f64> = clamped.iter().map(|&(_, v)| v).collect();
+        assert_eq!(clamped_values, vec![50.0, 100.0, 45.0, 0.0, 60.0]);
+        assert_eq!(clamped[1].0, base_time + 10);
+
+        assert!(gorilla.query_clamped("missing", 0, 10, 0.0, 1.0).is_none());
+
+        // Bounds that can't clamp anything are refused, not a panic
+        let end = base_time + 100;
+        assert_eq!(
+            gorilla.query_clamped("latency.ms", base_time, end, 100.0, 0.0),
+            None
+        );
+        assert_eq!(
+            gorilla.query_clamped("latency.ms", base_time, end, f64::NAN, 1.0),
+            None
+        );
+        assert_eq!(
+            gorilla.query_clamped("latency.ms", base_time, end, 0.0, f64::NAN),
+            None
+        );
+        // A single-value range is fine
+        let flat = gorilla.query_clamped("latency.ms", base_time, end, 7.0, 7.0);
+        assert!(flat.unwrap().iter().all(|&(_, value)| value == 7.0));
+    }
+
+    #[test]
+    fn test_query_where() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+
+        let values = [10.0, 75.0, 50.0, 51.0, 20.0, 99.5, -3.0];
+        for (i, &value) in values.iter().enumerate() {
+            gorilla.insert("disk.util", base_time + i as u64 * 60, value);
+        }
+
+        let above = gorilla.query_where("disk.util", base_time, base_time + 3600, |v| v > 50.0);
+        assert_eq!(
+            above,
+            vec![
+                (base_time + 60, 75.0),
+                (base_time + 180, 51.0),
+                (base_time + 300, 99.5)
+            ]
+        );
+
+        assert!(
+            gorilla
+                .query_where("missing", 0, u64::MAX, |_| true)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_query_value_range() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+
+        for i in 0..20 {
+            let value = if i == 7 { 500.0 } else { 10.0 };
+            gorilla.insert("errors", base_time + i * 600, value);
+        }
+
+        let spikes = gorilla
+            .query_value_range("errors", 0, u64::MAX, 100.0, f64::INFINITY)
+            .unwrap();
+        assert_eq!(spikes, vec![(base_time + 7 * 600, 500.0)]);
+    }
+
+    #[test]
+    fn test_merge_series_disjoint() {
+        let mut gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+
+        for i in 0..5 {
+            gorilla.insert("old.cpu", base_time + i * 60, i as f64);
+            gorilla.insert("cpu", base_time + 7200 + i * 60, 10.0 + i as f64);
+        }
+
+        let report = gorilla.merge_series("old.cpu", "cpu").unwrap();
+        assert_eq!(report.points_moved, 5);
+        assert_eq!(report.collisions, 0);
+
+        let merged = gorilla.query("cpu", 0, u64::MAX).unwrap();
+        assert_eq!(merged.len(), 10);
+        assert_eq!(merged[0], (base_time, 0.0));
+        assert_eq!(merged[9], (base_time + 7200 + 240, 14.0));
+        assert!(gorilla.query("old.cpu", 0, u64::MAX).is_none());
+    }
+
+    #[test]
+    fn test_merge_series_overlapping() {
+        let mut gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+
+        // src on odd minutes, dst on even minutes of the same window
+        for i in 0..10 {
+            let key = if i % 2 == 0 { "dst" } else { "src" };
+            gorilla.insert(key, base_time + i * 60, i as f64);
+        }
+
+        let report = gorilla.merge_series("src", "dst").unwrap();
+        assert_eq!(report.points_moved, 5);
+        assert_eq!(report.collisions, 0);
+
+        let merged = gorilla.query("dst", 0, u64::MAX).unwrap();
+        let expected: Vec<(u64, f64)> = (0..10).map(|i| (base_time + i * 60, i as f64)).collect();
+        assert_eq!(merged, expected);
+    }
+
+    #[test]
+    fn test_merge_series_duplicates() {
+        let base_time = 7200 * 100;
+
+        for (policy, expected) in [
+            (DuplicatePolicy::KeepLast, 2.0),
+            (DuplicatePolicy::KeepFirst, 1.0),
+        ] {
+            let mut gorilla = Gorilla::with_series_options(SeriesOptions {
+                duplicate_policy: policy,
+                ..SeriesOptions::default()
+            });
+            gorilla.insert("dst", base_time, 1.0);
+            gorilla.insert("dst", base_time + 60, 1.0);
+            gorilla.insert("src", base_time, 2.0);
+            gorilla.insert("src", base_time + 120, 2.0);
+
+            let dry_run = gorilla.merge_series_dry_run("src", "dst").unwrap();
+            assert_eq!(dry_run.points_moved, 2);
+            assert_eq!(dry_run.collisions, 1);
+            assert!(
+                gorilla.query("src", 0, u64::MAX).is_some(),
+                "dry run is read-only"
+            );
+
+            assert_eq!(gorilla.merge_series("src", "dst").unwrap(), dry_run);
+            let merged = gorilla.query("dst", 0, u64::MAX).unwrap();
+            assert_eq!(merged.len(), 3);
+            assert_eq!(merged[0], (base_time, expected));
+        }
+
+        let mut gorilla = Gorilla::new();
+        assert_eq!(
+            gorilla.merge_series("missing", "dst"),
+            Err(TsdbError::SeriesNotFound("missing".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_merge_series_stops_on_failed_write() {
+        let base_time = 7200 * 100;
+        let mut gorilla = Gorilla::with_config(GorillaConfig {
+            max_series: Some(1),
+            ..GorillaConfig::default()
+        })
+        .unwrap();
+        for i in 0..5 {
+            gorilla.insert("src", base_time + i * 60, i as f64);
+        }
+
+        // Creating dst would go over the limit: nothing moves, src stays
+        assert_eq!(
+            gorilla.merge_series("src", "dst"),
+            Err(TsdbError::Rejected(InsertError::CardinalityLimitExceeded {
+                current: 1,
+                limit: 1
+            }))
+        );
+        assert_eq!(gorilla.query("src", 0, u64::MAX).unwrap().len(), 5);
+        assert!(gorilla.query("dst", 0, u64::MAX).is_none());
+
+        // Merged points go through the insert path and its metrics
+        let mut gorilla = Gorilla::new();
+        for i in 0..5 {
+            gorilla.insert("src", base_time + i * 60, i as f64);
+        }
+        gorilla.merge_series("src", "dst").unwrap();
+        assert_eq!(gorilla.metrics().points_inserted, 10);
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("tsdb-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+
+        // Several series, each spanning multiple blocks
+        for s in 0..5u64 {
+            let key = format!("host{}.cpu", s);
+            for i in 0..300u64 {
+                let value = 40.0 + ((i * (s + 1)) % 17) as f64 * 0.5;
+                gorilla.insert(&key, base_time + i * 90, value);
+            }
+        }
+        gorilla.insert("single", base_time, 1.5);
+
+        let path = temp_path("round_trip.snap");
+        let info = gorilla.snapshot(&path).unwrap();
+        assert_eq!(info.series, 6);
+        assert_eq!(info.points, 1501);
+        assert!(info.blocks > 6);
+        assert_eq!(info.bytes, std::fs::metadata(&path).unwrap().len());
+
+        let loaded = Gorilla::load(&path).unwrap();
+        for key in ["host0.cpu", "host3.cpu", "host4.cpu", "single"] {
+            assert_eq!(
+                loaded.query(key, 0, u64::MAX),
+                gorilla.query(key, 0, u64::MAX)
+            );
+            let (a, b) = (loaded.get_stats(key), gorilla.get_stats(key));
+            assert_eq!(a.logical_uncompressed_bytes, b.logical_uncompressed_bytes);
+            assert_eq!(a.compressed_size, b.compressed_size);
+        }
+
+        // The loaded instance keeps accepting writes
+        loaded.insert("single", base_time + 60, 2.5);
+        assert_eq!(loaded.query("single", 0, u64::MAX).unwrap().len(), 2);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_compact() {
+        let config = GorillaConfig {
+            series_options: SeriesOptions {
+                max_points_per_block: Some(5),
+                ..SeriesOptions::default()
+            },
+            ..GorillaConfig::default()
+        };
+        let mut gorilla = Gorilla::with_config(config).unwrap();
+        let base_time = 7200 * 100;
+        for s in 0..4u64 {
+            for i in 0..105 {
+                let value = (i % 9) as f64 * 1.5 + s as f64;
+                gorilla.insert(&format!("tiny{s}"), base_time + i * 60, value);
+            }
+        }
+        gorilla.delete("tiny1").unwrap();
+        gorilla.delete("tiny3").unwrap();
+        let blocks_before = gorilla.block_boundaries("tiny0").len();
+
+        let path = temp_path("compact_full.snap");
+        let compact_path = temp_path("compact.snap");
+        let full = gorilla.snapshot(&path).unwrap();
+        let compact = gorilla.snapshot_compact(&compact_path).unwrap();
+        assert_eq!(compact.series, 2);
+        assert_eq!(compact.points, full.points);
+        // One closed block for the window plus the open block, per series
+        assert_eq!(compact.blocks, 4);
+        assert!(compact.bytes < full.bytes);
+
+        // The series in memory are left split
+        assert_eq!(gorilla.block_boundaries("tiny0").len(), blocks_before);
+
+        let loaded = Gorilla::load(&compact_path).unwrap();
+        assert_eq!(loaded.list_series(false).len(), 2);
+        assert!(loaded.query("tiny1", 0, u64::MAX).is_none());
+        for key in ["tiny0", "tiny2"] {
+            assert_eq!(
+                loaded.query(key, 0, u64::MAX),
+                gorilla.query(key, 0, u64::MAX)
+            );
+            assert_eq!(loaded.block_boundaries(key).len(), 2);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&compact_path).unwrap();
+    }
+
+    #[test]
+    fn test_drop_raw_on_close_survives_snapshot() {
+        let config = GorillaConfig {
+            series_options: SeriesOptions {
+                drop_raw_on_close: true,
+                ..SeriesOptions::default()
+            },
+            ..GorillaConfig::default()
+        };
+        let gorilla = Gorilla::with_config(config).unwrap();
+        let base_time = 7200 * 100;
+        for i in 0..300 {
+            gorilla.insert("cpu", base_time + i * 60, (i % 11) as f64);
+        }
+        let expected = gorilla.query("cpu", 0, u64::MAX).unwrap();
+        assert_eq!(expected.len(), 300);
+        let usage = gorilla.memory_usage();
+        // Only the open block's points remain uncompressed
+        assert!(usage.raw_points_bytes < 100 * size_of::<DataPoint>());
+
+        let path = temp_path("drop_raw.snap");
+        gorilla.snapshot(&path).unwrap();
+        let loaded = Gorilla::load(&path).unwrap();
+        assert_eq!(loaded.query("cpu", 0, u64::MAX).unwrap(), expected);
+        // The option is persisted, so loaded closed blocks drop theirs too
+        assert!(loaded.memory_usage().raw_points_bytes < 100 * size_of::<DataPoint>());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_series_iter() {
+        let mut gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        for i in 0..20 {
+            gorilla.insert(&format!("sensor.{}", i), base_time, i as f64);
+        }
+        gorilla.delete("sensor.3").unwrap();
+
+        let keys: Vec<String> = gorilla
+            .series_iter()
+            .map(|series| series.read().key.to_string())
+            .collect();
+        assert_eq!(keys.len(), 19);
+        assert!(!keys.contains(&"sensor.3".to_string()));
+
+        let hot = gorilla
+            .series_iter()
+            .find(|series| series.read().query(0, u64::MAX)[0].value == 7.0)
+            .unwrap();
+        assert_eq!(&*hot.read().key, "sensor.7");
+        assert_eq!(gorilla.series_iter().take(4).count(), 4);
+        assert_eq!(
+            gorilla
+                .series_iter()
+                .filter(|series| series.read().key.ends_with('3'))
+                .count(),
+            1 // sensor.13
+        );
+    }
+
+    #[test]
+    fn test_concurrent_writers_and_readers() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+
+        std::thread::scope(|scope| {
+            // Writers: each has its own key and shares one with the others
+            for writer in 0..4u64 {
+                let gorilla = &gorilla;
+                scope.spawn(move || {
+                    for i in 0..500u64 {
+                        gorilla.insert(&format!("writer{}", writer), base_time + i, i as f64);
+                        gorilla.insert("shared", base_time + i * 4 + writer, writer as f64);
+                    }
+                });
+            }
+
+            // Readers run queries, stats, scans and correlation meanwhile
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..50 {
+                        if let Some(points) = gorilla.query("shared", 0, u64::MAX) {
+                            assert!(points.windows(2).all(|pair| pair[0].0 < pair[1].0));
+                        }
+                        gorilla.get_stats("writer0");
+                        let mut scanned = 0;
+                        gorilla.scan(|_, _, _| scanned += 1);
+                        gorilla.find_correlated("writer1", base_time, base_time + 500, 3);
+                    }
+                });
+            }
+        });
+
+        for writer in 0..4 {
+            let points = gorilla
+                .query(&format!("writer{}", writer), 0, u64::MAX)
+                .unwrap();
+            assert_eq!(points.len(), 500);
+        }
+        let shared = gorilla.query("shared", 0, u64::MAX).unwrap();
+        assert_eq!(shared.len(), 2000);
+        assert!(shared.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(gorilla.metrics().points_inserted, 4000);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        for i in 0..50 {
+            gorilla.insert(&format!("test.{}", i), base_time, i as f64);
+            gorilla.insert_labeled("http", &[("host", "a")], base_time + i, 1.0);
+        }
+        assert_eq!(gorilla.metrics().series, 51);
+
+        gorilla.clear();
+        assert_eq!(gorilla.metrics().series, 0);
+        assert!(gorilla.query("test.0", 0, u64::MAX).is_none());
+        assert!(gorilla.query_selector("http", &[], 0, u64::MAX).is_empty());
+
+        gorilla.insert("test.0", base_time + 60, 7.0);
+        assert_eq!(
+            gorilla.query("test.0", 0, u64::MAX).unwrap(),
+            vec![(base_time + 60, 7.0)]
+        );
+        assert_eq!(gorilla.metrics().series, 1);
+    }
+
+    #[test]
+    fn test_try_insert_outcomes() {
+        let dir = temp_wal_dir("wal_try_insert");
+        let base_time = 7200 * 100;
+        let mut gorilla = Gorilla::with_config(GorillaConfig {
+            wal_dir: Some(dir.clone()),
+            clock: Arc::new(TestClock::new(base_time)),
+            max_series: Some(4),
+            ..GorillaConfig::default()
+        })
+        .unwrap();
+        let keep_first = SeriesOptions {
+            duplicate_policy: DuplicatePolicy::KeepFirst,
+            ..SeriesOptions::default()
+        };
+        let reject = SeriesOptions {
+            duplicate_policy: DuplicatePolicy::Reject,
+            ..SeriesOptions::default()
+        };
+        gorilla.create_series("first", keep_first).unwrap();
+        gorilla.create_series("strict", reject).unwrap();
+
+        // Outcomes
+        assert_eq!(
+            gorilla.try_insert("cpu", base_time, 1.0),
+            Ok(InsertOutcome::CreatedSeries)
+        );
+        assert_eq!(
+            gorilla.try_insert("cpu", base_time + 60, 2.0),
+            Ok(InsertOutcome::Inserted)
+        );
+        assert_eq!(
+            gorilla.try_insert("cpu", base_time, 3.0),
+            Ok(InsertOutcome::ReplacedDuplicate)
+        );
+        assert_eq!(
+            gorilla.try_insert("first", base_time, 1.0),
+            Ok(InsertOutcome::Inserted),
+            "created empty beforehand"
+        );
+        assert_eq!(
+            gorilla.try_insert("first", base_time, 2.0),
+            Ok(InsertOutcome::IgnoredDuplicate)
+        );
+        assert_eq!(
+            gorilla.query("cpu", 0, u64::MAX).unwrap(),
+            vec![(base_time, 3.0), (base_time + 60, 2.0)]
+        );
+        assert_eq!(gorilla.query("first", 0, u64::MAX).unwrap()[0].1, 1.0);
+
+        // Errors, each with what was refused
+        gorilla.try_insert("strict", base_time, 1.0).unwrap();
+        assert_eq!(
+            gorilla.try_insert("strict", base_time, 2.0),
+            Err(InsertError::DuplicateRejected {
+                timestamp: base_time
+            })
+        );
+        assert_eq!(
+            gorilla.try_insert("cpu", base_time, f64::NAN),
+            Err(InsertError::NanValue)
+        );
+        let max_allowed = base_time + DEFAULT_FUTURE_TOLERANCE_SECS;
+        assert_eq!(
+            gorilla.try_insert("cpu", max_allowed + 1, 1.0),
+            Err(InsertError::TimestampTooFarInFuture {
+                ts: max_allowed + 1,
+                max_allowed
+            })
+        );
+        let huge_key = "k".repeat(wal::MAX_RECORD_BYTES);
+        assert_eq!(
+            gorilla.try_insert(&huge_key, base_time, 1.0),
+            Err(InsertError::WalAppendFailed)
+        );
+        gorilla.try_insert("mem", base_time, 1.0).unwrap();
+        assert_eq!(
+            gorilla.try_insert("disk", base_time, 1.0),
+            Err(InsertError::CardinalityLimitExceeded {
+                current: 4,
+                limit: 4
+            })
+        );
+        gorilla.freeze("cpu").unwrap();
+        assert_eq!(
+            gorilla.try_insert("cpu", base_time + 120, 1.0),
+            Err(InsertError::SeriesFrozen)
+        );
+        assert_eq!(gorilla.metrics().inserts_rejected, 7);
+
+        // insert drops refused points the same way
+        gorilla.insert(&huge_key, base_time, 1.0);
+        gorilla.insert("disk", base_time, 1.0);
+        assert_eq!(gorilla.metrics().inserts_rejected, 9);
+        assert!(!gorilla.contains("disk"));
+        drop(gorilla);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_clear_is_logged() {
+        let dir = temp_wal_dir("wal_clear");
+        let config = GorillaConfig {
+            wal_dir: Some(dir.clone()),
+            ..GorillaConfig::default()
+        };
+        let mut gorilla = Gorilla::with_config(config).unwrap();
+        gorilla.insert("a", 7200 * 100, 1.0);
+        gorilla.insert("b", 7200 * 100, 2.0);
+        gorilla.clear();
+        gorilla.insert("c", 7200 * 100, 3.0);
+        drop(gorilla);
+
+        let (recovered, _) = Gorilla::recover(&dir).unwrap();
+        assert!(!recovered.contains("a"));
+        assert!(!recovered.contains("b"));
+        assert!(recovered.contains("c"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_self_check() {
+        let mut gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        for i in 0..50 {
+            gorilla.insert(&format!("series{}", i), base_time, i as f64);
+        }
+        for i in (0..50).step_by(3) {
+            gorilla.delete(&format!("series{}", i)).unwrap();
+        }
+        assert_eq!(gorilla.self_check(), Ok(()));
+        gorilla.reap_tombstones(u64::MAX);
+        gorilla.rename("series1", "renamed").unwrap();
+        gorilla.insert("new", base_time, 1.0);
+        assert_eq!(gorilla.self_check(), Ok(()));
+        gorilla.compact();
+        assert_eq!(gorilla.self_check(), Ok(()));
+    }
+
+    #[test]
+    fn test_compact_all() {
+        let config = GorillaConfig {
+            series_options: SeriesOptions {
+                max_points_per_block: Some(5),
+                ..SeriesOptions::default()
+            },
+            ..GorillaConfig::default()
+        };
+        let mut gorilla = Gorilla::with_config(config).unwrap();
+        let base_time = 7200 * 100;
+        for i in 0..105 {
+            gorilla.insert("tiny", base_time + i * 60, (i % 9) as f64 * 1.5);
+        }
+        gorilla.insert("single", base_time, 1.0);
+        assert_eq!(gorilla.block_boundaries("tiny").len(), 21);
+        let before = gorilla.query("tiny", 0, u64::MAX).unwrap();
+        let stats = gorilla.get_stats("tiny");
+
+        assert_eq!(gorilla.compact_all(), 19);
+        assert_eq!(gorilla.compact_all(), 0);
+
+        // One closed block for the window, plus the open block
+        assert_eq!(gorilla.block_boundaries("tiny").len(), 2);
+        assert_eq!(gorilla.query("tiny", 0, u64::MAX).unwrap(), before);
+        assert_eq!(
+            gorilla
+                .query_value_range("tiny", 0, u64::MAX, 12.0, 12.0)
+                .unwrap()
+                .len(),
+            before.iter().filter(|(_, v)| *v == 12.0).count()
+        );
+        let compacted = gorilla.get_stats("tiny");
+        assert_eq!(
+            compacted.logical_uncompressed_bytes,
+            stats.logical_uncompressed_bytes
+        );
+        assert!(compacted.compressed_size < stats.compressed_size);
+        assert_eq!(
+            gorilla.query("single", 0, u64::MAX).unwrap(),
+            vec![(base_time, 1.0)]
+        );
+    }
+
+    #[test]
+    fn test_max_points_per_block() {
+        let config = GorillaConfig {
+            series_options: SeriesOptions {
+                max_points_per_block: Some(100),
+                ..SeriesOptions::default()
+            },
+            ..GorillaConfig::default()
+        };
+        let gorilla = Gorilla::with_config(config).unwrap();
+        let base_time = 7200 * 100;
+        for i in 0..250 {
+            gorilla.insert("cpu", base_time + i, i as f64);
+        }
+
+        // One time window, but the point count closes two blocks
+        let blocks = gorilla.block_boundaries("cpu");
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks.iter().filter(|(_, _, open)| !open).count(), 2);
+        assert_eq!(gorilla.query("cpu", 0, u64::MAX).unwrap().len(), 250);
+
+        // The limit is persisted with the series
+        let path = temp_path("max_points.snap");
+        gorilla.snapshot(&path).unwrap();
+        let loaded = Gorilla::load(&path).unwrap();
+        assert_eq!(loaded.block_boundaries("cpu"), blocks);
+        for i in 250..300 {
+            loaded.insert("cpu", base_time + i, i as f64);
+        }
+        assert_eq!(loaded.block_boundaries("cpu").len(), 3);
+        loaded.insert("cpu", base_time + 300, 300.0);
+        assert_eq!(loaded.block_boundaries("cpu").len(), 4);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_corruption_is_an_error() {
+        let gorilla = Gorilla::new();
+        for i in 0..100 {
+            gorilla.insert("cpu", 7200 * 100 + i * 60, i as f64);
+        }
+        let path = temp_path("corrupt.snap");
+        gorilla.snapshot(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+
+        // Truncated at every possible length: always an error, never a panic
+        for len in 0..bytes.len() {
+            std::fs::write(&path, &bytes[..len]).unwrap();
+            let err = Gorilla::load(&path)
+                .err()
+                .expect("truncated snapshot loaded");
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        // Bad magic and flipped payload bits
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        std::fs::write(&path, &bad).unwrap();
+        let err = Gorilla::load(&path).err().unwrap();
+        assert!(err.to_string().contains("magic"), "{}", err);
+
+        let mut bad = bytes.clone();
+        let last = bad.len() - 3;
+        bad[last] ^= 0xFF;
+        std::fs::write(&path, &bad).unwrap();
+        let err = Gorilla::load(&path).err().unwrap();
+        assert!(err.to_string().contains("corrupt block"), "{}", err);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_replaced_atomically() {
+        let gorilla = Gorilla::new();
+        gorilla.insert("cpu", 7200 * 100, 1.0);
+        let path = temp_path("atomic.snap");
+        let temp = snapshot::temp_path(&path);
+        gorilla.snapshot(&path).unwrap();
+        assert!(!temp.exists());
+
+        // A write that died midway leaves only its temporary file
+        std::fs::write(&temp, b"TSDBSNAP partial").unwrap();
+        assert!(Gorilla::load(&path).unwrap().contains("cpu"));
+
+        gorilla.insert("mem", 7200 * 100, 2.0);
+        gorilla.snapshot(&path).unwrap();
+        assert!(!temp.exists());
+        assert!(Gorilla::load(&path).unwrap().contains("mem"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_versions() {
+        let gorilla = Gorilla::new();
+        for i in 0..100 {
+            gorilla.insert("cpu", 7200 * 100 + i * 60, i as f64);
+        }
+        let expected = gorilla.query("cpu", 0, u64::MAX).unwrap();
+        let path = temp_path("versions.snap");
+        gorilla.snapshot(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(
+            Gorilla::load(&path)
+                .unwrap()
+                .query("cpu", 0, u64::MAX)
+                .unwrap(),
+            expected
+        );
+
+        let load_error = |bytes: &[u8]| {
+            std::fs::write(&path, bytes).unwrap();
+            let err = Gorilla::load(&path).err().expect("snapshot loaded");
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            err.get_ref()
+                .and_then(|inner| inner.downcast_ref::<TsdbError>())
+                .cloned()
+        };
+
+        // Newer snapshot and block format versions are refused
+        let mut bumped = bytes.clone();
+        bumped[8..12].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            load_error(&bumped),
+            Some(TsdbError::UnsupportedVersion {
+                found: SNAPSHOT_VERSION + 1,
+                supported: SNAPSHOT_VERSION,
+            })
+        );
+        let mut bumped = bytes.clone();
+        bumped[12] = FORMAT_VERSION + 1;
+        assert_eq!(
+            load_error(&bumped),
+            Some(TsdbError::UnsupportedVersion {
+                found: u32::from(FORMAT_VERSION) + 1,
+                supported: FORMAT_VERSION.into(),
+            })
+        );
+
+        // The previous version (no align origin) still loads: drop the
+        // origin after the header, key, options, block duration, max
+        // points per block, codec, value hint, late grace and value codec
+        let origin_at = 37 + 4 + "cpu".len() + 1 + 8 + 4 + 1 + 1 + 8 + 1;
+        assert_eq!(bytes[origin_at..origin_at + 8], [0; 8]);
+        let mut previous = bytes.clone();
+        previous.drain(origin_at..origin_at + 8);
+        previous[8..12].copy_from_slice(&(SNAPSHOT_VERSION - 1).to_le_bytes());
+        std::fs::write(&path, &previous).unwrap();
+        let loaded = Gorilla::load(&path).unwrap();
+        assert_eq!(loaded.query("cpu", 0, u64::MAX).unwrap(), expected);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut gorilla = Gorilla::new();
+
+        let base_time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            - 86400;
+
+        for i in 0..10 {
+            gorilla.insert("cpu.usage", base_time + i * 60, 40.0 + i as f64);
+        }
+        gorilla.insert("cpu.idle", base_time, 60.0);
+        let stats_before = gorilla.get_stats("cpu.usage");
+
+        gorilla.rename("cpu.usage", "host1.cpu.usage").unwrap();
+
+        // History is queryable under the new key
+        let results = gorilla
+            .query("host1.cpu.usage", base_time, base_time + 600)
+            .unwrap();
+        assert_eq!(results.len(), 10);
+        assert_eq!(results[9], (base_time + 540, 49.0));
+        assert_eq!(
+            gorilla.get_stats("host1.cpu.usage").compressed_size,
+            stats_before.compressed_size
+        );
+
+        // Old key is gone
+        assert!(gorilla.query("cpu.usage", 0, u64::MAX).is_none());
+
+        // Missing source and taken destination are both errors
+        assert_eq!(
+            gorilla.rename("cpu.usage", "x"),
+            Err(TsdbError::SeriesNotFound("cpu.usage".to_string()))
+        );
+        assert_eq!(
+            gorilla.rename("host1.cpu.usage", "cpu.idle"),
+            Err(TsdbError::SeriesExists("cpu.idle".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_unlogged_rename_and_delete_change_nothing() {
+        let dir = temp_wal_dir("wal_unlogged");
+        // Every append rotates, so appends fail once the directory is gone
+        let mut gorilla = Gorilla::with_config(GorillaConfig {
+            wal_dir: Some(dir.clone()),
+            wal_segment_bytes: 1,
+            ..GorillaConfig::default()
+        })
+        .unwrap();
+        let base_time = 7200 * 100;
+        gorilla.insert("cpu", base_time, 1.0);
+        gorilla.insert("mem", base_time, 2.0);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            gorilla.rename("cpu", "cpu.usage"),
+            Err(TsdbError::WalAppendFailed)
+        );
+        assert!(gorilla.contains("cpu") && !gorilla.contains("cpu.usage"));
+        assert_eq!(gorilla.delete("mem"), Err(TsdbError::WalAppendFailed));
+        assert!(gorilla.contains("mem"));
+        assert_eq!(gorilla.expire_idle(u64::MAX, 0), Vec::<String>::new());
+        assert_eq!(gorilla.metrics().wal_errors, 4);
+    }
+
+    fn temp_wal_dir(name: &str) -> std::path::PathBuf {
+        let dir = temp_path(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_wal_recover() {
+        let dir = temp_wal_dir("wal_recover");
+        let config = GorillaConfig {
+            wal_dir: Some(dir.clone()),
+            wal_segment_bytes: 4096,
+            wal_sync_every: 50,
+            ..GorillaConfig::default()
+        };
+        let base_time = 7200 * 100;
+
+        let mut gorilla = Gorilla::with_config(config).unwrap();
+        for s in 0..4u64 {
+            for i in 0..200u64 {
+                gorilla.insert(
+                    &format!("host{}.cpu", s),
+                    base_time + i * 60,
+                    (i % 9) as f64,
+                );
+            }
+        }
+        gorilla.insert("host0.cpu", base_time, f64::NAN);
+        gorilla.rename("host1.cpu", "host1.cpu.usage").unwrap();
+        gorilla.delete("host2.cpu").unwrap();
+        drop(gorilla);
+
+        let (recovered, report) = Gorilla::recover(&dir).unwrap();
+        assert!(report.segments > 1);
+        assert_eq!(report.records_applied, 802);
+        assert_eq!(recovered.metrics().points_inserted, 800);
+
+        assert_eq!(
+            recovered.query("host0.cpu", 0, u64::MAX).unwrap().len(),
+            200
+        );
+        assert_eq!(
+            recovered
+                .query("host1.cpu.usage", 0, u64::MAX)
+                .unwrap()
+                .len(),
+            200
+        );
+        assert!(recovered.query("host1.cpu", 0, u64::MAX).is_none());
+        assert!(recovered.query("host2.cpu", 0, u64::MAX).is_none());
+
+        // Recovery keeps logging, in a new segment
+        recovered.insert("host3.cpu", base_time + 200 * 60, 1.0);
+        drop(recovered);
+        let (again, _) = Gorilla::recover(&dir).unwrap();
+        assert_eq!(again.query("host3.cpu", 0, u64::MAX).unwrap().len(), 201);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_wal_recover_series_options() {
+        let dir = temp_wal_dir("wal_create");
+        let config = GorillaConfig {
+            wal_dir: Some(dir.clone()),
+            ..GorillaConfig::default()
+        };
+        let base_time = 7200 * 100;
+        let options = SeriesOptions {
+            duplicate_policy: DuplicatePolicy::KeepFirst,
+            block_duration: Some(600),
+            ..SeriesOptions::default()
+        };
+
+        let gorilla = Gorilla::with_config(config).unwrap();
+        gorilla.create_series("cpu", options.clone()).unwrap();
+        gorilla.insert("cpu", base_time, 1.0);
+        gorilla.insert("cpu", base_time, 2.0);
+        gorilla.insert("cpu", base_time + 600, 3.0);
+        let expected = gorilla.query("cpu", 0, u64::MAX).unwrap();
+        assert_eq!(expected, [(base_time, 1.0), (base_time + 600, 3.0)]);
+        drop(gorilla);
+
+        // The duplicate is dropped again, and blocks keep their duration
+        let (recovered, report) = Gorilla::recover(&dir).unwrap();
+        assert_eq!(report.records_applied, 4);
+        assert_eq!(recovered.query("cpu", 0, u64::MAX).unwrap(), expected);
+        assert_eq!(
+            recovered.tsmap.get("cpu").unwrap().read().options(),
+            &options
+        );
+        assert_eq!(recovered.block_boundaries("cpu").len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_wal_recover_after_torn_write() {
+        let dir = temp_wal_dir("wal_torn_engine");
+        let config = GorillaConfig {
+            wal_dir: Some(dir.clone()),
+            ..GorillaConfig::default()
+        };
+        let base_time = 7200 * 100;
+
+        let gorilla = Gorilla::with_config(config).unwrap();
+        for i in 0..100u64 {
+            gorilla.insert("cpu", base_time + i * 60, i as f64);
+        }
+        drop(gorilla);
+
+        // Simulate a crash in the middle of the last record
+        let segment = std::fs::read_dir(&dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let bytes = std::fs::read(&segment).unwrap();
+        std::fs::write(&segment, &bytes[..bytes.len() - 5]).unwrap();
+
+        let (recovered, report) = Gorilla::recover(&dir).unwrap();
+        assert_eq!(report.records_applied, 99);
+        let points = recovered.query("cpu", 0, u64::MAX).unwrap();
+        assert_eq!(points.len(), 99);
+        assert_eq!(points[98], (base_time + 98 * 60, 98.0));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_wal_tops_up_snapshot() {
+        let dir = temp_wal_dir("wal_snapshot");
+        let snapshot_path = temp_path("wal_snapshot.snap");
+        let config = GorillaConfig {
+            wal_dir: Some(dir.clone()),
+            wal_segment_bytes: 1024,
+            ..GorillaConfig::default()
+        };
+        let base_time = 7200 * 100;
+
+        let gorilla = Gorilla::with_config(config).unwrap();
+        for i in 0..100u64 {
+            gorilla.insert("cpu", base_time + i * 60, i as f64);
+        }
+        gorilla.snapshot(&snapshot_path).unwrap();
+        for i in 100..150u64 {
+            gorilla.insert("cpu", base_time + i * 60, i as f64);
+        }
+        gorilla.insert("mem", base_time, 1.0);
+        let expected = gorilla.query("cpu", 0, u64::MAX);
+        drop(gorilla);
+
+        let (recovered, report) = Gorilla::recover_from_snapshot(&snapshot_path, &dir).unwrap();
+        assert_eq!(report.records_applied, 51);
+        assert_eq!(recovered.query("cpu", 0, u64::MAX), expected);
+        assert_eq!(recovered.query("mem", 0, u64::MAX).unwrap().len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_file(&snapshot_path).unwrap();
+    }
+
+    #[test]
+    fn test_injected_clock_drives_block_boundaries() {
+        // Start 100 seconds before a block boundary
+        let clock = Arc::new(TestClock::new(7200 * 100 - 100));
+        let mut gorilla = Gorilla::with_config(GorillaConfig {
+            clock: clock.clone(),
+            ..GorillaConfig::default()
+        })
+        .unwrap();
+
+        gorilla.insert("cpu", clock.now(), 1.0);
+        clock.advance(60);
+        gorilla.insert("cpu", clock.now(), 2.0);
+        assert_eq!(gorilla.metrics().blocks_closed, 0);
+
+        // Crossing the boundary seals the first block
+        clock.advance(60);
+        gorilla.insert("cpu", clock.now(), 3.0);
+        assert_eq!(gorilla.metrics().blocks_closed, 1);
+        assert_eq!(gorilla.query("cpu", 0, u64::MAX).unwrap().len(), 3);
+
+        // Tombstones are stamped with the injected clock too
+        gorilla.delete("cpu").unwrap();
+        assert_eq!(gorilla.reap_tombstones(clock.now()), 0);
+        clock.advance(3600);
+        assert_eq!(gorilla.reap_tombstones(clock.now()), 1);
+    }
+
+    #[test]
+    fn test_configured_tombstone_grace() {
+        let clock = Arc::new(TestClock::new(7200 * 100));
+        let mut gorilla = Gorilla::with_config(GorillaConfig {
+            clock: clock.clone(),
+            tombstone_grace: 60,
+            ..GorillaConfig::default()
+        })
+        .unwrap();
+        gorilla.insert("cpu", clock.now(), 1.0);
+        gorilla.delete("cpu").unwrap();
+        clock.advance(59);
+        assert_eq!(gorilla.reap_tombstones(clock.now()), 0);
+        clock.advance(1);
+        assert_eq!(gorilla.reap_tombstones(clock.now()), 1);
+    }
+
+    #[test]
+    fn test_close_stale_blocks() {
+        let base_time = 7200 * 100;
+        let gorilla = Gorilla::with_config(GorillaConfig {
+            clock: Arc::new(TestClock::new(base_time + 7200 * 4)),
+            ..GorillaConfig::default()
+        })
+        .unwrap();
+        for i in 0..10 {
+            gorilla.insert("idle", base_time + i * 60, i as f64);
+        }
+        let boundaries = |key: &str| gorilla.tsmap.get(key).unwrap().read().block_boundaries();
+
+        // Nothing closes while the window is still running
+        assert_eq!(gorilla.close_stale_blocks(base_time + 7199), 0);
+        assert_eq!(
+            boundaries("idle"),
+            vec![(base_time, base_time + 7200, true)]
+        );
+
+        let stats = gorilla.global_stats();
+        assert_eq!(gorilla.close_stale_blocks(base_time + 7200), 1);
+        assert_eq!(
+            boundaries("idle"),
+            vec![(base_time, base_time + 7200, false)]
+        );
+        assert_eq!(gorilla.metrics().blocks_closed, 1);
+        assert_eq!(
+            gorilla.global_stats().compressed_size,
+            stats.compressed_size
+        );
+
+        // The open block left behind is empty, so nothing closes again
+        assert_eq!(gorilla.close_stale_blocks(base_time + 7200 * 10), 0);
+        assert_eq!(gorilla.metrics().blocks_closed, 1);
+
+        // Later points open a fresh block in their own window
+        gorilla.insert("idle", base_time + 7200 * 3 + 5, 10.0);
+        assert_eq!(
+            boundaries("idle"),
+            vec![
+                (base_time, base_time + 7200, false),
+                (base_time + 7200 * 3, base_time + 7200 * 4, true),
+            ]
+        );
+        assert_eq!(gorilla.query("idle", 0, u64::MAX).unwrap().len(), 11);
+    }
+
+    #[test]
+    fn test_estimate_insert_bits() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+
+        // A brand-new series pays for the block header and a raw point
+        assert_eq!(
+            gorilla.estimate_insert_bits("mem", base_time, 8192.0),
+            64 + 14 + 64
+        );
+
+        gorilla.insert("mem", base_time, 8192.0);
+        gorilla.insert("mem", base_time + 60, 8192.0);
+
+        // Same interval, same value: '0' for the timestamp, '0' for the value
+        assert_eq!(
+            gorilla.estimate_insert_bits("mem", base_time + 120, 8192.0),
+            2
+        );
+        gorilla.insert("mem", base_time + 120, 8192.0);
+        assert_eq!(
+            gorilla.estimate_insert_bits("mem", base_time + 180, 8192.0),
+            2
+        );
+
+        // A jittered timestamp and a changed value cost more
+        assert!(gorilla.estimate_insert_bits("mem", base_time + 185, 9000.0) > 2);
+
+        // Starting a new block pays for the header again
+        assert_eq!(
+            gorilla.estimate_insert_bits("mem", base_time + 7200, 8192.0),
+            64 + 14 + 64
+        );
+
+        // Estimating never stores anything
+        assert_eq!(gorilla.query("mem", 0, u64::MAX).unwrap().len(), 3);
+        assert_eq!(gorilla.estimate_insert_bits("mem", base_time, f64::NAN), 0);
+    }
+
+    #[test]
+    fn test_series_meta() {
+        let clock = Arc::new(TestClock::new(7200 * 100));
+        let mut gorilla = Gorilla::with_config(GorillaConfig {
+            clock: clock.clone(),
+            ..GorillaConfig::default()
+        })
+        .unwrap();
+
+        // Metadata for a key that doesn't exist yet is an error
+        let meta = SeriesMeta {
+            unit: Some("bytes".to_string()),
+            description: Some("Resident memory".to_string()),
+            created_at: 1,
+            last_write: 1,
+            pinned: false,
+        };
+        assert_eq!(
+            gorilla.set_meta("mem", meta.clone()),
+            Err(TsdbError::SeriesNotFound("mem".to_string()))
+        );
+        assert!(gorilla.get_meta("mem").is_none());
+
+        gorilla.insert("mem", clock.now(), 8192.0);
+        let created = clock.now();
+        gorilla.set_meta("mem", meta).unwrap();
+
+        clock.advance(300);
+        gorilla.insert("mem", clock.now(), 8193.0);
+
+        let stored = gorilla.get_meta("mem").unwrap();
+        assert_eq!(stored.unit.as_deref(), Some("bytes"));
+        assert_eq!(stored.description.as_deref(), Some("Resident memory"));
+        assert_eq!(
+            stored.created_at, created,
+            "engine-maintained, not user-set"
+        );
+        assert_eq!(stored.last_write, created + 300);
+
+        // A rejected write doesn't count as a write
+        clock.advance(300);
+        gorilla.insert("mem", clock.now(), f64::NAN);
+        assert_eq!(gorilla.get_meta("mem").unwrap().last_write, created + 300);
+
+        // Listing only carries metadata on request
+        gorilla.insert("cpu", clock.now(), 1.0);
+        let keys = gorilla.list_series(false);
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].key, "cpu");
+        assert!(keys.iter().all(|entry| entry.meta.is_none()));
+        let with_meta = gorilla.list_series(true);
+        assert_eq!(with_meta[1].meta.as_ref(), Some(&stored));
+
+        // Metadata survives a snapshot round trip
+        let path = temp_path("meta.snap");
+        gorilla.snapshot(&path).unwrap();
+        let loaded = Gorilla::load(&path).unwrap();
+        assert_eq!(loaded.get_meta("mem"), Some(stored));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_increase() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+
+        // Clean monotonic counter: 100, 110, ..., 190
+        for i in 0..10 {
+            gorilla.insert("requests", base_time + i * 60, 100.0 + i as f64 * 10.0);
+        }
+        assert_eq!(gorilla.increase("requests", 0, u64::MAX), 90.0);
+        assert_eq!(
+            gorilla.increase("requests", base_time + 120, base_time + 300),
+            30.0
+        );
+
+        // Reset mid-range: 50 -> 80, restart at 5 -> 25
+        for (i, value) in [50.0, 60.0, 80.0, 5.0, 15.0, 25.0].into_iter().enumerate() {
+            gorilla.insert("bytes_sent", base_time + i as u64 * 60, value);
+        }
+        assert_eq!(
+            gorilla.increase("bytes_sent", 0, u64::MAX),
+            30.0 + 5.0 + 20.0
+        );
+
+        // Not enough points, or no series at all
+        assert_eq!(gorilla.increase("requests", base_time, base_time), 0.0);
+        assert_eq!(gorilla.increase("missing", 0, u64::MAX), 0.0);
+    }
+
+    #[test]
+    fn test_rate_and_derivative() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        // A counter sampled every 10s, reset at +60 (a bucket's first
+        // point) and at +130, with a lone point at +210
+        let counter = [
+            (0, 0.0),
+            (10, 10.0),
+            (20, 20.0),
+            (30, 30.0),
+            (40, 40.0),
+            (50, 50.0),
+            (60, 5.0),
+            (70, 25.0),
+            (80, 45.0),
+            (90, 65.0),
+            (100, 85.0),
+            (110, 105.0),
+            (120, 125.0),
+            (130, 3.0),
+            (140, 13.0),
+            (150, 23.0),
+            (160, 33.0),
+            (170, 43.0),
+            (210, 73.0),
+        ];
+        for (offset, value) in counter {
+            gorilla.insert("requests", base_time + offset, value);
+        }
+        // Inserts refuse NaN; stored ones are skipped
+        let series = gorilla.tsmap.get("requests").unwrap();
+        series.write().insert(base_time + 200, f64::NAN);
+
+        let end = base_time + 239;
+        assert_eq!(
+            gorilla.rate("requests", base_time, end, 60).unwrap(),
+            [
+                (base_time, 50.0 / 50.0),
+                // 5 after the reset, then 20 a step
+                (base_time + 60, 105.0 / 60.0),
+                // 20, 3 after the reset, then 10 a step
+                (base_time + 120, 63.0 / 60.0),
+                // The single point: 30 over the 40s since +170
+                (base_time + 180, 30.0 / 40.0),
+            ]
+        );
+        assert_eq!(
+            gorilla.derivative("requests", base_time, end, 60).unwrap(),
+            [
+                (base_time, 50.0 / 50.0),
+                (base_time + 60, 55.0 / 60.0),
+                (base_time + 120, -62.0 / 60.0),
+                (base_time + 180, 30.0 / 40.0),
+            ]
+        );
+
+        // From the reset on: it has no step into it, so +60 counts 100
+        // over 50s
+        assert_eq!(
+            gorilla.rate("requests", base_time + 60, base_time + 119, 60),
+            Some(vec![(base_time + 60, 100.0 / 50.0)])
+        );
+        // A range holding one point, and a missing series
+        assert_eq!(
+            gorilla.rate("requests", base_time + 210, end, 60),
+            Some(vec![])
+        );
+        assert_eq!(gorilla.rate("missing", 0, u64::MAX, 60), None);
+        assert_eq!(gorilla.derivative("missing", 0, u64::MAX, 60), None);
+    }
+
+    #[test]
+    fn test_smooth() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        // A sawtooth of period 4 across three blocks
+        for i in 0..360 {
+            gorilla.insert("temp", base_time + i * 60, (i % 4) as f64);
+        }
+
+        let window = Smoothing::MovingAvg { window_points: 4 };
+        let smoothed = gorilla
+            .smooth("temp", base_time, u64::MAX, window, WarmUp::Skip)
+            .unwrap();
+        assert_eq!(smoothed.len(), 357);
+        assert_eq!(smoothed[0].0, base_time + 180);
+        assert!(smoothed.iter().all(|&(_, value)| value == 1.5));
+
+        // The same over a time window, from an inner start
+        let window = Smoothing::MovingAvgTime { window_secs: 240 };
+        let start = base_time + 7200;
+        let smoothed = gorilla
+            .smooth("temp", start, start + 3599, window, WarmUp::Partial)
+            .unwrap();
+        assert_eq!(smoothed.len(), 60);
+        assert_eq!(smoothed[0], (start, 0.0));
+        assert_eq!(smoothed[3], (start + 180, 1.5));
+        assert!(smoothed[3..].iter().all(|&(_, value)| value == 1.5));
+
+        let ewma = Smoothing::Ewma { alpha: 0.5 };
+        assert_eq!(
+            gorilla.smooth("missing", 0, u64::MAX, ewma, WarmUp::Skip),
+            None
+        );
+    }
+
+    #[test]
+    fn test_insert_rfc3339() {
+        let mut gorilla = Gorilla::new();
+        gorilla
+            .insert_rfc3339("logins", "2021-01-01T00:00:00Z", 3.0)
+            .unwrap();
+        gorilla
+            .insert_rfc3339("logins", "2021-01-01T01:00:30.5+01:00", 4.0)
+            .unwrap();
+        assert_eq!(
+            gorilla.query("logins", 1_609_459_200, 1_609_459_230),
+            Some(vec![(1_609_459_200, 3.0), (1_609_459_230, 4.0)])
+        );
+
+        assert_eq!(
+            gorilla.insert_rfc3339("logins", "2021-01-01 midnight", 5.0),
+            Err(TsdbError::InvalidTimestamp("2021-01-01 midnight".into()))
+        );
+        assert_eq!(gorilla.query("logins", 0, u64::MAX).unwrap().len(), 2);
+
+        // Points the engine refuses are reported, not dropped quietly
+        assert_eq!(
+            gorilla.insert_rfc3339("logins", "2021-01-01T00:01:00Z", f64::NAN),
+            Err(TsdbError::Rejected(InsertError::NanValue))
+        );
+        assert!(matches!(
+            gorilla.insert_rfc3339("logins", "2999-01-01T00:00:00Z", 1.0),
+            Err(TsdbError::Rejected(
+                InsertError::TimestampTooFarInFuture { .. }
+            ))
+        ));
+        gorilla.freeze("logins").unwrap();
+        let frozen = gorilla.insert_rfc3339("logins", "2021-01-01T00:02:00Z", 6.0);
+        assert_eq!(frozen, Err(TsdbError::Rejected(InsertError::SeriesFrozen)));
+        assert_eq!(
+            frozen.unwrap_err().to_string(),
+            "point rejected: series is frozen"
+        );
+        assert_eq!(gorilla.query("logins", 0, u64::MAX).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_quantiles() {
+        let gorilla = Gorilla::with_config(GorillaConfig {
+            exact_quantile_points: 2000,
+            future_tolerance: None,
+            ..GorillaConfig::default()
+        })
+        .unwrap();
+        let base_time = 7200 * 100;
+        // Latencies cycling through 1..=100ms, one every 10s
+        let latency = |i: u64| ((i * 37) % 100 + 1) as f64;
+        for i in 0..5000 {
+            gorilla.insert("latency", base_time + i * 10, latency(i));
+        }
+        let series = gorilla.tsmap.get("latency").unwrap();
+        series.write().insert(base_time + 5, f64::NAN);
+
+        // A range of 1001 points: 1ms 11 times, the rest ten times each
+        let end = base_time + 10_000;
+        let exact = gorilla
+            .quantiles("latency", base_time, end, &[0.5, 0.95, 0.99, 1.0])
+            .unwrap();
+        assert_eq!(exact.method, QuantileMethod::Exact);
+        assert_eq!(exact.values, [50.0, 95.0, 99.0, 100.0]);
+        assert_eq!(gorilla.quantile("latency", base_time, end, 0.0), Some(1.0));
+        assert_eq!(gorilla.quantile("latency", 0, base_time - 1, 0.5), None);
+        assert_eq!(gorilla.quantile("missing", 0, u64::MAX, 0.5), None);
+
+        // Over the limit: estimated, within 1% of rank
+        let qs = [0.5, 0.95, 0.99];
+        let approximate = gorilla.quantiles("latency", 0, u64::MAX, &qs).unwrap();
+        assert_eq!(approximate.method, QuantileMethod::Approximate);
+        for (q, estimate) in qs.into_iter().zip(approximate.values) {
+            let at_or_below = (0..5000).filter(|&i| latency(i) <= estimate).count();
+            let rank = at_or_below as f64 / 5000.0;
+            assert!((rank - q).abs() <= 0.01, "q {} ranked {}", q, rank);
+        }
+    }
+
+    fn selected_keys(selected: &[SelectedSeries]) -> Vec<&str> {
+        selected.iter().map(|series| series.key.as_str()).collect()
+    }
+
+    fn labeled_gorilla(base_time: u64) -> Gorilla {
+        let gorilla = Gorilla::new();
+        for (i, (host, region)) in [("web01", "eu"), ("web02", "eu"), ("db01", "us")]
+            .into_iter()
+            .enumerate()
+        {
+            for t in 0..3 {
+                let labels = [("host", host), ("region", region)];
+                gorilla.insert_labeled("http.requests", &labels, base_time + t * 60, i as f64);
+            }
+        }
+        gorilla.insert_labeled("cpu", &[("host", "web01")], base_time, 1.0);
+        gorilla
+    }
+
+    #[test]
+    fn test_query_selector() {
+        let base_time = 7200 * 100;
+        let gorilla = labeled_gorilla(base_time);
+        let eq = |value: &str| Matcher::Eq(value.to_string());
+
+        let eu = gorilla.query_selector("http.requests", &[("region", eq("eu"))], 0, u64::MAX);
+        assert_eq!(
+            selected_keys(&eu),
+            vec![
+                "http.requests{host=\"web01\",region=\"eu\"}",
+                "http.requests{host=\"web02\",region=\"eu\"}"
+            ]
+        );
+        assert_eq!(eu[0].points.len(), 3);
+        assert_eq!(eu[1].labels["host"], "web02");
+
+        // Several matchers are ANDed
+        let selected = gorilla.query_selector(
+            "http.requests",
+            &[
+                ("region", eq("eu")),
+                ("host", Matcher::Neq("web01".to_string())),
+            ],
+            0,
+            u64::MAX,
+        );
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].labels["host"], "web02");
+
+        let web = gorilla.query_selector(
+            "http.requests",
+            &[("host", Matcher::Prefix("web".to_string()))],
+            base_time + 60,
+            base_time + 60,
+        );
+        assert_eq!(web.len(), 2);
+        assert!(web.iter().all(|series| series.points.len() == 1));
+
+        // Name alone selects every series with that name
+        assert_eq!(
+            gorilla
+                .query_selector("http.requests", &[], 0, u64::MAX)
+                .len(),
+            3
+        );
+
+        // Selectors that match nothing
+        assert!(
+            gorilla
+                .query_selector("http.requests", &[("region", eq("ap"))], 0, u64::MAX)
+                .is_empty()
+        );
+        assert!(
+            gorilla
+                .query_selector("http.requests", &[("zone", eq("a"))], 0, u64::MAX)
+                .is_empty()
+        );
+        assert!(
+            gorilla
+                .query_selector("missing", &[], 0, u64::MAX)
+                .is_empty()
+        );
+
+        // The plain key API sees labeled series under their canonical key
+        let key = "cpu{host=\"web01\"}";
+        assert_eq!(
+            gorilla.query(key, 0, u64::MAX).unwrap(),
+            vec![(base_time, 1.0)]
+        );
+    }
+
+    #[test]
+    fn test_label_index_after_delete() {
+        let base_time = 7200 * 100;
+        let mut gorilla = labeled_gorilla(base_time);
+        let us = [("region", Matcher::Eq("us".to_string()))];
+        let db_key = "http.requests{host=\"db01\",region=\"us\"}";
+
+        gorilla.delete(db_key).unwrap();
+        assert!(
+            gorilla
+                .query_selector("http.requests", &us, 0, u64::MAX)
+                .is_empty()
+        );
+        assert_eq!(
+            gorilla
+                .query_selector("http.requests", &[], 0, u64::MAX)
+                .len(),
+            2
+        );
+
+        // Recreated series is indexed again, and compaction renumbers
+        // slots without losing index entries
+        gorilla.insert_labeled(
+            "http.requests",
+            &[("region", "us"), ("host", "db01")],
+            base_time,
+            9.0,
+        );
+        gorilla
+            .delete("http.requests{host=\"web01\",region=\"eu\"}")
+            .unwrap();
+        gorilla.compact();
+        let selected = gorilla.query_selector("http.requests", &us, 0, u64::MAX);
+        assert_eq!(selected_keys(&selected), vec![db_key]);
+        assert_eq!(selected[0].points, vec![(base_time, 9.0)]);
+        assert_eq!(
+            gorilla
+                .query_selector("http.requests", &[], 0, u64::MAX)
+                .len(),
+            2
+        );
+
+        // Plain keys are indexed by their key, and follow renames
+        gorilla.insert("mem", base_time, 1.0);
+        gorilla.rename("mem", "memory").unwrap();
+        assert!(gorilla.query_selector("mem", &[], 0, u64::MAX).is_empty());
+        assert_eq!(gorilla.query_selector("memory", &[], 0, u64::MAX).len(), 1);
+    }
+
+    #[test]
+    fn test_labels_survive_snapshot_and_wal() {
+        let base_time = 7200 * 100;
+        let dir = temp_wal_dir("wal_labels");
+        let snapshot_path = temp_path("labels.snap");
+        let eu = [("region", Matcher::Eq("eu".to_string()))];
+
+        let gorilla = Gorilla::with_config(GorillaConfig {
+            wal_dir: Some(dir.clone()),
+            ..GorillaConfig::default()
+        })
+        .unwrap();
+        gorilla.insert_labeled("cpu", &[("region", "eu")], base_time, 1.0);
+        gorilla.snapshot(&snapshot_path).unwrap();
+        gorilla.insert_labeled("cpu", &[("region", "eu")], base_time + 60, 2.0);
+        gorilla.insert_labeled("cpu", &[("region", "us")], base_time, 3.0);
+        drop(gorilla);
+
+        let loaded = Gorilla::load(&snapshot_path).unwrap();
+        assert_eq!(
+            loaded.query_selector("cpu", &eu, 0, u64::MAX)[0]
+                .points
+                .len(),
+            1
+        );
+
+        for (recovered, _) in [
+            Gorilla::recover(&dir).unwrap(),
+            Gorilla::recover_from_snapshot(&snapshot_path, &dir).unwrap(),
+        ] {
+            let selected = recovered.query_selector("cpu", &eu, 0, u64::MAX);
+            assert_eq!(selected.len(), 1);
+            assert_eq!(
+                selected[0].points,
+                vec![(base_time, 1.0), (base_time + 60, 2.0)]
+            );
+            assert_eq!(recovered.query_selector("cpu", &[], 0, u64::MAX).len(), 2);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_file(&snapshot_path).unwrap();
+    }
+
+    #[test]
+    fn test_query_cache() {
+        let config = GorillaConfig {
+            query_cache: Some(8),
+            ..GorillaConfig::default()
+        };
+        let mut gorilla = Gorilla::with_config(config).unwrap();
+        let base_time = 7200 * 100;
+        for i in 0..100 {
+            gorilla.insert("cpu", base_time + i * 60, i as f64);
+        }
+        gorilla.insert("mem", base_time, 1.0);
+        let counts = |gorilla: &Gorilla| {
+            let metrics = gorilla.metrics();
+            (metrics.query_cache_hits, metrics.query_cache_misses)
+        };
+
+        let first = gorilla.query("cpu", base_time, base_time + 3000).unwrap();
+        assert_eq!(counts(&gorilla), (0, 1));
+        let second = gorilla.query("cpu", base_time, base_time + 3000).unwrap();
+        assert_eq!(second, first);
+        assert_eq!(counts(&gorilla), (1, 1));
+        // Another range is another entry
+        gorilla.query("cpu", base_time, base_time + 60).unwrap();
+        assert_eq!(counts(&gorilla), (1, 2));
+
+        // A write drops the key's results; other keys keep theirs
+        gorilla.query("mem", 0, u64::MAX).unwrap();
+        gorilla.insert("cpu", base_time + 30, -1.0);
+        let after = gorilla.query("cpu", base_time, base_time + 3000).unwrap();
+        assert_eq!(counts(&gorilla), (1, 4));
+        assert_eq!(after.len(), first.len() + 1);
+        assert_eq!(after[1], (base_time + 30, -1.0));
+        gorilla.query("mem", 0, u64::MAX).unwrap();
+        assert_eq!(counts(&gorilla), (2, 4));
+
+        // So does a batch write, and a delete; a series created again
+        // under the key starts uncached
+        gorilla
+            .insert_batch("cpu", &[(base_time + 90, -2.0)])
+            .unwrap();
+        assert!(
+            gorilla
+                .query("cpu", base_time, base_time + 3000)
+                .unwrap()
+                .contains(&(base_time + 90, -2.0))
+        );
+        gorilla.delete("cpu").unwrap();
+        assert_eq!(gorilla.query("cpu", base_time, base_time + 3000), None);
+        gorilla.insert("cpu", base_time, 5.0);
+        assert_eq!(
+            gorilla.query("cpu", base_time, base_time + 3000).unwrap(),
+            [(base_time, 5.0)]
+        );
+        assert_eq!(counts(&gorilla), (2, 6));
+
+        // Without a configured cache nothing is counted
+        let plain = Gorilla::new();
+        plain.insert("cpu", base_time, 1.0);
+        plain.query("cpu", 0, u64::MAX);
+        plain.query("cpu", 0, u64::MAX);
+        assert_eq!(counts(&plain), (0, 0));
+    }
+
+    #[test]
+    fn test_query_many() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+
+        for i in 0..5 {
+            gorilla.insert("cpu", base_time + i * 60, i as f64);
+            gorilla.insert("mem", base_time + i * 60, 100.0 + i as f64);
+        }
+
+        gorilla.insert("idle", base_time + 7200, 1.0);
+
+        let results =
+            gorilla.query_many(&["mem", "disk", "cpu", "idle"], base_time, base_time + 120);
+        let keys: Vec<&str> = results.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["mem", "disk", "cpu", "idle"]);
+        assert_eq!(results[0].1.as_ref().unwrap().len(), 3);
+        // Missing is None; existing with nothing in range is empty
+        assert_eq!(results[1].1, None);
+        assert_eq!(
+            results[2].1,
+            Some(vec![
+                (base_time, 0.0),
+                (base_time + 60, 1.0),
+                (base_time + 120, 2.0)
+            ])
+        );
+        assert_eq!(results[3].1, Some(vec![]));
+        assert!(gorilla.query_many(&[], 0, u64::MAX).is_empty());
+
+        let results = gorilla.query_glob("?dl*", base_time, base_time + 120);
+        assert_eq!(results, vec![("idle".to_string(), vec![])]);
+        let keys: Vec<String> = gorilla
+            .query_glob("*", 0, u64::MAX)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, ["cpu", "idle", "mem"]);
+        assert!(gorilla.query_glob("disk*", 0, u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn test_query_downsampled() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        // 26 hours of 15-second data, in halves so sums stay exact
+        let points: Vec<(u64, f64)> = (0..26 * 240)
+            .map(|i| (base_time + i * 15, (i % 17) as f64 * 0.5))
+            .collect();
+        gorilla.insert_batch("cpu", &points).unwrap();
+
+        let aggregations = [
+            Aggregation::Avg,
+            Aggregation::Min,
+            Aggregation::Max,
+            Aggregation::Sum,
+            Aggregation::Count,
+            Aggregation::Last,
+            Aggregation::First,
+        ];
+        let (start, end) = (base_time + 123, base_time + 26 * 3600 - 77);
+        for step in [60, 45, 7000, 10_000, 86_400] {
+            // Brute force: group the points in range by bucket
+            let mut expected: Vec<(u64, Vec<f64>)> = Vec::new();
+            for &(ts, value) in points.iter().filter(|&&(ts, _)| ts >= start && ts <= end) {
+                let bucket = start + (ts - start) / step * step;
+                match expected.last_mut() {
+                    Some((last, values)) if *last == bucket => values.push(value),
+                    _ => expected.push((bucket, vec![value])),
+                }
+            }
+            for aggregation in aggregations {
+                let brute: Vec<(u64, f64)> = expected
+                    .iter()
+                    .map(|(bucket, values)| {
+                        let value = match aggregation {
+                            Aggregation::Avg => values.iter().sum::<f64>() / values.len() as f64,
+                            Aggregation::Min => {
+                                values.iter().copied().fold(f64::INFINITY, f64::min)
+                            }
+                            Aggregation::Max => {
+                                values.iter().copied().fold(f64::NEG_INFINITY, f64::max)
+                            }
+                            Aggregation::Sum => values.iter().sum(),
+                            Aggregation::Count => values.len() as f64,
+                            Aggregation::Last => values[values.len() - 1],
+                            Aggregation::First => values[0],
+                        };
+                        (*bucket, value)
+                    })
+                    .collect();
+                let bucketed = gorilla
+                    .query_downsampled("cpu", start, end, step, aggregation, EmptyBuckets::Omit)
+                    .unwrap();
+                assert_eq!(bucketed, brute, "step {} {:?}", step, aggregation);
+            }
+        }
+
+        // Gaps: empty buckets left out, or emitted as NaN
+        gorilla.insert("sparse", base_time, 1.0);
+        gorilla.insert("sparse", base_time + 250, 2.0);
+        let omitted = gorilla
+            .query_downsampled(
+                "sparse",
+                base_time,
+                base_time + 299,
+                100,
+                Aggregation::Sum,
+                EmptyBuckets::Omit,
+            )
+            .unwrap();
+        assert_eq!(omitted, vec![(base_time, 1.0), (base_time + 200, 2.0)]);
+        let gaps = gorilla
+            .query_downsampled(
+                "sparse",
+                base_time,
+                base_time + 299,
+                100,
+                Aggregation::Sum,
+                EmptyBuckets::Nan,
+            )
+            .unwrap();
+        assert_eq!(gaps.len(), 3);
+        assert_eq!(gaps[0], (base_time, 1.0));
+        assert!(gaps[1].0 == base_time + 100 && gaps[1].1.is_nan());
+        assert_eq!(gaps[2], (base_time + 200, 2.0));
+        assert!(
+            gorilla
+                .query_downsampled(
+                    "missing",
+                    0,
+                    u64::MAX,
+                    60,
+                    Aggregation::Avg,
+                    EmptyBuckets::Omit
+                )
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        // 26 hours of 15-second data, in halves so sums stay exact
+        let points: Vec<(u64, f64)> = (0..26 * 240)
+            .map(|i| (base_time + i * 15, (i % 17) as f64 * 0.5))
+            .collect();
+        gorilla.insert_batch("cpu", &points).unwrap();
+
+        let aggregations = [
+            Aggregation::Avg,
+            Aggregation::Min,
+            Aggregation::Max,
+            Aggregation::Sum,
+            Aggregation::Count,
+            Aggregation::Last,
+            Aggregation::First,
+        ];
+        let ranges = [
+            (0, u64::MAX),
+            (base_time + 123, base_time + 26 * 3600 - 77),
+            (base_time + 7200, base_time + 3 * 7200 - 1),
+            (base_time + 10, base_time + 50),
+        ];
+        for (start, end) in ranges {
+            let values: Vec<f64> = points
+                .iter()
+                .filter(|&&(ts, _)| ts >= start && ts <= end)
+                .map(|&(_, value)| value)
+                .collect();
+            for aggregation in aggregations {
+                let brute = match aggregation {
+                    Aggregation::Avg => values.iter().sum::<f64>() / values.len() as f64,
+                    Aggregation::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+                    Aggregation::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+                    Aggregation::Sum => values.iter().sum(),
+                    Aggregation::Count => values.len() as f64,
+                    Aggregation::Last => values[values.len() - 1],
+                    Aggregation::First => values[0],
+                };
+                assert_eq!(
+                    gorilla.aggregate("cpu", start, end, aggregation, NanValues::Skip),
+                    Some(brute),
+                    "[{}, {}] {:?}",
+                    start,
+                    end,
+                    aggregation
+                );
+            }
+            assert_eq!(gorilla.count("cpu", start, end), values.len());
+        }
+
+        // Empty ranges and missing series
+        let max = |key: &str, start: u64, end: u64| {
+            gorilla.aggregate(key, start, end, Aggregation::Max, NanValues::Skip)
+        };
+        assert_eq!(max("cpu", 0, base_time - 1), None);
+        assert_eq!(max("cpu", base_time + 100, base_time + 10), None);
+        assert_eq!(max("missing", 0, u64::MAX), None);
+        assert_eq!(gorilla.count("cpu", 0, base_time - 1), 0);
+        assert_eq!(gorilla.count("missing", 0, u64::MAX), 0);
+
+        assert_eq!(
+            gorilla.aggregate_many(
+                &["missing", "cpu"],
+                base_time,
+                base_time + 30,
+                Aggregation::Sum,
+                NanValues::Skip
+            ),
+            vec![
+                ("missing".to_string(), None),
+                ("cpu".to_string(), Some(1.5))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_aggregate_nan() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        for (offset, value) in [(0, 1.0), (60, 2.0), (120, 4.0), (7200, 8.0)] {
+            gorilla.insert("nan", base_time + offset, value);
+        }
+        // Inserts refuse NaN; only restored or imported data holds it
+        let series = gorilla.tsmap.get("nan").unwrap();
+        series.write().insert(base_time + 30, f64::NAN);
+        series.write().insert(base_time + 7230, f64::NAN);
+
+        let aggregate = |start: u64, end: u64, aggregation, nan| {
+            gorilla.aggregate("nan", start, end, aggregation, nan)
+        };
+        // The closed block holding NaN, whole, then in part
+        for (start, end) in [(0, u64::MAX), (base_time + 10, base_time + 7200)] {
+            let skipped = aggregate(start, end, Aggregation::Sum, NanValues::Skip);
+            let expected = if start == 0 { 15.0 } else { 14.0 };
+            assert_eq!(skipped, Some(expected));
+            let propagated = aggregate(start, end, Aggregation::Max, NanValues::Propagate);
+            assert!(propagated.unwrap().is_nan());
+        }
+        assert_eq!(
+            aggregate(0, u64::MAX, Aggregation::Max, NanValues::Skip),
+            Some(8.0)
+        );
+        assert_eq!(
+            aggregate(0, u64::MAX, Aggregation::Count, NanValues::Skip),
+            Some(4.0)
+        );
+        assert_eq!(
+            aggregate(0, u64::MAX, Aggregation::Count, NanValues::Propagate),
+            Some(6.0)
+        );
+        assert_eq!(gorilla.count("nan", 0, u64::MAX), 6);
+
+        // A range holding nothing but NaN
+        let only_nan = (base_time + 20, base_time + 40);
+        assert_eq!(
+            aggregate(only_nan.0, only_nan.1, Aggregation::Avg, NanValues::Skip),
+            None
+        );
+        assert!(
+            aggregate(
+                only_nan.0,
+                only_nan.1,
+                Aggregation::Avg,
+                NanValues::Propagate
+            )
+            .unwrap()
+            .is_nan()
+        );
+
+        // Ranges clear of NaN aren't affected by the policy
+        assert_eq!(
+            aggregate(0, base_time + 20, Aggregation::First, NanValues::Propagate),
+            Some(1.0)
+        );
+    }
+
+    #[test]
+    fn test_rollup() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        // Two and a half hours of minute data
+        for i in 0..150u64 {
+            gorilla.insert("cpu", base_time + i * 60, (i % 10) as f64);
+            gorilla.insert("mem", base_time + i * 60, (i * 2) as f64);
+        }
+        gorilla.insert("disk", base_time, 1.0);
+        let template = "rollup.1h.avg.{key}";
+        let run = |end: u64| gorilla.rollup("*", 0, end, 3600, Aggregation::Avg, template);
+
+        // Only the two complete hours are rolled up
+        let end = base_time + 149 * 60;
+        assert_eq!(
+            run(end),
+            RollupReport {
+                series: 3,
+                points: 5
+            }
+        );
+        let avg = |from: u64| (from..from + 60).map(|i| (i % 10) as f64).sum::<f64>() / 60.0;
+        assert_eq!(
+            gorilla.query("rollup.1h.avg.cpu", 0, u64::MAX).unwrap(),
+            [(base_time, avg(0)), (base_time + 3600, avg(60))]
+        );
+        assert_eq!(
+            gorilla.query("rollup.1h.avg.mem", 0, u64::MAX).unwrap(),
+            [(base_time, 59.0), (base_time + 3600, 179.0)]
+        );
+        assert_eq!(
+            gorilla.query("rollup.1h.avg.disk", 0, u64::MAX).unwrap(),
+            [(base_time, 1.0)]
+        );
+
+        // Running again writes nothing, and outputs aren't rolled up
+        assert_eq!(run(end), RollupReport::default());
+        assert!(!gorilla.contains("rollup.1h.avg.rollup.1h.avg.cpu"));
+        let cpu = gorilla.query("rollup.1h.avg.cpu", 0, u64::MAX).unwrap();
+        assert_eq!(cpu.len(), 2);
+
+        // Once the range covers the third hour, only it is processed
+        for i in 150..180u64 {
+            gorilla.insert("cpu", base_time + i * 60, (i % 10) as f64);
+        }
+        let report = run(base_time + 3 * 3600 - 1);
+        assert_eq!(
+            report,
+            RollupReport {
+                series: 2,
+                points: 2
+            }
+        );
+        assert_eq!(
+            gorilla.query("rollup.1h.avg.cpu", 0, u64::MAX).unwrap()[2],
+            (base_time + 7200, avg(120))
+        );
+        assert_eq!(
+            gorilla.query("rollup.1h.avg.mem", 0, u64::MAX).unwrap()[2],
+            (base_time + 7200, 269.0)
+        );
+
+        // A narrower pattern, another aggregation and start bound
+        let report = gorilla.rollup(
+            "c?u",
+            base_time + 1,
+            base_time + 3 * 3600 - 1,
+            3600,
+            Aggregation::Max,
+            "{key}.max",
+        );
+        assert_eq!(
+            report,
+            RollupReport {
+                series: 1,
+                points: 2
+            }
+        );
+        assert_eq!(
+            gorilla.query("cpu.max", 0, u64::MAX).unwrap(),
+            [(base_time + 3600, 9.0), (base_time + 7200, 9.0)]
+        );
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("web*.cpu", "web01.cpu"));
+        assert!(glob_matches("web*.cpu", "web.cpu"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("a*b*c", "aXbYbZc"));
+        assert!(glob_matches("web0?.*", "web01.mem"));
+        assert!(!glob_matches("web0?.*", "web1.mem"));
+        assert!(!glob_matches("web*.cpu", "web01.cpu.total"));
+        assert!(!glob_matches("cpu", "cpu2"));
+        assert!(!glob_matches("?", ""));
+    }
+
+    #[test]
+    fn test_best_lag_finds_shift() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+
+        // Irregular values, so only the true shift lines up
+        let mut state = 12345u64;
+        let values: Vec<f64> = (0..120)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 33) as f64 / 1000.0
+            })
+            .collect();
+        for (i, &value) in values.iter().enumerate() {
+            let ts = base_time + i as u64 * 60;
+            gorilla.insert("cpu", ts, value);
+            // latency trails cpu by three samples
+            let trailing = if i >= 3 { values[i - 3] } else { 0.0 };
+            gorilla.insert("latency", ts, trailing * 2.0 + 5.0);
+        }
+
+        let (lag, correlation) = gorilla.best_lag("cpu", "latency", 0, u64::MAX, 10).unwrap();
+        assert_eq!(lag, 3);
+        assert!(correlation > 0.999, "{}", correlation);
+
+        // Swapping the series flips the lag
+        let (lag, _) = gorilla.best_lag("latency", "cpu", 0, u64::MAX, 10).unwrap();
+        assert_eq!(lag, -3);
+
+        assert_eq!(gorilla.best_lag("cpu", "missing", 0, u64::MAX, 10), None);
+    }
+
+    #[test]
+    fn test_rolling_correlation_sign_flip() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        for i in 0..200u64 {
+            let signal = (i as f64 * 0.7).sin() * 10.0;
+            let follower = if i < 100 { signal * 2.0 + 1.0 } else { -signal };
+            gorilla.insert("a", base_time + i * 60, signal);
+            gorilla.insert("b", base_time + i * 60, follower);
+        }
+        // An unshared timestamp is skipped by the alignment
+        gorilla.insert("a", base_time + 30, 99.0);
+
+        let rolling = gorilla.rolling_correlation("a", "b", 0, u64::MAX, 20);
+        assert_eq!(rolling.len(), 200 - 20 + 1);
+        assert_eq!(rolling[0].0, base_time + 19 * 60);
+        assert_eq!(rolling.last().unwrap().0, base_time + 199 * 60);
+
+        // Correlated windows first, anti-correlated ones at the end
+        assert!(rolling[..81].iter().all(|&(_, r)| r > 0.99));
+        assert!(rolling[100..].iter().all(|&(_, r)| r < -0.99));
+
+        assert!(
+            gorilla
+                .rolling_correlation("a", "b", 0, u64::MAX, 1)
+                .is_empty()
+        );
+        assert!(
+            gorilla
+                .rolling_correlation("a", "b", 0, u64::MAX, 500)
+                .is_empty()
+        );
+        assert!(
+            gorilla
+                .rolling_correlation("a", "missing", 0, u64::MAX, 5)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_find_correlated_reports_key_names() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+
+        for i in 0..10 {
+            let t = base_time + i * 60;
+            gorilla.insert("needle", t, i as f64);
+            gorilla.insert("follows", t, 2.0 * i as f64);
+            gorilla.insert("opposes", t, -(i as f64));
+            gorilla.insert("short", base_time, 1.0);
+        }
+
+        let found = gorilla.find_correlated("needle", base_time, base_time + 600, 5);
+        let mut keys: Vec<&str> = found.iter().map(|(key, _)| key.as_str()).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["follows", "opposes"]);
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let mut gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        let empty = gorilla.memory_usage();
+
+        for i in 0..100 {
+            gorilla.insert("small", base_time + i * 60, i as f64);
+        }
+        let after_small = gorilla.memory_usage();
+        assert!(after_small.raw_points_bytes >= 100 * size_of::<DataPoint>());
+        assert!(after_small.compressed_bytes > 0);
+        assert!(after_small.total() > empty.total());
+
+        // Crosses into a second block
+        for i in 0..1000 {
+            gorilla.insert("big", base_time + i * 10, (i % 7) as f64);
+        }
+        let big = gorilla.tsmap.get("big").unwrap().read().memory_usage();
+        let after_big = gorilla.memory_usage();
+        assert!(after_big.total() > after_small.total());
+        assert!(big.raw_points_bytes >= 1000 * size_of::<DataPoint>());
+
+        gorilla.insert("tiny", base_time, 1.0);
+        let heaviest = gorilla.heaviest_series(2);
+        let keys: Vec<&str> = heaviest.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, vec!["big", "small"]);
+        assert!(heaviest[0].1.total() > heaviest[1].1.total());
+        assert_eq!(gorilla.heaviest_series(10).len(), 3);
+
+        // Dropping a series gives its memory back
+        let before_delete = gorilla.memory_usage();
+        gorilla.delete("big").unwrap();
+        assert_eq!(
+            gorilla.memory_usage().total(),
+            before_delete.total() - big.total()
+        );
+    }
+
+    fn spill_files(dir: &Path) -> usize {
+        std::fs::read_dir(dir).unwrap().count()
+    }
+
+    fn spilling_gorilla(name: &str, memory_budget: Option<usize>) -> (Gorilla, Arc<TestClock>) {
+        let clock = Arc::new(TestClock::new(7200 * 103));
+        let config = GorillaConfig {
+            clock: clock.clone(),
+            spill: Some(SpillConfig {
+                dir: temp_wal_dir(name),
+                idle_secs: 600,
+                memory_budget,
+            }),
+            ..GorillaConfig::default()
+        };
+        let gorilla = Gorilla::with_config(config).unwrap();
+
+        // Three blocks per series: two closed, one open
+        let base_time = 7200 * 100;
+        for i in 0..360 {
+            gorilla.insert("cpu", base_time + i * 60, (i % 13) as f64);
+            gorilla.insert("mem", base_time + i * 60, 1000.0 + i as f64);
+        }
+        (gorilla, clock)
+    }
+
+    #[test]
+    fn test_spill_idle_blocks() {
+        let dir = temp_path("spill_idle");
+        let (mut gorilla, clock) = spilling_gorilla("spill_idle", None);
+        let base_time = 7200 * 100;
+        let expected_cpu = gorilla.query("cpu", 0, u64::MAX).unwrap();
+        let expected_mem = gorilla.query("mem", 0, u64::MAX).unwrap();
+        let before = gorilla.memory_usage();
+
+        // The first pass only starts the idle clock
+        assert_eq!(gorilla.spill_cold().unwrap().blocks_spilled, 0);
+
+        // A block read since the last pass stays in memory
+        clock.advance(600);
+        gorilla.query("cpu", base_time, base_time + 60);
+        let report = gorilla.spill_cold().unwrap();
+        assert_eq!(report.blocks_spilled, 3);
+        assert!(report.bytes_spilled > 0);
+        assert_eq!(spill_files(&dir), 3);
+        assert!(gorilla.memory_usage().total() < before.total());
+
+        // Spilled blocks are read back transparently
+        assert_eq!(gorilla.query("cpu", 0, u64::MAX).unwrap(), expected_cpu);
+        assert_eq!(gorilla.query("mem", 0, u64::MAX).unwrap(), expected_mem);
+        let metrics = gorilla.metrics();
+        assert_eq!(metrics.blocks_spilled, 3);
+        assert_eq!(metrics.blocks_reloaded, 3);
+        assert_eq!(metrics.spill_read_errors, 0);
+
+        // Snapshots include spilled blocks
+        let snap = temp_path("spill_idle.snap");
+        gorilla.snapshot(&snap).unwrap();
+        let loaded = Gorilla::load(&snap).unwrap();
+        assert_eq!(loaded.query("mem", 0, u64::MAX).unwrap(), expected_mem);
+        std::fs::remove_file(&snap).unwrap();
+
+        // A backfill brings its block back into memory and drops the file
+        gorilla.insert("mem", base_time + 30, -1.0);
+        assert_eq!(spill_files(&dir), 2);
+        assert_eq!(gorilla.query("mem", 0, u64::MAX).unwrap().len(), 361);
+
+        // Deleting a series removes its spill files
+        gorilla.delete("mem").unwrap();
+        assert_eq!(spill_files(&dir), 1);
+        drop(gorilla);
+        assert_eq!(spill_files(&dir), 0);
+    }
+
+    #[test]
+    fn test_spill_to_memory_budget() {
+        let dir = temp_path("spill_budget");
+        let (mut gorilla, _clock) = spilling_gorilla("spill_budget", Some(0));
+        let expected = gorilla.query("cpu", 0, u64::MAX).unwrap();
+
+        // Over budget: every closed block goes, open blocks stay
+        let report = gorilla.spill_cold().unwrap();
+        assert_eq!(report.blocks_spilled, 4);
+        assert_eq!(spill_files(&dir), 4);
+        assert_eq!(gorilla.spill_cold().unwrap().blocks_spilled, 0);
+
+        assert_eq!(gorilla.query("cpu", 0, u64::MAX).unwrap(), expected);
+        gorilla.delete("cpu").unwrap();
+        gorilla.delete("mem").unwrap();
+        assert_eq!(spill_files(&dir), 0);
+    }
+
+    #[test]
+    fn test_corrupt_spilled_block() {
+        let dir = temp_path("spill_corrupt");
+        let (mut gorilla, _clock) = spilling_gorilla("spill_corrupt", Some(0));
+        let base_time = 7200 * 100;
+        gorilla.delete("mem").unwrap();
+        assert_eq!(gorilla.spill_cold().unwrap().blocks_spilled, 2);
+
+        // Three blocks: two spilled, one open. Damage the middle one.
+        let bad_start = base_time + 7200;
+        let bad_file = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| {
+                path.to_string_lossy()
+                    .ends_with(&format!("-{}.blk", bad_start))
+            })
+            .unwrap();
+        let mut bytes = std::fs::read(&bad_file).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        std::fs::write(&bad_file, bytes).unwrap();
+
+        let err = gorilla.query_result("cpu", 0, u64::MAX, false).unwrap_err();
+        assert_eq!(
+            err,
+            QueryError::CorruptBlock {
+                key: "cpu".to_string(),
+                block_start: bad_start,
+            }
+        );
+
+        // Opting into partial results returns the two healthy blocks
+        let partial = gorilla.query_result("cpu", 0, u64::MAX, true).unwrap();
+        assert_eq!(partial.points.len(), 240);
+        assert_eq!(partial.corrupt_blocks, vec![bad_start]);
+        assert!(
+            partial
+                .points
+                .iter()
+                .all(|&(ts, _)| ts < bad_start || ts >= bad_start + 7200)
+        );
+
+        // Ranges that miss the bad block are unaffected
+        let healthy = gorilla
+            .query_result("cpu", 0, bad_start - 1, false)
+            .unwrap();
+        assert_eq!(healthy.points.len(), 120);
+        assert!(healthy.corrupt_blocks.is_empty());
+        assert!(gorilla.metrics().spill_read_errors > 0);
+
+        // The repair removes the block and its file
+        assert_eq!(gorilla.drop_corrupt_blocks("cpu").unwrap(), vec![bad_start]);
+        assert_eq!(spill_files(&dir), 1);
+        let repaired = gorilla.query_result("cpu", 0, u64::MAX, false).unwrap();
+        assert_eq!(repaired.points, partial.points);
+        assert!(gorilla.drop_corrupt_blocks("cpu").unwrap().is_empty());
+        assert_eq!(
+            gorilla.drop_corrupt_blocks("missing"),
+            Err(TsdbError::SeriesNotFound("missing".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_worst_compressed_blocks() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+
+        // Pseudo-random noise (xorshift) next to steady series
+        let mut state: u64 = 0x2545_F491_4F6C_DD1D;
+        for i in 0..300 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let ts = base_time + i * 60;
+            gorilla.insert("noise", ts, f64::from_bits(state >> 2));
+            gorilla.insert("flat", ts, 42.0);
+            gorilla.insert("counter", ts, i as f64);
+            gorilla.insert("gauge", ts, 50.0 + (i % 4) as f64);
+        }
+
+        // Two closed blocks per series; the open ones don't count
+        let worst = gorilla.worst_compressed_blocks(usize::MAX);
+        assert_eq!(worst.len(), 8);
+        assert_eq!(worst[0].0, "noise");
+        assert_eq!(worst[1].0, "noise");
+        assert!(worst[0].2 >= worst[1].2);
+        assert!(worst[1].2 > 2.0 * worst[2].2);
+        assert_eq!(worst.last().unwrap().0, "flat");
+
+        let top = gorilla.worst_compressed_blocks(1);
+        assert_eq!(top, worst[..1]);
+        assert!(Gorilla::new().worst_compressed_blocks(5).is_empty());
+    }
+
+    #[test]
+    fn test_persist_and_open() {
+        let dir = temp_path("persist_open");
+        let _ = std::fs::remove_dir_all(&dir);
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        let hourly = SeriesOptions {
+            block_duration: Some(3600),
+            ..SeriesOptions::default()
+        };
+        gorilla.create_series("billing", hourly.clone()).unwrap();
+        for i in 0..300 {
+            gorilla.insert("cpu", base_time + i * 60, (i % 17) as f64);
+            gorilla.insert("billing", base_time + i * 60, i as f64);
+        }
+
+        let info = gorilla.persist_to(&dir).unwrap();
+        assert_eq!((info.generation, info.series, info.points), (1, 2, 600));
+        let reopened = Gorilla::open(&dir).unwrap();
+        for key in ["cpu", "billing"] {
+            assert_eq!(
+                reopened.query(key, 0, u64::MAX),
+                gorilla.query(key, 0, u64::MAX)
+            );
+        }
+        assert_eq!(
+            reopened.tsmap.get("billing").unwrap().read().options(),
+            &hourly
+        );
+        assert_eq!(
+            reopened.block_boundaries("billing"),
+            gorilla.block_boundaries("billing")
+        );
+
+        // Writes continue in the reopened open block
+        reopened.insert("cpu", base_time + 300 * 60, 99.0);
+        assert_eq!(reopened.query("cpu", 0, u64::MAX).unwrap().len(), 301);
+
+        // A persist whose marker is missing leaves the earlier state
+        gorilla.insert("late", base_time, 1.0);
+        assert_eq!(gorilla.persist_to(&dir).unwrap().generation, 2);
+        assert!(Gorilla::open(&dir).unwrap().contains("late"));
+        std::fs::remove_file(dir.join("2.checkpoint")).unwrap();
+        let fallback = Gorilla::open(&dir).unwrap();
+        assert!(!fallback.contains("late"));
+        assert_eq!(fallback.query("cpu", 0, u64::MAX).unwrap().len(), 300);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tenant_namespaces_are_isolated() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        let acme = gorilla.namespace("acme");
+        let globex = gorilla.namespace("globex");
+
+        acme.insert("cpu", base_time, 1.0);
+        acme.insert("mem", base_time, 2.0);
+        globex.insert("cpu", base_time, 100.0);
+
+        assert_eq!(
+            acme.query("cpu", 0, u64::MAX).unwrap(),
+            vec![(base_time, 1.0)]
+        );
+        assert_eq!(
+            globex.query("cpu", 0, u64::MAX).unwrap(),
+            vec![(base_time, 100.0)]
+        );
+        assert!(!globex.contains("mem"));
+        assert_eq!(
+            globex.try_query("mem", 0, u64::MAX),
+            Err(QueryError::SeriesNotFound("mem".to_string()))
+        );
+
+        // Neither the bare key nor another tenant's prefix reaches them
+        assert!(gorilla.query("cpu", 0, u64::MAX).is_none());
+        assert!(gorilla.namespace("acm").list_series().is_empty());
+        assert!(gorilla.namespace("").list_series().is_empty());
+
+        assert_eq!(acme.list_series(), ["cpu", "mem"]);
+        assert_eq!(gorilla.list_series_in("globex"), ["cpu"]);
+        assert_eq!(gorilla.list_series(false).len(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "NUL")]
+    fn test_tenant_name_rejects_separator() {
+        Gorilla::new().namespace("a\0b");
+    }
+
+    #[test]
+    fn test_recover_from_chunks() {
+        let dir = temp_wal_dir("chunks_recover");
+        let config = |flush_bytes| GorillaConfig {
+            chunks: Some(ChunkConfig {
+                dir: dir.clone(),
+                flush_bytes,
+            }),
+            ..GorillaConfig::default()
+        };
+        let base_time = 7200 * 100;
+        let insert_all = |gorilla: &Gorilla| {
+            for i in 0..300 {
+                gorilla.insert("cpu", base_time + i * 60, (i % 13) as f64 * 0.5);
+            }
+        };
+
+        // Open block bytes never reach the threshold: only closed blocks survive
+        let gorilla = Gorilla::with_config(config(1 << 20)).unwrap();
+        insert_all(&gorilla);
+        let expected = gorilla.query("cpu", 0, u64::MAX).unwrap();
+        drop(gorilla);
+        let (recovered, report) = Gorilla::recover_chunks(config(1 << 20)).unwrap();
+        assert_eq!(
+            (report.blocks, report.points, report.torn_bytes),
+            (2, 240, 0)
+        );
+        assert_eq!(
+            recovered.query("cpu", 0, u64::MAX).unwrap(),
+            expected[..240]
+        );
+
+        // A small threshold flushes the open block in chunks; only the tail
+        // since the last one is lost
+        let gorilla = Gorilla::with_config(config(16)).unwrap();
+        insert_all(&gorilla);
+        drop(gorilla);
+        let (recovered, report) = Gorilla::recover_chunks(config(16)).unwrap();
+        assert!(report.chunks > 3);
+        let points = recovered.query("cpu", 0, u64::MAX).unwrap();
+        assert!(points.len() > 240 && points.len() <= 300);
+        assert_eq!(points, expected[..points.len()]);
+
+        // Streaming carries on into the same files after recovery
+        let resumed = points.len() as u64;
+        for i in resumed..300 {
+            recovered.insert("cpu", base_time + i * 60, (i % 13) as f64 * 0.5);
+        }
+        recovered.insert("cpu", base_time + 3 * 7200, 1.0);
+        drop(recovered);
+        let (again, _) = Gorilla::recover_chunks(config(16)).unwrap();
+        assert_eq!(
+            again.query("cpu", 0, base_time + 3 * 7200 - 1),
+            Some(expected)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recover_with_streams_chunks() {
+        let wal_dir = temp_wal_dir("chunks_after_wal");
+        let chunk_dir = temp_wal_dir("chunks_after_wal_files");
+        let snapshot_path = temp_path("chunks_after_wal.snap");
+        let base_time = 7200 * 100;
+        let gorilla = Gorilla::with_config(GorillaConfig {
+            wal_dir: Some(wal_dir.clone()),
+            ..GorillaConfig::default()
+        })
+        .unwrap();
+        for i in 0..150 {
+            gorilla.insert("cpu", base_time + i * 60, i as f64);
+        }
+        gorilla.snapshot(&snapshot_path).unwrap();
+        for i in 150..300 {
+            gorilla.insert("cpu", base_time + i * 60, i as f64);
+        }
+        let expected = gorilla.query("cpu", 0, u64::MAX).unwrap();
+        drop(gorilla);
+
+        // Both the snapshot's blocks and the replayed points reach the
+        // chunk files
+        let config = GorillaConfig {
+            wal_dir: Some(wal_dir.clone()),
+            chunks: Some(ChunkConfig {
+                dir: chunk_dir.clone(),
+                flush_bytes: 16,
+            }),
+            ..GorillaConfig::default()
+        };
+        let (recovered, _) = Gorilla::recover_with(config.clone(), Some(&snapshot_path)).unwrap();
+        assert_eq!(recovered.query("cpu", 0, u64::MAX).unwrap(), expected);
+        drop(recovered);
+        let (from_chunks, _) = Gorilla::recover_chunks(config).unwrap();
+        let points = from_chunks.query("cpu", 0, u64::MAX).unwrap();
+        assert!(points.len() > 240 && points.len() <= 300);
+        assert_eq!(points, expected[..points.len()]);
+
+        std::fs::remove_dir_all(&wal_dir).unwrap();
+        std::fs::remove_dir_all(&chunk_dir).unwrap();
+        std::fs::remove_file(&snapshot_path).unwrap();
+    }
+
+    #[test]
+    fn test_for_each_block() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        for i in 0..300 {
+            gorilla.insert("cpu", base_time + i * 60, i as f64);
+        }
+        gorilla.insert("mem", base_time, 1.0);
+
+        let mut blocks: BTreeMap<String, Vec<(u64, usize)>> = BTreeMap::new();
+        let mut exported = 0;
+        gorilla.for_each_block(|key, block| {
+            let points = block.decode().unwrap();
+            assert_eq!(points.len(), block.len());
+            assert!(points.iter().all(|p| p.timestamp >= block.start_time));
+            exported += points.len();
+            blocks
+                .entry(key.to_string())
+                .or_default()
+                .push((block.start_time, block.len()));
+        });
+        assert_eq!(exported, 301);
+
+        for (key, blocks) in &blocks {
+            let starts: Vec<u64> = gorilla
+                .block_boundaries(key)
+                .iter()
+                .map(|&(start, _, _)| start)
+                .collect();
+            assert_eq!(blocks.iter().map(|b| b.0).collect::<Vec<_>>(), starts);
+        }
+        assert_eq!(
+            blocks["cpu"],
+            [
+                (base_time, 120),
+                (base_time + 7200, 120),
+                (base_time + 14400, 60)
+            ]
+        );
+        assert_eq!(blocks["mem"].len(), 1);
+    }
+
+    #[test]
+    fn test_encode_range_streams_back() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        for i in 0..300 {
+            gorilla.insert("cpu", base_time + i * 60, (i % 9) as f64 * 1.5);
+        }
+
+        let bytes = gorilla
+            .encode_range("cpu", base_time, base_time + 9000)
+            .unwrap();
+        let expected = gorilla.query("cpu", base_time, base_time + 9000).unwrap();
+        let streamed: Vec<(u64, f64)> = stream::GorillaStreamIter::new(&bytes).unwrap().collect();
+        assert_eq!(streamed, expected);
+        assert_eq!(stream::decode_range(&bytes).unwrap(), expected);
+        assert!(gorilla.encode_range("missing", 0, u64::MAX).is_none());
+    }
+
+    #[test]
+    fn test_query_compressed() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        for i in 0..300 {
+            gorilla.insert("cpu", base_time + i * 60 + i % 4, (i % 11) as f64 * 0.25);
+        }
+
+        for (start, end) in [
+            (0, u64::MAX),
+            (base_time + 3000, base_time + 9000),
+            (0, base_time - 1),
+        ] {
+            let bytes = gorilla.query_compressed("cpu", start, end).unwrap();
+            assert_eq!(
+                Some(&bytes),
+                gorilla.encode_range("cpu", start, end).as_ref()
+            );
+            let decoded: Vec<(u64, f64)> =
+                stream::GorillaStreamIter::new(&bytes).unwrap().collect();
+            assert_eq!(decoded, gorilla.query("cpu", start, end).unwrap());
+        }
+        assert!(gorilla.query_compressed("missing", 0, u64::MAX).is_none());
+    }
+
+    #[test]
+    fn test_expire_idle_series() {
+        let clock = Arc::new(TestClock::new(7200 * 100));
+        let config = GorillaConfig {
+            clock: clock.clone(),
+            // Points run ahead of the clock, which is advanced by hand
+            future_tolerance: None,
+            ..GorillaConfig::default()
+        };
+        let mut gorilla = Gorilla::with_config(config).unwrap();
+        let base_time = 7200 * 100;
+
+        for key in ["ci.job1", "ci.job2", "pod.active", "pod.pinned"] {
+            for i in 0..50 {
+                gorilla.insert(key, base_time + i * 60, i as f64);
+            }
+        }
+        gorilla
+            .set_meta(
+                "pod.pinned",
+                SeriesMeta {
+                    pinned: true,
+                    ..SeriesMeta::default()
+                },
+            )
+            .unwrap();
+
+        // An hour later only one series is still written to
+        clock.advance(3600);
+        gorilla.insert("pod.active", base_time + 3600, 1.0);
+        let before = gorilla.memory_usage();
+
+        // Not idle long enough yet
+        assert!(gorilla.expire_idle(clock.now(), 3600).is_empty());
+
+        let mut archived = Vec::new();
+        let expired = gorilla.expire_idle_with(clock.now(), 1800, |key, points| {
+            archived.push((key.to_string(), points.len()));
+        });
+        assert_eq!(expired, vec!["ci.job1", "ci.job2"]);
+        assert_eq!(
+            archived,
+            vec![("ci.job1".to_string(), 50), ("ci.job2".to_string(), 50)]
+        );
+
+        assert!(gorilla.query("ci.job1", 0, u64::MAX).is_none());
+        assert!(gorilla.query("pod.active", 0, u64::MAX).is_some());
+        assert!(gorilla.query("pod.pinned", 0, u64::MAX).is_some());
+        assert!(gorilla.memory_usage().total() < before.total());
+
+        // The pinned flag survives a snapshot
+        let path = temp_path("expire_idle.snap");
+        gorilla.snapshot(&path).unwrap();
+        let loaded = Gorilla::load(&path).unwrap();
+        assert!(loaded.get_meta("pod.pinned").unwrap().pinned);
+        assert!(!loaded.get_meta("pod.active").unwrap().pinned);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_future_timestamps_rejected() {
+        let now = 7200 * 100;
+        let clock = Arc::new(TestClock::new(now));
+        let gorilla = Gorilla::with_config(GorillaConfig {
+            clock: clock.clone(),
+            ..GorillaConfig::default()
+        })
+        .unwrap();
+
+        gorilla.insert("cpu", now - 60, 1.0);
+        let blocks = gorilla.block_boundaries("cpu");
+
+        // A day ahead is refused and leaves the open block alone
+        let max_allowed = now + DEFAULT_FUTURE_TOLERANCE_SECS;
+        assert_eq!(
+            gorilla.try_insert("cpu", now + 86400, 2.0),
+            Err(InsertError::TimestampTooFarInFuture {
+                ts: now + 86400,
+                max_allowed,
+            })
+        );
+        assert_eq!(gorilla.block_boundaries("cpu"), blocks);
+        assert!(gorilla.try_insert("mem", now + 86400, 2.0).is_err());
+        assert!(gorilla.query("mem", 0, u64::MAX).is_none());
+
+        // Up to the tolerance is fine
+        gorilla.try_insert("cpu", now, 3.0).unwrap();
+        gorilla.try_insert("cpu", max_allowed, 4.0).unwrap();
+        assert_eq!(
+            gorilla.query("cpu", 0, u64::MAX).unwrap(),
+            vec![(now - 60, 1.0), (now, 3.0), (max_allowed, 4.0)]
+        );
+
+        // Imports skip the check
+        gorilla.import("backfill", now + 86400, 5.0).unwrap();
+        assert_eq!(
+            gorilla.query("backfill", 0, u64::MAX).unwrap(),
+            vec![(now + 86400, 5.0)]
+        );
+    }
+
+    #[test]
+    fn test_memory_cap_evicts_least_recently_queried() {
+        let clock = Arc::new(TestClock::new(7200 * 100));
+        let config = GorillaConfig {
+            clock: clock.clone(),
+            ..GorillaConfig::default()
+        };
+        let fill = |gorilla: &Gorilla, key: &str| {
+            clock.advance(10);
+            for i in 0..100 {
+                gorilla.insert(key, clock.now() - 1000 + i * 10, (i % 13) as f64);
+            }
+        };
+
+        // Room for four series, with some slack
+        let uncapped = Gorilla::with_config(config.clone()).unwrap();
+        for key in ["s0", "s1", "s2", "s3"] {
+            fill(&uncapped, key);
+        }
+        let one = uncapped.heaviest_series(1)[0].1.total();
+        let cap = uncapped.memory_usage().total() + one / 2;
+
+        let mut gorilla = Gorilla::with_config(GorillaConfig {
+            max_memory_bytes: Some(cap),
+            ..config
+        })
+        .unwrap();
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let sink = evicted.clone();
+        gorilla.on_evict(move |key| sink.lock().unwrap().push(key.to_string()));
+
+        for key in ["s0", "s1", "s2", "s3"] {
+            fill(&gorilla, key);
+        }
+        assert!(evicted.lock().unwrap().is_empty());
+
+        // Querying s0 makes s1 the least recently accessed
+        clock.advance(10);
+        assert!(gorilla.query("s0", 0, u64::MAX).is_some());
+        fill(&gorilla, "s4");
+        fill(&gorilla, "s5");
+        assert_eq!(*evicted.lock().unwrap(), vec!["s1", "s2"]);
+        assert!(gorilla.contains("s0"));
+        assert!(!gorilla.contains("s1"));
+
+        for i in 6..20 {
+            fill(&gorilla, &format!("s{}", i));
+            assert!(gorilla.memory_usage().total() <= cap);
+        }
+        let evicted = evicted.lock().unwrap().len();
+        assert_eq!(evicted + gorilla.tsmap.series_count(), 20);
+        assert_eq!(gorilla.metrics().series_evicted, evicted as u64);
+    }
+
+    #[test]
+    fn test_freeze_series() {
+        let clock = Arc::new(TestClock::new(7200 * 100 + 3600));
+        let mut gorilla = Gorilla::with_config(GorillaConfig {
+            clock: clock.clone(),
+            ..GorillaConfig::default()
+        })
+        .unwrap();
+        let base_time = 7200 * 100;
+        for i in 0..10 {
+            gorilla.insert("disk", base_time + i * 60, i as f64);
+        }
+        gorilla.insert("scratch", base_time, 1.0);
+
+        assert_eq!(
+            gorilla.freeze("missing"),
+            Err(TsdbError::SeriesNotFound("missing".to_string()))
+        );
+        gorilla.freeze("disk").unwrap();
+
+        // Sealed: the window's points sit in a closed block
+        assert_eq!(
+            gorilla.block_boundaries("disk"),
+            vec![(base_time, base_time + 7200, false)]
+        );
+        assert_eq!(
+            gorilla.try_insert("disk", base_time + 600, 10.0),
+            Err(InsertError::SeriesFrozen)
+        );
+        assert_eq!(
+            gorilla.merge_series("scratch", "disk"),
+            Err(TsdbError::Frozen("disk".to_string()))
+        );
+        assert_eq!(gorilla.expire_idle(clock.now() + 7200, 60), vec!["scratch"]);
+
+        // Reads are unaffected, and the flag survives a snapshot
+        assert_eq!(gorilla.query("disk", 0, u64::MAX).unwrap().len(), 10);
+        assert!(gorilla.get_stats("disk").compressed_size > 0);
+        let path = temp_path("freeze.snap");
+        gorilla.snapshot(&path).unwrap();
+        let loaded = Gorilla::load(&path).unwrap();
+        assert_eq!(
+            loaded.try_insert("disk", base_time + 600, 10.0),
+            Err(InsertError::SeriesFrozen)
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        // Writes resume in a new block after the sealed one
+        gorilla.unfreeze("disk").unwrap();
+        gorilla.try_insert("disk", base_time + 600, 10.0).unwrap();
+        assert_eq!(
+            gorilla.block_boundaries("disk"),
+            vec![
+                (base_time, base_time + 541, false),
+                (base_time + 541, base_time + 7200, true),
+            ]
+        );
+        assert_eq!(gorilla.query("disk", 0, u64::MAX).unwrap().len(), 11);
+    }
+
+    #[test]
+    fn test_freeze_is_logged() {
+        let dir = temp_wal_dir("wal_freeze");
+        let config = GorillaConfig {
+            wal_dir: Some(dir.clone()),
+            ..GorillaConfig::default()
+        };
+        let mut gorilla = Gorilla::with_config(config).unwrap();
+        gorilla.insert("a", 7200 * 100, 1.0);
+        gorilla.insert("b", 7200 * 100, 1.0);
+        gorilla.freeze("a").unwrap();
+        gorilla.freeze("b").unwrap();
+        gorilla.unfreeze("b").unwrap();
+        gorilla.insert("b", 7200 * 100 + 60, 2.0);
+        drop(gorilla);
+
+        let (recovered, _) = Gorilla::recover(&dir).unwrap();
+        assert_eq!(
+            recovered.try_insert("a", 7200 * 100 + 60, 2.0),
+            Err(InsertError::SeriesFrozen)
+        );
+        assert_eq!(recovered.query("b", 0, u64::MAX).unwrap().len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_align_origin() {
+        // Days starting at midnight UTC+5 (19:00 UTC the day before)
+        let origin = 86400 * 19000 - 5 * 3600;
+        let options = SeriesOptions {
+            block_duration: Some(86400),
+            align_origin: origin,
+            downsample: vec![DownsampleTier::new(86400, Aggregation::Count)],
+            ..SeriesOptions::default()
+        };
+        let gorilla = Gorilla::new();
+        gorilla.create_series("sales", options).unwrap();
+        let start = origin - 86400 + 3600;
+        for i in 0..96 {
+            gorilla.insert("sales", start + i * 3600, 1.0);
+        }
+
+        let boundaries = gorilla.block_boundaries("sales");
+        let starts: Vec<u64> = boundaries.iter().map(|&(start, _, _)| start).collect();
+        assert_eq!(
+            starts,
+            vec![
+                origin - 86400,
+                origin,
+                origin + 86400,
+                origin + 2 * 86400,
+                origin + 3 * 86400
+            ]
+        );
+        assert!(
+            boundaries
+                .iter()
+                .all(|&(start, end, _)| end - start == 86400)
+        );
+
+        // Daily rollups count the points of each local day
+        assert_eq!(
+            gorilla.query("sales:86400s:count", 0, u64::MAX).unwrap(),
+            vec![
+                (origin - 86400, 23.0),
+                (origin, 24.0),
+                (origin + 86400, 24.0),
+                (origin + 2 * 86400, 24.0)
+            ]
+        );
+
+        // The origin is kept in snapshots
+        let path = temp_path("align_origin.snap");
+        gorilla.snapshot(&path).unwrap();
+        let loaded = Gorilla::load(&path).unwrap();
+        loaded.insert("sales", start + 96 * 3600, 1.0);
+        assert_eq!(loaded.block_boundaries("sales").len(), 5);
+        loaded.insert("sales", origin + 4 * 86400, 1.0);
+        assert_eq!(
+            loaded.block_boundaries("sales").last().unwrap().0,
+            origin + 4 * 86400
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_enforce_tiers() {
+        let base_time = 7200 * 100;
+        let now = base_time + 3 * 86400;
+        let clock = Arc::new(TestClock::new(base_time));
+        let gorilla = Gorilla::with_config(GorillaConfig {
+            clock: clock.clone(),
+            future_tolerance: None,
+            ..GorillaConfig::default()
+        })
+        .unwrap();
+        // Raw for 2h, 5m averages for a day, hourly ones for good
+        let options = SeriesOptions {
+            retention: Some(7200),
+            downsample: vec![
+                DownsampleTier::new(3600, Aggregation::Avg),
+                DownsampleTier::new(300, Aggregation::Avg).with_retention(86400),
+            ],
+            ..SeriesOptions::default()
+        };
+        gorilla.create_series("cpu", options.clone()).unwrap();
+        // Three days of minutely points
+        for i in 0..3 * 1440 {
+            gorilla.insert("cpu", base_time + i * 60, i as f64);
+        }
+        clock.set(now);
+        // Until raw blocks are dropped, raw data answers every range
+        assert_eq!(gorilla.query("cpu", 0, u64::MAX).unwrap().len(), 3 * 1440);
+
+        // 35 of 36 raw blocks, and 24 of the 5m companion's 36
+        assert_eq!(gorilla.enforce_tiers(now), 35 + 24);
+        assert_eq!(gorilla.block_boundaries("cpu").len(), 1);
+        assert_eq!(gorilla.block_boundaries("cpu:300s:avg").len(), 12);
+        assert_eq!(gorilla.block_boundaries("cpu:3600s:avg").len(), 36);
+        assert_eq!(gorilla.enforce_tiers(now), 0);
+
+        // The last hour is still raw
+        let recent = gorilla.query("cpu", now - 3600, now).unwrap();
+        assert_eq!(recent.len(), 60);
+        assert_eq!(recent[0], (now - 3600, (3 * 1440 - 60) as f64));
+
+        // Further back than raw is kept: 5m averages up to the oldest
+        // raw block, then its raw points
+        let held_from = base_time + 35 * 7200;
+        let day = gorilla.query("cpu", now - 20000, now).unwrap();
+        assert_eq!(day.len(), 42 + 120);
+        assert_eq!(day[0], (base_time + 239400, 3992.0));
+        assert_eq!(day[41], (held_from - 300, 4197.0));
+        assert_eq!(day[42], (held_from, 4200.0));
+        assert_eq!(
+            Some(day[..42].to_vec()),
+            gorilla.query("cpu:300s:avg", now - 20000, held_from - 1)
+        );
+        assert_eq!(
+            Some(day[42..].to_vec()),
+            gorilla.query("cpu", held_from, now)
+        );
+
+        // Further back than a day: hourly averages, then raw points,
+        // including those written since
+        gorilla.insert("cpu", now, 1.0);
+        let week = gorilla.query("cpu", now - 2 * 86400, now).unwrap();
+        assert_eq!(week.len(), 46 + 121);
+        assert_eq!(week[0], (base_time + 86400, 1469.5));
+        assert_eq!(week[45], (held_from - 3600, 4169.5));
+        assert_eq!(week[46], (held_from, 4200.0));
+        assert_eq!(week.last(), Some(&(now, 1.0)));
+        assert_eq!(gorilla.query("cpu", 0, u64::MAX).unwrap().len(), 70 + 121);
+        // Encoding stays on raw data
+        let encoded = gorilla.encode_range("cpu", 0, u64::MAX).unwrap();
+        assert_eq!(stream::decode_range(&encoded).unwrap().len(), 121);
+
+        // The retentions are kept in snapshots
+        let path = temp_path("enforce_tiers.snap");
+        gorilla.snapshot(&path).unwrap();
+        let loaded = Gorilla::load(&path).unwrap();
+        assert_eq!(loaded.tsmap.get("cpu").unwrap().read().options(), &options);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_downsample_tiers() {
+        let base_time = 7200 * 100;
+        let config = GorillaConfig {
+            clock: Arc::new(TestClock::new(base_time + 86400 + 60)),
+            series_options: SeriesOptions {
+                downsample: vec![
+                    DownsampleTier::new(300, Aggregation::Avg),
+                    DownsampleTier::new(3600, Aggregation::Max),
+                ],
+                ..SeriesOptions::default()
+            },
+            ..GorillaConfig::default()
+        };
+        let gorilla = Gorilla::with_config(config).unwrap();
+
+        // First raw block: nothing rolls up until it closes
+        for i in 0..720 {
+            gorilla.insert("cpu", base_time + i * 10, i as f64);
+        }
+        assert!(!gorilla.contains("cpu:300s:avg"));
+        gorilla.insert("cpu", base_time + 7200, 720.0);
+
+        let avg = gorilla.query("cpu:300s:avg", 0, u64::MAX).unwrap();
+        assert_eq!(avg.len(), 24);
+        assert_eq!(avg[0], (base_time, 14.5));
+        assert_eq!(avg[23], (base_time + 23 * 300, 23.0 * 30.0 + 14.5));
+        assert_eq!(
+            gorilla.query("cpu:3600s:max", 0, u64::MAX).unwrap(),
+            vec![(base_time, 359.0), (base_time + 3600, 719.0)]
+        );
+
+        // A day of data: the long-range view is 30x smaller than raw
+        for i in 721..8640 {
+            gorilla.insert("cpu", base_time + i * 10, i as f64);
+        }
+        gorilla.insert("cpu", base_time + 86400, 8640.0);
+        let raw = gorilla.query("cpu", base_time, base_time + 86399).unwrap();
+        let avg = gorilla.query("cpu:300s:avg", base_time, base_time + 86399);
+        assert_eq!(raw.len(), 8640);
+        assert_eq!(avg.unwrap().len(), 288);
+        assert_eq!(
+            gorilla.query("cpu:3600s:max", 0, u64::MAX).unwrap().len(),
+            24
+        );
+
+        // Companions are ordinary series, but are never downsampled again
+        let companion = gorilla.tsmap.get("cpu:300s:avg").unwrap();
+        assert!(companion.read().options().downsample.is_empty());
+        assert!(!gorilla.contains("cpu:300s:avg:300s:avg"));
+        assert_eq!(gorilla.tsmap.series_count(), 3);
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_query_desc() {
+        let gorilla = Gorilla::with_series_options(SeriesOptions {
+            drop_raw_on_close: true,
+            ..SeriesOptions::default()
+        });
+        let base_time = 7200 * 100;
+        for i in 0..480 {
+            gorilla.insert("cpu", base_time + i * 60, i as f64);
+        }
+        assert_eq!(gorilla.block_boundaries("cpu").len(), 4);
+
+        let mut ascending = gorilla.query("cpu", 0, u64::MAX).unwrap();
+        ascending.reverse();
+        assert_eq!(
+            gorilla.query_desc("cpu", 0, u64::MAX, None),
+            Some(ascending)
+        );
+
+        // The last five points come from the newest block alone
+        let series = gorilla.tsmap.get("cpu").unwrap();
+        let before = series.read().blocks_read();
+        let tail = gorilla.query_desc("cpu", 0, u64::MAX, Some(5)).unwrap();
+        assert_eq!(
+            tail,
+            (475..480)
+                .rev()
+                .map(|i| (base_time + i * 60, i as f64))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(series.read().blocks_read() - before, 1);
+        assert!(
+            gorilla
+                .query_desc("missing", 0, u64::MAX, Some(5))
+                .is_none()
+        );
+    }
 
     #[test]
-    fn test_basic_operations() {
-        let mut gorilla = Gorilla::new();
+    fn test_delta_timestamp_codec() {
+        let gorilla = Gorilla::with_series_options(SeriesOptions {
+            timestamp_codec: TimestampCodec::Delta,
+            ..SeriesOptions::default()
+        });
+        let base_time = 7200 * 100;
+        for i in 0..300 {
+            // Jittery intervals, across three blocks
+            gorilla.insert("cpu", base_time + i * 60 + (i % 5) * 7, i as f64);
+        }
+        let expected = gorilla.query("cpu", 0, u64::MAX).unwrap();
+        assert_eq!(expected.len(), 300);
 
-        // Use current time to ensure we're within a valid block
-        let base_time = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        // Blocks keep decoding with the codec they were written with
+        let path = temp_path("codec.snap");
+        gorilla.snapshot(&path).unwrap();
+        let loaded = Gorilla::load(&path).unwrap();
+        assert_eq!(loaded.query("cpu", 0, u64::MAX).unwrap(), expected);
+        let series = loaded.tsmap.get("cpu").unwrap();
+        assert_eq!(
+            series.read().options().timestamp_codec,
+            TimestampCodec::Delta
+        );
 
-        // Insert some data
-        gorilla.insert("cpu.usage", base_time, 45.2);
-        gorilla.insert("cpu.usage", base_time + 60, 46.1);
-        gorilla.insert("cpu.usage", base_time + 120, 45.8);
+        std::fs::remove_file(&path).unwrap();
+    }
 
-        // Query it back
-        let results = gorilla
-            .query("cpu.usage", base_time, base_time + 200)
+    #[test]
+    fn test_try_query_ranges() {
+        let gorilla = Gorilla::with_config(GorillaConfig {
+            future_tolerance: None,
+            ..GorillaConfig::default()
+        })
+        .unwrap();
+        let base_time = 7200 * 100;
+        for i in 0..10 {
+            gorilla.insert("cpu", base_time + i * 60, i as f64);
+        }
+
+        // Inverted ranges are an error, reported before the key
+        let inverted = Err(QueryError::InvalidRange {
+            start: 500,
+            end: 100,
+        });
+        assert_eq!(gorilla.try_query("cpu", 500, 100), inverted);
+        assert_eq!(gorilla.try_query("missing", 500, 100), inverted);
+        assert_eq!(
+            gorilla.try_query("missing", 0, 100),
+            Err(QueryError::SeriesNotFound("missing".to_string()))
+        );
+
+        // query keeps treating them as empty
+        assert_eq!(gorilla.query("cpu", 500, 100), Some(Vec::new()));
+        assert_eq!(gorilla.query("missing", 500, 100), None);
+
+        // start == end is a one-timestamp range
+        let single = base_time + 120;
+        assert_eq!(
+            gorilla.try_query("cpu", single, single),
+            Ok(vec![(single, 2.0)])
+        );
+        assert_eq!(
+            gorilla.try_query("cpu", single + 1, single + 1),
+            Ok(Vec::new())
+        );
+
+        // Ranges (and blocks) reaching u64::MAX don't overflow
+        assert_eq!(gorilla.try_query("cpu", 0, u64::MAX).unwrap().len(), 10);
+        for offset in [10, 5, 0] {
+            gorilla.insert("edge", u64::MAX - offset, offset as f64);
+        }
+        assert_eq!(
+            gorilla.try_query("edge", u64::MAX - 7, u64::MAX),
+            Ok(vec![(u64::MAX - 5, 5.0), (u64::MAX, 0.0)])
+        );
+        assert_eq!(
+            gorilla.try_query("edge", u64::MAX, u64::MAX).unwrap().len(),
+            1
+        );
+        assert_eq!(gorilla.close_stale_blocks(u64::MAX), 2, "both series");
+        assert_eq!(gorilla.query("edge", 0, u64::MAX).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_create_series_with_own_options() {
+        let base_time = 86400 * 10;
+        let gorilla = Gorilla::with_config(GorillaConfig {
+            clock: Arc::new(TestClock::new(base_time + 86400 * 2)),
+            ..GorillaConfig::default()
+        })
+        .unwrap();
+        let probe = SeriesOptions {
+            block_duration: Some(600),
+            duplicate_policy: DuplicatePolicy::Reject,
+            timestamp_codec: TimestampCodec::Delta,
+            ..SeriesOptions::default()
+        };
+        let billing = SeriesOptions {
+            block_duration: Some(86400),
+            duplicate_policy: DuplicatePolicy::KeepFirst,
+            value_hint: ValueHint::IntegerSmall,
+            late_grace: Some(120),
+            ..SeriesOptions::default()
+        };
+        gorilla.create_series("probe", probe.clone()).unwrap();
+        gorilla.create_series("billing", billing.clone()).unwrap();
+
+        // A day of points at the same instants in every series
+        for i in 0..48 {
+            let timestamp = base_time + i * 1800;
+            for key in ["probe", "billing", "plain"] {
+                gorilla.insert(key, timestamp, i as f64);
+            }
+        }
+        let windows = |key: &str| gorilla.block_boundaries(key).len();
+        assert_eq!(windows("probe"), 48, "one 10-minute block per point");
+        assert_eq!(windows("billing"), 1, "one day-long block");
+        assert_eq!(windows("plain"), 12, "two-hour default");
+
+        // Each series applies its own duplicate policy
+        assert_eq!(
+            gorilla.try_insert("probe", base_time, -1.0),
+            Err(InsertError::DuplicateRejected {
+                timestamp: base_time
+            })
+        );
+        gorilla.insert("billing", base_time, -1.0);
+        gorilla.insert("plain", base_time, -1.0);
+        let first = |key: &str| gorilla.query(key, base_time, base_time).unwrap()[0].1;
+        assert_eq!(
+            (first("probe"), first("billing"), first("plain")),
+            (0.0, 0.0, -1.0)
+        );
+
+        // Creating again is fine with the same options only
+        gorilla.create_series("probe", probe.clone()).unwrap();
+        assert_eq!(
+            gorilla.create_series("probe", billing.clone()),
+            Err(TsdbError::SeriesExists("probe".to_string()))
+        );
+        assert!(
+            gorilla
+                .create_series("plain", SeriesOptions::default())
+                .is_ok()
+        );
+
+        // Snapshots keep the options, block duration included
+        let path = temp_path("create_series.snap");
+        gorilla.snapshot(&path).unwrap();
+        let loaded = Gorilla::load(&path).unwrap();
+        loaded.create_series("probe", probe).unwrap();
+        loaded.create_series("billing", billing).unwrap();
+        assert_eq!(loaded.block_boundaries("probe").len(), 48);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_insert_with_codec() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        assert_eq!(
+            gorilla.insert_with_codec("fresh", base_time, 0.0, CodecChoice::Auto),
+            Ok(InsertOutcome::CreatedSeries)
+        );
+        for i in 0..200 {
+            let timestamp = base_time + i * 60;
+            gorilla
+                .insert_with_codec("requests", timestamp, (i * 3) as f64, CodecChoice::Auto)
+                .unwrap();
+            gorilla
+                .insert_with_codec("cpu", timestamp, i as f64 * 0.37, CodecChoice::Auto)
+                .unwrap();
+        }
+        // A later choice doesn't change an existing series
+        gorilla
+            .insert_with_codec(
+                "cpu",
+                base_time + 200 * 60,
+                1.0,
+                CodecChoice::Fixed(ValueCodec::Raw),
+            )
             .unwrap();
+
+        let codecs = |gorilla: &Gorilla, key: &str| -> Vec<ValueCodec> {
+            let series = gorilla.tsmap.get(key).unwrap();
+            let series = series.read();
+            series
+                .blocks()
+                .map(|block| block.encoding().value_codec)
+                .collect()
+        };
         assert_eq!(
-            results.len(),
-            3,
-            "Expected 3 results, got {}",
-            results.len()
+            codecs(&gorilla, "requests"),
+            vec![ValueCodec::IntegerDelta; 2]
         );
-        assert_eq!(results[0].1, 45.2);
+        assert_eq!(codecs(&gorilla, "cpu"), vec![ValueCodec::Xor; 2]);
 
-        // Check compression
-        let stats = gorilla.get_stats("cpu.usage");
-        println!("Compression: {}x", stats.compression_ratio);
-        assert!(stats.compression_ratio > 1.0);
+        let requests = gorilla.query("requests", 0, u64::MAX).unwrap();
+        assert_eq!(requests.len(), 200);
+        assert!(
+            requests
+                .iter()
+                .all(|&(t, v)| v == ((t - base_time) / 60 * 3) as f64)
+        );
+        let cpu = gorilla.query("cpu", 0, u64::MAX).unwrap();
+        assert_eq!(cpu[199], (base_time + 199 * 60, 199.0 * 0.37));
 
-        // Test that key field is accessible
-        gorilla.scan(|key, _ts, _val| {
-            println!("Scanned series: {}", key);
+        // The codec is kept in snapshots, for the series and its blocks
+        let path = temp_path("insert_with_codec.snap");
+        gorilla.snapshot(&path).unwrap();
+        let loaded = Gorilla::load(&path).unwrap();
+        assert_eq!(
+            codecs(&loaded, "requests"),
+            vec![ValueCodec::IntegerDelta; 2]
+        );
+        assert_eq!(loaded.query("requests", 0, u64::MAX).unwrap(), requests);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_persist_by_day() {
+        let root = temp_path("persist_by_day");
+        let _ = std::fs::remove_dir_all(&root);
+        let gorilla = Gorilla::new();
+        let day = 86400 * 19000;
+        for i in 0..200 {
+            gorilla.insert("cpu", day + i * 900, i as f64);
+        }
+
+        let manifests = gorilla.persist_by_day(&root).unwrap();
+        assert_eq!(manifests.len(), 3);
+        assert_eq!(manifests.iter().map(|m| m.points).sum::<usize>(), 200);
+        let reopened = Gorilla::open_by_day(&root).unwrap();
+        assert_eq!(
+            reopened.query("cpu", 0, u64::MAX),
+            gorilla.query("cpu", 0, u64::MAX)
+        );
+
+        // Writes continue in the reopened open block
+        reopened.insert("cpu", day + 200 * 900, 200.0);
+        assert_eq!(reopened.query("cpu", 0, u64::MAX).unwrap().len(), 201);
+
+        // A ranged open reads only the days it overlaps
+        let second = Gorilla::open_by_day_range(&root, day + 86400, day + 90000).unwrap();
+        let points = second.query("cpu", 0, u64::MAX).unwrap();
+        assert_eq!(points.len(), 96);
+        assert_eq!(points[0], (day + 86400, 96.0));
+
+        // Pruning drops the days entirely before the horizon
+        let pruned = Gorilla::prune_days_before(&root, day + 2 * 86400).unwrap();
+        assert_eq!(pruned, [manifests[0].day.clone(), manifests[1].day.clone()]);
+        let last = Gorilla::open_by_day(&root).unwrap();
+        assert_eq!(last.query("cpu", 0, u64::MAX).unwrap().len(), 8);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_query_last() {
+        let now = 7200 * 100 + 3600;
+        let gorilla = Gorilla::with_config(GorillaConfig {
+            clock: Arc::new(TestClock::new(now)),
+            ..GorillaConfig::default()
+        })
+        .unwrap();
+
+        // One point a minute over the last hour
+        for i in 0..60 {
+            gorilla.insert("cpu", now - 3600 + i * 60, i as f64);
+        }
+        let recent = gorilla.query_last("cpu", 1800);
+        assert_eq!(recent.len(), 30);
+        assert_eq!(recent[0], (now - 1800, 30.0));
+        assert!(recent.iter().all(|&(ts, _)| ts >= now - 1800 && ts <= now));
+
+        assert_eq!(gorilla.query_last("cpu", 3600).len(), 60);
+        assert_eq!(gorilla.query_last("cpu", u64::MAX).len(), 60);
+        assert!(gorilla.query_last("cpu", 0).is_empty());
+        assert!(gorilla.query_last("missing", 3600).is_empty());
+    }
+
+    #[test]
+    fn test_query_open() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        for i in 0..240 {
+            gorilla.insert("cpu", base_time + i * 60, i as f64);
+        }
+
+        // Only the second window's points, from the open block alone
+        let series = gorilla.tsmap.get("cpu").unwrap();
+        let before = series.read().blocks_read();
+        let open = gorilla.query_open("cpu");
+        assert_eq!(series.read().blocks_read() - before, 1);
+        assert_eq!(open.len(), 120);
+        assert_eq!(open[0], (base_time + 7200, 120.0));
+        assert_eq!(
+            open,
+            gorilla.query("cpu", base_time + 7200, u64::MAX).unwrap()
+        );
+
+        assert!(gorilla.query_open("missing").is_empty());
+    }
+
+    #[test]
+    fn test_exclusive_end_windows() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+
+        // Points on every block and half-hour boundary over two blocks
+        for i in 0..=8 {
+            gorilla.insert("cpu", base_time + i * 1800, i as f64);
+        }
+        let exclusive = QueryOpts {
+            end_exclusive: true,
+            ..QueryOpts::default()
+        };
+
+        // Consecutive hourly windows partition the points exactly once
+        let mut seen = Vec::new();
+        for hour in 0..5 {
+            let start = base_time + hour * 3600;
+            let window = gorilla
+                .query_opts("cpu", start, start + 3600, exclusive)
+                .unwrap();
+            assert_eq!(window.len(), 2.min(9 - 2 * hour as usize));
+            assert!(
+                window
+                    .iter()
+                    .all(|&(ts, _)| ts >= start && ts < start + 3600)
+            );
+            seen.extend(window);
+        }
+        assert_eq!(seen, gorilla.query("cpu", 0, u64::MAX).unwrap());
+
+        // Inclusive windows share their boundary point, across blocks too
+        let boundary = base_time + 7200;
+        let inclusive = |start| {
+            gorilla
+                .query_opts("cpu", start, start + 3600, QueryOpts::default())
+                .unwrap()
+        };
+        assert_eq!(inclusive(boundary - 3600).last(), Some(&(boundary, 4.0)));
+        assert_eq!(inclusive(boundary).first(), Some(&(boundary, 4.0)));
+
+        // Exclusive ranges: [t, t) is empty, and the end can be 0
+        let opts = |end| gorilla.query_opts("cpu", boundary, end, exclusive).unwrap();
+        assert!(opts(boundary).is_empty());
+        assert_eq!(opts(boundary + 1), vec![(boundary, 4.0)]);
+        assert!(
+            gorilla
+                .query_opts("cpu", 0, 0, exclusive)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(gorilla.query_opts("missing", 0, 0, exclusive).is_none());
+    }
+
+    #[test]
+    fn test_query_pages() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        for i in 0..10_000 {
+            gorilla.insert("cpu", base_time + i * 60, i as f64);
+        }
+        let all = gorilla.query("cpu", 0, u64::MAX).unwrap();
+        let series = gorilla.tsmap.get("cpu").unwrap();
+        let total_blocks = gorilla.block_boundaries("cpu").len();
+
+        for order in [Order::Ascending, Order::Descending] {
+            let mut paged = Vec::new();
+            for page in 0..10 {
+                let opts = QueryOpts {
+                    limit: Some(1000),
+                    offset: page * 1000,
+                    order,
+                    ..QueryOpts::default()
+                };
+                let before = series.read().blocks_read();
+                let points = gorilla.query_opts("cpu", 0, u64::MAX, opts).unwrap();
+                assert_eq!(points.len(), 1000);
+                paged.extend(points);
+
+                // Reading stops once the page is full: blocks of 120 points
+                // up to the end of this page, not the whole range
+                let read = series.read().blocks_read() - before;
+                let needed = ((page + 1) * 1000).div_ceil(120) + 1;
+                assert!(read <= needed, "page {} read {} blocks", page, read);
+                assert!(page == 9 || read < total_blocks);
+            }
+            if order == Order::Descending {
+                paged.reverse();
+            }
+            assert_eq!(paged, all);
+        }
+
+        // Past the end, and an offset with no limit
+        let opts = QueryOpts {
+            offset: 10_000,
+            ..QueryOpts::default()
+        };
+        assert_eq!(gorilla.query_opts("cpu", 0, u64::MAX, opts), Some(vec![]));
+        let opts = QueryOpts {
+            offset: 9_998,
+            ..QueryOpts::default()
+        };
+        assert_eq!(
+            gorilla.query_opts("cpu", 0, u64::MAX, opts).unwrap(),
+            all[9_998..]
+        );
+    }
+
+    #[test]
+    fn test_global_stats() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        for i in 0..500u64 {
+            let timestamp = base_time + i * 60;
+            gorilla.insert("flat", timestamp, 1.0);
+            gorilla.insert("noisy", timestamp, (i as f64 * 0.37).sin() * 1000.0);
+            gorilla.insert("counter", timestamp, i as f64);
+        }
+
+        let ratios: Vec<f64> = ["flat", "noisy", "counter"]
+            .iter()
+            .map(|key| gorilla.get_stats(key).logical_compression_ratio)
+            .collect();
+        let lowest = ratios.iter().cloned().fold(f64::INFINITY, f64::min);
+        let highest = ratios.iter().cloned().fold(0.0, f64::max);
+        assert!(highest > lowest * 2.0);
+
+        let global = gorilla.global_stats();
+        assert_eq!(global.logical_uncompressed_bytes, 3 * 500 * 16);
+        assert!(
+            global.logical_compression_ratio > lowest && global.logical_compression_ratio < highest
+        );
+        assert_eq!(Gorilla::new().global_stats().logical_compression_ratio, 0.0);
+    }
+
+    #[test]
+    fn test_query_projections() {
+        let gorilla = Gorilla::with_series_options(SeriesOptions {
+            drop_raw_on_close: true,
+            ..SeriesOptions::default()
         });
+        let base_time = 7200 * 100;
+        for i in 0..400 {
+            gorilla.insert("cpu", base_time + i * 60 + i % 3, (i as f64 * 0.3).cos());
+        }
+        let (start, end) = (base_time + 3000, base_time + 20_000);
+        let full = gorilla.query("cpu", start, end).unwrap();
+        let series = gorilla.tsmap.get("cpu").unwrap();
+
+        // Timestamps come from the closed blocks without decoding values
+        let decodes = series.read().value_decodes();
+        let timestamps = gorilla.query_timestamps("cpu", start, end).unwrap();
+        assert_eq!(series.read().value_decodes(), decodes);
+        assert_eq!(
+            timestamps,
+            full.iter().map(|&(ts, _)| ts).collect::<Vec<_>>()
+        );
+
+        let values = gorilla.query_values("cpu", start, end).unwrap();
+        assert!(series.read().value_decodes() > decodes);
+        assert_eq!(values, full.iter().map(|&(_, v)| v).collect::<Vec<_>>());
+
+        assert!(gorilla.query_timestamps("missing", 0, u64::MAX).is_none());
+        assert!(gorilla.query_values("missing", 0, u64::MAX).is_none());
+
+        // query_points is query without the conversion to tuples
+        let points = gorilla.query_points("cpu", start, end).unwrap();
+        assert!(points.is_sorted());
+        assert_eq!(
+            points,
+            full.iter().map(|&p| DataPoint::from(p)).collect::<Vec<_>>()
+        );
+        assert!(gorilla.query_points("missing", 0, u64::MAX).is_none());
     }
 
     #[test]
-    fn test_compression_efficiency() {
+    fn test_insert_batch() {
+        let dir = temp_wal_dir("wal_insert_batch");
+        let config = GorillaConfig {
+            wal_dir: Some(dir.clone()),
+            ..GorillaConfig::default()
+        };
+        let base_time = 7200 * 100;
+        {
+            let mut gorilla = Gorilla::with_config(config).unwrap();
+            let mut points: Vec<(u64, f64)> =
+                (0..300).map(|i| (base_time + i * 60, i as f64)).collect();
+            points.push((base_time + 5, f64::NAN));
+            points.push((u64::MAX, 1.0));
+            assert_eq!(
+                gorilla.insert_batch("cpu", &points),
+                Ok(BatchOutcome {
+                    inserted: 300,
+                    skipped: 2
+                })
+            );
+            assert_eq!(
+                gorilla.insert_batch("cpu", &[]),
+                Ok(BatchOutcome::default())
+            );
+
+            let metrics = gorilla.metrics();
+            assert_eq!(metrics.points_inserted, 300);
+            assert_eq!(metrics.inserts_rejected, 2);
+            assert_eq!(metrics.blocks_closed, 2);
+            assert_eq!(gorilla.query("cpu", 0, u64::MAX).unwrap(), points[..300]);
+
+            gorilla.freeze("cpu").unwrap();
+            assert_eq!(
+                gorilla.insert_batch("cpu", &points[..10]),
+                Err(InsertError::SeriesFrozen)
+            );
+            assert_eq!(gorilla.metrics().inserts_rejected, 12);
+        }
+
+        // Batched points were logged like single inserts
+        let (recovered, _) = Gorilla::recover(&dir).unwrap();
+        assert_eq!(recovered.query("cpu", 0, u64::MAX).unwrap().len(), 300);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_insert_batch_matches_single_inserts() {
+        let base_time = 7200 * 100;
+        // Out of order, across windows, with repeated timestamps
+        let points: Vec<(u64, f64)> = (0..500u64)
+            .map(|i| (base_time + (i * 7919 % 400) * 45, i as f64))
+            .collect();
+
+        for policy in [
+            DuplicatePolicy::KeepLast,
+            DuplicatePolicy::KeepFirst,
+            DuplicatePolicy::Reject,
+        ] {
+            let options = SeriesOptions {
+                duplicate_policy: policy,
+                max_points_per_block: Some(64),
+                ..SeriesOptions::default()
+            };
+            let single = Gorilla::new();
+            single.create_series("cpu", options.clone()).unwrap();
+            let stored = points
+                .iter()
+                .filter(|&&(ts, value)| {
+                    matches!(
+                        single.try_insert("cpu", ts, value),
+                        Ok(InsertOutcome::Inserted | InsertOutcome::ReplacedDuplicate)
+                    )
+                })
+                .count();
+            let batch = Gorilla::new();
+            batch.create_series("cpu", options).unwrap();
+            let outcome = batch.insert_batch("cpu", &points).unwrap();
+
+            assert_eq!(outcome.inserted, stored, "{:?}", policy);
+            assert_eq!(outcome.skipped, points.len() - stored, "{:?}", policy);
+            assert_eq!(
+                batch.query("cpu", 0, u64::MAX),
+                single.query("cpu", 0, u64::MAX),
+                "{:?}",
+                policy
+            );
+            let (batch, single) = (batch.metrics(), single.metrics());
+            assert_eq!(batch.points_inserted, single.points_inserted);
+            assert_eq!(batch.inserts_rejected, single.inserts_rejected);
+        }
+
+        // In timestamp order the blocks come out the same too
+        let mut sorted = points.clone();
+        sorted.sort_by_key(|&(ts, _)| ts);
+        sorted.dedup_by_key(|&mut (ts, _)| ts);
+        let single = Gorilla::new();
+        sorted
+            .iter()
+            .for_each(|&(ts, value)| single.insert("cpu", ts, value));
+        let batch = Gorilla::new();
+        assert_eq!(
+            batch.insert_batch("cpu", &sorted),
+            Ok(BatchOutcome {
+                inserted: sorted.len(),
+                skipped: 0
+            })
+        );
+        assert_eq!(
+            batch.block_boundaries("cpu"),
+            single.block_boundaries("cpu")
+        );
+        assert_eq!(
+            batch.get_stats("cpu").compressed_size,
+            single.get_stats("cpu").compressed_size
+        );
+        assert_eq!(
+            batch.metrics().bytes_compressed,
+            single.metrics().bytes_compressed
+        );
+    }
+
+    #[test]
+    fn test_insert_batch_speedup() {
+        let base_time = 7200 * 100;
+        let points: Vec<(u64, f64)> = (0..10_000)
+            .map(|i| (base_time + i * 10, (i % 100) as f64 * 0.5))
+            .collect();
+
+        let single = Gorilla::new();
+        let started = std::time::Instant::now();
+        for &(ts, value) in &points {
+            single.insert("cpu", ts, value);
+        }
+        let single_time = started.elapsed();
+
+        let batch = Gorilla::new();
+        let started = std::time::Instant::now();
+        let outcome = batch.insert_batch("cpu", &points).unwrap();
+        let batch_time = started.elapsed();
+
+        // Single inserts re-encode the open block for every point
+        assert_eq!(outcome.inserted, points.len());
+        assert_eq!(
+            batch.query("cpu", 0, u64::MAX),
+            single.query("cpu", 0, u64::MAX)
+        );
+        assert!(
+            batch_time * 4 < single_time,
+            "batch {:?}, single inserts {:?}",
+            batch_time,
+            single_time
+        );
+    }
+
+    #[test]
+    fn test_write_samples() {
         let mut gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        gorilla.insert("host.cpu", base_time - 60, 10.0);
+        gorilla.insert("host.frozen", base_time - 60, 0.0);
+        gorilla.freeze("host.frozen").unwrap();
 
-        // Use current time
-        let base_time = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let sample = |key, offset, value| Sample {
+            key,
+            timestamp: base_time + offset,
+            value,
+        };
+        let batch = [
+            sample("host.cpu", 0, 11.0),
+            sample("host.mem", 60, 2.0),
+            sample("host.disk", 0, f64::NAN),
+            sample("host.mem", 0, 1.0),
+            sample("host.frozen", 0, 1.0),
+            sample("host.cpu", 0, 12.0),
+            sample("host.disk", 60, 3.0),
+        ];
+        assert_eq!(
+            gorilla.write(&batch),
+            vec![
+                Ok(InsertOutcome::Inserted),
+                Ok(InsertOutcome::CreatedSeries),
+                Err(InsertError::NanValue),
+                Ok(InsertOutcome::Inserted),
+                Err(InsertError::SeriesFrozen),
+                Ok(InsertOutcome::ReplacedDuplicate),
+                Ok(InsertOutcome::CreatedSeries),
+            ]
+        );
 
-        // Insert identical values (should compress to ~1 bit each)
-        for i in 0..100 {
-            gorilla.insert("memory.used", base_time + i * 60, 8192.0);
+        // Each key's samples were applied in input order
+        assert_eq!(
+            gorilla.query("host.cpu", 0, u64::MAX).unwrap(),
+            vec![(base_time - 60, 10.0), (base_time, 12.0)]
+        );
+        assert_eq!(
+            gorilla.query("host.mem", 0, u64::MAX).unwrap(),
+            vec![(base_time, 1.0), (base_time + 60, 2.0)]
+        );
+        assert_eq!(
+            gorilla.query("host.disk", 0, u64::MAX).unwrap(),
+            vec![(base_time + 60, 3.0)]
+        );
+        assert_eq!(gorilla.query("host.frozen", 0, u64::MAX).unwrap().len(), 1);
+
+        let metrics = gorilla.metrics();
+        assert_eq!(metrics.points_inserted, 2 + 5);
+        assert_eq!(metrics.inserts_rejected, 2);
+        assert!(gorilla.write(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_explain_query() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        // Four windows of 120 points; the last one stays open
+        for i in 0..480 {
+            gorilla.insert("cpu", base_time + i * 60, i as f64);
         }
+        // Half a window, then a gap
+        for i in 0..60 {
+            gorilla.insert("sparse", base_time + i * 60, i as f64);
+        }
+        gorilla.insert("sparse", base_time + 7200, 60.0);
 
-        let stats = gorilla.get_stats("memory.used");
-        println!("100 identical values:");
-        println!("  Original: {} bytes", stats.original_size);
-        println!("  Compressed: {} bytes", stats.compressed_size);
-        println!("  Ratio: {:.2}x", stats.compression_ratio);
+        // A narrow range inside the second block
+        let narrow = (base_time + 7200 + 600, base_time + 7200 + 900);
+        let plan = gorilla.explain_query("cpu", narrow.0, narrow.1);
+        assert_eq!(
+            plan,
+            QueryPlan {
+                closed_blocks: 1,
+                open_block: false,
+                estimated_points: 120,
+                pruned_blocks: 0,
+            }
+        );
+        assert!(gorilla.query("cpu", narrow.0, narrow.1).unwrap().len() <= plan.estimated_points);
 
-        // Should achieve very high compression
-        assert!(stats.compression_ratio > 10.0);
+        // Straddling the last closed block and the open one
+        let plan = gorilla.explain_query("cpu", base_time + 3 * 7200 - 60, base_time + 3 * 7200);
+        assert_eq!((plan.closed_blocks, plan.open_block), (1, true));
+        assert_eq!(plan.estimated_points, 240);
+        assert_eq!(gorilla.explain_query("cpu", 0, u64::MAX).closed_blocks, 3);
+
+        // The empty half of the sparse block is pruned by its extent
+        let plan = gorilla.explain_query("sparse", base_time + 5000, base_time + 6000);
+        assert_eq!((plan.closed_blocks, plan.pruned_blocks), (0, 1));
+        assert_eq!(plan.estimated_points, 0);
+
+        assert_eq!(
+            gorilla.explain_query("missing", 0, u64::MAX),
+            QueryPlan::default()
+        );
+    }
+
+    #[test]
+    fn test_contains_and_block_boundaries() {
+        let mut gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        assert!(!gorilla.contains("cpu"));
+        assert!(gorilla.block_boundaries("cpu").is_empty());
+
+        gorilla.insert("cpu", base_time + 60, 1.0);
+        gorilla.insert("cpu", base_time + 7200 + 60, 2.0);
+        assert!(gorilla.contains("cpu"));
+        assert_eq!(
+            gorilla.block_boundaries("cpu"),
+            vec![
+                (base_time, base_time + 7200, false),
+                (base_time + 7200, base_time + 14400, true),
+            ]
+        );
+
+        gorilla.delete("cpu").unwrap();
+        assert!(!gorilla.contains("cpu"));
+    }
+
+    #[test]
+    fn test_max_series_limit() {
+        let config = GorillaConfig {
+            max_series: Some(3),
+            ..GorillaConfig::default()
+        };
+        let mut gorilla = Gorilla::with_config(config).unwrap();
+        let base_time = 7200 * 100;
+
+        let results: Vec<Result<InsertOutcome, InsertError>> = (0..5)
+            .map(|i| gorilla.try_insert(&format!("req.{}", i), base_time, 1.0))
+            .collect();
+        assert!(results[..3].iter().all(|result| result.is_ok()));
+        for result in &results[3..] {
+            assert_eq!(
+                *result,
+                Err(InsertError::CardinalityLimitExceeded {
+                    current: 3,
+                    limit: 3
+                })
+            );
+        }
+        assert!(!gorilla.contains("req.3"));
+
+        // Existing series keep accepting points
+        for i in 0..3 {
+            let key = format!("req.{}", i);
+            assert_eq!(
+                gorilla.try_insert(&key, base_time + 60, 2.0),
+                Ok(InsertOutcome::Inserted)
+            );
+            assert_eq!(gorilla.query(&key, 0, u64::MAX).unwrap().len(), 2);
+        }
+
+        let metrics = gorilla.metrics();
+        assert_eq!(metrics.series, 3);
+        assert_eq!(metrics.series_rejected, 2);
+        assert_eq!(metrics.inserts_rejected, 2);
+
+        // Deleting a series frees room for a new one
+        gorilla.delete("req.0").unwrap();
+        assert_eq!(
+            gorilla.try_insert("req.3", base_time, 1.0),
+            Ok(InsertOutcome::CreatedSeries)
+        );
+        assert_eq!(
+            gorilla.try_insert("req.4", base_time, f64::NAN),
+            Err(InsertError::NanValue)
+        );
     }
 }
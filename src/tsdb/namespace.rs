@@ -0,0 +1,107 @@
+// Per-tenant key namespaces
+//
+// A tenant's series are stored under tenant + '\0' + key, so two tenants
+// can use the same logical key without seeing each other's points. The
+// NUL separator can't appear in a tenant name, which keeps the mapping
+// from (tenant, key) to stored key one-to-one.
+
+use super::{BatchOutcome, Gorilla, InsertError, InsertOutcome, QueryError};
+
+/// Separates the tenant from the logical key in stored keys
+pub const TENANT_SEPARATOR: char = '\0';
+
+/// The stored key for a tenant's logical key
+pub fn tenant_key(tenant: &str, key: &str) -> String {
+    format!("{}{}{}", tenant, TENANT_SEPARATOR, key)
+}
+
+/// The logical key of a stored key, if it belongs to `tenant`
+pub fn strip_tenant<'k>(tenant: &str, stored: &'k str) -> Option<&'k str> {
+    stored.strip_prefix(tenant)?.strip_prefix(TENANT_SEPARATOR)
+}
+
+/// One tenant's view of a Gorilla instance (see Gorilla::namespace)
+///
+/// Keys passed in and returned are the tenant's logical keys.
+pub struct Namespace<'a> {
+    gorilla: &'a Gorilla,
+    tenant: String,
+}
+
+impl<'a> Namespace<'a> {
+    /// Panics if `tenant` contains the NUL separator
+    pub(super) fn new(gorilla: &'a Gorilla, tenant: &str) -> Self {
+        assert!(
+            !tenant.contains(TENANT_SEPARATOR),
+            "tenant names can't contain NUL"
+        );
+        Namespace {
+            gorilla,
+            tenant: tenant.to_string(),
+        }
+    }
+
+    pub fn tenant(&self) -> &str {
+        &self.tenant
+    }
+
+    /// Insert a data point into the tenant's series (see Gorilla::insert)
+    pub fn insert(&self, key: &str, timestamp: u64, value: f64) {
+        self.gorilla.insert(&self.key(key), timestamp, value);
+    }
+
+    /// Insert a data point, reporting what happened to it
+    pub fn try_insert(
+        &self,
+        key: &str,
+        timestamp: u64,
+        value: f64,
+    ) -> Result<InsertOutcome, InsertError> {
+        self.gorilla.try_insert(&self.key(key), timestamp, value)
+    }
+
+    /// Insert several points into one of the tenant's series
+    pub fn insert_batch(
+        &self,
+        key: &str,
+        points: &[(u64, f64)],
+    ) -> Result<BatchOutcome, InsertError> {
+        self.gorilla.insert_batch(&self.key(key), points)
+    }
+
+    /// Query one of the tenant's series (see Gorilla::query)
+    pub fn query(&self, key: &str, start: u64, end: u64) -> Option<Vec<(u64, f64)>> {
+        self.gorilla.query(&self.key(key), start, end)
+    }
+
+    /// Query one of the tenant's series, reporting why it failed
+    ///
+    /// A missing series is reported under its logical key.
+    pub fn try_query(
+        &self,
+        key: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<(u64, f64)>, QueryError> {
+        self.gorilla
+            .try_query(&self.key(key), start, end)
+            .map_err(|err| match err {
+                QueryError::SeriesNotFound(_) => QueryError::SeriesNotFound(key.to_string()),
+                other => other,
+            })
+    }
+
+    /// Whether the tenant has a series under `key`
+    pub fn contains(&self, key: &str) -> bool {
+        self.gorilla.contains(&self.key(key))
+    }
+
+    /// The tenant's series keys in sorted order
+    pub fn list_series(&self) -> Vec<String> {
+        self.gorilla.list_series_in(&self.tenant)
+    }
+
+    fn key(&self, key: &str) -> String {
+        tenant_key(&self.tenant, key)
+    }
+}
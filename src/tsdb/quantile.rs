@@ -0,0 +1,238 @@
+// Quantiles of a range, for Gorilla::quantiles
+//
+// Ranges of up to GorillaConfig::exact_quantile_points points are
+// answered exactly: their values are collected and each quantile picked
+// with select_nth_unstable, interpolating linearly between the two
+// nearest ranks (as numpy's default does). Larger ranges are streamed
+// through one P² estimator per quantile (Jain & Chlamtac, "The P²
+// algorithm for dynamic calculation of quantiles and histograms without
+// storing observations", 1985), which keeps five markers whose heights
+// track the quantile, so memory doesn't grow with the range.
+//
+// P² has no worst-case bound. On the smooth, unimodal data latency series
+// usually hold, its estimate lands within 1% of the target rank (the
+// share of values at or below it is within 0.01 of q); the tests check
+// that on uniform and long-tailed synthetic data. Adversarial orderings,
+// such as sorted or multi-modal data, can do worse.
+
+/// How a quantile was computed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuantileMethod {
+    /// From every value, interpolated between the nearest ranks
+    Exact,
+    /// Streamed through the P² estimator (see quantile.rs for its error)
+    Approximate,
+}
+
+/// The quantiles of a range, in the order they were asked for
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantileResult {
+    pub values: Vec<f64>,
+    pub method: QuantileMethod,
+}
+
+/// Exact quantiles of `values`, which is reordered; None if it is empty
+pub(super) fn exact(values: &mut [f64], qs: &[f64]) -> Option<Vec<f64>> {
+    if values.is_empty() {
+        return None;
+    }
+    let last = values.len() - 1;
+    let quantiles = qs
+        .iter()
+        .map(|&q| {
+            let rank = q * last as f64;
+            let below = rank.floor() as usize;
+            let (_, &mut low, above) = values.select_nth_unstable_by(below, f64::total_cmp);
+            let fraction = rank - below as f64;
+            if fraction == 0.0 {
+                return low;
+            }
+            // Everything after the nth element is at least as large
+            let high = above.iter().copied().fold(f64::INFINITY, f64::min);
+            low + (high - low) * fraction
+        })
+        .collect();
+    Some(quantiles)
+}
+
+/// Streaming estimate of one quantile with the P² algorithm
+#[derive(Debug, Clone)]
+pub(super) struct P2 {
+    q: f64,
+    // Marker heights, and their (1-based) positions among the values seen
+    heights: [f64; 5],
+    positions: [f64; 5],
+    // Where the markers should be, and how far that moves per value
+    desired: [f64; 5],
+    increments: [f64; 5],
+    count: usize,
+}
+
+impl P2 {
+    pub(super) fn new(q: f64) -> Self {
+        P2 {
+            q,
+            heights: [0.0; 5],
+            positions: [1.0, 2.0, 3.0, 4.0, 5.0],
+            desired: [1.0, 1.0 + 2.0 * q, 1.0 + 4.0 * q, 3.0 + 2.0 * q, 5.0],
+            increments: [0.0, q / 2.0, q, (1.0 + q) / 2.0, 1.0],
+            count: 0,
+        }
+    }
+
+    pub(super) fn add(&mut self, value: f64) {
+        if self.count < 5 {
+            self.heights[self.count] = value;
+            self.count += 1;
+            if self.count == 5 {
+                self.heights.sort_unstable_by(f64::total_cmp);
+            }
+            return;
+        }
+        self.count += 1;
+
+        // The cell the value falls in, stretching the ends to fit it
+        let h = &mut self.heights;
+        let cell = if value < h[0] {
+            h[0] = value;
+            0
+        } else if value >= h[4] {
+            h[4] = value;
+            3
+        } else {
+            (1..4).find(|&i| value < h[i]).map_or(3, |i| i - 1)
+        };
+        for position in &mut self.positions[cell + 1..] {
+            *position += 1.0;
+        }
+        for (desired, increment) in self.desired.iter_mut().zip(self.increments) {
+            *desired += increment;
+        }
+
+        // Move the middle markers toward their desired positions
+        for i in 1..4 {
+            let offset = self.desired[i] - self.positions[i];
+            let (gap_after, gap_before) = (
+                self.positions[i + 1] - self.positions[i],
+                self.positions[i - 1] - self.positions[i],
+            );
+            if (offset >= 1.0 && gap_after > 1.0) || (offset <= -1.0 && gap_before < -1.0) {
+                let step = offset.signum();
+                let parabolic = self.parabolic(i, step);
+                self.heights[i] =
+                    if self.heights[i - 1] < parabolic && parabolic < self.heights[i + 1] {
+                        parabolic
+                    } else {
+                        self.linear(i, step)
+                    };
+                self.positions[i] += step;
+            }
+        }
+    }
+
+    /// Piecewise-parabolic prediction of marker `i`'s height moved by `step`
+    fn parabolic(&self, i: usize, step: f64) -> f64 {
+        let (h, n) = (&self.heights, &self.positions);
+        h[i] + step / (n[i + 1] - n[i - 1])
+            * ((n[i] - n[i - 1] + step) * (h[i + 1] - h[i]) / (n[i + 1] - n[i])
+                + (n[i + 1] - n[i] - step) * (h[i] - h[i - 1]) / (n[i] - n[i - 1]))
+    }
+
+    /// Linear prediction, for when the parabola overshoots a neighbour
+    fn linear(&self, i: usize, step: f64) -> f64 {
+        let j = if step > 0.0 { i + 1 } else { i - 1 };
+        let (h, n) = (&self.heights, &self.positions);
+        h[i] + step * (h[j] - h[i]) / (n[j] - n[i])
+    }
+
+    /// The estimate so far; None before any value. Exact until five
+    /// values are in, and for q = 0 or 1 (the extreme markers).
+    pub(super) fn estimate(&self) -> Option<f64> {
+        match self.count {
+            0 => None,
+            1..5 => exact(&mut self.heights[..self.count].to_vec(), &[self.q]).map(|q| q[0]),
+            _ if self.q == 0.0 => Some(self.heights[0]),
+            _ if self.q == 1.0 => Some(self.heights[4]),
+            _ => Some(self.heights[2]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Quantile by linear interpolation on a sorted copy
+    fn reference(values: &[f64], q: f64) -> f64 {
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let rank = q * (sorted.len() - 1) as f64;
+        let (below, above) = (rank.floor() as usize, rank.ceil() as usize);
+        sorted[below] + (sorted[above] - sorted[below]) * (rank - below as f64)
+    }
+
+    /// Deterministic values in [0, 1) (xorshift)
+    fn uniform(n: usize, mut state: u64) -> Vec<f64> {
+        (0..n)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 11) as f64 / (1u64 << 53) as f64
+            })
+            .collect()
+    }
+
+    /// Share of `values` at or below `estimate`
+    fn rank_of(values: &[f64], estimate: f64) -> f64 {
+        values.iter().filter(|&&value| value <= estimate).count() as f64 / values.len() as f64
+    }
+
+    const QS: [f64; 7] = [0.0, 0.1, 0.5, 0.9, 0.95, 0.99, 1.0];
+
+    #[test]
+    fn test_exact_matches_sorted_reference() {
+        for n in [1, 2, 5, 10, 101, 1000] {
+            let values: Vec<f64> = uniform(n, 7 + n as u64)
+                .into_iter()
+                .map(|value| (value * 100.0).round())
+                .collect();
+            let quantiles = exact(&mut values.clone(), &QS).unwrap();
+            for (&q, &quantile) in QS.iter().zip(&quantiles) {
+                assert_eq!(quantile, reference(&values, q), "n {} q {}", n, q);
+            }
+        }
+        assert_eq!(exact(&mut [], &[0.5]), None);
+        assert_eq!(exact(&mut [1.0, 2.0, 3.0, 4.0], &[0.5]), Some(vec![2.5]));
+    }
+
+    #[test]
+    fn test_p2_stays_within_one_percent_of_rank() {
+        let uniform = uniform(100_000, 42);
+        // Long-tailed, like latencies: exponential with a 20ms mean
+        let latencies: Vec<f64> = uniform.iter().map(|&u| -20.0 * (1.0 - u).ln()).collect();
+        for values in [&uniform, &latencies] {
+            for q in QS {
+                let mut estimator = P2::new(q);
+                values.iter().for_each(|&value| estimator.add(value));
+                let estimate = estimator.estimate().unwrap();
+                let rank = rank_of(values, estimate);
+                assert!((rank - q).abs() <= 0.01, "q {} ranked {}", q, rank);
+            }
+        }
+    }
+
+    #[test]
+    fn test_p2_few_values() {
+        let mut estimator = P2::new(0.5);
+        assert_eq!(estimator.estimate(), None);
+        for value in [4.0, 1.0, 3.0] {
+            estimator.add(value);
+        }
+        assert_eq!(estimator.estimate(), Some(3.0));
+        for value in [2.0, 5.0, 6.0] {
+            estimator.add(value);
+        }
+        assert_eq!(estimator.count, 6);
+    }
+}
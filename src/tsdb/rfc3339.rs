@@ -0,0 +1,139 @@
+// RFC 3339 timestamps, for Gorilla::insert_rfc3339
+//
+// Accepts `YYYY-MM-DDTHH:MM:SS`, an optional fraction, then `Z` or a
+// `+HH:MM` / `-HH:MM` offset (`t`, `z` and a space separator are also
+// allowed, as RFC 3339 permits). The engine stores whole seconds, so
+// fractions are truncated; a leap second (`:60`) reads as the second
+// after `:59`. Instants before the UNIX epoch are refused.
+
+/// Seconds since the UNIX epoch of an RFC 3339 timestamp, or None if it
+/// is malformed or before the epoch
+pub(super) fn parse(text: &str) -> Option<u64> {
+    let bytes = text.as_bytes();
+    if bytes.len() < 20
+        || bytes[4] != b'-'
+        || bytes[7] != b'-'
+        || !matches!(bytes[10], b'T' | b't' | b' ')
+        || bytes[13] != b':'
+        || bytes[16] != b':'
+    {
+        return None;
+    }
+    let year = digits(&bytes[0..4])?;
+    let month = digits(&bytes[5..7])?;
+    let day = digits(&bytes[8..10])?;
+    let hour = digits(&bytes[11..13])?;
+    let minute = digits(&bytes[14..16])?;
+    let second = digits(&bytes[17..19])?;
+    if !(1..=12).contains(&month)
+        || day == 0
+        || day > days_in_month(year, month)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    // Fraction, then the offset
+    let mut rest = &bytes[19..];
+    if let Some(fraction) = rest.strip_prefix(b".") {
+        let len = fraction.iter().take_while(|b| b.is_ascii_digit()).count();
+        if len == 0 {
+            return None;
+        }
+        rest = &fraction[len..];
+    }
+    let offset = match rest {
+        [b'Z' | b'z'] => 0,
+        [sign @ (b'+' | b'-'), h1, h2, b':', m1, m2] => {
+            let (hours, minutes) = (digits(&[*h1, *h2])?, digits(&[*m1, *m2])?);
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            let offset = (hours * 3600 + minutes * 60) as i64;
+            if *sign == b'+' { offset } else { -offset }
+        }
+        _ => return None,
+    };
+
+    let local =
+        days_from_epoch(year, month, day) * 86400 + (hour * 3600 + minute * 60 + second) as i64;
+    u64::try_from(local - offset).ok()
+}
+
+/// A run of ASCII digits as a number
+fn digits(bytes: &[u8]) -> Option<u32> {
+    bytes.iter().try_fold(0, |number, &b| {
+        b.is_ascii_digit().then(|| number * 10 + (b - b'0') as u32)
+    })
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => {
+            29
+        }
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days from 1970-01-01 to a proleptic Gregorian date (Howard Hinnant's
+/// days_from_civil)
+fn days_from_epoch(year: u32, month: u32, day: u32) -> i64 {
+    // Years starting in March, so the leap day ends the year
+    let year = year as i64 - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse("2021-01-01T00:00:00Z"), Some(1_609_459_200));
+        assert_eq!(parse("2000-02-29T12:30:45Z"), Some(951_827_445));
+        assert_eq!(parse("2023-11-14T22:13:20Z"), Some(1_700_000_000));
+        // Offsets, fractions (truncated) and the lenient forms
+        assert_eq!(parse("2021-01-01T05:30:00+05:30"), Some(1_609_459_200));
+        assert_eq!(parse("2020-12-31T19:00:00-05:00"), Some(1_609_459_200));
+        assert_eq!(parse("2021-01-01T00:00:00.999Z"), Some(1_609_459_200));
+        assert_eq!(parse("2021-01-01t00:00:00z"), Some(1_609_459_200));
+        assert_eq!(parse("2021-01-01 00:00:00Z"), Some(1_609_459_200));
+        assert_eq!(parse("2016-12-31T23:59:60Z"), Some(1_483_228_800));
+    }
+
+    #[test]
+    fn test_malformed() {
+        for text in [
+            "",
+            "2021-01-01",
+            "2021-01-01T00:00:00",
+            "2021-01-01T00:00Z",
+            "2021-13-01T00:00:00Z",
+            "2021-02-29T00:00:00Z",
+            "1900-02-29T00:00:00Z",
+            "2021-01-01T24:00:00Z",
+            "2021-01-01T00:00:00.Z",
+            "2021-01-01T00:00:00+0530",
+            "2021-01-01T00:00:00+24:00",
+            "2021-01-01T00:00:00Z ",
+            "2021-01-0aT00:00:00Z",
+            "+021-01-01T00:00:00Z",
+            // Before the epoch
+            "1969-12-31T23:59:59Z",
+            "1970-01-01T00:00:00+00:01",
+        ] {
+            assert_eq!(parse(text), None, "{:?}", text);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+// Reservoir sampling of a series, for approximate analytics
+//
+// Algorithm R: the first k points fill the reservoir, then point i (from
+// 0) replaces a random slot with probability k / (i + 1). One pass over
+// the range, O(k) memory, and every point is equally likely to end up in
+// the sample. The random numbers come from SplitMix64 seeded by the
+// caller, so a seed always picks the same sample of the same data.
+
+use super::Gorilla;
+
+/// Small seedable generator (SplitMix64); not for cryptographic use
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, n), by multiply-shift
+    fn below(&mut self, n: u64) -> u64 {
+        ((self.next_u64() as u128 * n as u128) >> 64) as u64
+    }
+}
+
+impl Gorilla {
+    /// A uniform random sample of up to `k` points in [start, end]
+    ///
+    /// Returns min(k, points in range) points in timestamp order; the
+    /// same seed gives the same sample. Empty if the series doesn't
+    /// exist.
+    pub fn sample(&self, key: &str, start: u64, end: u64, k: usize, seed: u64) -> Vec<(u64, f64)> {
+        let Some(series) = self.get_queried(key) else {
+            return Vec::new();
+        };
+        let mut rng = SplitMix64(seed);
+        let mut reservoir = Vec::with_capacity(k);
+        for (seen, point) in series.read().iter_range(start, end).enumerate() {
+            if reservoir.len() < k {
+                reservoir.push(point);
+            } else {
+                let slot = rng.below(seen as u64 + 1) as usize;
+                if slot < k {
+                    reservoir[slot] = point;
+                }
+            }
+        }
+        reservoir.sort_by_key(|point| point.timestamp);
+        reservoir.into_iter().map(Into::into).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample() {
+        let gorilla = Gorilla::new();
+        let base_time = 7200 * 100;
+        for i in 0..1000 {
+            gorilla.insert("cpu", base_time + i * 60, i as f64);
+        }
+
+        let sample = gorilla.sample("cpu", 0, u64::MAX, 50, 42);
+        assert_eq!(sample.len(), 50);
+        assert_eq!(sample, gorilla.sample("cpu", 0, u64::MAX, 50, 42));
+        assert_ne!(sample, gorilla.sample("cpu", 0, u64::MAX, 50, 43));
+        assert!(sample.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(
+            sample
+                .iter()
+                .all(|&(t, v)| v == ((t - base_time) / 60) as f64)
+        );
+
+        // The sample spreads over the whole range rather than its start
+        assert!(sample.iter().any(|&(t, _)| t >= base_time + 500 * 60));
+
+        // Fewer points than k: all of them
+        let all = gorilla.query("cpu", base_time, base_time + 9 * 60).unwrap();
+        assert_eq!(
+            gorilla.sample("cpu", base_time, base_time + 9 * 60, 50, 42),
+            all
+        );
+        assert!(gorilla.sample("cpu", 0, u64::MAX, 0, 42).is_empty());
+        assert!(gorilla.sample("mem", 0, u64::MAX, 50, 42).is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+// Smoothing transforms for Gorilla::smooth
+//
+// Every input point gets one output point at its timestamp, except those
+// in the warm-up (see WarmUp). Points are consumed one at a time from the
+// series' lazy iterator; moving averages keep only their window's points
+// and a running sum, and EWMA a single value, so besides the output a
+// smoothed query holds no more than one window.
+
+use crate::storage::DataPoint;
+use std::collections::VecDeque;
+
+/// How Gorilla::smooth smooths a series
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Smoothing {
+    /// Mean of the last `window_points` points, the current one included;
+    /// warms up over the first `window_points - 1`
+    MovingAvg { window_points: usize },
+    /// Mean of the points in (t - window_secs, t]; warms up over the
+    /// points less than `window_secs` after the first, whose window
+    /// reaches back before the data
+    MovingAvgTime { window_secs: u64 },
+    /// `alpha * value + (1 - alpha) * previous`, seeded with the first
+    /// value; warms up over the first `2 / alpha - 2` points (rounded
+    /// up), the window of a moving average with the same mean age
+    Ewma { alpha: f64 },
+}
+
+/// What Gorilla::smooth emits for points whose window isn't full yet
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WarmUp {
+    /// Nothing: the output starts at the first full window
+    #[default]
+    Skip,
+    /// The average over what the window holds so far
+    Partial,
+}
+
+/// Smooth time-ordered points
+///
+/// NaN values are skipped. Panics on a zero window, or an alpha outside
+/// (0, 1].
+pub(super) fn smooth(
+    points: impl Iterator<Item = DataPoint>,
+    smoothing: Smoothing,
+    warm_up: WarmUp,
+) -> Vec<(u64, f64)> {
+    match smoothing {
+        Smoothing::MovingAvg { window_points } => {
+            assert!(window_points > 0, "smoothing window must be positive")
+        }
+        Smoothing::MovingAvgTime { window_secs } => {
+            assert!(window_secs > 0, "smoothing window must be positive")
+        }
+        Smoothing::Ewma { alpha } => {
+            assert!(alpha > 0.0 && alpha <= 1.0, "EWMA alpha must be in (0, 1]")
+        }
+    }
+
+    let mut smoothed = Vec::new();
+    // Moving averages: the window's points and their sum
+    let mut window: VecDeque<(u64, f64)> = VecDeque::new();
+    let mut sum = 0.0;
+    let mut ewma: Option<f64> = None;
+    let mut first: Option<u64> = None;
+    let mut seen = 0;
+    for point in points.filter(|point| !point.value.is_nan()) {
+        let first = *first.get_or_insert(point.timestamp);
+        seen += 1;
+        let (value, full) = match smoothing {
+            Smoothing::MovingAvg { window_points } => {
+                window.push_back((point.timestamp, point.value));
+                sum += point.value;
+                if window.len() > window_points
+                    && let Some((_, old)) = window.pop_front()
+                {
+                    sum -= old;
+                }
+                (sum / window.len() as f64, window.len() == window_points)
+            }
+            Smoothing::MovingAvgTime { window_secs } => {
+                window.push_back((point.timestamp, point.value));
+                sum += point.value;
+                while let Some(&(timestamp, old)) = window.front()
+                    && timestamp.saturating_add(window_secs) <= point.timestamp
+                {
+                    window.pop_front();
+                    sum -= old;
+                }
+                let full = point.timestamp - first >= window_secs;
+                (sum / window.len() as f64, full)
+            }
+            Smoothing::Ewma { alpha } => {
+                let value = ewma.map_or(point.value, |prev| {
+                    alpha * point.value + (1.0 - alpha) * prev
+                });
+                ewma = Some(value);
+                (value, seen > ewma_warm_up(alpha))
+            }
+        };
+        if full || warm_up == WarmUp::Partial {
+            smoothed.push((point.timestamp, value));
+        }
+    }
+    smoothed
+}
+
+/// Points an EWMA warms up over: one less than the window of the moving
+/// average whose points have the same mean age, 2 / alpha - 1
+fn ewma_warm_up(alpha: f64) -> usize {
+    (2.0 / alpha - 2.0).ceil() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Irregularly spaced points with uneven values
+    fn points() -> Vec<DataPoint> {
+        (0..50u64)
+            .map(|i| DataPoint {
+                timestamp: 1000 + i * 10 + i % 3 * 4,
+                value: (i * 7 % 11) as f64 * 1.5 - 4.0,
+            })
+            .collect()
+    }
+
+    fn mean(values: &[f64]) -> f64 {
+        values.iter().sum::<f64>() / values.len() as f64
+    }
+
+    fn assert_close(actual: &[(u64, f64)], expected: &[(u64, f64)]) {
+        assert_eq!(actual.len(), expected.len());
+        for (&(t1, v1), &(t2, v2)) in actual.iter().zip(expected) {
+            assert_eq!(t1, t2);
+            assert!((v1 - v2).abs() < 1e-9, "{} vs {} at {}", v1, v2, t1);
+        }
+    }
+
+    /// Moving average by point count, recomputed for every point
+    fn reference_points(points: &[DataPoint], window: usize) -> Vec<(u64, f64)> {
+        let values: Vec<f64> = points.iter().map(|point| point.value).collect();
+        (0..points.len())
+            .map(|i| {
+                let from = (i + 1).saturating_sub(window);
+                (points[i].timestamp, mean(&values[from..=i]))
+            })
+            .collect()
+    }
+
+    /// Moving average by time, recomputed for every point
+    fn reference_time(points: &[DataPoint], window: u64) -> Vec<(u64, f64)> {
+        points
+            .iter()
+            .map(|point| {
+                let values: Vec<f64> = points
+                    .iter()
+                    .filter(|other| {
+                        other.timestamp <= point.timestamp
+                            && other.timestamp + window > point.timestamp
+                    })
+                    .map(|other| other.value)
+                    .collect();
+                (point.timestamp, mean(&values))
+            })
+            .collect()
+    }
+
+    fn reference_ewma(points: &[DataPoint], alpha: f64) -> Vec<(u64, f64)> {
+        let mut ewma = points[0].value;
+        points
+            .iter()
+            .map(|point| {
+                ewma = alpha * point.value + (1.0 - alpha) * ewma;
+                (point.timestamp, ewma)
+            })
+            .collect()
+    }
+
+    fn run(points: &[DataPoint], smoothing: Smoothing, warm_up: WarmUp) -> Vec<(u64, f64)> {
+        smooth(points.iter().copied(), smoothing, warm_up)
+    }
+
+    #[test]
+    fn test_moving_averages() {
+        let points = points();
+        let by_points = Smoothing::MovingAvg { window_points: 5 };
+        let expected = reference_points(&points, 5);
+        assert_close(&run(&points, by_points, WarmUp::Partial), &expected);
+        assert_close(&run(&points, by_points, WarmUp::Skip), &expected[4..]);
+
+        // The first full window is at the first point 35s or more after
+        // the first one, 1000
+        let by_time = Smoothing::MovingAvgTime { window_secs: 35 };
+        let expected = reference_time(&points, 35);
+        assert_close(&run(&points, by_time, WarmUp::Partial), &expected);
+        let skipped = run(&points, by_time, WarmUp::Skip);
+        assert_eq!(skipped[0].0, 1044);
+        assert_close(&skipped, &expected[4..]);
+    }
+
+    #[test]
+    fn test_ewma() {
+        let points = points();
+        for (alpha, warm_up) in [(0.5, 2), (0.3, 5), (1.0, 0)] {
+            let ewma = Smoothing::Ewma { alpha };
+            let expected = reference_ewma(&points, alpha);
+            assert_close(&run(&points, ewma, WarmUp::Partial), &expected);
+            assert_close(&run(&points, ewma, WarmUp::Skip), &expected[warm_up..]);
+        }
+        // alpha 1 follows the data exactly
+        let ewma = run(&points, Smoothing::Ewma { alpha: 1.0 }, WarmUp::Skip);
+        assert!(ewma.iter().zip(&points).all(|(&(_, v), p)| v == p.value));
+    }
+
+    #[test]
+    fn test_windows_larger_than_the_data() {
+        let points = points();
+        let by_points = Smoothing::MovingAvg { window_points: 100 };
+        let by_time = Smoothing::MovingAvgTime {
+            window_secs: 10_000,
+        };
+        assert!(run(&points, by_points, WarmUp::Skip).is_empty());
+        assert!(run(&points, by_time, WarmUp::Skip).is_empty());
+        // Partial windows: the running mean of everything so far
+        let running = reference_points(&points, points.len());
+        assert_close(&run(&points, by_points, WarmUp::Partial), &running);
+        assert_close(&run(&points, by_time, WarmUp::Partial), &running);
+
+        assert!(run(&[], by_points, WarmUp::Partial).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "alpha")]
+    fn test_bad_alpha() {
+        run(&points(), Smoothing::Ewma { alpha: 0.0 }, WarmUp::Skip);
+    }
+}
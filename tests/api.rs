@@ -0,0 +1,193 @@
+// The library's public API, used the way a dependent crate would use it
+//
+// Everything here goes through `tsdb::...` paths only; tests of the
+// internals live next to them in src/.
+
+use std::sync::Arc;
+use tsdb::compression::stream::{decode_range, encode_range};
+use tsdb::compression::timestamp::{TimestampCompressor, TimestampDecompressor};
+use tsdb::compression::value::{ValueCompressor, ValueDecompressor};
+use tsdb::compression::{BitReader, BitWriter};
+use tsdb::storage::clock::TestClock;
+use tsdb::{
+    DataPoint, DuplicatePolicy, Gorilla, GorillaConfig, InsertError, QueryError, SeriesOptions,
+    TsdbError,
+};
+
+const BASE_TIME: u64 = 7200 * 100;
+
+fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("tsdb_api_{}_{}", std::process::id(), name))
+}
+
+#[test]
+fn test_insert_query_and_stats() {
+    let gorilla = Gorilla::new();
+    for i in 0..100 {
+        gorilla.insert("server1.cpu", BASE_TIME + i * 60, 40.0 + (i % 7) as f64);
+    }
+
+    let points = gorilla
+        .query("server1.cpu", BASE_TIME, BASE_TIME + 120)
+        .unwrap();
+    assert_eq!(
+        points,
+        vec![
+            (BASE_TIME, 40.0),
+            (BASE_TIME + 60, 41.0),
+            (BASE_TIME + 120, 42.0)
+        ]
+    );
+    assert!(gorilla.query("server1.mem", 0, u64::MAX).is_none());
+    assert!(gorilla.contains("server1.cpu"));
+
+    let stats = gorilla.get_stats("server1.cpu");
+    assert_eq!(stats.logical_uncompressed_bytes, 100 * 16);
+    assert!(stats.compressed_size < stats.logical_uncompressed_bytes);
+    assert_eq!(gorilla.metrics().points_inserted, 100);
+}
+
+#[test]
+fn test_errors() {
+    let gorilla = Gorilla::with_config(GorillaConfig {
+        clock: Arc::new(TestClock::new(BASE_TIME)),
+        ..GorillaConfig::default()
+    })
+    .unwrap();
+    assert!(matches!(
+        gorilla.try_insert("cpu", BASE_TIME + 86400, 1.0),
+        Err(InsertError::TimestampTooFarInFuture { .. })
+    ));
+    assert_eq!(
+        gorilla.try_insert("cpu", BASE_TIME, f64::NAN),
+        Err(InsertError::NanValue)
+    );
+
+    gorilla
+        .create_series(
+            "cpu",
+            SeriesOptions {
+                duplicate_policy: DuplicatePolicy::Reject,
+                ..SeriesOptions::default()
+            },
+        )
+        .unwrap();
+    gorilla.try_insert("cpu", BASE_TIME, 1.0).unwrap();
+    assert_eq!(
+        gorilla.try_insert("cpu", BASE_TIME, 2.0),
+        Err(InsertError::DuplicateRejected {
+            timestamp: BASE_TIME
+        })
+    );
+    assert_eq!(
+        gorilla.create_series("cpu", SeriesOptions::default()),
+        Err(TsdbError::SeriesExists("cpu".to_string()))
+    );
+
+    let error: &dyn std::error::Error = &QueryError::SeriesNotFound("mem".to_string());
+    assert!(error.to_string().contains("mem"));
+}
+
+#[test]
+fn test_series_are_read_through_handles() {
+    let gorilla = Gorilla::new();
+    for i in 0..10 {
+        gorilla.insert("a", BASE_TIME + i * 60, i as f64);
+        gorilla.insert("b", BASE_TIME + i * 60, -(i as f64));
+    }
+
+    let mut keys: Vec<String> = gorilla
+        .series_iter()
+        .map(|handle| handle.read().key.to_string())
+        .collect();
+    keys.sort();
+    assert_eq!(keys, ["a", "b"]);
+
+    let handle = gorilla
+        .series_iter()
+        .find(|handle| &*handle.read().key == "a")
+        .unwrap();
+    let series = handle.read();
+    let points: Vec<DataPoint> = series.iter_range(BASE_TIME, BASE_TIME + 60).collect();
+    assert_eq!(points.len(), 2);
+    assert_eq!(
+        (points[1].timestamp, points[1].value),
+        (BASE_TIME + 60, 1.0)
+    );
+
+    let mut count = 0;
+    gorilla.scan(|_key, _ts, _val| count += 1);
+    assert_eq!(count, 20);
+}
+
+#[test]
+fn test_delete_and_correlate() {
+    let mut gorilla = Gorilla::new();
+    for i in 0..10 {
+        let time = BASE_TIME + i * 60;
+        gorilla.insert("web01.cpu", time, 50.0 + i as f64 * 2.0);
+        gorilla.insert("web01.latency", time, 100.0 + i as f64 * 5.0);
+        gorilla.insert("web01.idle", time, 50.0 - i as f64 * 2.0);
+    }
+
+    let correlated = gorilla.find_correlated("web01.cpu", BASE_TIME, BASE_TIME + 600, 5);
+    assert_eq!(correlated.len(), 2);
+    assert!(correlated.iter().all(|(_, corr)| corr.abs() > 0.99));
+
+    gorilla.delete("web01.latency").unwrap();
+    assert!(gorilla.query("web01.latency", 0, u64::MAX).is_none());
+    assert_eq!(
+        gorilla
+            .find_correlated("web01.cpu", BASE_TIME, BASE_TIME + 600, 5)
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn test_snapshot_round_trip() {
+    let gorilla = Gorilla::new();
+    for i in 0..500 {
+        gorilla.insert("cpu", BASE_TIME + i * 60, i as f64 * 0.5);
+    }
+
+    let path = temp_path("snapshot.snap");
+    gorilla.snapshot(&path).unwrap();
+    let loaded = Gorilla::load(&path).unwrap();
+    assert_eq!(
+        loaded.query("cpu", 0, u64::MAX),
+        gorilla.query("cpu", 0, u64::MAX)
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_compression_primitives() {
+    let points: Vec<(u64, f64)> = (0..100)
+        .map(|i| (BASE_TIME + i * 60 + i % 2, 20.0 + (i % 5) as f64 * 0.25))
+        .collect();
+
+    let bytes = encode_range(&points).unwrap();
+    assert!(bytes.len() < points.len() * 16 / 4);
+    assert_eq!(decode_range(&bytes).unwrap(), points);
+    assert!(decode_range(&bytes[..bytes.len() / 2]).is_err());
+
+    // The codecs on their own, over one bit stream
+    let (first_timestamp, first_value) = points[0];
+    let mut writer = BitWriter::new();
+    let mut timestamps = TimestampCompressor::new(first_timestamp);
+    let mut values = ValueCompressor::new(first_value);
+    for &(timestamp, value) in &points[1..] {
+        timestamps.add_timestamp(&mut writer, timestamp);
+        values.add_value(&mut writer, value);
+    }
+    let buffer = writer.finish();
+
+    let mut reader = BitReader::new(&buffer);
+    let mut timestamps = TimestampDecompressor::new(first_timestamp);
+    let mut values = ValueDecompressor::new(first_value);
+    for &(timestamp, value) in &points[1..] {
+        assert_eq!(timestamps.next_timestamp(&mut reader), Some(timestamp));
+        assert_eq!(values.next_value(&mut reader), Some(value));
+    }
+}
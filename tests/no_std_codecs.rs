@@ -0,0 +1,81 @@
+// Builds the compression codecs in a #![no_std] crate and round-trips a
+// series through them, so a std dependency creeping into
+// src/compression fails the test build
+//
+// The codecs are compiled from their sources, since the library this test
+// links against is built with the default features (std). Runs without
+// the test harness, which needs std; std is only linked from main, for
+// the runtime and panic handler.
+
+#![no_std]
+
+extern crate alloc;
+
+// Most of the API goes unused here, as do the unit-test modules: cfg(test)
+// is set, but without the harness their #[test] functions are dropped
+#[allow(unused)]
+#[path = "../src/compression/mod.rs"]
+mod compression;
+
+// stream reports errors as crate::tsdb::TsdbError
+#[allow(unused)]
+#[path = "../src/tsdb/error.rs"]
+mod error;
+
+mod tsdb {
+    pub use super::error::TsdbError;
+}
+
+use alloc::vec::Vec;
+use compression::stream::{decode_range, encode_range};
+use compression::timestamp::{TimestampCompressor, TimestampDecompressor};
+use compression::value::{ValueCompressor, ValueDecompressor};
+use compression::{BitReader, BitWriter};
+
+/// Sensor readings every ~10s with a little jitter
+fn readings() -> Vec<(u64, f64)> {
+    (0..500u64)
+        .map(|i| {
+            (
+                1_700_000_000 + i * 10 + i % 3,
+                20.0 + (i % 17) as f64 * 0.25,
+            )
+        })
+        .collect()
+}
+
+fn round_trip_codecs(points: &[(u64, f64)]) {
+    let (first_timestamp, first_value) = points[0];
+    let mut writer = BitWriter::new();
+    let mut timestamps = TimestampCompressor::new(first_timestamp);
+    let mut values = ValueCompressor::new(first_value);
+    for &(timestamp, value) in &points[1..] {
+        timestamps.add_timestamp(&mut writer, timestamp);
+        values.add_value(&mut writer, value);
+    }
+    let bytes = writer.finish();
+    assert!(
+        bytes.len() < points.len() * 16 / 4,
+        "compresses at least 4x"
+    );
+
+    let mut reader = BitReader::new(&bytes);
+    let mut timestamps = TimestampDecompressor::new(first_timestamp);
+    let mut values = ValueDecompressor::new(first_value);
+    for &(timestamp, value) in &points[1..] {
+        assert_eq!(timestamps.next_timestamp(&mut reader), Some(timestamp));
+        assert_eq!(values.next_value(&mut reader), Some(value));
+    }
+}
+
+fn main() {
+    extern crate std;
+
+    let points = readings();
+    round_trip_codecs(&points);
+
+    let stream = encode_range(&points).unwrap();
+    assert_eq!(decode_range(&stream).unwrap(), points);
+
+    std::println!("no_std codecs: round-tripped {} points", points.len());
+}